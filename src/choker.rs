@@ -0,0 +1,193 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+// Tit-for-tat choking (BEP 3).
+// Every rechoke the interested peers that transfer the fastest get the
+// regular upload slots: the peers we download from fastest while
+// downloading, the peers we upload to fastest while seeding. Peers that
+// stopped sending us data are snubbed and only get the optimistic slot.
+// One more interested peer is unchoked optimistically regardless of its
+// rate, rotated every third rechoke, so new peers get a chance to show
+// their rate. Newly connected peers are three times as likely to be picked.
+
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+// a downloading peer is snubbed if it sent no block for this long
+pub const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+// peers connected for less than this count as new for the optimistic unchoke
+pub const NEW_PEER_AGE: Duration = Duration::from_secs(60);
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+// the optimistic unchoke rotates every 30 seconds
+const OPTIMISTIC_ROUNDS: u32 = 3;
+const NEW_PEER_WEIGHT: u32 = 3;
+
+/**
+ * Upload slots for an upload limit in bytes per second: few slots on a slow
+ * link so that each unchoked peer gets a useful rate.
+ */
+pub fn upload_slots_for_rate(rate: Option<u64>) -> usize {
+    let kib = match rate {
+        Some(r) => r as f64 / 1024.0,
+        None => return DEFAULT_UPLOAD_SLOTS,
+    };
+    if kib < 9.0 {
+        2
+    } else if kib < 15.0 {
+        3
+    } else if kib < 42.0 {
+        4
+    } else {
+        (kib * 0.6).sqrt() as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ChokeCandidate {
+    pub peer: usize,
+    pub interested: bool,
+    // bytes per second, compared between peers only
+    pub rate: f64,
+    pub snubbed: bool,
+    pub new: bool,
+}
+
+pub struct Choker {
+    slots: usize,
+    optimistic: Option<usize>,
+    round: u32,
+}
+
+impl Choker {
+    pub fn new(slots: usize) -> Self {
+        Choker {
+            slots,
+            optimistic: None,
+            round: 0,
+        }
+    }
+
+    pub fn slots(&self) -> usize {
+        self.slots
+    }
+
+    pub fn set_slots(&mut self, slots: usize) {
+        self.slots = slots;
+    }
+
+    /**
+     * Pick the peers to unchoke among the connected `candidates`.
+     */
+    pub fn rechoke<R: Rng>(&mut self, candidates: &[ChokeCandidate], rng: &mut R) -> Vec<usize> {
+        let mut ranked: Vec<&ChokeCandidate> = candidates
+            .iter()
+            .filter(|c| c.interested && !c.snubbed)
+            .collect();
+        ranked.sort_by(|a, b| b.rate.total_cmp(&a.rate).then(a.peer.cmp(&b.peer)));
+        let mut unchoked: Vec<usize> = ranked.iter().take(self.slots).map(|c| c.peer).collect();
+
+        // rotate on schedule, or early if the optimistic peer left, lost
+        // interest or earned a regular slot
+        let optimistic_valid = self.optimistic.is_some_and(|o| {
+            !unchoked.contains(&o) && candidates.iter().any(|c| c.peer == o && c.interested)
+        });
+        if self.round.is_multiple_of(OPTIMISTIC_ROUNDS) || !optimistic_valid {
+            self.optimistic = self.pick_optimistic(candidates, &unchoked, rng);
+        }
+        self.round += 1;
+        if let Some(o) = self.optimistic {
+            unchoked.push(o);
+        }
+        unchoked
+    }
+
+    fn pick_optimistic<R: Rng>(
+        &self,
+        candidates: &[ChokeCandidate],
+        unchoked: &[usize],
+        rng: &mut R,
+    ) -> Option<usize> {
+        let choked: Vec<&ChokeCandidate> = candidates
+            .iter()
+            .filter(|c| c.interested && !unchoked.contains(&c.peer))
+            .collect();
+        let weight = |c: &ChokeCandidate| if c.new { NEW_PEER_WEIGHT } else { 1 };
+        let total: u32 = choked.iter().map(|c| weight(c)).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut target = rng.gen_range(0..total);
+        for c in choked {
+            if target < weight(c) {
+                return Some(c.peer);
+            }
+            target -= weight(c);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn candidate(peer: usize, rate: f64) -> ChokeCandidate {
+        ChokeCandidate {
+            peer,
+            interested: true,
+            rate,
+            snubbed: false,
+            new: false,
+        }
+    }
+
+    #[test]
+    fn unchoke_fastest_and_optimistic() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut choker = Choker::new(2);
+        let mut candidates = vec![
+            candidate(0, 10.0),
+            candidate(1, 50.0),
+            candidate(2, 30.0),
+            candidate(3, 40.0),
+            candidate(4, 0.0),
+        ];
+        candidates[1].snubbed = true;
+        candidates[4].interested = false;
+
+        let unchoked = choker.rechoke(&candidates, &mut rng);
+        assert_eq!(unchoked[..2], [3, 2]);
+        assert_eq!(unchoked.len(), 3);
+        let optimistic = unchoked[2];
+        // snubbed peers may still be unchoked optimistically
+        assert!([0, 1].contains(&optimistic));
+
+        // kept for three rounds
+        for _ in 0..2 {
+            assert_eq!(choker.rechoke(&candidates, &mut rng)[2], optimistic);
+        }
+    }
+
+    #[test]
+    fn optimistic_prefers_new_peers() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut choker = Choker::new(0);
+        let mut candidates: Vec<ChokeCandidate> = (0..4).map(|i| candidate(i, 0.0)).collect();
+        candidates[3].new = true;
+        let mut picked = [0; 4];
+        for _ in 0..600 {
+            choker.round = 0;
+            picked[choker.rechoke(&candidates, &mut rng)[0]] += 1;
+        }
+        // the new peer has half the weight
+        assert!(picked[3] > 240 && picked[3] < 360, "{:?}", picked);
+    }
+
+    #[test]
+    fn slots_from_upload_limit() {
+        assert_eq!(upload_slots_for_rate(None), DEFAULT_UPLOAD_SLOTS);
+        assert_eq!(upload_slots_for_rate(Some(4 * 1024)), 2);
+        assert_eq!(upload_slots_for_rate(Some(20 * 1024)), 4);
+        assert_eq!(upload_slots_for_rate(Some(1000 * 1024)), 24);
+    }
+}
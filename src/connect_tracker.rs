@@ -1,18 +1,33 @@
 pub mod tracker {
     use reqwest::{self};
+    use socket2::{Domain, Socket, Type};
     pub use std::fmt::Display;
-    use std::{borrow::Borrow, error::Error, str::from_utf8, u8, vec};
+    use std::{
+        error::Error,
+        net::{IpAddr, SocketAddr},
+        sync::Arc,
+        vec,
+    };
     use tokio::{
-        io::{AsyncReadExt, AsyncWriteExt},
-        net::{TcpStream, TcpListener},
+        io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+        net::{TcpListener, TcpStream},
     };
     use url::form_urlencoded::byte_serialize;
 
-    use crate::parse_tracker_res::peers::PeerList;
+    use crate::{
+        mse::{self, EncryptionMode, PeerStream},
+        proxy::Proxy,
+        utp::{Transport, Utp, UtpStream},
+    };
+
+    pub const LISTENING_PORT: i32 = 6800;
 
-    const LISTENING_PORT: i32 = 6800;
+    // largest message length accepted from a peer: the bitfield of a torrent
+    // with 2^21 pieces, which also fits a 16 KiB block and its 13 byte header
+    pub const MAX_MESSAGE: usize = (1 << 21) / 8 + 1;
 
-    enum Event {
+    #[derive(Clone, Copy)]
+    pub enum Event {
         Started,
         Stopped,
         Completed,
@@ -28,28 +43,56 @@ pub mod tracker {
         }
     }
 
+    #[derive(Clone)]
     pub struct AnnounceURL {
         url: String,
         peer_id: String,
         port: i32,
-        uploaded: i32,
-        downloaded: i32,
-        left: i32,
-        event: Event,
+        uploaded: i64,
+        downloaded: i64,
+        left: i64,
+        // omitted on the regular announces between start and stop
+        event: Option<Event>,
+        proxy: Option<Proxy>,
     }
 
     impl AnnounceURL {
-        pub fn new(url: String, peer_id: String, left: i32) -> AnnounceURL {
+        pub fn new(url: String, peer_id: String, left: i64) -> AnnounceURL {
             AnnounceURL {
                 url,
                 peer_id,
                 port: LISTENING_PORT,
                 uploaded: 0,
                 downloaded: 0,
-                left, // TODO: extract the total size of the file
-                event: Event::Started,
+                left,
+                event: Some(Event::Started),
+                proxy: None,
             }
         }
+
+        pub fn set_port(&mut self, port: i32) {
+            self.port = port;
+        }
+
+        pub fn set_event(&mut self, event: Option<Event>) {
+            self.event = event;
+        }
+
+        /**
+         * Announce through `proxy`.
+         */
+        pub fn set_proxy(&mut self, proxy: Option<Proxy>) {
+            self.proxy = proxy;
+        }
+
+        /**
+         * Update the transfer totals reported on the next announce.
+         */
+        pub fn set_progress(&mut self, uploaded: i64, downloaded: i64, left: i64) {
+            self.uploaded = uploaded;
+            self.downloaded = downloaded;
+            self.left = left;
+        }
     }
 
     #[derive(Debug)]
@@ -65,6 +108,14 @@ pub mod tracker {
         Piece,
         Cancel,
         Port,
+        // Fast extension (BEP 6)
+        SuggestPiece,
+        HaveAll,
+        HaveNone,
+        RejectRequest,
+        AllowedFast,
+        // Extension protocol (BEP 10)
+        Extended,
     }
 
     impl MessageId {
@@ -73,13 +124,19 @@ pub mod tracker {
                 0 => MessageId::Choke,
                 1 => MessageId::Unchoke,
                 2 => MessageId::Interested,
-                3 => MessageId::Interested,
-                5 => MessageId::Have,
-                6 => MessageId::Bitfield,
-                7 => MessageId::Request,
-                8 => MessageId::Piece,
-                9 => MessageId::Cancel,
-                10 => MessageId::Port,
+                3 => MessageId::NotInterested,
+                4 => MessageId::Have,
+                5 => MessageId::Bitfield,
+                6 => MessageId::Request,
+                7 => MessageId::Piece,
+                8 => MessageId::Cancel,
+                9 => MessageId::Port,
+                13 => MessageId::SuggestPiece,
+                14 => MessageId::HaveAll,
+                15 => MessageId::HaveNone,
+                16 => MessageId::RejectRequest,
+                17 => MessageId::AllowedFast,
+                20 => MessageId::Extended,
                 _ => MessageId::KeepAlive,
             }
         }
@@ -89,14 +146,20 @@ pub mod tracker {
                 MessageId::Choke => 0,
                 MessageId::Unchoke => 1,
                 MessageId::Interested => 2,
-                MessageId::Interested => 3,
-                MessageId::Have => 5,
-                MessageId::Bitfield => 6,
-                MessageId::Request => 7,
-                MessageId::Piece => 8,
-                MessageId::Cancel => 9,
-                MessageId::Port => 10,
-                _ => 0,
+                MessageId::NotInterested => 3,
+                MessageId::Have => 4,
+                MessageId::Bitfield => 5,
+                MessageId::Request => 6,
+                MessageId::Piece => 7,
+                MessageId::Cancel => 8,
+                MessageId::Port => 9,
+                MessageId::SuggestPiece => 13,
+                MessageId::HaveAll => 14,
+                MessageId::HaveNone => 15,
+                MessageId::RejectRequest => 16,
+                MessageId::AllowedFast => 17,
+                MessageId::Extended => 20,
+                MessageId::KeepAlive => 0,
             }
         }
     }
@@ -109,45 +172,103 @@ pub mod tracker {
     }
 
     impl Message {
+        pub fn new(id: MessageId, payload: Option<Vec<u8>>) -> Self {
+            let length = 1 + payload.as_ref().map_or(0, |p| p.len()) as u32;
+            Message {
+                length,
+                id: Some(id),
+                payload,
+            }
+        }
+
+        pub fn keep_alive() -> Self {
+            Message {
+                length: 0,
+                id: None,
+                payload: None,
+            }
+        }
+
+        /**
+         * Request or cancel a block: <index><begin><length>
+         */
+        pub fn block(id: MessageId, index: u32, begin: u32, length: u32) -> Self {
+            let mut payload = index.to_be_bytes().to_vec();
+            payload.extend_from_slice(&begin.to_be_bytes());
+            payload.extend_from_slice(&length.to_be_bytes());
+            Message::new(id, Some(payload))
+        }
+
+        /**
+         * Message whose payload is a piece index: have, suggest piece, allowed fast
+         */
+        pub fn piece_index(id: MessageId, index: u32) -> Self {
+            Message::new(id, Some(index.to_be_bytes().to_vec()))
+        }
+
+        /**
+         * Extension protocol message: <extended id><bencoded dictionary>
+         */
+        pub fn extended(id: u8, payload: &[u8]) -> Self {
+            let mut bytes = vec![id];
+            bytes.extend_from_slice(payload);
+            Message::new(MessageId::Extended, Some(bytes))
+        }
+
+        /**
+         * Block of piece data: <index><begin><block>
+         */
+        pub fn piece(index: u32, begin: u32, block: &[u8]) -> Self {
+            let mut payload = index.to_be_bytes().to_vec();
+            payload.extend_from_slice(&begin.to_be_bytes());
+            payload.extend_from_slice(block);
+            Message::new(MessageId::Piece, Some(payload))
+        }
+
+        /**
+         * Read the big endian u32 at `offset` in the payload.
+         */
+        pub fn payload_u32(&self, offset: usize) -> Option<u32> {
+            let bytes = self.payload.as_ref()?.get(offset..(offset + 4))?;
+            Some(u32::from_be_bytes(bytes.try_into().ok()?))
+        }
+
         /**
          * Serialize message into bit pattern: <length><id><payload>.
          * Length must be big endian.
          */
         pub fn byte_serialize(&self) -> Vec<u8> {
             match &self.id {
-                None => return vec![0x00, 0x00],
+                None => vec![0x00, 0x00, 0x00, 0x00],
                 Some(id) => {
-                    let length = &self.length.to_be_bytes();
-                    let id = id.convert();
-                    let mut ret = vec![];
-                    ret.append(&mut length.to_vec());
-                    ret.push(id);
-                    match &self.payload {
-                        None => {
-                            return ret;
-                        }
-                        Some(m) => {
-                            ret.append(&mut m.clone());
-                            return ret;
-                        }
+                    let mut ret = self.length.to_be_bytes().to_vec();
+                    ret.push(id.convert());
+                    if let Some(m) = &self.payload {
+                        ret.extend_from_slice(m);
                     }
+                    ret
                 }
             }
         }
 
         pub fn read(message: Vec<u8>) -> Result<Self, Box<dyn Error>> {
-            let length = u32::from_be_bytes(
-                message[0..4]
-                    .try_into()
-                    .unwrap_or_else(|_| panic!("Message is less than 4 bytes!")),
-            );
-            let id = u8::from(message[5])
-                .try_into()
-                .unwrap_or_else(|_| panic!("Message doesn't have the 5th byte!"));
-            if message.len() < (length as usize + 5) {
-                panic!("Message length {} is less than the encoded length: {}", message.len(), length);
+            let length = match message.get(0..4) {
+                Some(b) => u32::from_be_bytes(b.try_into()?),
+                None => return Err("Message is less than 4 bytes!".into()),
+            };
+            if length == 0 {
+                return Ok(Message::keep_alive());
             }
-            let payload = message[5..length as usize].to_vec();
+            if message.len() < (length as usize + 4) {
+                return Err(format!(
+                    "Message length {} is less than the encoded length: {}",
+                    message.len(),
+                    length
+                )
+                .into());
+            }
+            let id = message[4];
+            let payload = message[5..(length as usize + 4)].to_vec();
 
             Ok(Message {
                 length,
@@ -157,13 +278,69 @@ pub mod tracker {
         }
     }
 
+    // Protocol extensions advertised in the reserved bytes of the handshake
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct Extensions {
+        // BEP 6, bit 0x04 of the last byte
+        pub fast: bool,
+        // BEP 10, bit 0x10 of the sixth byte
+        pub extended: bool,
+        // BEP 5, bit 0x01 of the last byte: the peer takes `port` messages
+        pub dht: bool,
+    }
+
+    impl Extensions {
+        /**
+         * Extensions implemented by this client, the DHT only counts while
+         * a node is running.
+         */
+        pub fn supported() -> Self {
+            Extensions {
+                fast: true,
+                extended: true,
+                dht: false,
+            }
+        }
+
+        pub fn from_reserved(reserved: &[u8]) -> Self {
+            Extensions {
+                fast: reserved.get(7).is_some_and(|b| b & 0x04 != 0),
+                extended: reserved.get(5).is_some_and(|b| b & 0x10 != 0),
+                dht: reserved.get(7).is_some_and(|b| b & 0x01 != 0),
+            }
+        }
+
+        fn write_reserved(&self, reserved: &mut [u8]) {
+            if self.fast {
+                reserved[7] |= 0x04;
+            }
+            if self.extended {
+                reserved[5] |= 0x10;
+            }
+            if self.dht {
+                reserved[7] |= 0x01;
+            }
+        }
+
+        /**
+         * Extensions both sides of a connection support.
+         */
+        pub fn common(&self, other: &Extensions) -> Self {
+            Extensions {
+                fast: self.fast && other.fast,
+                extended: self.extended && other.extended,
+                dht: self.dht && other.dht,
+            }
+        }
+    }
+
     #[derive(Debug)]
     pub struct Handshake {
         // length of the pstr, always 0x13
         pstrlen: usize,
         // name of the protocol: `BitTorrent protocol`
         pstr: String,
-        // 8 empty bytes
+        // 8 bytes, bits set for supported extensions
         pub reserved_bytes: Vec<u8>,
         pub info_hash: Vec<u8>,
         pub peer_id: String,
@@ -180,6 +357,15 @@ pub mod tracker {
             }
         }
 
+        pub fn with_extensions(mut self, extensions: Extensions) -> Self {
+            extensions.write_reserved(&mut self.reserved_bytes);
+            self
+        }
+
+        pub fn extensions(&self) -> Extensions {
+            Extensions::from_reserved(&self.reserved_bytes)
+        }
+
         // handshake: <pstrlen><pstr><reserved><info_hash><peer_id>
         pub fn serialize(&self) -> Vec<u8> {
             let mut s_bytes: Vec<u8> = vec![];
@@ -189,20 +375,20 @@ pub mod tracker {
             s_bytes.append(&mut self.reserved_bytes.clone());
             s_bytes.append(&mut self.info_hash.clone());
             s_bytes.append(&mut self.peer_id.as_bytes().to_vec());
-            return s_bytes;
+            s_bytes
         }
 
         pub fn deserialize(message: Vec<u8>) -> Result<Self, Box<dyn Error>> {
-            let hash = message.get((message.len() - 40)..(message.len() - 20));
-            let peer_id = message.get((message.len() - 20)..message.len());
-
-            if let Some(h) = hash {
-                if let Some(p_id) = peer_id {
-                    let handshake = Handshake::new(h.to_vec(), from_utf8(p_id).unwrap());
-                    return Ok(handshake);
-                }
+            if message.len() < 68 {
+                return Err("Error parsing handshake!".into());
             }
-            panic!("Error parsing handshake!");
+            let reserved = &message[(message.len() - 48)..(message.len() - 40)];
+            let hash = &message[(message.len() - 40)..(message.len() - 20)];
+            let peer_id = &message[(message.len() - 20)..message.len()];
+
+            let mut handshake = Handshake::new(hash.to_vec(), &String::from_utf8_lossy(peer_id));
+            handshake.reserved_bytes = reserved.to_vec();
+            Ok(handshake)
         }
 
         pub fn get_hash(&self) -> &Vec<u8> {
@@ -220,8 +406,8 @@ pub mod tracker {
     fn parse_query(params: &[(&str, String)]) -> String {
         let mut query_string = String::from("");
         for pair in params {
-            let (ref k, ref v) = pair.borrow();
-            if query_string.len() == 0 {
+            let (k, v) = pair;
+            if query_string.is_empty() {
                 query_string = format!("?{k}={v}");
             } else {
                 query_string = format!("{query_string}&{k}={v}");
@@ -230,8 +416,17 @@ pub mod tracker {
         query_string
     }
 
-    pub async fn listen()  {
-        let listener = TcpListener::bind(format!("127.0.0.1:{}", LISTENING_PORT)).await.expect("Port may be in use!");
+    /**
+     * HTTP client for tracker requests, through `proxy` if given.
+     */
+    fn http_client(proxy: Option<&Proxy>) -> Result<reqwest::Client, Box<dyn Error>> {
+        let client = match proxy {
+            Some(proxy) => reqwest::Client::builder()
+                .proxy(proxy.to_reqwest()?)
+                .build()?,
+            None => reqwest::Client::new(),
+        };
+        Ok(client)
     }
 
     /**
@@ -239,20 +434,24 @@ pub mod tracker {
      */
     pub async fn fetch_tracker_data(
         request: &mut AnnounceURL,
-        hash: &Vec<u8>,
+        hash: &[u8],
     ) -> Result<Vec<u8>, Box<dyn Error>> {
-        let client = reqwest::Client::new();
+        let client = http_client(request.proxy.as_ref())?;
         let url = &request.url;
-        let info_hash = byte_serialize(&hash).collect::<String>();
+        let info_hash = byte_serialize(hash).collect::<String>();
 
-        let query = parse_query(&[
+        let mut params = vec![
             ("info_hash", info_hash),
             ("peer_id", request.peer_id.to_owned()),
+            ("port", request.port.to_string()),
             ("uploaded", request.uploaded.to_string()),
             ("downloaded", request.downloaded.to_string()),
             ("left", request.left.to_string()),
-            ("event", request.event.to_string()),
-        ]);
+        ];
+        if let Some(event) = &request.event {
+            params.push(("event", event.to_string()));
+        }
+        let query = parse_query(&params);
 
         let url = format!("{url}{query}");
         println!("url: {}", url);
@@ -261,26 +460,179 @@ pub mod tracker {
         Ok(response.to_vec())
     }
 
+    /**
+     * Scrape URL of an announce URL: by convention the last path segment
+     * `announce` becomes `scrape`, trackers without it don't support scrapes.
+     */
+    pub fn scrape_url(announce: &str) -> Option<String> {
+        let (base, last) = announce.rsplit_once('/')?;
+        let rest = last.strip_prefix("announce")?;
+        Some(format!("{base}/scrape{rest}"))
+    }
+
+    /**
+     * Ask the tracker for the number of seeders and leechers of a torrent.
+     */
+    pub async fn fetch_scrape_data(
+        url: &str,
+        hash: &[u8],
+        proxy: Option<&Proxy>,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let info_hash = byte_serialize(hash).collect::<String>();
+        let separator = if url.contains('?') { '&' } else { '?' };
+        let url = format!("{url}{separator}info_hash={info_hash}");
+        let response = http_client(proxy)?
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        Ok(response.to_vec())
+    }
+
     pub struct PeerConnection {
         ip: String,
         port: i32,
-        stream: TcpStream
+        stream: PeerStream,
     }
 
     impl PeerConnection {
-        pub async fn listen() -> Result<TcpListener, std::io::Error> {
-            TcpListener::bind(format!("127.0.0.1:{}",LISTENING_PORT)).await
+        /**
+         * Bind a listener for incoming peers. IPv6 sockets only accept IPv6
+         * so that an IPv4 listener can share the port.
+         */
+        pub fn listen(addr: SocketAddr) -> Result<TcpListener, std::io::Error> {
+            let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+            if addr.is_ipv6() {
+                socket.set_only_v6(true)?;
+            }
+            socket.set_reuse_address(true)?;
+            socket.set_nonblocking(true)?;
+            socket.bind(&addr.into())?;
+            socket.listen(128)?;
+            TcpListener::from_std(socket.into())
+        }
+
+        /**
+         * Wrap a connection accepted by a listener.
+         */
+        pub fn from_stream(stream: TcpStream, addr: SocketAddr) -> Self {
+            PeerConnection {
+                ip: addr.ip().to_string(),
+                port: addr.port() as i32,
+                stream: PeerStream::new(Transport::Tcp(stream)),
+            }
         }
 
-        pub async fn new(ip: String, port: i32) -> Result<Self, Box<dyn Error>> {
-            println!("yooooo {} {}!", ip, port);
-            let stream = TcpStream::connect(format!("{}:{}", ip, port)).await;
+        /**
+         * Wrap an incoming uTP connection.
+         */
+        pub fn from_utp(stream: UtpStream) -> Self {
+            let addr = stream.peer_addr();
+            PeerConnection {
+                ip: addr.ip().to_string(),
+                port: addr.port() as i32,
+                stream: PeerStream::new(Transport::Utp(stream)),
+            }
+        }
+
+        /**
+         * Connect over TCP, through `proxy` if given.
+         */
+        pub async fn new(
+            ip: String,
+            port: i32,
+            proxy: Option<&Proxy>,
+        ) -> Result<Self, Box<dyn Error>> {
+            let stream = match (proxy, ip.parse::<IpAddr>()) {
+                (Some(proxy), _) => proxy.connect(&ip, port as u16).await,
+                (None, Ok(addr)) => TcpStream::connect(SocketAddr::new(addr, port as u16)).await,
+                (None, Err(_)) => TcpStream::connect(format!("{}:{}", ip, port)).await,
+            };
 
             match stream {
-                Ok(s) => Ok(PeerConnection { ip, port, stream: s }),
-                Err(e) => Err(Box::new(e)) 
+                Ok(s) => Ok(PeerConnection {
+                    ip,
+                    port,
+                    stream: PeerStream::new(Transport::Tcp(s)),
+                }),
+                Err(e) => Err(Box::new(e)),
             }
+        }
 
+        /**
+         * Connect over uTP when possible, TCP otherwise or if the peer
+         * doesn't answer over uTP. Proxied connections are TCP only.
+         */
+        async fn open(
+            ip: String,
+            port: i32,
+            utp: Option<&Arc<Utp>>,
+            proxy: Option<&Proxy>,
+        ) -> Result<Self, Box<dyn Error>> {
+            let utp = utp.filter(|_| proxy.is_none());
+            if let (Some(utp), Ok(addr)) = (utp, ip.parse::<IpAddr>()) {
+                let addr = SocketAddr::new(addr, port as u16);
+                match utp.connect(addr).await {
+                    Ok(stream) => return Ok(PeerConnection::from_utp(stream)),
+                    Err(e) => println!("{}, trying TCP", e),
+                }
+            }
+            PeerConnection::new(ip, port, proxy).await
+        }
+
+        /**
+         * Connect to a peer of `info_hash`, first over `utp` if given, and
+         * encrypted as `mode` asks. When encryption is only preferred, peers
+         * failing the encrypted handshake are connected to again in plaintext.
+         * With a `proxy`, only through it.
+         */
+        pub async fn connect(
+            ip: String,
+            port: i32,
+            info_hash: &[u8],
+            mode: EncryptionMode,
+            utp: Option<Arc<Utp>>,
+            proxy: Option<Proxy>,
+        ) -> Result<Self, Box<dyn Error>> {
+            let mut connection =
+                PeerConnection::open(ip.clone(), port, utp.as_ref(), proxy.as_ref()).await?;
+            if mode == EncryptionMode::Disable {
+                return Ok(connection);
+            }
+            // the error is kept as a string so that the future stays `Send`
+            let result = mse::initiate(&mut connection.stream, info_hash, mode)
+                .await
+                .map_err(|e| e.to_string());
+            match result {
+                Ok(()) => Ok(connection),
+                Err(e) if mode == EncryptionMode::Prefer => {
+                    println!("encryption with {}:{} failed: {}", ip, port, e);
+                    PeerConnection::open(ip, port, utp.as_ref(), proxy.as_ref()).await
+                }
+                Err(e) => Err(e.into()),
+            }
+        }
+
+        /**
+         * Encryption handshake of an incoming peer, if it starts one. Encrypted
+         * peers must be after one of `info_hashes`.
+         */
+        pub async fn accept_encryption(
+            &mut self,
+            info_hashes: &[Vec<u8>],
+            mode: EncryptionMode,
+        ) -> Result<(), Box<dyn Error>> {
+            mse::respond(&mut self.stream, info_hashes, mode).await
+        }
+
+        pub fn is_encrypted(&self) -> bool {
+            self.stream.is_encrypted()
+        }
+
+        pub fn is_utp(&self) -> bool {
+            self.stream.is_utp()
         }
 
         pub async fn handshake_with_peer(
@@ -289,36 +641,89 @@ pub mod tracker {
         ) -> Result<(), Box<dyn Error>> {
             self.stream
                 .write_all(&handshake_message.serialize())
-                .await
-                .expect("Could not send message!");
+                .await?;
+            self.stream.flush().await?;
             Ok(())
         }
 
+        pub async fn read_handshake(&mut self) -> Result<Handshake, Box<dyn Error>> {
+            let mut buffer = vec![0; 68];
+            self.stream.read_exact(&mut buffer).await?;
+            Handshake::deserialize(buffer)
+        }
+
+        /**
+         * Split the connection so that messages can be read while others are sent.
+         */
+        pub fn split(self) -> (MessageReader, MessageWriter) {
+            let (reader, writer) = tokio::io::split(self.stream);
+            (
+                MessageReader { stream: reader },
+                MessageWriter { stream: writer },
+            )
+        }
+    }
+
+    /**
+     * Read one length prefixed message off the stream.
+     */
+    async fn read_message<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Message, Box<dyn Error>> {
+        let mut buffer = vec![0; 4];
+        stream.read_exact(&mut buffer).await?;
+        let length = u32::from_be_bytes(buffer[0..4].try_into()?) as usize;
+        if length > MAX_MESSAGE {
+            return Err(format!("Message length {} exceeds {}", length, MAX_MESSAGE).into());
+        }
+        buffer.resize(4 + length, 0);
+        stream.read_exact(&mut buffer[4..]).await?;
+        Message::read(buffer)
+    }
+
+    pub struct MessageReader {
+        stream: ReadHalf<PeerStream>,
+    }
+
+    impl MessageReader {
+        pub async fn read_message(&mut self) -> Result<Message, Box<dyn Error>> {
+            read_message(&mut self.stream).await
+        }
+    }
+
+    pub struct MessageWriter {
+        stream: WriteHalf<PeerStream>,
+    }
+
+    impl MessageWriter {
         pub async fn send_messsage_to_peer(
             &mut self,
             message: &Message,
         ) -> Result<(), Box<dyn Error>> {
-                self.stream
-                    .write_all(&message.byte_serialize())
-                    .await
-                    .expect("Could not send message!");
+            self.stream.write_all(&message.byte_serialize()).await?;
+            // encrypted streams hold data back until flushed
+            self.stream.flush().await?;
             Ok(())
         }
-
-        pub async fn read_from_stream(&mut self) -> Vec<u8> {
-                let mut buffer = Vec::new();
-                let m = self.stream.read_to_end(&mut buffer).await;
-                return buffer[..m.expect("Could not read response!")].to_vec();
-        }
     }
-
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tracker::Handshake;
-    use tracker::Message;
+    use tracker::{scrape_url, Handshake};
+
+    #[test]
+    fn scrape_urls() {
+        assert_eq!(
+            scrape_url("http://example.com/announce").as_deref(),
+            Some("http://example.com/scrape")
+        );
+        assert_eq!(
+            scrape_url("http://example.com/x/announce.php?passkey=1").as_deref(),
+            Some("http://example.com/x/scrape.php?passkey=1")
+        );
+        assert_eq!(scrape_url("http://example.com/a"), None);
+        assert_eq!(scrape_url("http://example.com/announce/x"), None);
+    }
 
     #[test]
     fn message_serialize() {
@@ -347,4 +752,18 @@ mod tests {
             payload
         );
     }
+
+    #[test]
+    fn handshake_extensions() {
+        let handshake = Handshake::new(vec![0; 20], "-TR2940-k8hj0wgej6ch")
+            .with_extensions(tracker::Extensions::supported());
+        let bytes = handshake.serialize();
+        assert_eq!(bytes[20 + 7], 0x04);
+        assert_eq!(bytes[20 + 5], 0x10);
+        let peer = Handshake::deserialize(bytes).unwrap();
+        assert!(peer.extensions().fast);
+        assert!(peer.extensions().extended);
+        let plain = Handshake::new(vec![0; 20], "-TR2940-k8hj0wgej6ch");
+        assert!(!peer.extensions().common(&plain.extensions()).fast);
+    }
 }
@@ -0,0 +1,1718 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex, MutexGuard, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use bendy::{decoding::FromBencode, encoding::ToBencode};
+use rand::{seq::IteratorRandom, thread_rng, RngCore};
+use sha1_smol::Sha1;
+use socket2::{Domain, Socket, Type};
+use tokio::{
+    net::UdpSocket,
+    sync::oneshot,
+    task::{JoinHandle, JoinSet},
+    time::timeout,
+};
+
+use crate::{
+    dht_storage::{immutable_target, mutable_target, Item, ItemStore, MutableItem},
+    krpc::{Dict, KrpcBody, KrpcMessage, Value, ERROR_METHOD_UNKNOWN, ERROR_PROTOCOL},
+    pex::{compact_addr, compact_addrs},
+    proxy::UdpRelay,
+    utp::Utp,
+};
+
+// Mainline DHT (BEP 5), a Kademlia network storing peers by info hash.
+// Nodes have 160 bit ids and the distance between two ids is their XOR.
+// The routing table keeps up to 8 nodes per bucket, bucket `i` holding the
+// nodes whose distance to us starts with `i` zero bits, so a node knows many
+// nodes close to it and few far away. Nodes already in a bucket are kept
+// over new ones until they stop answering.
+// Lookups query the 3 closest unqueried nodes at a time, moving towards the
+// target until the 8 closest nodes found have all answered.
+// `get_peers` returns the peers announced for an info hash, or the closest
+// nodes we know, plus a token to pass back in `announce_peer`. A token is a
+// hash of the querying IP and a secret replaced every 5 minutes, tokens of
+// the previous secret are still accepted.
+// `get` and `put` store small values on the nodes closest to their target,
+// see `dht_storage`.
+// Swarm sizes are estimated without connecting to the peers (BEP 33): with
+// `scrape` set, `get_peers` also returns bloom filters of the addresses of
+// the seeds and of the other peers announced, whose union over the closest
+// nodes counts the distinct addresses. `sample_infohashes` (BEP 51) returns
+// a random sample of the info hashes a node stores peers for, for indexers.
+// The node id and routing table are saved on exit and loaded on start, so
+// that the node doesn't need the bootstrap routers every time.
+//
+// IPv6 (BEP 32) runs as a separate network over its own socket, with its own
+// routing table and node id, and lookups run in both at once. Queries list
+// the address families of the nodes wanted back in `want`, returned as
+// `nodes` and `nodes6`.
+// Node ids are tied to the external address (BEP 42): the first 21 bits are
+// a CRC32-C of the masked address and of 3 bits of the last byte, so a node
+// can't pick an id next to an info hash. Nodes whose id doesn't match their
+// address are kept out of the routing table, local addresses are exempt.
+// Our own address is reported by the tracker, or voted on by the nodes
+// answering (`ip`) and the peers (`yourip`), and once known our id is
+// derived from it.
+
+// bucket size, and number of closest nodes a lookup converges on
+pub const K: usize = 8;
+// queries in flight during a lookup
+const ALPHA: usize = 3;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+// announced peers are forgotten unless announced again
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+const MAX_PEERS_PER_INFO_HASH: usize = 100;
+const MAX_INFO_HASHES: usize = 1000;
+// peers per `get_peers` response, to fit in a UDP packet
+const MAX_VALUES: usize = 50;
+// peers a lookup collects
+const MAX_LOOKUP_PEERS: usize = 500;
+// info hashes per `sample_infohashes` response
+const MAX_SAMPLES: usize = 20;
+// how long indexers should wait before sampling a node again
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+// bits of a scrape bloom filter
+const BLOOM_BITS: usize = 2048;
+// longest pause between receives while the socket keeps failing
+const MAX_RECEIVE_BACKOFF: Duration = Duration::from_secs(5);
+// nodes failing this many queries in a row can be replaced
+const MAX_FAILURES: u32 = 2;
+// peers or nodes reporting the same external address before it is believed
+const EXTERNAL_IP_VOTES: usize = 3;
+// external addresses voted for at once
+const MAX_IP_VOTES: usize = 50;
+// torrents are announced again after this long
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+pub const DEFAULT_BOOTSTRAP: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub [u8; 20]);
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+    last_seen: Instant,
+    // queries without an answer since the last answer
+    failures: u32,
+}
+
+impl NodeId {
+    pub fn random() -> Self {
+        let mut id = [0; 20];
+        thread_rng().fill_bytes(&mut id);
+        NodeId(id)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(NodeId(bytes.try_into().ok()?))
+    }
+
+    pub fn distance(&self, other: &NodeId) -> [u8; 20] {
+        std::array::from_fn(|i| self.0[i] ^ other.0[i])
+    }
+
+    /**
+     * A random id valid for the external address `ip` (BEP 42).
+     */
+    pub fn secure(ip: IpAddr) -> Self {
+        let mut id = NodeId::random();
+        let crc = secure_prefix(ip, id.0[19]);
+        id.0[0] = (crc >> 24) as u8;
+        id.0[1] = (crc >> 16) as u8;
+        id.0[2] = ((crc >> 8) as u8 & 0xf8) | (id.0[2] & 0x07);
+        id
+    }
+
+    /**
+     * Whether a node at `ip` may use this id.
+     */
+    pub fn is_secure_for(&self, ip: IpAddr) -> bool {
+        if is_local(ip) {
+            return true;
+        }
+        let crc = secure_prefix(ip, self.0[19]);
+        self.0[0] == (crc >> 24) as u8
+            && self.0[1] == (crc >> 16) as u8
+            && self.0[2] & 0xf8 == (crc >> 8) as u8 & 0xf8
+    }
+
+    /**
+     * Number of leading bits shared with `other`, i.e. its bucket in our table.
+     */
+    fn common_prefix(&self, other: &NodeId) -> usize {
+        let distance = self.distance(other);
+        match distance.iter().position(|b| *b != 0) {
+            Some(i) => i * 8 + distance[i].leading_zeros() as usize,
+            None => 160,
+        }
+    }
+}
+
+fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/**
+ * CRC32-C the first 21 bits of a node id at `ip` come from, `r` being the
+ * last byte of the id.
+ */
+fn secure_prefix(ip: IpAddr, r: u8) -> u32 {
+    let (mut bytes, mask) = match ip {
+        IpAddr::V4(ip) => (ip.octets().to_vec(), &[0x03, 0x0f, 0x3f, 0xff][..]),
+        IpAddr::V6(ip) => (
+            ip.octets()[..8].to_vec(),
+            &[0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff][..],
+        ),
+    };
+    for (byte, mask) in bytes.iter_mut().zip(mask) {
+        *byte &= mask;
+    }
+    bytes[0] |= (r & 0x07) << 5;
+    crc32c(&bytes)
+}
+
+/**
+ * Local network addresses, exempt from node id checks.
+ */
+fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unique_local() || ip.is_unicast_link_local(),
+    }
+}
+
+/**
+ * Compact node info: the 20 byte id followed by the compact address.
+ */
+pub fn compact_nodes<'a>(nodes: impl IntoIterator<Item = &'a Node>) -> Vec<u8> {
+    let mut bytes = vec![];
+    for node in nodes {
+        bytes.extend_from_slice(&node.id.0);
+        bytes.extend_from_slice(&compact_addr(&node.addr));
+    }
+    bytes
+}
+
+pub fn parse_compact_nodes(bytes: &[u8], ipv6: bool) -> Vec<(NodeId, SocketAddr)> {
+    let size = if ipv6 { 38 } else { 26 };
+    bytes
+        .chunks_exact(size)
+        .filter_map(|chunk| {
+            let id = NodeId::from_bytes(&chunk[..20])?;
+            let addr = *compact_addrs(&chunk[20..], ipv6).first()?;
+            Some((id, addr))
+        })
+        .collect()
+}
+
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> Self {
+        RoutingTable {
+            id,
+            buckets: vec![vec![]; 160],
+        }
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.len()).sum()
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &Node> {
+        self.buckets.iter().flatten()
+    }
+
+    /**
+     * Change our id, the nodes are sorted into the buckets of the new id as
+     * long as they fit.
+     */
+    pub fn set_id(&mut self, id: NodeId) {
+        let nodes: Vec<Node> = self.buckets.iter_mut().flat_map(std::mem::take).collect();
+        self.id = id;
+        for node in nodes {
+            if node.id == id {
+                continue;
+            }
+            let bucket = &mut self.buckets[id.common_prefix(&node.id)];
+            if bucket.len() < K {
+                bucket.push(node);
+            }
+        }
+    }
+
+    /**
+     * Add a node that answered or queried us, returns false if its bucket is
+     * full of good nodes or its id doesn't match its address. A known id is
+     * only refreshed from its known address.
+     */
+    pub fn insert(&mut self, id: NodeId, addr: SocketAddr) -> bool {
+        if id == self.id || !id.is_secure_for(addr.ip()) {
+            return false;
+        }
+        let bucket = &mut self.buckets[self.id.common_prefix(&id)];
+        if let Some(node) = bucket.iter_mut().find(|n| n.id == id) {
+            if node.addr != addr {
+                return false;
+            }
+            node.last_seen = Instant::now();
+            node.failures = 0;
+            return true;
+        }
+        let node = Node {
+            id,
+            addr,
+            last_seen: Instant::now(),
+            failures: 0,
+        };
+        if bucket.len() < K {
+            bucket.push(node);
+            return true;
+        }
+        match bucket.iter_mut().find(|n| n.failures >= MAX_FAILURES) {
+            Some(bad) => {
+                *bad = node;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /**
+     * Count a query to `addr` that went unanswered.
+     */
+    pub fn failed(&mut self, addr: SocketAddr) {
+        for node in self.buckets.iter_mut().flatten() {
+            if node.addr == addr {
+                node.failures += 1;
+            }
+        }
+    }
+
+    /**
+     * Up to `count` responsive nodes closest to `target`.
+     */
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Node> {
+        let mut nodes: Vec<&Node> = self.nodes().filter(|n| n.failures < MAX_FAILURES).collect();
+        nodes.sort_by_key(|n| n.id.distance(target));
+        nodes.into_iter().take(count).cloned().collect()
+    }
+}
+
+/**
+ * Bloom filter of IP addresses (BEP 33), 2048 bits set by 2 hashes.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter([u8; BLOOM_BITS / 8]);
+
+impl Default for BloomFilter {
+    fn default() -> Self {
+        BloomFilter([0; BLOOM_BITS / 8])
+    }
+}
+
+impl BloomFilter {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(BloomFilter(bytes.try_into().ok()?))
+    }
+
+    pub fn insert(&mut self, ip: IpAddr) {
+        let hash = match ip {
+            IpAddr::V4(ip) => Sha1::from(ip.octets()).digest().bytes(),
+            IpAddr::V6(ip) => Sha1::from(ip.octets()).digest().bytes(),
+        };
+        for i in [0, 2] {
+            let index = (hash[i] as usize | (hash[i + 1] as usize) << 8) % BLOOM_BITS;
+            self.0[index / 8] |= 1 << (index % 8);
+        }
+    }
+
+    pub fn union(&mut self, other: &BloomFilter) {
+        for (byte, other) in self.0.iter_mut().zip(other.0) {
+            *byte |= other;
+        }
+    }
+
+    /**
+     * Number of addresses inserted, from the share of bits still unset.
+     */
+    pub fn estimate(&self) -> usize {
+        let m = BLOOM_BITS as f64;
+        let ones: u32 = self.0.iter().map(|b| b.count_ones()).sum();
+        let zeros = (BLOOM_BITS - ones as usize).max(1) as f64;
+        ((zeros / m).ln() / (2.0 * (1.0 - 1.0 / m).ln())).round() as usize
+    }
+}
+
+struct Tokens {
+    secret: [u8; 8],
+    previous: [u8; 8],
+    rotated: Instant,
+}
+
+impl Tokens {
+    fn new() -> Self {
+        let mut tokens = Tokens {
+            secret: [0; 8],
+            previous: [0; 8],
+            rotated: Instant::now(),
+        };
+        thread_rng().fill_bytes(&mut tokens.secret);
+        thread_rng().fill_bytes(&mut tokens.previous);
+        tokens
+    }
+
+    fn token(secret: &[u8], ip: IpAddr) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        match ip {
+            IpAddr::V4(ip) => hasher.update(&ip.octets()),
+            IpAddr::V6(ip) => hasher.update(&ip.octets()),
+        }
+        hasher.update(secret);
+        hasher.digest().bytes()[..8].to_vec()
+    }
+
+    fn rotate(&mut self) {
+        if self.rotated.elapsed() >= TOKEN_ROTATION {
+            self.previous = self.secret;
+            thread_rng().fill_bytes(&mut self.secret);
+            self.rotated = Instant::now();
+        }
+    }
+
+    fn generate(&mut self, ip: IpAddr) -> Vec<u8> {
+        self.rotate();
+        Tokens::token(&self.secret, ip)
+    }
+
+    fn verify(&mut self, ip: IpAddr, token: &[u8]) -> bool {
+        self.rotate();
+        token == Tokens::token(&self.secret, ip) || token == Tokens::token(&self.previous, ip)
+    }
+}
+
+// announced peer, whether it is a seed, and when it was announced
+type StoredPeer = (SocketAddr, bool, Instant);
+
+/**
+ * Peers announced to us, by info hash.
+ */
+#[derive(Default)]
+struct PeerStore {
+    peers: HashMap<NodeId, Vec<StoredPeer>>,
+}
+
+impl PeerStore {
+    fn add(&mut self, info_hash: NodeId, addr: SocketAddr, seed: bool) {
+        if !self.peers.contains_key(&info_hash) && self.peers.len() >= MAX_INFO_HASHES {
+            self.expire();
+            if self.peers.len() >= MAX_INFO_HASHES {
+                return;
+            }
+        }
+        let peers = self.peers.entry(info_hash).or_default();
+        if let Some(peer) = peers.iter_mut().find(|(a, _, _)| *a == addr) {
+            *peer = (addr, seed, Instant::now());
+        } else if peers.len() < MAX_PEERS_PER_INFO_HASH {
+            peers.push((addr, seed, Instant::now()));
+        }
+    }
+
+    fn get(&self, info_hash: &NodeId, ipv6: bool) -> Vec<SocketAddr> {
+        self.peers.get(info_hash).map_or(vec![], |peers| {
+            peers
+                .iter()
+                .filter(|(a, _, t)| a.is_ipv6() == ipv6 && t.elapsed() < PEER_TTL)
+                .take(MAX_VALUES)
+                .map(|(a, _, _)| *a)
+                .collect()
+        })
+    }
+
+    /**
+     * Bloom filters of the seeds and of the other peers of `info_hash`.
+     */
+    fn bloom_filters(&self, info_hash: &NodeId) -> Option<(BloomFilter, BloomFilter)> {
+        let peers = self.peers.get(info_hash)?;
+        let (mut seeds, mut downloaders) = (BloomFilter::default(), BloomFilter::default());
+        for (addr, seed, _) in peers.iter().filter(|(_, _, t)| t.elapsed() < PEER_TTL) {
+            if *seed {
+                seeds.insert(addr.ip());
+            } else {
+                downloaders.insert(addr.ip());
+            }
+        }
+        Some((seeds, downloaders))
+    }
+
+    /**
+     * Up to `count` random info hashes we store peers for, and their number.
+     */
+    fn sample(&self, count: usize) -> (Vec<NodeId>, usize) {
+        let sample = self
+            .peers
+            .keys()
+            .copied()
+            .choose_multiple(&mut thread_rng(), count);
+        (sample, self.peers.len())
+    }
+
+    fn expire(&mut self) {
+        for peers in self.peers.values_mut() {
+            peers.retain(|(_, _, t)| t.elapsed() < PEER_TTL);
+        }
+        self.peers.retain(|_, peers| !peers.is_empty());
+    }
+}
+
+struct DhtState {
+    // nodes of the IPv4 and IPv6 networks, each table with its own id
+    table: RoutingTable,
+    table6: RoutingTable,
+    tokens: Tokens,
+    peers: PeerStore,
+    items: ItemStore,
+    // addresses reported as ours, with who reported them
+    ip_votes: HashMap<IpAddr, HashSet<IpAddr>>,
+}
+
+impl DhtState {
+    fn table(&self, ipv6: bool) -> &RoutingTable {
+        if ipv6 {
+            &self.table6
+        } else {
+            &self.table
+        }
+    }
+
+    fn table_mut(&mut self, ipv6: bool) -> &mut RoutingTable {
+        if ipv6 {
+            &mut self.table6
+        } else {
+            &mut self.table
+        }
+    }
+}
+
+// queried address and where its answer goes, by transaction id
+type PendingQuery = (SocketAddr, oneshot::Sender<Result<Dict, String>>);
+
+// node that answered a lookup, with the token it returned
+type LookupNode = (NodeId, SocketAddr, Option<Vec<u8>>);
+
+// nodes that answered a lookup, closest first, the peers they returned, and
+// all the answers, for the items or bloom filters they hold
+struct Lookup {
+    nodes: Vec<LookupNode>,
+    peers: Vec<SocketAddr>,
+    responses: Vec<Dict>,
+}
+
+/**
+ * Node id and nodes saved by `Dht::save`.
+ */
+pub struct SavedState {
+    pub id: NodeId,
+    pub id6: NodeId,
+    pub nodes: Vec<(NodeId, SocketAddr)>,
+}
+
+pub struct Dht {
+    socket: Option<Arc<UdpSocket>>,
+    socket6: Option<Arc<UdpSocket>>,
+    state: Mutex<DhtState>,
+    pending: Mutex<HashMap<Vec<u8>, PendingQuery>>,
+    next_transaction: AtomicU16,
+    receivers: Mutex<Vec<JoinHandle<()>>>,
+    // uTP connections sharing the sockets
+    utp: Mutex<Option<Arc<Utp>>>,
+    // SOCKS5 association all packets go through
+    relay: Option<UdpRelay>,
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        for receiver in self.receivers.get_mut().unwrap().drain(..) {
+            receiver.abort();
+        }
+    }
+}
+
+fn bind_socket(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, None)?;
+    // IPv4 has its own socket, which may share the port
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/**
+ * Dispatch incoming packets until the node is dropped.
+ */
+async fn receive(socket: Arc<UdpSocket>, dht: Weak<Dht>) {
+    let mut buf = vec![0; 65536];
+    let mut backoff = Duration::from_millis(10);
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(r) => r,
+            // e.g. an ICMP port unreachable of an earlier query
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionRefused
+                ) =>
+            {
+                continue
+            }
+            Err(e) => {
+                if dht.strong_count() == 0 {
+                    return;
+                }
+                println!("DHT socket error: {}", e);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_RECEIVE_BACKOFF);
+                continue;
+            }
+        };
+        backoff = Duration::from_millis(10);
+        let dht = match dht.upgrade() {
+            Some(d) => d,
+            None => return,
+        };
+        let (packet, from) = match &dht.relay {
+            Some(_) => match UdpRelay::decapsulate(&buf[..len]) {
+                Some((from, packet)) => (packet, from),
+                None => continue,
+            },
+            None => (&buf[..len], from),
+        };
+        // KRPC messages are bencoded dictionaries, anything else may be uTP
+        if packet.first() != Some(&b'd') {
+            if let Some(utp) = dht.utp() {
+                utp.receive(packet, from);
+            }
+        } else if let Ok(message) = KrpcMessage::decode(packet) {
+            dht.handle_message(message, from);
+        }
+    }
+}
+
+fn node_arg(args: &Dict, key: &[u8]) -> Result<NodeId, (i64, &'static str)> {
+    args.get(key)
+        .and_then(Value::as_bytes)
+        .and_then(NodeId::from_bytes)
+        .ok_or((ERROR_PROTOCOL, "missing or invalid argument"))
+}
+
+impl Dht {
+    /**
+     * Start a DHT node with a socket on each of `addrs`, one per address
+     * family, it answers queries right away. Addresses that can't be bound
+     * are skipped as long as one can.
+     */
+    pub async fn bind(addrs: &[SocketAddr], id: NodeId) -> io::Result<Arc<Dht>> {
+        let mut sockets = [None, None];
+        let mut error = io::Error::new(io::ErrorKind::InvalidInput, "no address to bind");
+        for addr in addrs {
+            let socket = &mut sockets[addr.is_ipv6() as usize];
+            if socket.is_none() {
+                match bind_socket(*addr) {
+                    Ok(s) => *socket = Some(Arc::new(s)),
+                    Err(e) => error = e,
+                }
+            }
+        }
+        let [socket, socket6] = sockets;
+        if socket.is_none() && socket6.is_none() {
+            return Err(error);
+        }
+        Ok(Dht::start(socket, socket6, None, id))
+    }
+
+    /**
+     * Start a DHT node sending and receiving through a SOCKS5 UDP `relay`,
+     * in the IPv4 network only.
+     */
+    pub async fn bind_relayed(relay: UdpRelay, id: NodeId) -> Arc<Dht> {
+        Dht::start(Some(relay.socket()), None, Some(relay), id)
+    }
+
+    fn start(
+        socket: Option<Arc<UdpSocket>>,
+        socket6: Option<Arc<UdpSocket>>,
+        relay: Option<UdpRelay>,
+        id: NodeId,
+    ) -> Arc<Dht> {
+        let dht = Arc::new(Dht {
+            socket,
+            socket6,
+            state: Mutex::new(DhtState {
+                table: RoutingTable::new(id),
+                table6: RoutingTable::new(id),
+                tokens: Tokens::new(),
+                peers: PeerStore::default(),
+                items: ItemStore::default(),
+                ip_votes: HashMap::new(),
+            }),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction: AtomicU16::new(0),
+            receivers: Mutex::new(vec![]),
+            utp: Mutex::new(None),
+            relay,
+        });
+        let receivers = [&dht.socket, &dht.socket6]
+            .into_iter()
+            .flatten()
+            .map(|socket| tokio::spawn(receive(socket.clone(), Arc::downgrade(&dht))))
+            .collect();
+        *dht.receivers.lock().expect("Error unable to lock mutex!") = receivers;
+        dht
+    }
+
+    fn lock(&self) -> MutexGuard<'_, DhtState> {
+        self.state.lock().expect("Error unable to lock mutex!")
+    }
+
+    /**
+     * Run uTP on the sockets of the node, on the same port.
+     */
+    pub fn start_utp(&self) -> Arc<Utp> {
+        let utp = Utp::new(self.socket.clone(), self.socket6.clone());
+        *self.utp.lock().expect("Error unable to lock mutex!") = Some(utp.clone());
+        utp
+    }
+
+    /**
+     * Whether packets go through a proxy, the sockets can't be shared then.
+     */
+    pub fn is_relayed(&self) -> bool {
+        self.relay.is_some()
+    }
+
+    /**
+     * The packet carrying `bytes` to `to` and where to send it, the relay if
+     * there is one.
+     */
+    fn datagram(&self, bytes: Vec<u8>, to: SocketAddr) -> (Vec<u8>, SocketAddr) {
+        match &self.relay {
+            Some(relay) => (relay.encapsulate(&bytes, to), relay.relay_addr()),
+            None => (bytes, to),
+        }
+    }
+
+    fn utp(&self) -> Option<Arc<Utp>> {
+        self.utp
+            .lock()
+            .expect("Error unable to lock mutex!")
+            .clone()
+    }
+
+    fn socket(&self, ipv6: bool) -> Option<&Arc<UdpSocket>> {
+        if ipv6 {
+            self.socket6.as_ref()
+        } else {
+            self.socket.as_ref()
+        }
+    }
+
+    /**
+     * Our id in the IPv4 network.
+     */
+    pub fn id(&self) -> NodeId {
+        self.lock().table.id()
+    }
+
+    /**
+     * Our id in the IPv6 network.
+     */
+    pub fn id6(&self) -> NodeId {
+        self.lock().table6.id()
+    }
+
+    /**
+     * Address of the IPv4 socket, or of the IPv6 one without it.
+     */
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match &self.socket {
+            Some(socket) => socket.local_addr(),
+            None => self
+                .socket6
+                .as_ref()
+                .expect("DHT without socket")
+                .local_addr(),
+        }
+    }
+
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        [&self.socket, &self.socket6]
+            .into_iter()
+            .flatten()
+            .filter_map(|s| s.local_addr().ok())
+            .collect()
+    }
+
+    pub fn node_count(&self) -> usize {
+        let state = self.lock();
+        state.table.len() + state.table6.len()
+    }
+
+    /**
+     * Add nodes without querying them, e.g. from a saved routing table.
+     */
+    pub fn add_nodes(&self, nodes: &[(NodeId, SocketAddr)]) {
+        let mut state = self.lock();
+        for (id, addr) in nodes {
+            state.table_mut(addr.is_ipv6()).insert(*id, *addr);
+        }
+    }
+
+    /**
+     * Take over the ids and nodes of a previous run.
+     */
+    pub fn restore(&self, saved: &SavedState) {
+        {
+            let mut state = self.lock();
+            state.table.set_id(saved.id);
+            state.table6.set_id(saved.id6);
+        }
+        self.add_nodes(&saved.nodes);
+    }
+
+    /**
+     * Count a report of our external address by `voter`, a node or peer, or
+     * by a trusted source like the tracker if `None`. Once the address is
+     * believed, the table of its family gets an id derived from it unless it
+     * already has one. Returns whether the id changed.
+     */
+    pub fn vote_external_ip(&self, ip: IpAddr, voter: Option<IpAddr>) -> bool {
+        if is_local(ip) {
+            return false;
+        }
+        let mut state = self.lock();
+        if let Some(voter) = voter {
+            if state.ip_votes.len() >= MAX_IP_VOTES && !state.ip_votes.contains_key(&ip) {
+                state.ip_votes.clear();
+            }
+            let voters = state.ip_votes.entry(ip).or_default();
+            voters.insert(voter);
+            if voters.len() < EXTERNAL_IP_VOTES {
+                return false;
+            }
+        }
+        let table = state.table_mut(ip.is_ipv6());
+        if table.id().is_secure_for(ip) {
+            return false;
+        }
+        table.set_id(NodeId::secure(ip));
+        // the other addresses of the family lost
+        state
+            .ip_votes
+            .retain(|a, _| a.is_ipv6() != ip.is_ipv6() || *a == ip);
+        true
+    }
+
+    async fn query(&self, addr: SocketAddr, method: &str, mut args: Dict) -> Result<Dict, String> {
+        let socket = self
+            .socket(addr.is_ipv6())
+            .ok_or_else(|| format!("no DHT socket for {}", addr))?;
+        let id = self.lock().table(addr.is_ipv6()).id();
+        args.insert(b"id".to_vec(), id.0.to_vec().into());
+        let transaction = self
+            .next_transaction
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes()
+            .to_vec();
+        let (tx, rx) = oneshot::channel();
+        self.pending
+            .lock()
+            .expect("Error unable to lock mutex!")
+            .insert(transaction.clone(), (addr, tx));
+        let message = KrpcMessage::query(transaction.clone(), method, args);
+        let (packet, to) = self.datagram(message.encode(), addr);
+        let result = match socket.send_to(&packet, to).await {
+            Ok(_) => timeout(QUERY_TIMEOUT, rx).await,
+            Err(e) => Ok(Ok(Err(e.to_string()))),
+        };
+        match result {
+            Ok(Ok(answer)) => answer,
+            _ => {
+                self.pending
+                    .lock()
+                    .expect("Error unable to lock mutex!")
+                    .remove(&transaction);
+                self.lock().table_mut(addr.is_ipv6()).failed(addr);
+                Err(format!("{} query to {} timed out", method, addr))
+            }
+        }
+    }
+
+    fn handle_message(&self, message: KrpcMessage, from: SocketAddr) {
+        let answer = match message.body {
+            KrpcBody::Query { method, args } => {
+                let reply = match self.handle_query(&method, &args, from) {
+                    Ok(values) => {
+                        let mut reply = KrpcMessage::response(message.transaction, values);
+                        reply.ip = Some(from);
+                        reply
+                    }
+                    Err((code, error)) => KrpcMessage::error(message.transaction, code, error),
+                };
+                // dropped if the socket buffer is full, like any UDP packet
+                if let Some(socket) = self.socket(from.is_ipv6()) {
+                    let (packet, to) = self.datagram(reply.encode(), from);
+                    let _ = socket.try_send_to(&packet, to);
+                }
+                return;
+            }
+            KrpcBody::Response(values) => Ok(values),
+            KrpcBody::Error { code, message } => Err(format!("error {}: {}", code, message)),
+        };
+        let tx = {
+            let mut pending = self.pending.lock().expect("Error unable to lock mutex!");
+            match pending.get(&message.transaction) {
+                // answers from another address than the one queried are ignored
+                Some((addr, _)) if *addr == from => pending.remove(&message.transaction),
+                _ => None,
+            }
+        };
+        if let Some((_, tx)) = tx {
+            if let Ok(values) = &answer {
+                let id = values.get(&b"id"[..]).and_then(Value::as_bytes);
+                if let Some(id) = id.and_then(NodeId::from_bytes) {
+                    self.lock().table_mut(from.is_ipv6()).insert(id, from);
+                }
+                if let Some(ip) = message.ip {
+                    self.vote_external_ip(ip.ip(), Some(from.ip()));
+                }
+            }
+            let _ = tx.send(answer);
+        }
+    }
+
+    fn handle_query(
+        &self,
+        method: &[u8],
+        args: &Dict,
+        from: SocketAddr,
+    ) -> Result<Dict, (i64, &'static str)> {
+        let id = node_arg(args, b"id")?;
+        let ipv6 = from.is_ipv6();
+        let mut state = self.lock();
+        state.table_mut(ipv6).insert(id, from);
+        let mut values = Dict::new();
+        values.insert(b"id".to_vec(), state.table(ipv6).id().0.to_vec().into());
+        // the families asked for, or the one of the querying node
+        let want = args.get(&b"want"[..]).and_then(Value::as_list).map(|want| {
+            let wants = |family: &[u8]| want.iter().any(|w| w.as_bytes() == Some(family));
+            (wants(b"n4"), wants(b"n6"))
+        });
+        let (want4, want6) = want.unwrap_or((!ipv6, ipv6));
+        let closest_nodes = |values: &mut Dict, state: &DhtState, target: &NodeId| {
+            if want4 {
+                let nodes = state.table.closest(target, K);
+                values.insert(b"nodes".to_vec(), compact_nodes(&nodes).into());
+            }
+            if want6 {
+                let nodes = state.table6.closest(target, K);
+                values.insert(b"nodes6".to_vec(), compact_nodes(&nodes).into());
+            }
+        };
+        match method {
+            b"ping" => {}
+            b"find_node" => {
+                let target = node_arg(args, b"target")?;
+                closest_nodes(&mut values, &state, &target);
+            }
+            b"get_peers" => {
+                let info_hash = node_arg(args, b"info_hash")?;
+                let token = state.tokens.generate(from.ip());
+                values.insert(b"token".to_vec(), token.into());
+                if args.get(&b"scrape"[..]).and_then(Value::as_int) == Some(1) {
+                    if let Some((seeds, peers)) = state.peers.bloom_filters(&info_hash) {
+                        values.insert(b"BFsd".to_vec(), seeds.0.to_vec().into());
+                        values.insert(b"BFpe".to_vec(), peers.0.to_vec().into());
+                    }
+                }
+                let peers = state.peers.get(&info_hash, ipv6);
+                if peers.is_empty() {
+                    closest_nodes(&mut values, &state, &info_hash);
+                } else {
+                    let peers = peers.iter().map(|p| compact_addr(p).into()).collect();
+                    values.insert(b"values".to_vec(), Value::List(peers));
+                }
+            }
+            b"announce_peer" => {
+                let info_hash = node_arg(args, b"info_hash")?;
+                let token = args.get(&b"token"[..]).and_then(Value::as_bytes);
+                if !token.is_some_and(|t| state.tokens.verify(from.ip(), t)) {
+                    return Err((ERROR_PROTOCOL, "bad token"));
+                }
+                // the port the query came from, for peers behind a NAT
+                let implied = args.get(&b"implied_port"[..]).and_then(Value::as_int) == Some(1);
+                let port = match args.get(&b"port"[..]).and_then(Value::as_int) {
+                    _ if implied => from.port(),
+                    Some(p) if (1..=65535).contains(&p) => p as u16,
+                    _ => return Err((ERROR_PROTOCOL, "missing or invalid port")),
+                };
+                let seed = args.get(&b"seed"[..]).and_then(Value::as_int) == Some(1);
+                let addr = SocketAddr::new(from.ip(), port);
+                state.peers.add(info_hash, addr, seed);
+            }
+            b"sample_infohashes" => {
+                let target = node_arg(args, b"target")?;
+                closest_nodes(&mut values, &state, &target);
+                let (sample, count) = state.peers.sample(MAX_SAMPLES);
+                let samples: Vec<u8> = sample.iter().flat_map(|h| h.0).collect();
+                values.insert(b"samples".to_vec(), samples.into());
+                values.insert(b"num".to_vec(), Value::Int(count as i64));
+                let interval = SAMPLE_INTERVAL.as_secs() as i64;
+                values.insert(b"interval".to_vec(), Value::Int(interval));
+            }
+            b"get" => {
+                let target = node_arg(args, b"target")?;
+                let token = state.tokens.generate(from.ip());
+                values.insert(b"token".to_vec(), token.into());
+                closest_nodes(&mut values, &state, &target);
+                match state.items.get(&target) {
+                    Some(Item::Immutable(value)) => {
+                        values.insert(b"v".to_vec(), value.clone());
+                    }
+                    // only the sequence number if the querier has this version
+                    Some(Item::Mutable(item)) => {
+                        let seq = args.get(&b"seq"[..]).and_then(Value::as_int);
+                        if seq.is_some_and(|s| s >= item.seq) {
+                            values.insert(b"seq".to_vec(), Value::Int(item.seq));
+                        } else {
+                            item.write(&mut values);
+                        }
+                    }
+                    None => {}
+                }
+            }
+            b"put" => {
+                let token = args.get(&b"token"[..]).and_then(Value::as_bytes);
+                if !token.is_some_and(|t| state.tokens.verify(from.ip(), t)) {
+                    return Err((ERROR_PROTOCOL, "bad token"));
+                }
+                state.items.put(args)?;
+            }
+            _ => return Err((ERROR_METHOD_UNKNOWN, "method unknown")),
+        }
+        Ok(values)
+    }
+
+    /**
+     * Iterative lookup of the nodes closest to `target` in the IPv4 or IPv6
+     * network, starting from the routing table and `seeds`, nodes whose id we
+     * don't know yet. Every query carries `args` besides the target.
+     */
+    async fn lookup(
+        self: &Arc<Self>,
+        target: NodeId,
+        method: &'static str,
+        args: &Dict,
+        seeds: &[SocketAddr],
+        ipv6: bool,
+    ) -> Lookup {
+        let key: &[u8] = if method == "get_peers" {
+            b"info_hash"
+        } else {
+            b"target"
+        };
+        let (nodes_key, want): (&[u8], &[u8]) = if ipv6 {
+            (b"nodes6", b"n6")
+        } else {
+            (b"nodes", b"n4")
+        };
+        let (own_id, closest) = {
+            let state = self.lock();
+            let table = state.table(ipv6);
+            (table.id(), table.closest(&target, K))
+        };
+        let mut candidates: BTreeMap<[u8; 20], SocketAddr> = closest
+            .into_iter()
+            .map(|n| (n.id.distance(&target), n.addr))
+            .collect();
+        let mut seeds: Vec<SocketAddr> = seeds
+            .iter()
+            .filter(|a| a.is_ipv6() == ipv6)
+            .copied()
+            .collect();
+        let mut queried = HashSet::new();
+        let mut responded: BTreeMap<[u8; 20], LookupNode> = BTreeMap::new();
+        let mut peers = vec![];
+        let mut responses = vec![];
+        let mut in_flight = JoinSet::new();
+        loop {
+            while in_flight.len() < ALPHA {
+                // only nodes that could still be among the K closest are queried
+                let kth = responded.keys().nth(K - 1).copied();
+                let next = seeds.pop().or_else(|| {
+                    candidates
+                        .iter()
+                        .find(|(d, a)| !queried.contains(*a) && kth.is_none_or(|k| **d < k))
+                        .map(|(_, a)| *a)
+                });
+                let addr = match next {
+                    Some(a) => a,
+                    None => break,
+                };
+                if !queried.insert(addr) {
+                    continue;
+                }
+                let dht = self.clone();
+                let mut args = args.clone();
+                args.insert(key.to_vec(), target.0.to_vec().into());
+                args.insert(b"want".to_vec(), Value::List(vec![want.into()]));
+                in_flight.spawn(async move { (addr, dht.query(addr, method, args).await) });
+            }
+            let (addr, values) = match in_flight.join_next().await {
+                Some(Ok((addr, Ok(values)))) => (addr, values),
+                Some(_) => continue,
+                None => break,
+            };
+            let id = values.get(&b"id"[..]).and_then(Value::as_bytes);
+            let id = match id.and_then(NodeId::from_bytes) {
+                Some(id) => id,
+                None => continue,
+            };
+            let token = values.get(&b"token"[..]).and_then(Value::as_bytes);
+            responded.insert(id.distance(&target), (id, addr, token.map(|t| t.to_vec())));
+            if let Some(nodes) = values.get(nodes_key).and_then(Value::as_bytes) {
+                for (id, addr) in parse_compact_nodes(nodes, ipv6) {
+                    if id != own_id {
+                        candidates.entry(id.distance(&target)).or_insert(addr);
+                    }
+                }
+            }
+            let found = values.get(&b"values"[..]).and_then(Value::as_list);
+            for value in found.unwrap_or(&[]).iter().filter_map(Value::as_bytes) {
+                for peer in compact_addrs(value, value.len() == 18) {
+                    if peers.len() < MAX_LOOKUP_PEERS && !peers.contains(&peer) {
+                        peers.push(peer);
+                    }
+                }
+            }
+            responses.push(values);
+        }
+        Lookup {
+            nodes: responded.into_values().take(K).collect(),
+            peers,
+            responses,
+        }
+    }
+
+    /**
+     * Run a lookup in each network we have a socket for, towards `target` or
+     * towards our own id in that network if `None`.
+     */
+    async fn lookup_all(
+        self: &Arc<Self>,
+        target: Option<NodeId>,
+        method: &'static str,
+        args: Dict,
+        seeds: &[SocketAddr],
+    ) -> Vec<Lookup> {
+        let mut lookups = JoinSet::new();
+        for ipv6 in [false, true] {
+            if self.socket(ipv6).is_none() {
+                continue;
+            }
+            let target = target.unwrap_or_else(|| self.lock().table(ipv6).id());
+            let (dht, args, seeds) = (self.clone(), args.clone(), seeds.to_vec());
+            lookups.spawn(async move { dht.lookup(target, method, &args, &seeds, ipv6).await });
+        }
+        let mut results = vec![];
+        while let Some(lookup) = lookups.join_next().await {
+            results.extend(lookup);
+        }
+        results
+    }
+
+    pub async fn ping(&self, addr: SocketAddr) -> Result<NodeId, String> {
+        let values = self.query(addr, "ping", Dict::new()).await?;
+        values
+            .get(&b"id"[..])
+            .and_then(Value::as_bytes)
+            .and_then(NodeId::from_bytes)
+            .ok_or_else(|| String::from("invalid node id"))
+    }
+
+    /**
+     * Fill the routing table by looking up our own id, starting from the
+     * known nodes and `routers`. Returns the number of nodes known after.
+     */
+    pub async fn bootstrap(self: &Arc<Self>, routers: &[SocketAddr]) -> usize {
+        self.lookup_all(None, "find_node", Dict::new(), routers)
+            .await;
+        self.node_count()
+    }
+
+    /**
+     * The nodes closest to `target` that answered, of both networks.
+     */
+    pub async fn find_node(self: &Arc<Self>, target: NodeId) -> Vec<(NodeId, SocketAddr)> {
+        let lookups = self
+            .lookup_all(Some(target), "find_node", Dict::new(), &[])
+            .await;
+        let mut nodes: Vec<(NodeId, SocketAddr)> = lookups
+            .into_iter()
+            .flat_map(|l| l.nodes)
+            .map(|(id, a, _)| (id, a))
+            .collect();
+        nodes.sort_by_key(|(id, _)| id.distance(&target));
+        nodes
+    }
+
+    pub async fn get_peers(self: &Arc<Self>, info_hash: NodeId) -> Vec<SocketAddr> {
+        let lookups = self
+            .lookup_all(Some(info_hash), "get_peers", Dict::new(), &[])
+            .await;
+        merge_peers(&lookups)
+    }
+
+    /**
+     * Find peers of `info_hash` and announce that we accept connections on
+     * `port`, or on the port of the DHT socket if `None`, as a seed if we
+     * have the whole torrent.
+     */
+    pub async fn announce(
+        self: &Arc<Self>,
+        info_hash: NodeId,
+        port: Option<u16>,
+        seed: bool,
+    ) -> Vec<SocketAddr> {
+        let lookups = self
+            .lookup_all(Some(info_hash), "get_peers", Dict::new(), &[])
+            .await;
+        let mut announces = JoinSet::new();
+        for (_, addr, token) in lookups.iter().flat_map(|l| l.nodes.clone()) {
+            let token = match token {
+                Some(t) => t,
+                None => continue,
+            };
+            let mut args = Dict::new();
+            args.insert(b"info_hash".to_vec(), info_hash.0.to_vec().into());
+            args.insert(b"token".to_vec(), token.into());
+            args.insert(b"port".to_vec(), Value::Int(port.unwrap_or(0) as i64));
+            if port.is_none() {
+                args.insert(b"implied_port".to_vec(), Value::Int(1));
+            }
+            if seed {
+                args.insert(b"seed".to_vec(), Value::Int(1));
+            }
+            let dht = self.clone();
+            announces.spawn(async move { dht.query(addr, "announce_peer", args).await });
+        }
+        while announces.join_next().await.is_some() {}
+        merge_peers(&lookups)
+    }
+
+    /**
+     * Estimate the number of seeds and of downloaders of `info_hash` from
+     * the bloom filters of the nodes closest to it.
+     */
+    pub async fn scrape(self: &Arc<Self>, info_hash: NodeId) -> (usize, usize) {
+        let mut args = Dict::new();
+        args.insert(b"scrape".to_vec(), Value::Int(1));
+        let lookups = self
+            .lookup_all(Some(info_hash), "get_peers", args, &[])
+            .await;
+        let (mut seeds, mut downloaders) = (BloomFilter::default(), BloomFilter::default());
+        for values in lookups.iter().flat_map(|l| &l.responses) {
+            let filter = |key: &[u8]| {
+                let bytes = values.get(key).and_then(Value::as_bytes);
+                bytes.and_then(BloomFilter::from_bytes)
+            };
+            if let (Some(sd), Some(pe)) = (filter(b"BFsd"), filter(b"BFpe")) {
+                seeds.union(&sd);
+                downloaders.union(&pe);
+            }
+        }
+        (seeds.estimate(), downloaders.estimate())
+    }
+
+    /**
+     * Info hashes sampled from the nodes closest to `target`, and the total
+     * number of info hashes those nodes store.
+     */
+    pub async fn sample_infohashes(self: &Arc<Self>, target: NodeId) -> (Vec<NodeId>, usize) {
+        let lookups = self
+            .lookup_all(Some(target), "sample_infohashes", Dict::new(), &[])
+            .await;
+        let (mut samples, mut total) = (vec![], 0);
+        for values in lookups.iter().flat_map(|l| &l.responses) {
+            let num = values.get(&b"num"[..]).and_then(Value::as_int);
+            total += num.unwrap_or(0).max(0) as usize;
+            let bytes = values.get(&b"samples"[..]).and_then(Value::as_bytes);
+            let found = bytes.unwrap_or(&[]).chunks_exact(20);
+            for info_hash in found.filter_map(NodeId::from_bytes) {
+                if !samples.contains(&info_hash) {
+                    samples.push(info_hash);
+                }
+            }
+        }
+        (samples, total)
+    }
+
+    /**
+     * Put an item on the nodes closest to `target` that gave us a token,
+     * `args` holding the item. Returns the number of nodes that stored it.
+     */
+    async fn put(self: &Arc<Self>, target: NodeId, args: Dict) -> Result<usize, String> {
+        let lookups = self.lookup_all(Some(target), "get", Dict::new(), &[]).await;
+        let mut puts = JoinSet::new();
+        for (_, addr, token) in lookups.into_iter().flat_map(|l| l.nodes) {
+            if let Some(token) = token {
+                let mut args = args.clone();
+                args.insert(b"token".to_vec(), token.into());
+                let dht = self.clone();
+                puts.spawn(async move { dht.query(addr, "put", args).await });
+            }
+        }
+        let mut stored = 0;
+        let mut error = String::from("no node to store the item on");
+        while let Some(result) = puts.join_next().await {
+            match result {
+                Ok(Ok(_)) => stored += 1,
+                Ok(Err(e)) => error = e,
+                Err(e) => error = e.to_string(),
+            }
+        }
+        if stored == 0 {
+            return Err(error);
+        }
+        Ok(stored)
+    }
+
+    /**
+     * Store `value` in the DHT, returns its target.
+     */
+    pub async fn put_immutable(self: &Arc<Self>, value: Value) -> Result<NodeId, String> {
+        let target = immutable_target(&value);
+        let mut args = Dict::new();
+        args.insert(b"v".to_vec(), value);
+        self.put(target, args).await?;
+        Ok(target)
+    }
+
+    pub async fn get_immutable(self: &Arc<Self>, target: NodeId) -> Option<Value> {
+        let lookups = self.lookup_all(Some(target), "get", Dict::new(), &[]).await;
+        lookups
+            .into_iter()
+            .flat_map(|l| l.responses)
+            .filter_map(|mut values| values.remove(&b"v"[..]))
+            .find(|value| immutable_target(value) == target)
+    }
+
+    /**
+     * Store a signed item, replacing the one with sequence number `cas` if
+     * given. Returns the number of nodes that stored it.
+     */
+    pub async fn put_mutable(
+        self: &Arc<Self>,
+        item: &MutableItem,
+        cas: Option<i64>,
+    ) -> Result<usize, String> {
+        let mut args = Dict::new();
+        item.write(&mut args);
+        if let Some(cas) = cas {
+            args.insert(b"cas".to_vec(), Value::Int(cas));
+        }
+        self.put(item.target(), args).await
+    }
+
+    /**
+     * The item of `key` and `salt` with the highest sequence number and a
+     * valid signature.
+     */
+    pub async fn get_mutable(self: &Arc<Self>, key: &[u8; 32], salt: &[u8]) -> Option<MutableItem> {
+        let target = mutable_target(key, salt);
+        let lookups = self.lookup_all(Some(target), "get", Dict::new(), &[]).await;
+        lookups
+            .into_iter()
+            .flat_map(|l| l.responses)
+            .filter_map(|mut values| {
+                // nodes don't send the salt back
+                if !salt.is_empty() {
+                    values.insert(b"salt".to_vec(), salt.to_vec().into());
+                }
+                MutableItem::from_dict(&values).ok()
+            })
+            .filter(|item| item.key == *key)
+            .max_by_key(|item| item.seq)
+    }
+
+    /**
+     * Save the node ids and routing tables to `path`.
+     */
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let state = self.lock();
+        let good = |n: &&Node| n.failures < MAX_FAILURES;
+        let mut dict = Dict::new();
+        dict.insert(b"id".to_vec(), state.table.id().0.to_vec().into());
+        dict.insert(b"id6".to_vec(), state.table6.id().0.to_vec().into());
+        let nodes = compact_nodes(state.table.nodes().filter(good));
+        dict.insert(b"nodes".to_vec(), nodes.into());
+        let nodes6 = compact_nodes(state.table6.nodes().filter(good));
+        dict.insert(b"nodes6".to_vec(), nodes6.into());
+        let bytes = Value::Dict(dict)
+            .to_bencode()
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        // write then rename so that a crash never leaves half a file
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, bytes)?;
+        std::fs::rename(tmp, path)
+    }
+}
+
+/**
+ * Peers found by lookups in both networks, without duplicates.
+ */
+fn merge_peers(lookups: &[Lookup]) -> Vec<SocketAddr> {
+    let mut peers = vec![];
+    for peer in lookups.iter().flat_map(|l| &l.peers) {
+        if !peers.contains(peer) {
+            peers.push(*peer);
+        }
+    }
+    peers
+}
+
+pub fn load_state(path: &Path) -> io::Result<SavedState> {
+    let invalid = |e: &str| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+    let bytes = std::fs::read(path)?;
+    let value = Value::from_bencode(&bytes).map_err(|e| invalid(&e.to_string()))?;
+    let dict = value.as_dict().ok_or_else(|| invalid("not a dictionary"))?;
+    let get = |key: &[u8]| dict.get(key).and_then(Value::as_bytes).unwrap_or(&[]);
+    let id = NodeId::from_bytes(get(b"id")).ok_or_else(|| invalid("missing node id"))?;
+    // files saved before IPv6 support have a single id
+    let id6 = NodeId::from_bytes(get(b"id6")).unwrap_or(id);
+    let mut nodes = parse_compact_nodes(get(b"nodes"), false);
+    nodes.extend(parse_compact_nodes(get(b"nodes6"), true));
+    Ok(SavedState { id, id6, nodes })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(first: u8) -> NodeId {
+        let mut id = [0; 20];
+        id[0] = first;
+        NodeId(id)
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn routing_table() {
+        let mut table = RoutingTable::new(id(0));
+        assert_eq!(id(0).common_prefix(&id(0x80)), 0);
+        assert_eq!(id(0).common_prefix(&id(0x01)), 7);
+        assert!(!table.insert(id(0), addr(1)));
+
+        // bucket 0 holds the ids starting with a 1 bit
+        for i in 0..K as u8 {
+            assert!(table.insert(id(0x80 + i), addr(100 + i as u16)));
+        }
+        assert!(!table.insert(id(0xf0), addr(200)));
+        // the known address is kept
+        assert!(!table.insert(id(0x80), addr(300)));
+        assert!(table.insert(id(0x80), addr(100)));
+
+        // unresponsive nodes are replaced
+        table.failed(addr(101));
+        table.failed(addr(101));
+        assert!(table.insert(id(0xf0), addr(200)));
+        assert!(table.insert(id(0x01), addr(2)));
+        assert_eq!(table.len(), K + 1);
+
+        let closest: Vec<NodeId> = table.closest(&id(0x03), 3).iter().map(|n| n.id).collect();
+        assert_eq!(closest, vec![id(0x01), id(0x83), id(0x82)]);
+    }
+
+    #[test]
+    fn tokens() {
+        let mut tokens = Tokens::new();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let token = tokens.generate(ip);
+        assert!(tokens.verify(ip, &token));
+        assert!(!tokens.verify("10.0.0.2".parse().unwrap(), &token));
+        // valid for one more rotation
+        tokens.rotated -= TOKEN_ROTATION;
+        assert!(tokens.verify(ip, &token));
+        tokens.rotated -= TOKEN_ROTATION;
+        assert!(!tokens.verify(ip, &token));
+    }
+
+    #[test]
+    fn bloom_filters() {
+        // test vector of BEP 33: 192.0.2.0-255 and 2001:db8::0-3e7 are
+        // estimated at 1224.93
+        let mut filter = BloomFilter::default();
+        for i in 0..=255u8 {
+            filter.insert(IpAddr::from([192, 0, 2, i]));
+        }
+        for i in 0..1000u16 {
+            filter.insert(IpAddr::from([0x2001, 0xdb8, 0, 0, 0, 0, 0, i]));
+        }
+        assert_eq!(filter.estimate(), 1225);
+        assert_eq!(BloomFilter::default().estimate(), 0);
+
+        let mut other = BloomFilter::default();
+        other.insert("10.0.0.1".parse().unwrap());
+        assert_eq!(other.estimate(), 1);
+        assert_eq!(BloomFilter::from_bytes(&other.0), Some(other.clone()));
+        assert_eq!(BloomFilter::from_bytes(&[0; 10]), None);
+        other.union(&filter);
+        assert!(other.estimate() >= filter.estimate());
+    }
+
+    #[test]
+    fn secure_node_ids() {
+        // examples from BEP 42, the third byte only has 5 bits set by the address
+        let examples = [
+            ("124.31.75.21", 0x01, [0x5f, 0xbf, 0xbf]),
+            ("21.75.31.124", 0x56, [0x5a, 0x3c, 0xe9]),
+            ("65.23.51.170", 0x16, [0xa5, 0xd4, 0x32]),
+            ("84.124.73.14", 0x41, [0x1b, 0x03, 0x21]),
+            ("43.213.53.83", 0x5a, [0xe5, 0x6f, 0x6c]),
+        ];
+        for (ip, r, prefix) in examples {
+            let ip: IpAddr = ip.parse().unwrap();
+            let mut id = id(0);
+            id.0[..3].copy_from_slice(&prefix);
+            id.0[19] = r;
+            assert!(id.is_secure_for(ip));
+            id.0[2] ^= 0x07;
+            assert!(id.is_secure_for(ip));
+            id.0[2] ^= 0x08;
+            assert!(!id.is_secure_for(ip));
+        }
+        for ip in ["124.31.75.21", "2001:db8::1"] {
+            let ip: IpAddr = ip.parse().unwrap();
+            assert!(NodeId::secure(ip).is_secure_for(ip));
+            assert!(!NodeId::secure(ip).is_secure_for("21.75.31.124".parse().unwrap()));
+        }
+
+        // non-compliant nodes are refused, unless on a local network
+        let mut table = RoutingTable::new(NodeId::random());
+        let public: SocketAddr = "124.31.75.21:6881".parse().unwrap();
+        assert!(!table.insert(id(0x80), public));
+        assert!(table.insert(NodeId::secure(public.ip()), public));
+        assert!(table.insert(id(0x80), "192.168.1.2:6881".parse().unwrap()));
+        assert!(table.insert(id(0x81), "[fd00::2]:6881".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn external_ip_votes() {
+        let dht = Dht::bind(&[addr(0)], id(0x80)).await.unwrap();
+        let node = NodeId::secure("124.31.75.21".parse().unwrap());
+        dht.add_nodes(&[
+            (id(0x40), addr(6881)),
+            (node, "124.31.75.21:6881".parse().unwrap()),
+        ]);
+        let ip: IpAddr = "21.75.31.124".parse().unwrap();
+        assert!(!dht.vote_external_ip("10.0.0.1".parse().unwrap(), None));
+        // a few peers have to agree
+        for voter in 1..EXTERNAL_IP_VOTES as u8 {
+            let voter = IpAddr::from([65, 23, 51, voter]);
+            assert!(!dht.vote_external_ip(ip, Some(voter)));
+            assert!(!dht.vote_external_ip(ip, Some(voter)));
+        }
+        assert!(dht.vote_external_ip(ip, Some("65.23.51.170".parse().unwrap())));
+        assert!(dht.id().is_secure_for(ip));
+        assert_eq!(dht.id6(), id(0x80));
+        // the nodes are kept under the new id
+        assert_eq!(dht.node_count(), 2);
+        assert!(!dht.vote_external_ip(ip, None));
+
+        // the tracker is believed right away
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        assert!(dht.vote_external_ip(ip, None));
+        assert!(dht.id6().is_secure_for(ip));
+    }
+
+    #[tokio::test]
+    async fn save_routing_table() {
+        let dht = Dht::bind(&[addr(0)], NodeId::random()).await.unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        let v6_id = NodeId::secure(v6.ip());
+        dht.add_nodes(&[(id(0x80), addr(6881)), (v6_id, v6)]);
+        dht.vote_external_ip("2001:db8::2".parse().unwrap(), None);
+        let path = std::env::temp_dir().join(format!("dht-state-{}", std::process::id()));
+        dht.save(&path).unwrap();
+        let mut saved = load_state(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!((saved.id, saved.id6), (dht.id(), dht.id6()));
+        assert_ne!(saved.id, saved.id6);
+        saved.nodes.sort();
+        let mut expected = vec![(id(0x80), addr(6881)), (v6_id, v6)];
+        expected.sort();
+        assert_eq!(saved.nodes, expected);
+
+        let restored = Dht::bind(&[addr(0)], NodeId::random()).await.unwrap();
+        restored.restore(&saved);
+        assert_eq!((restored.id(), restored.id6()), (dht.id(), dht.id6()));
+        assert_eq!(restored.node_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn localhost_swarm() {
+        let mut nodes = vec![];
+        for _ in 0..20 {
+            nodes.push(Dht::bind(&[addr(0)], NodeId::random()).await.unwrap());
+        }
+        let router = nodes[0].local_addr().unwrap();
+        for node in &nodes[1..] {
+            node.bootstrap(&[router]).await;
+        }
+        // a second round spreads the nodes that joined late
+        for node in &nodes {
+            node.bootstrap(&[]).await;
+        }
+        assert!(nodes.iter().all(|n| n.node_count() >= K));
+
+        let target = nodes[10].id();
+        let found = nodes[3].find_node(target).await;
+        assert_eq!(found[0], (target, nodes[10].local_addr().unwrap()));
+
+        let info_hash = NodeId::random();
+        assert!(nodes[5]
+            .announce(info_hash, Some(6881), false)
+            .await
+            .is_empty());
+        nodes[7].announce(info_hash, None, false).await;
+        let mut peers = nodes[15].get_peers(info_hash).await;
+        peers.sort();
+        let implied = nodes[7].local_addr().unwrap();
+        assert_eq!(peers, vec![addr(6881), implied]);
+
+        // announcing needs a token from get_peers
+        let mut args = Dict::new();
+        args.insert(b"info_hash".to_vec(), info_hash.0.to_vec().into());
+        args.insert(b"token".to_vec(), b"invalid".to_vec().into());
+        args.insert(b"port".to_vec(), Value::Int(6882));
+        let to = nodes[1].local_addr().unwrap();
+        let result = nodes[2].query(to, "announce_peer", args).await;
+        assert_eq!(result, Err(String::from("error 203: bad token")));
+        assert_eq!(nodes[2].ping(to).await, Ok(nodes[1].id()));
+
+        // all the peers share one address, a seed once announced as one
+        assert_eq!(nodes[11].scrape(info_hash).await, (0, 1));
+        nodes[5].announce(info_hash, Some(6881), true).await;
+        assert_eq!(nodes[11].scrape(info_hash).await, (1, 1));
+        assert_eq!(nodes[11].scrape(NodeId::random()).await, (0, 0));
+        let (samples, total) = nodes[9].sample_infohashes(info_hash).await;
+        assert_eq!(samples, vec![info_hash]);
+        assert!(total >= 1);
+
+        // stored items are found from any node
+        let value = Value::from(&b"Hello World!"[..]);
+        let target = nodes[4].put_immutable(value.clone()).await.unwrap();
+        assert_eq!(nodes[12].get_immutable(target).await, Some(value.clone()));
+        assert_eq!(nodes[12].get_immutable(NodeId::random()).await, None);
+
+        let key = ed25519_dalek::SigningKey::from_bytes(&[3; 32]);
+        let public_key = key.verifying_key().to_bytes();
+        let first = MutableItem::sign(&key, b"nightly", 1, value);
+        assert!(nodes[6].put_mutable(&first, None).await.unwrap() > 0);
+        let second = MutableItem::sign(&key, b"nightly", 2, Value::Int(2));
+        assert!(nodes[8].put_mutable(&second, Some(1)).await.is_ok());
+        assert_eq!(
+            nodes[14].get_mutable(&public_key, b"nightly").await,
+            Some(second)
+        );
+        assert_eq!(nodes[14].get_mutable(&public_key, b"other").await, None);
+    }
+
+    #[tokio::test]
+    async fn dual_stack_swarm() {
+        let v6 = |port: u16| SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], port));
+        let mut nodes = vec![];
+        for _ in 0..12 {
+            let dht = Dht::bind(&[addr(0), v6(0)], NodeId::random())
+                .await
+                .unwrap();
+            nodes.push(dht);
+        }
+        let routers = nodes[0].local_addrs();
+        assert_eq!(routers.len(), 2);
+        for node in &nodes[1..] {
+            node.bootstrap(&routers).await;
+        }
+        for node in &nodes {
+            node.bootstrap(&[]).await;
+        }
+        for node in &nodes {
+            let state = node.lock();
+            assert!(state.table.len() >= K && state.table6.len() >= K);
+            assert!(state.table.nodes().all(|n| n.addr.is_ipv4()));
+            assert!(state.table6.nodes().all(|n| n.addr.is_ipv6()));
+        }
+
+        // announced in both networks, from each address
+        let info_hash = NodeId::random();
+        nodes[4].announce(info_hash, Some(6881), false).await;
+        let mut peers = nodes[9].get_peers(info_hash).await;
+        peers.sort();
+        assert_eq!(peers, vec![addr(6881), v6(6881)]);
+
+        // `want` picks the families of the returned nodes
+        let to = nodes[1].local_addrs()[0];
+        let mut args = Dict::new();
+        args.insert(b"target".to_vec(), info_hash.0.to_vec().into());
+        let values = nodes[2].query(to, "find_node", args.clone()).await.unwrap();
+        assert!(values.contains_key(&b"nodes"[..]) && !values.contains_key(&b"nodes6"[..]));
+        args.insert(
+            b"want".to_vec(),
+            Value::List(vec![b"n4".to_vec().into(), b"n6".to_vec().into()]),
+        );
+        let values = nodes[2].query(to, "find_node", args).await.unwrap();
+        let nodes6 = values
+            .get(&b"nodes6"[..])
+            .and_then(Value::as_bytes)
+            .unwrap();
+        assert!(!parse_compact_nodes(nodes6, true).is_empty());
+        assert!(values.contains_key(&b"nodes"[..]));
+    }
+}
@@ -0,0 +1,303 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use bendy::encoding::ToBencode;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha1_smol::Sha1;
+
+use crate::{
+    dht::NodeId,
+    krpc::{
+        Dict, Value, ERROR_CAS_MISMATCH, ERROR_INVALID_SIGNATURE, ERROR_MESSAGE_TOO_BIG,
+        ERROR_PROTOCOL, ERROR_SALT_TOO_BIG, ERROR_SEQ_TOO_LOW,
+    },
+};
+
+// Arbitrary data stored in the DHT (BEP 44), with the `get` and `put`
+// queries. Items are stored on the nodes closest to their target:
+// - immutable items are found by the SHA-1 of their bencoded value
+// - mutable items are signed with an ed25519 key and found by the SHA-1 of
+//   the public key and an optional salt, so one key can publish several
+//   items. Each update has a higher sequence number, and a put may carry
+//   the sequence number it expects to replace (`cas`) so that concurrent
+//   writers don't overwrite each other.
+// Values are at most 1000 bytes bencoded, and nodes forget items after two
+// hours unless they are put again.
+
+// bencoded size of a value
+pub const MAX_VALUE_SIZE: usize = 1000;
+pub const MAX_SALT_SIZE: usize = 64;
+const ITEM_TTL: Duration = Duration::from_secs(2 * 60 * 60);
+const MAX_ITEMS: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MutableItem {
+    pub key: [u8; 32],
+    pub salt: Vec<u8>,
+    pub seq: i64,
+    pub value: Value,
+    pub signature: [u8; 64],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Item {
+    Immutable(Value),
+    Mutable(MutableItem),
+}
+
+pub fn immutable_target(value: &Value) -> NodeId {
+    let bytes = value.to_bencode().unwrap_or_default();
+    NodeId(Sha1::from(bytes).digest().bytes())
+}
+
+pub fn mutable_target(key: &[u8; 32], salt: &[u8]) -> NodeId {
+    let mut hasher = Sha1::new();
+    hasher.update(key);
+    hasher.update(salt);
+    NodeId(hasher.digest().bytes())
+}
+
+/**
+ * Bencoded size of `value`, `None` past the nesting limit.
+ */
+fn value_size(value: &Value) -> Option<usize> {
+    value.to_bencode().ok().map(|b| b.len())
+}
+
+impl MutableItem {
+    pub fn sign(signing_key: &SigningKey, salt: &[u8], seq: i64, value: Value) -> Self {
+        let signature = signing_key.sign(&MutableItem::signed_bytes(salt, seq, &value));
+        MutableItem {
+            key: signing_key.verifying_key().to_bytes(),
+            salt: salt.to_vec(),
+            seq,
+            value,
+            signature: signature.to_bytes(),
+        }
+    }
+
+    /**
+     * What the signature covers: the salt if any, the sequence number and
+     * the value, as they would appear in a bencoded dictionary.
+     */
+    fn signed_bytes(salt: &[u8], seq: i64, value: &Value) -> Vec<u8> {
+        let mut bytes = vec![];
+        if !salt.is_empty() {
+            bytes.extend_from_slice(format!("4:salt{}:", salt.len()).as_bytes());
+            bytes.extend_from_slice(salt);
+        }
+        bytes.extend_from_slice(format!("3:seqi{}e1:v", seq).as_bytes());
+        bytes.extend(value.to_bencode().unwrap_or_default());
+        bytes
+    }
+
+    pub fn verify(&self) -> bool {
+        let key = match VerifyingKey::from_bytes(&self.key) {
+            Ok(k) => k,
+            Err(_) => return false,
+        };
+        let bytes = MutableItem::signed_bytes(&self.salt, self.seq, &self.value);
+        key.verify(&bytes, &Signature::from_bytes(&self.signature))
+            .is_ok()
+    }
+
+    pub fn target(&self) -> NodeId {
+        mutable_target(&self.key, &self.salt)
+    }
+
+    /**
+     * Read an item from the arguments of a put or the values of a get,
+     * checking its size and signature.
+     */
+    pub fn from_dict(dict: &Dict) -> Result<Self, (i64, &'static str)> {
+        let invalid = (ERROR_PROTOCOL, "missing or invalid argument");
+        let bytes = |key: &[u8]| dict.get(key).and_then(Value::as_bytes);
+        let salt = bytes(b"salt").unwrap_or(&[]);
+        if salt.len() > MAX_SALT_SIZE {
+            return Err((ERROR_SALT_TOO_BIG, "salt too big"));
+        }
+        let value = dict.get(&b"v"[..]).ok_or(invalid)?;
+        if value_size(value).is_none_or(|s| s > MAX_VALUE_SIZE) {
+            return Err((ERROR_MESSAGE_TOO_BIG, "message too big"));
+        }
+        let item = MutableItem {
+            key: bytes(b"k").and_then(|k| k.try_into().ok()).ok_or(invalid)?,
+            salt: salt.to_vec(),
+            seq: dict
+                .get(&b"seq"[..])
+                .and_then(Value::as_int)
+                .ok_or(invalid)?,
+            value: value.clone(),
+            signature: bytes(b"sig")
+                .and_then(|s| s.try_into().ok())
+                .ok_or(invalid)?,
+        };
+        if !item.verify() {
+            return Err((ERROR_INVALID_SIGNATURE, "invalid signature"));
+        }
+        Ok(item)
+    }
+
+    /**
+     * Add the item to put arguments or get values.
+     */
+    pub fn write(&self, dict: &mut Dict) {
+        dict.insert(b"k".to_vec(), self.key.to_vec().into());
+        if !self.salt.is_empty() {
+            dict.insert(b"salt".to_vec(), self.salt.clone().into());
+        }
+        dict.insert(b"seq".to_vec(), Value::Int(self.seq));
+        dict.insert(b"sig".to_vec(), self.signature.to_vec().into());
+        dict.insert(b"v".to_vec(), self.value.clone());
+    }
+}
+
+/**
+ * Items put on our node by others.
+ */
+#[derive(Default)]
+pub struct ItemStore {
+    items: HashMap<NodeId, (Item, Instant)>,
+}
+
+impl ItemStore {
+    pub fn get(&self, target: &NodeId) -> Option<&Item> {
+        self.items
+            .get(target)
+            .filter(|(_, t)| t.elapsed() < ITEM_TTL)
+            .map(|(item, _)| item)
+    }
+
+    /**
+     * Store the value of a put, replacing a mutable item only with a newer
+     * one, and only if its sequence number is `cas` when given.
+     */
+    pub fn put(&mut self, args: &Dict) -> Result<NodeId, (i64, &'static str)> {
+        let item = if args.contains_key(&b"k"[..]) {
+            Item::Mutable(MutableItem::from_dict(args)?)
+        } else {
+            let value = args
+                .get(&b"v"[..])
+                .ok_or((ERROR_PROTOCOL, "missing or invalid argument"))?;
+            if value_size(value).is_none_or(|s| s > MAX_VALUE_SIZE) {
+                return Err((ERROR_MESSAGE_TOO_BIG, "message too big"));
+            }
+            Item::Immutable(value.clone())
+        };
+        let target = match &item {
+            Item::Immutable(value) => immutable_target(value),
+            Item::Mutable(item) => item.target(),
+        };
+        if let (Item::Mutable(new), Some(Item::Mutable(old))) = (&item, self.get(&target)) {
+            let cas = args.get(&b"cas"[..]).and_then(Value::as_int);
+            if cas.is_some_and(|cas| cas != old.seq) {
+                return Err((ERROR_CAS_MISMATCH, "CAS mismatch"));
+            }
+            // the same item may be put again to keep it alive
+            if new.seq < old.seq || (new.seq == old.seq && new.value != old.value) {
+                return Err((ERROR_SEQ_TOO_LOW, "sequence number less than current"));
+            }
+        }
+        if !self.items.contains_key(&target) && self.items.len() >= MAX_ITEMS {
+            self.items.retain(|_, (_, t)| t.elapsed() < ITEM_TTL);
+            if self.items.len() >= MAX_ITEMS {
+                return Err((ERROR_PROTOCOL, "storage full"));
+            }
+        }
+        self.items.insert(target, (item, Instant::now()));
+        Ok(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn dht_items() {
+        // test vectors from BEP 44
+        let value = Value::from(&b"Hello World!"[..]);
+        let mut item = MutableItem {
+            key: hex("77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548")
+                .try_into()
+                .unwrap(),
+            salt: vec![],
+            seq: 1,
+            value: value.clone(),
+            signature: hex("305ac8aeb6c9c151fa120f120ea2cfb923564e11552d06a5d856091e5e853cff1260d3f39e4999684aa92eb73ffd136e6f4f3ecbfda0ce53a1608ecd7ae21f01").try_into().unwrap(),
+        };
+        assert_eq!(
+            MutableItem::signed_bytes(&item.salt, item.seq, &item.value),
+            b"3:seqi1e1:v12:Hello World!".to_vec()
+        );
+        assert!(item.verify());
+        assert_eq!(
+            item.target().0.to_vec(),
+            hex("4a533d47ec9c7d95b1ad75f576cffc641853b750")
+        );
+        item.salt = b"foobar".to_vec();
+        item.signature = hex("6834284b6b24c3204eb2fea824d82f88883a3d95e8b4a21b8c0ded553d17d17ddf9a8a7104b1258f30bed3787e6cb896fca78c58f8e03b5f18f14951a87d9a08").try_into().unwrap();
+        assert!(item.verify());
+        assert_eq!(
+            item.target().0.to_vec(),
+            hex("411eba73b6f087ca51a3795d9c8c938d365e32c1")
+        );
+        item.seq = 2;
+        assert!(!item.verify());
+        assert_eq!(
+            immutable_target(&value).0.to_vec(),
+            hex("e5f96f6f38320f0f33959cb4d3d656452117aadb")
+        );
+
+        // signed items round trip through put arguments
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let item = MutableItem::sign(&signing_key, b"salt", 1, value.clone());
+        let mut args = Dict::new();
+        item.write(&mut args);
+        assert_eq!(MutableItem::from_dict(&args), Ok(item.clone()));
+        let mut forged = args.clone();
+        forged.insert(b"v".to_vec(), Value::from(&b"Goodbye"[..]));
+        assert_eq!(
+            MutableItem::from_dict(&forged).unwrap_err().0,
+            ERROR_INVALID_SIGNATURE
+        );
+        let mut salted = args.clone();
+        salted.insert(b"salt".to_vec(), vec![0; 65].into());
+        assert_eq!(
+            MutableItem::from_dict(&salted).unwrap_err().0,
+            ERROR_SALT_TOO_BIG
+        );
+
+        let mut store = ItemStore::default();
+        assert_eq!(store.put(&args), Ok(item.target()));
+        // putting the same item again refreshes it
+        assert_eq!(store.put(&args), Ok(item.target()));
+        let older = MutableItem::sign(&signing_key, b"salt", 0, value.clone());
+        let mut older_args = Dict::new();
+        older.write(&mut older_args);
+        assert_eq!(store.put(&older_args).unwrap_err().0, ERROR_SEQ_TOO_LOW);
+        let newer = MutableItem::sign(&signing_key, b"salt", 2, Value::Int(2));
+        let mut newer_args = Dict::new();
+        newer.write(&mut newer_args);
+        newer_args.insert(b"cas".to_vec(), Value::Int(0));
+        assert_eq!(store.put(&newer_args).unwrap_err().0, ERROR_CAS_MISMATCH);
+        newer_args.insert(b"cas".to_vec(), Value::Int(1));
+        assert_eq!(store.put(&newer_args), Ok(item.target()));
+        assert_eq!(store.get(&item.target()), Some(&Item::Mutable(newer)));
+
+        let mut immutable = Dict::new();
+        immutable.insert(b"v".to_vec(), value.clone());
+        assert_eq!(store.put(&immutable), Ok(immutable_target(&value)));
+        immutable.insert(b"v".to_vec(), vec![0; MAX_VALUE_SIZE].into());
+        assert_eq!(store.put(&immutable).unwrap_err().0, ERROR_MESSAGE_TOO_BIG);
+    }
+}
@@ -0,0 +1,318 @@
+use std::{collections::BTreeMap, net::SocketAddr};
+
+use bendy::{
+    decoding::{Error, FromBencode, Object},
+    encoding::{self, SingleItemEncoder, ToBencode},
+};
+
+use crate::pex::{compact_addr, compact_addrs};
+
+// KRPC, the DHT message format (BEP 5): a bencoded dictionary per UDP packet.
+// Every message has a transaction id `t`, echoed by the reply, and a type `y`:
+// - `q`: query, the method name in `q` and the arguments in `a`
+// - `r`: response, the return values in `r`
+// - `e`: error, a list of an error code and a message in `e`
+// Arguments and return values are kept as generic bencode values since their
+// keys depend on the method.
+// Responses also carry the address the query came from in `ip` (BEP 42), so
+// that nodes learn their external address.
+
+// nesting of values accepted from the network
+const MAX_DEPTH: usize = 32;
+
+pub const ERROR_GENERIC: i64 = 201;
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+// storage errors (BEP 44)
+pub const ERROR_MESSAGE_TOO_BIG: i64 = 205;
+pub const ERROR_INVALID_SIGNATURE: i64 = 206;
+pub const ERROR_SALT_TOO_BIG: i64 = 207;
+pub const ERROR_CAS_MISMATCH: i64 = 301;
+pub const ERROR_SEQ_TOO_LOW: i64 = 302;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+pub type Dict = BTreeMap<Vec<u8>, Value>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KrpcBody {
+    Query { method: Vec<u8>, args: Dict },
+    Response(Dict),
+    Error { code: i64, message: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KrpcMessage {
+    pub transaction: Vec<u8>,
+    pub body: KrpcBody,
+    // client version of the sender
+    pub version: Option<Vec<u8>>,
+    // address of the receiver as seen by the sender
+    pub ip: Option<SocketAddr>,
+}
+
+impl Value {
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            Value::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&Dict> {
+        match self {
+            Value::Dict(d) => Some(d),
+            _ => None,
+        }
+    }
+}
+
+impl From<&[u8]> for Value {
+    fn from(bytes: &[u8]) -> Self {
+        Value::Bytes(bytes.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(bytes: Vec<u8>) -> Self {
+        Value::Bytes(bytes)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Self {
+        Value::Int(i)
+    }
+}
+
+impl ToBencode for Value {
+    const MAX_DEPTH: usize = MAX_DEPTH;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), encoding::Error> {
+        match self {
+            Value::Int(i) => encoder.emit_int(*i),
+            Value::Bytes(b) => encoder.emit_bytes(b),
+            Value::List(l) => encoder.emit_list(|e| {
+                for v in l {
+                    e.emit(v)?;
+                }
+                Ok(())
+            }),
+            // a `BTreeMap` iterates the keys in the sorted order bencode needs
+            Value::Dict(d) => encoder.emit_dict(|mut e| {
+                for (k, v) in d {
+                    e.emit_pair(k, v)?;
+                }
+                Ok(())
+            }),
+        }
+    }
+}
+
+impl FromBencode for Value {
+    const EXPECTED_RECURSION_DEPTH: usize = MAX_DEPTH;
+
+    fn decode_bencode_object(object: Object) -> Result<Self, Error>
+    where
+        Self: Sized,
+    {
+        match object {
+            Object::Integer(_) => i64::decode_bencode_object(object).map(Value::Int),
+            Object::Bytes(b) => Ok(Value::Bytes(b.to_vec())),
+            Object::List(mut list) => {
+                let mut values = vec![];
+                while let Some(item) = list.next_object()? {
+                    values.push(Value::decode_bencode_object(item)?);
+                }
+                Ok(Value::List(values))
+            }
+            Object::Dict(mut dict) => {
+                let mut values = BTreeMap::new();
+                while let Some((key, item)) = dict.next_pair()? {
+                    values.insert(key.to_vec(), Value::decode_bencode_object(item)?);
+                }
+                Ok(Value::Dict(values))
+            }
+        }
+    }
+}
+
+impl KrpcMessage {
+    pub fn query(transaction: Vec<u8>, method: &str, args: Dict) -> Self {
+        KrpcMessage {
+            transaction,
+            body: KrpcBody::Query {
+                method: method.as_bytes().to_vec(),
+                args,
+            },
+            version: None,
+            ip: None,
+        }
+    }
+
+    pub fn response(transaction: Vec<u8>, values: Dict) -> Self {
+        KrpcMessage {
+            transaction,
+            body: KrpcBody::Response(values),
+            version: None,
+            ip: None,
+        }
+    }
+
+    pub fn error(transaction: Vec<u8>, code: i64, message: &str) -> Self {
+        KrpcMessage {
+            transaction,
+            body: KrpcBody::Error {
+                code,
+                message: message.to_string(),
+            },
+            version: None,
+            ip: None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut dict = Dict::new();
+        dict.insert(b"t".to_vec(), self.transaction.clone().into());
+        match &self.body {
+            KrpcBody::Query { method, args } => {
+                dict.insert(b"y".to_vec(), b"q".to_vec().into());
+                dict.insert(b"q".to_vec(), method.clone().into());
+                dict.insert(b"a".to_vec(), Value::Dict(args.clone()));
+            }
+            KrpcBody::Response(values) => {
+                dict.insert(b"y".to_vec(), b"r".to_vec().into());
+                dict.insert(b"r".to_vec(), Value::Dict(values.clone()));
+            }
+            KrpcBody::Error { code, message } => {
+                dict.insert(b"y".to_vec(), b"e".to_vec().into());
+                let error = vec![Value::Int(*code), message.as_bytes().into()];
+                dict.insert(b"e".to_vec(), Value::List(error));
+            }
+        }
+        if let Some(ip) = &self.ip {
+            dict.insert(b"ip".to_vec(), compact_addr(ip).into());
+        }
+        if let Some(version) = &self.version {
+            dict.insert(b"v".to_vec(), version.clone().into());
+        }
+        // encoding a `Value` only fails past the nesting limit
+        Value::Dict(dict)
+            .to_bencode()
+            .expect("KRPC message nested too deep")
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let value = Value::from_bencode(bytes).map_err(|e| e.to_string())?;
+        let dict = value.as_dict().ok_or("message is not a dictionary")?;
+        let get_bytes = |key: &[u8]| dict.get(key).and_then(Value::as_bytes);
+        let transaction = get_bytes(b"t").ok_or("missing transaction id")?.to_vec();
+        let body = match get_bytes(b"y") {
+            Some(b"q") => KrpcBody::Query {
+                method: get_bytes(b"q").ok_or("missing method")?.to_vec(),
+                args: dict
+                    .get(&b"a"[..])
+                    .and_then(Value::as_dict)
+                    .ok_or("missing arguments")?
+                    .clone(),
+            },
+            Some(b"r") => KrpcBody::Response(
+                dict.get(&b"r"[..])
+                    .and_then(Value::as_dict)
+                    .ok_or("missing response values")?
+                    .clone(),
+            ),
+            Some(b"e") => {
+                let error = dict
+                    .get(&b"e"[..])
+                    .and_then(Value::as_list)
+                    .ok_or("missing error")?;
+                KrpcBody::Error {
+                    code: error
+                        .first()
+                        .and_then(Value::as_int)
+                        .unwrap_or(ERROR_GENERIC),
+                    message: error
+                        .get(1)
+                        .and_then(Value::as_bytes)
+                        .map(|m| String::from_utf8_lossy(m).into_owned())
+                        .unwrap_or_default(),
+                }
+            }
+            _ => return Err("unknown message type".to_string()),
+        };
+        Ok(KrpcMessage {
+            transaction,
+            body,
+            version: get_bytes(b"v").map(|v| v.to_vec()),
+            ip: get_bytes(b"ip")
+                .filter(|ip| ip.len() == 6 || ip.len() == 18)
+                .and_then(|ip| compact_addrs(ip, ip.len() == 18).first().copied()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn krpc_messages() {
+        // examples from BEP 5
+        let ping = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        let message = KrpcMessage::decode(ping).unwrap();
+        let mut args = Dict::new();
+        args.insert(b"id".to_vec(), b"abcdefghij0123456789".to_vec().into());
+        assert_eq!(message, KrpcMessage::query(b"aa".to_vec(), "ping", args));
+        assert_eq!(message.encode(), ping.to_vec());
+
+        let response = b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re";
+        let message = KrpcMessage::decode(response).unwrap();
+        assert!(matches!(message.body, KrpcBody::Response(_)));
+        assert_eq!(message.encode(), response.to_vec());
+
+        let mut message = KrpcMessage::response(b"aa".to_vec(), Dict::new());
+        message.ip = Some("10.0.0.1:6881".parse().unwrap());
+        let bytes = message.encode();
+        assert_eq!(
+            bytes,
+            b"d2:ip6:\x0a\x00\x00\x01\x1a\xe11:rde1:t2:aa1:y1:re".to_vec()
+        );
+        assert_eq!(KrpcMessage::decode(&bytes).unwrap(), message);
+
+        let error = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+        let message = KrpcMessage::decode(error).unwrap();
+        assert_eq!(
+            message.body,
+            KrpcBody::Error {
+                code: 201,
+                message: String::from("A Generic Error Ocurred")
+            }
+        );
+        assert_eq!(message.encode(), error.to_vec());
+
+        assert!(KrpcMessage::decode(b"d1:t2:aa1:y1:xe").is_err());
+        assert!(KrpcMessage::decode(b"i3e").is_err());
+        assert!(KrpcMessage::decode(b"d1:ad").is_err());
+    }
+}
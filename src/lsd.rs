@@ -0,0 +1,300 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use socket2::{Domain, Socket, Type};
+use tokio::net::UdpSocket;
+
+use crate::magnet::{from_hex, to_hex};
+
+// Local Service Discovery (BEP 14): peers on the same network find each
+// other without a tracker by multicasting `BT-SEARCH` announces, HTTP-like
+// requests listing info hashes and the port accepting peers:
+//
+//   BT-SEARCH * HTTP/1.1\r\n
+//   Host: 239.192.152.143:6771\r\n
+//   Port: 6881\r\n
+//   Infohash: <40 hex digits>\r\n
+//   cookie: <random>\r\n
+//   \r\n\r\n
+//
+// The peer's address is the source of the packet. Multicast packets are
+// looped back to the sender, so each client puts a random cookie in its
+// announces to ignore its own. An info hash is announced every 5 minutes
+// and never more than once a minute. Private torrents are never announced.
+
+pub const LSD_PORT: u16 = 6771;
+const MULTICAST_V4: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+const MULTICAST_V6: Ipv6Addr = Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f);
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+const COOKIE_LENGTH: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announce {
+    pub port: u16,
+    pub info_hashes: Vec<Vec<u8>>,
+    // identifies the sender, absent in announces of older clients
+    pub cookie: Option<String>,
+}
+
+impl Announce {
+    /**
+     * The announce as sent to `host`, the multicast group and port.
+     */
+    pub fn to_bytes(&self, host: &str) -> Vec<u8> {
+        let mut message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n",
+            host, self.port
+        );
+        for info_hash in &self.info_hashes {
+            message.push_str(&format!("Infohash: {}\r\n", to_hex(info_hash)));
+        }
+        if let Some(cookie) = &self.cookie {
+            message.push_str(&format!("cookie: {}\r\n", cookie));
+        }
+        message.push_str("\r\n\r\n");
+        message.into_bytes()
+    }
+
+    /**
+     * Read an announce, `None` if it isn't one or lacks a port or info hash.
+     * Header names are case insensitive and lines may end with `\n` only.
+     */
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let text = std::str::from_utf8(bytes).ok()?;
+        let mut lines = text.lines();
+        if lines.next()?.trim_end() != "BT-SEARCH * HTTP/1.1" {
+            return None;
+        }
+        let mut announce = Announce {
+            port: 0,
+            info_hashes: vec![],
+            cookie: None,
+        };
+        for line in lines {
+            let (name, value) = match line.split_once(':') {
+                Some(header) => header,
+                None => continue,
+            };
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => announce.port = value.parse().ok()?,
+                "infohash" => {
+                    let info_hash = from_hex(value).filter(|h| h.len() == 20)?;
+                    announce.info_hashes.push(info_hash);
+                }
+                "cookie" => announce.cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        if announce.port == 0 || announce.info_hashes.is_empty() {
+            return None;
+        }
+        Some(announce)
+    }
+}
+
+/**
+ * Socket receiving the announces sent to `group`, on all interfaces.
+ */
+fn bind_multicast(group: IpAddr, port: u16) -> io::Result<UdpSocket> {
+    let addr = match group {
+        IpAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)),
+        IpAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)),
+    };
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, None)?;
+    // other clients on this machine listen on the same port
+    socket.set_reuse_address(true)?;
+    match group {
+        IpAddr::V4(group) => {
+            socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+            socket.set_multicast_loop_v4(true)?;
+        }
+        IpAddr::V6(group) => {
+            socket.set_only_v6(true)?;
+            socket.join_multicast_v6(&group, 0)?;
+            socket.set_multicast_loop_v6(true)?;
+        }
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+async fn recv_from(socket: &Option<UdpSocket>, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+pub struct Lsd {
+    socket: Option<UdpSocket>,
+    socket6: Option<UdpSocket>,
+    port: u16,
+    cookie: String,
+    // when each info hash was last announced
+    announced: Mutex<HashMap<Vec<u8>, Instant>>,
+}
+
+impl Lsd {
+    /**
+     * Join the IPv4 and IPv6 groups on `port`, `LSD_PORT` but for tests.
+     * Fails only if neither can be joined.
+     */
+    pub async fn bind(port: u16) -> io::Result<Arc<Lsd>> {
+        let socket = bind_multicast(MULTICAST_V4.into(), port);
+        let socket6 = bind_multicast(MULTICAST_V6.into(), port);
+        if let (Err(e), Err(_)) = (&socket, &socket6) {
+            return Err(io::Error::new(e.kind(), e.to_string()));
+        }
+        let cookie = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(COOKIE_LENGTH)
+            .map(char::from)
+            .collect();
+        Ok(Arc::new(Lsd {
+            socket: socket.ok(),
+            socket6: socket6.ok(),
+            port,
+            cookie,
+            announced: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /**
+     * Whether `info_hash` may be announced now, if so it counts as announced.
+     */
+    fn due(&self, info_hash: &[u8]) -> bool {
+        let mut announced = self.announced.lock().expect("Error unable to lock mutex!");
+        if announced
+            .get(info_hash)
+            .is_some_and(|t| t.elapsed() < MIN_ANNOUNCE_INTERVAL)
+        {
+            return false;
+        }
+        announced.insert(info_hash.to_vec(), Instant::now());
+        true
+    }
+
+    /**
+     * Announce that we accept peers of `info_hash` on `listen_port`, unless
+     * it was announced less than a minute ago. Returns whether it was sent
+     * to a group.
+     */
+    pub async fn announce(&self, info_hash: &[u8], listen_port: u16) -> bool {
+        if !self.due(info_hash) {
+            return false;
+        }
+        let announce = Announce {
+            port: listen_port,
+            info_hashes: vec![info_hash.to_vec()],
+            cookie: Some(self.cookie.clone()),
+        };
+        let mut sent = false;
+        if let Some(socket) = &self.socket {
+            let group = SocketAddr::from((MULTICAST_V4, self.port));
+            let message = announce.to_bytes(&group.to_string());
+            sent |= socket.send_to(&message, group).await.is_ok();
+        }
+        if let Some(socket) = &self.socket6 {
+            let group = SocketAddr::from((MULTICAST_V6, self.port));
+            let message = announce.to_bytes(&group.to_string());
+            sent |= socket.send_to(&message, group).await.is_ok();
+        }
+        sent
+    }
+
+    /**
+     * Wait for the next announce of another client, and the address of the
+     * peer it announces.
+     */
+    pub async fn recv(&self) -> (Announce, SocketAddr) {
+        let (mut buf, mut buf6) = (vec![0; 1500], vec![0; 1500]);
+        loop {
+            let (received, packet) = tokio::select! {
+                r = recv_from(&self.socket, &mut buf) => (r, &buf),
+                r = recv_from(&self.socket6, &mut buf6) => (r, &buf6),
+            };
+            let (len, from) = match received {
+                Ok(r) => r,
+                Err(_) => continue,
+            };
+            match Announce::parse(&packet[..len]) {
+                Some(a) if a.cookie.as_ref() != Some(&self.cookie) => {
+                    let peer = SocketAddr::new(from.ip(), a.port);
+                    return (a, peer);
+                }
+                _ => continue,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn local_discovery() {
+        let info_hash = vec![0xab; 20];
+        let announce = Announce {
+            port: 6881,
+            info_hashes: vec![info_hash.clone()],
+            cookie: Some(String::from("x1y2")),
+        };
+        let bytes = announce.to_bytes("239.192.152.143:6771");
+        assert_eq!(
+            bytes,
+            format!(
+                "BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\n\
+                 Infohash: {}\r\ncookie: x1y2\r\n\r\n\r\n",
+                "ab".repeat(20)
+            )
+            .into_bytes()
+        );
+        assert_eq!(Announce::parse(&bytes), Some(announce));
+        let lenient = format!(
+            "BT-SEARCH * HTTP/1.1\nPORT: 51413\ninfohash: {}\n\n",
+            "AB".repeat(20)
+        );
+        let parsed = Announce::parse(lenient.as_bytes()).unwrap();
+        assert_eq!(
+            (parsed.port, parsed.info_hashes),
+            (51413, vec![info_hash.clone()])
+        );
+        assert_eq!(parsed.cookie, None);
+        assert_eq!(
+            Announce::parse(b"M-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n"),
+            None
+        );
+        assert_eq!(
+            Announce::parse(b"BT-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n"),
+            None
+        );
+
+        // a port other than 6771 keeps the test off the network's announces
+        let port = 16771;
+        let (a, b) = (
+            Lsd::bind(port).await.unwrap(),
+            Lsd::bind(port).await.unwrap(),
+        );
+        assert!(a.announce(&info_hash, 6881).await);
+        // at most once a minute
+        assert!(!a.announce(&info_hash, 6881).await);
+        let (received, peer) = tokio::time::timeout(Duration::from_secs(5), b.recv())
+            .await
+            .unwrap();
+        assert_eq!(received.info_hashes, vec![info_hash.clone()]);
+        assert_eq!(peer.port(), 6881);
+        // our own announces are ignored
+        let own = tokio::time::timeout(Duration::from_millis(200), a.recv()).await;
+        assert!(own.is_err());
+    }
+}
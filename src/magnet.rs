@@ -0,0 +1,170 @@
+use std::{collections::BTreeMap, fmt};
+
+use url::{form_urlencoded::byte_serialize, Url};
+
+use crate::krpc::Value;
+
+// Magnet links identify a torrent without its metainfo file:
+// `magnet:?xt=urn:btih:<info hash>&dn=<name>&tr=<tracker>`.
+// A mutable torrent (BEP 46) is identified instead by the public key and
+// salt of a DHT item, `magnet:?xs=urn:btpk:<key>&s=<salt>` in hex. The
+// item's value is a dictionary with the current info hash under `ih`, which
+// the publisher updates as new versions of the torrent are released.
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MagnetLink {
+    pub info_hash: Option<Vec<u8>>,
+    // ed25519 key of a mutable torrent
+    pub public_key: Option<[u8; 32]>,
+    pub salt: Vec<u8>,
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/**
+ * RFC 4648 base32, the older encoding of info hashes in magnet links.
+ */
+fn from_base32(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in text.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+impl MagnetLink {
+    pub fn parse(link: &str) -> Result<Self, String> {
+        let url = Url::parse(link).map_err(|e| format!("invalid magnet link: {}", e))?;
+        if url.scheme() != "magnet" {
+            return Err(String::from("not a magnet link"));
+        }
+        let mut magnet = MagnetLink::default();
+        for (key, value) in url.query_pairs() {
+            match &*key {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        let info_hash = match hash.len() {
+                            40 => from_hex(hash),
+                            32 => from_base32(hash),
+                            _ => None,
+                        };
+                        magnet.info_hash = Some(info_hash.ok_or("invalid info hash")?);
+                    }
+                }
+                "xs" => {
+                    if let Some(key) = value.strip_prefix("urn:btpk:") {
+                        let key = from_hex(key).and_then(|k| k.try_into().ok());
+                        magnet.public_key = Some(key.ok_or("invalid public key")?);
+                    }
+                }
+                "s" => magnet.salt = from_hex(&value).ok_or("invalid salt")?,
+                "dn" => magnet.name = Some(value.into_owned()),
+                "tr" => magnet.trackers.push(value.into_owned()),
+                _ => {}
+            }
+        }
+        if magnet.info_hash.is_none() && magnet.public_key.is_none() {
+            return Err(String::from("magnet link without info hash or public key"));
+        }
+        Ok(magnet)
+    }
+}
+
+impl fmt::Display for MagnetLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut params = vec![];
+        if let Some(info_hash) = &self.info_hash {
+            params.push(format!("xt=urn:btih:{}", to_hex(info_hash)));
+        }
+        if let Some(key) = &self.public_key {
+            params.push(format!("xs=urn:btpk:{}", to_hex(key)));
+        }
+        if !self.salt.is_empty() {
+            params.push(format!("s={}", to_hex(&self.salt)));
+        }
+        if let Some(name) = &self.name {
+            params.push(format!(
+                "dn={}",
+                byte_serialize(name.as_bytes()).collect::<String>()
+            ));
+        }
+        for tracker in &self.trackers {
+            let tracker: String = byte_serialize(tracker.as_bytes()).collect();
+            params.push(format!("tr={}", tracker));
+        }
+        write!(f, "magnet:?{}", params.join("&"))
+    }
+}
+
+/**
+ * Value of the DHT item a mutable torrent points to `info_hash` with.
+ */
+pub fn torrent_pointer(info_hash: &[u8]) -> Value {
+    Value::Dict(BTreeMap::from([(b"ih".to_vec(), info_hash.into())]))
+}
+
+pub fn pointed_info_hash(value: &Value) -> Option<Vec<u8>> {
+    let info_hash = value.as_dict()?.get(&b"ih"[..])?.as_bytes()?;
+    (info_hash.len() == 20).then(|| info_hash.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn magnet_links() {
+        let key = "8543d3e6115f0f98c944077a4493dcd543e49c739fd998550a1f614ab36ed63e";
+        let link = format!("magnet:?xs=urn:btpk:{}&s=6e696768746c79", key);
+        let magnet = MagnetLink::parse(&link).unwrap();
+        assert_eq!(magnet.public_key.map(|k| to_hex(&k)), Some(key.to_string()));
+        assert_eq!(magnet.salt, b"nightly");
+        assert_eq!(magnet.info_hash, None);
+        assert_eq!(magnet.to_string(), link);
+
+        let hex = "c12fe1c06bba254a9dc9f519b335aa7c1367a88a";
+        let magnet = MagnetLink::parse(&format!(
+            "magnet:?xt=urn:btih:{}&dn=build%201&tr=http%3A%2F%2Ftracker%2Fannounce",
+            hex
+        ))
+        .unwrap();
+        assert_eq!(magnet.info_hash, from_hex(hex));
+        assert_eq!(magnet.name.as_deref(), Some("build 1"));
+        assert_eq!(magnet.trackers, vec!["http://tracker/announce"]);
+        let base32 = MagnetLink::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK");
+        assert_eq!(base32.unwrap().info_hash, from_hex(hex));
+
+        assert!(MagnetLink::parse("magnet:?dn=nothing").is_err());
+        assert!(MagnetLink::parse("magnet:?xs=urn:btpk:abcd").is_err());
+        assert!(MagnetLink::parse("http://example.com/?xt=urn:btih:").is_err());
+
+        let pointer = torrent_pointer(&from_hex(hex).unwrap());
+        assert_eq!(pointed_info_hash(&pointer), from_hex(hex));
+        assert_eq!(pointed_info_hash(&Value::Int(1)), None);
+    }
+}
@@ -1,20 +1,66 @@
+mod choker;
 mod connect_tracker;
+mod dht;
+mod dht_storage;
+mod krpc;
+mod lsd;
+mod magnet;
+mod mse;
 mod parse_torrent;
 mod parse_tracker_res;
+mod pex;
+mod picker;
+mod port_mapping;
+mod proxy;
 mod queue;
+mod rate;
+mod seeding;
+mod session;
+mod storage;
+mod stream;
+mod utp;
+mod verify;
+mod webseed;
 
-use std::str::from_utf8;
+use std::{
+    io::Write,
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
 
 use crate::connect_tracker::tracker;
 use crate::parse_torrent::torrent_info::TorrentInfo;
 use crate::parse_tracker_res::peers::PeerList;
+use crate::parse_tracker_res::scrape::{ScrapeResponse, ScrapeStats};
 use bendy::decoding::FromBencode;
-use clap::Parser;
-use connect_tracker::tracker::{AnnounceURL, Handshake, Message, PeerConnection};
-use parse_torrent::torrent_info;
-use queue::{create_queue, TorrentState};
-use rand::{self, distributions::Alphanumeric, thread_rng, Rng};
-use tokio::runtime::Runtime;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use connect_tracker::tracker::{AnnounceURL, Event, PeerConnection, LISTENING_PORT};
+use dht::{Dht, NodeId};
+use dht_storage::MutableItem;
+use ed25519_dalek::SigningKey;
+use krpc::Value;
+use lsd::Lsd;
+use magnet::MagnetLink;
+use mse::EncryptionMode;
+use picker::Priority;
+use port_mapping::{PortMapper, Protocol};
+use proxy::{Proxy, ProxyKind, ProxyMode};
+use queue::{
+    create_queue, DeadlineEvent, SharedTorrentState, TorrentState, DEFAULT_MAX_CONNECTIONS,
+};
+use rand::{self, distributions::Alphanumeric, thread_rng, Rng, RngCore};
+use rate::RateLimits;
+use seeding::{GoalAction, SeedGoals};
+use session::Session;
+use storage::{is_disk_full, Allocation, Storage};
+use tokio::{net::TcpListener, runtime::Runtime};
+use utp::Utp;
+use verify::VerifyReport;
+use webseed::WebSeed;
 
 /// TODO
 /// - [ ] Multifile support
@@ -24,13 +70,376 @@ use tokio::runtime::Runtime;
 
 #[derive(Parser)]
 struct Cli {
-    torrent: std::path::PathBuf,
+    #[command(subcommand)]
+    command: Command,
 }
 
-fn main() {
-    let args = Cli::parse();
-    let file = std::fs::read(args.torrent).expect("could not read file");
-    let torrent_info = TorrentInfo::from_bencode(&file).unwrap();
+#[derive(Args)]
+struct DownloadArgs {
+    torrent: PathBuf,
+    /// Directory holding the torrent data
+    #[arg(short, long, default_value = ".")]
+    output: PathBuf,
+    /// Number of threads hashing existing data
+    #[arg(short, long)]
+    workers: Option<usize>,
+    /// How files are created before their pieces arrive
+    #[arg(short, long, value_enum, default_value_t = Allocation::default())]
+    allocation: Allocation,
+    /// Priority of a file by its index in the torrent, e.g. `2=skip`
+    #[arg(short, long = "priority", value_parser = parse_file_priority)]
+    priorities: Vec<(usize, Priority)>,
+    /// Download pieces in order, fetching this many pieces ahead of the cursor
+    #[arg(long, value_name = "READAHEAD", num_args = 0..=1, default_missing_value = "8")]
+    sequential: Option<usize>,
+    /// Serve the files over HTTP while downloading, e.g. `127.0.0.1:8080`
+    #[arg(long, value_name = "ADDR")]
+    stream: Option<SocketAddr>,
+    /// Address accepting incoming peers, repeat for several; defaults to all
+    /// IPv4 and IPv6 interfaces
+    #[arg(long = "listen", value_name = "ADDR")]
+    listen: Vec<SocketAddr>,
+    /// Don't ask the router to forward the peer port (PCP, NAT-PMP, UPnP)
+    #[arg(long)]
+    no_port_mapping: bool,
+    /// Router to ask for port mappings; defaults to the default gateway,
+    /// or a UPnP gateway found on the network
+    #[arg(long, value_name = "IP")]
+    gateway: Option<Ipv4Addr>,
+    /// Maximum number of connected peers across all torrents
+    #[arg(long, default_value_t = session::DEFAULT_MAX_CONNECTIONS)]
+    max_connections: usize,
+    /// Maximum number of connected peers for this torrent
+    #[arg(long, default_value_t = DEFAULT_MAX_CONNECTIONS)]
+    max_torrent_connections: usize,
+    /// Only connect to peers over TCP, not uTP (BEP 29)
+    #[arg(long)]
+    no_utp: bool,
+    /// Message stream encryption of peer connections
+    #[arg(long, value_enum, default_value_t = EncryptionMode::default())]
+    encryption: EncryptionMode,
+    /// Keep uploading to peers after the download completes, until ctrl-c
+    /// or a seeding goal is reached
+    #[arg(long)]
+    seed: bool,
+    /// Reveal pieces to peers one at a time until they propagate (BEP 16),
+    /// for the initial seeder; implies `--seed`
+    #[arg(long)]
+    super_seed: bool,
+    #[command(flatten)]
+    seed_goals: SeedGoalArgs,
+    /// Largest block peers may request, in bytes
+    #[arg(long, value_name = "BYTES", default_value_t = 16384)]
+    max_request: u32,
+    /// Number of peers uploaded to at once, plus one optimistic unchoke;
+    /// derived from the upload limit by default
+    #[arg(long)]
+    upload_slots: Option<usize>,
+    #[command(flatten)]
+    limits: LimitArgs,
+    #[command(flatten)]
+    dht: DhtArgs,
+    /// Don't look for peers on the local network (BEP 14), never used for
+    /// private torrents
+    #[arg(long)]
+    no_lsd: bool,
+    #[command(flatten)]
+    proxy: ProxyArgs,
+}
+
+/// Proxy for outgoing connections
+#[derive(Args)]
+struct ProxyArgs {
+    /// `socks5://[user:password@]host:port`, or `http://...` for an HTTP
+    /// CONNECT proxy
+    #[arg(long, value_name = "URL", value_parser = Proxy::parse)]
+    proxy: Option<Proxy>,
+    /// What goes through the proxy
+    #[arg(long, value_enum, default_value_t = ProxyMode::default())]
+    proxy_mode: ProxyMode,
+    /// Never connect around the proxy: incoming peers are refused, uTP,
+    /// local discovery and port mapping are off, and the DHT only runs
+    /// through a SOCKS5 proxy; implies `--proxy-mode all`
+    #[arg(long, requires = "proxy")]
+    proxy_only: bool,
+}
+
+impl ProxyArgs {
+    /**
+     * Proxy of the peers, web seeds and the DHT, `None` when only trackers
+     * go through it.
+     */
+    fn peer_proxy(&self) -> Option<&Proxy> {
+        self.proxy
+            .as_ref()
+            .filter(|_| self.proxy_only || self.proxy_mode == ProxyMode::All)
+    }
+}
+
+/// Mainline DHT, never used for private torrents
+#[derive(Args)]
+struct DhtArgs {
+    /// Don't look for peers in the DHT
+    #[arg(long)]
+    no_dht: bool,
+    /// UDP address of the DHT node, one IPv4 and one IPv6; defaults to the
+    /// peer port on all IPv4 and IPv6 interfaces
+    #[arg(long = "dht-listen", value_name = "ADDR")]
+    dht_listen: Vec<SocketAddr>,
+    /// Node to join the DHT through, repeat for several; defaults to well
+    /// known routers
+    #[arg(long = "dht-bootstrap", value_name = "HOST:PORT")]
+    dht_bootstrap: Vec<String>,
+    /// File keeping the node id and routing table between runs
+    #[arg(long, value_name = "FILE", default_value = "dht.dat")]
+    dht_state: PathBuf,
+}
+
+/// Seeding goals, any of them implies `--seed`
+#[derive(Args)]
+struct SeedGoalArgs {
+    /// Stop seeding at this ratio of uploaded to downloaded bytes
+    #[arg(long, value_name = "RATIO")]
+    seed_ratio: Option<f64>,
+    /// Stop seeding after this many minutes
+    #[arg(long, value_name = "MINUTES")]
+    seed_time: Option<u64>,
+    /// Stop seeding after this many minutes without uploads
+    #[arg(long, value_name = "MINUTES")]
+    seed_idle: Option<u64>,
+    /// What happens to the torrent once a goal is reached
+    #[arg(long, value_enum)]
+    seed_goal_action: Option<GoalAction>,
+    /// Goals of this torrent only, taking precedence over the ones above
+    #[arg(long, value_name = "RATIO")]
+    torrent_seed_ratio: Option<f64>,
+    #[arg(long, value_name = "MINUTES")]
+    torrent_seed_time: Option<u64>,
+    #[arg(long, value_name = "MINUTES")]
+    torrent_seed_idle: Option<u64>,
+    #[arg(long, value_enum)]
+    torrent_seed_goal_action: Option<GoalAction>,
+}
+
+/// Rate limits in bytes per second, 0 is unlimited
+#[derive(Args)]
+struct LimitArgs {
+    /// Upload limit across all torrents
+    #[arg(long, value_name = "BYTES_PER_SEC")]
+    max_upload: Option<u64>,
+    /// Download limit across all torrents
+    #[arg(long, value_name = "BYTES_PER_SEC")]
+    max_download: Option<u64>,
+    #[arg(long, value_name = "BYTES_PER_SEC")]
+    torrent_max_upload: Option<u64>,
+    #[arg(long, value_name = "BYTES_PER_SEC")]
+    torrent_max_download: Option<u64>,
+    #[arg(long, value_name = "BYTES_PER_SEC")]
+    peer_max_upload: Option<u64>,
+    #[arg(long, value_name = "BYTES_PER_SEC")]
+    peer_max_download: Option<u64>,
+    /// Bytes that may be transferred at once above the limits, one second
+    /// worth of the limit by default
+    #[arg(long, value_name = "BYTES")]
+    burst: Option<u64>,
+    /// Count message headers and protocol messages against the limits
+    #[arg(long)]
+    count_overhead: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Download a torrent, only fetching pieces missing from the output directory
+    Download(Box<DownloadArgs>),
+    /// Check existing data against the piece hashes, exits 1 if a piece is
+    /// corrupt and 2 if pieces are missing
+    Verify {
+        torrent: PathBuf,
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+        #[arg(short, long)]
+        workers: Option<usize>,
+    },
+    /// Point a mutable torrent (BEP 46) at a torrent and print its magnet
+    /// link; the DHT forgets it after two hours unless published again
+    Publish {
+        torrent: PathBuf,
+        /// File holding the ed25519 key the pointer is signed with, created
+        /// on first use
+        #[arg(long, value_name = "FILE", default_value = "publish.key")]
+        key: PathBuf,
+        /// Name publishing several torrents under one key, e.g. a release
+        /// channel
+        #[arg(long)]
+        salt: Option<String>,
+        #[command(flatten)]
+        dht: DhtArgs,
+        #[command(flatten)]
+        proxy: ProxyArgs,
+    },
+    /// Look up the torrent a `magnet:?xs=urn:btpk:` link currently points to
+    Resolve {
+        magnet: String,
+        #[command(flatten)]
+        dht: DhtArgs,
+        #[command(flatten)]
+        proxy: ProxyArgs,
+    },
+    /// Print the number of seeders and leechers of a torrent, from its
+    /// tracker or else estimated from the DHT
+    Scrape {
+        torrent: PathBuf,
+        #[command(flatten)]
+        dht: DhtArgs,
+        #[command(flatten)]
+        proxy: ProxyArgs,
+    },
+    /// List info hashes sampled from the DHT nodes closest to a target, a
+    /// random one by default
+    Sample {
+        /// Target node id in hex
+        #[arg(long)]
+        target: Option<String>,
+        #[command(flatten)]
+        dht: DhtArgs,
+        #[command(flatten)]
+        proxy: ProxyArgs,
+    },
+    /// Store a string in the DHT (BEP 44) and print the target it is found
+    /// under; the DHT forgets it after two hours unless put again
+    Put {
+        value: String,
+        #[command(flatten)]
+        dht: DhtArgs,
+        #[command(flatten)]
+        proxy: ProxyArgs,
+    },
+    /// Print the string stored in the DHT under a target
+    Get {
+        /// Target in hex, as printed by `put`
+        target: String,
+        #[command(flatten)]
+        dht: DhtArgs,
+        #[command(flatten)]
+        proxy: ProxyArgs,
+    },
+    /// List the DHT nodes closest to a target, our own node id by default
+    Nodes {
+        /// Target node id in hex
+        #[arg(long)]
+        target: Option<String>,
+        #[command(flatten)]
+        dht: DhtArgs,
+        #[command(flatten)]
+        proxy: ProxyArgs,
+    },
+}
+
+fn parse_file_priority(arg: &str) -> Result<(usize, Priority), String> {
+    let (index, priority) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected <file>=<priority>, got `{}`", arg))?;
+    let index = index
+        .parse()
+        .map_err(|e| format!("invalid file index: {}", e))?;
+    let priority = Priority::from_str(priority, true)?;
+    Ok((index, priority))
+}
+
+fn read_torrent(path: &Path) -> TorrentInfo {
+    let file = std::fs::read(path).expect("could not read file");
+    TorrentInfo::from_bencode(&file).unwrap()
+}
+
+fn default_workers() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+fn recheck(storage: &Storage, torrent_info: &TorrentInfo, workers: Option<usize>) -> VerifyReport {
+    let workers = workers.unwrap_or_else(default_workers);
+    let report = verify::verify(
+        storage,
+        &torrent_info.info_data,
+        workers,
+        |checked, total| {
+            print!("\rverifying: {}/{} pieces", checked, total);
+            std::io::stdout().flush().ok();
+        },
+    );
+    println!();
+    report
+}
+
+fn download(args: Box<DownloadArgs>) -> ExitCode {
+    let torrent_info = read_torrent(&args.torrent);
+    let file_count = torrent_info.info_data.file_piece_ranges().len();
+    let mut priorities = vec![Priority::Normal; file_count];
+    for (index, priority) in args.priorities {
+        match priorities.get_mut(index) {
+            Some(p) => *p = priority,
+            None => {
+                println!(
+                    "file index {} out of range, torrent has {} files",
+                    index, file_count
+                );
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let mut storage = Storage::new(&args.output, &torrent_info.info_data, args.allocation);
+    storage.set_file_priorities(&priorities);
+    let storage = Arc::new(storage);
+    let report = recheck(&storage, &torrent_info, args.workers);
+
+    let mut state = TorrentState::new(torrent_info.clone(), &PeerList::default());
+    state.set_file_priorities(&priorities);
+    state.load_verified(&report);
+    state.set_sequential(args.sequential);
+    if let Some(cursor) = state.cursor() {
+        println!("downloading sequentially from piece {}", cursor);
+    }
+    state.set_max_connections(args.max_torrent_connections);
+    state.set_max_request(args.max_request);
+    let minutes = |m: u64| Duration::from_secs(m * 60);
+    let goals = &args.seed_goals;
+    let seed_goals = SeedGoals {
+        ratio: goals.seed_ratio,
+        seed_time: goals.seed_time.map(minutes),
+        idle: goals.seed_idle.map(minutes),
+        action: goals.seed_goal_action,
+    };
+    let torrent_seed_goals = SeedGoals {
+        ratio: goals.torrent_seed_ratio,
+        seed_time: goals.torrent_seed_time.map(minutes),
+        idle: goals.torrent_seed_idle.map(minutes),
+        action: goals.torrent_seed_goal_action,
+    };
+    let seed = args.seed || args.super_seed || seed_goals.is_set() || torrent_seed_goals.is_set();
+    state.set_seeding(seed);
+    state.set_seed_goals(torrent_seed_goals);
+    state.set_super_seeding(args.super_seed);
+    let limits = &args.limits;
+    let upload_limit = match (limits.max_upload, limits.torrent_max_upload) {
+        (Some(a), Some(b)) if a > 0 && b > 0 => Some(a.min(b)),
+        (a, b) => a.filter(|r| *r > 0).or(b.filter(|r| *r > 0)),
+    };
+    state.set_upload_slots(
+        args.upload_slots
+            .unwrap_or_else(|| choker::upload_slots_for_rate(upload_limit)),
+    );
+    if state.is_complete() && !seed {
+        println!("all wanted pieces present, nothing to download");
+        return ExitCode::SUCCESS;
+    }
+    if let Err(e) = storage.allocate() {
+        if is_disk_full(&e) {
+            println!("not enough disk space to allocate files: {}", e);
+        } else {
+            println!("could not allocate files: {}", e);
+        }
+        return ExitCode::FAILURE;
+    }
 
     let client_id: String = thread_rng()
         .sample_iter(&Alphanumeric)
@@ -38,40 +447,906 @@ fn main() {
         .map(char::from)
         .collect();
 
-    let mut req_data = AnnounceURL::new(
-        torrent_info.announce.clone(),
-        client_id.to_string(),
-        torrent_info.info_data.length,
+    let rt = Runtime::new().unwrap();
+    let session = Arc::new(Session::new(client_id.clone(), args.max_connections));
+    session.set_rate_limits(RateLimits {
+        upload: limits.max_upload,
+        download: limits.max_download,
+        burst: limits.burst,
+    });
+    session.set_count_overhead(limits.count_overhead);
+    session.set_seed_goals(seed_goals);
+    session.set_encryption(args.encryption);
+    let proxy_only = args.proxy.proxy_only;
+    if let Some(proxy) = &args.proxy.proxy {
+        println!("connecting through proxy {}", proxy);
+    }
+    // peers, web seeds and the DHT
+    let proxy = args.proxy.peer_proxy().cloned();
+    session.set_proxy(proxy.clone());
+    let listen_addrs = if proxy_only {
+        println!("refusing incoming peers, proxy only");
+        vec![]
+    } else if args.listen.is_empty() {
+        vec![
+            SocketAddr::from(([0, 0, 0, 0], LISTENING_PORT as u16)),
+            SocketAddr::from(([0u16; 8], LISTENING_PORT as u16)),
+        ]
+    } else {
+        args.listen.clone()
+    };
+    let mut listen_port = None;
+    for addr in listen_addrs {
+        // `from_std` needs a runtime
+        let _guard = rt.enter();
+        match PeerConnection::listen(addr) {
+            Ok(listener) => {
+                println!("accepting peers on {}", addr);
+                listen_port.get_or_insert(addr.port());
+                rt.spawn(session::listen(session.clone(), listener));
+            }
+            Err(e) => println!("could not listen on {}: {}", addr, e),
+        }
+    }
+
+    let socks_proxy = proxy.as_ref().filter(|p| p.kind == ProxyKind::Socks5);
+    let dht = if args.dht.no_dht || torrent_info.info_data.private {
+        None
+    } else if proxy_only && socks_proxy.is_none() {
+        println!("DHT disabled, only SOCKS5 proxies relay it");
+        None
+    } else {
+        start_dht(&rt, &args.dht, listen_port, socks_proxy)
+    };
+    session.set_dht(dht.clone());
+    if !args.no_utp && !proxy_only {
+        // relayed DHT sockets can't be shared
+        let dht = dht.as_ref().filter(|d| !d.is_relayed());
+        let utp = start_utp(&rt, dht, listen_port);
+        if let Some(utp) = &utp {
+            rt.spawn(session::listen_utp(session.clone(), utp.clone()));
+        }
+        session.set_utp(utp);
+    }
+    let has_tracker = !torrent_info.announce.is_empty();
+    if !has_tracker
+        && dht.is_none()
+        && torrent_info.url_list.is_empty()
+        && torrent_info.http_seeds.is_empty()
+    {
+        println!("torrent has no tracker or web seed and the DHT is disabled");
+        return ExitCode::FAILURE;
+    }
+    let port_mapper = if args.no_port_mapping || proxy_only {
+        None
+    } else {
+        // uTP shares the port of the DHT node
+        let udp_port = match (&dht, session.utp()) {
+            (Some(dht), _) => dht.local_addrs().first().map(|a| a.port()),
+            (None, Some(utp)) => utp.local_addr().ok().map(|a| a.port()),
+            (None, None) => None,
+        };
+        start_port_mapping(&rt, args.gateway, listen_port, udp_port)
+    };
+
+    let left = state.left();
+    let mut req_data = AnnounceURL::new(torrent_info.announce.clone(), client_id.to_string(), left);
+    req_data.set_proxy(args.proxy.proxy.clone());
+    if let Some(port) = listen_port {
+        session.set_listen_port(port);
+        // peers outside the router connect to the mapped port
+        let external_port = port_mapper
+            .as_ref()
+            .and_then(|m| m.external_port(Protocol::Tcp, port));
+        req_data.set_port(external_port.unwrap_or(port) as i32);
+    }
+
+    let mut peer_list = PeerList::default();
+    if has_tracker {
+        let request = tracker::fetch_tracker_data(&mut req_data, &torrent_info.info_hash);
+        // keep going without peers, the DHT, web seeds or the next announce may find some
+        match rt.block_on(request) {
+            Ok(response) => match PeerList::from_bencode(&response) {
+                Ok(list) => {
+                    peer_list = list;
+                    println!(
+                        "tracker response: {} peers, {} bytes left",
+                        peer_list.peers.len(),
+                        left
+                    );
+                }
+                Err(e) => println!("invalid tracker response: {}", e),
+            },
+            Err(e) => println!("tracker announce failed: {}", e),
+        }
+        if let (Some(dht), Some(ip)) = (&dht, peer_list.external_ip) {
+            if dht.vote_external_ip(ip, None) {
+                println!("external address {}, new DHT node id", ip);
+            }
+        }
+    }
+
+    state.add_peers(&peer_list.peers);
+    let web_seeds = torrent_info.url_list.iter().cloned().map(WebSeed::Url);
+    let http_seeds = torrent_info
+        .http_seeds
+        .iter()
+        .cloned()
+        .map(WebSeed::HttpSeed);
+    for seed in web_seeds.chain(http_seeds) {
+        println!("web seed {}", seed);
+        state.add_web_seed(seed);
+    }
+    let state = Arc::new(SharedTorrentState::new(state));
+    state.set_rate_limits(RateLimits {
+        upload: limits.torrent_max_upload,
+        download: limits.torrent_max_download,
+        burst: limits.burst,
+    });
+    state.set_peer_rate_limits(RateLimits {
+        upload: limits.peer_max_upload,
+        download: limits.peer_max_download,
+        burst: limits.burst,
+    });
+    session.add_torrent(state.clone(), storage.clone());
+    if has_tracker {
+        rt.spawn(announce_progress(
+            req_data.clone(),
+            torrent_info.info_hash.clone(),
+            state.clone(),
+            Duration::from_secs(peer_list.interval.max(60) as u64),
+            port_mapper.clone().zip(listen_port),
+        ));
+    }
+    if let Some(dht) = &dht {
+        let bootstrap = bootstrap_hosts(&args.dht, &torrent_info.nodes);
+        let info_hash = NodeId::from_bytes(&torrent_info.info_hash).unwrap();
+        state.set_discovering(true);
+        rt.spawn(dht_announce(
+            dht.clone(),
+            bootstrap,
+            info_hash,
+            listen_port,
+            state.clone(),
+        ));
+    }
+    if !args.no_lsd && !torrent_info.info_data.private && !proxy_only {
+        match rt.block_on(Lsd::bind(lsd::LSD_PORT)) {
+            Ok(lsd) => {
+                state.set_discovering(true);
+                rt.spawn(local_discovery(
+                    lsd,
+                    torrent_info.info_hash.clone(),
+                    listen_port,
+                    state.clone(),
+                ));
+            }
+            Err(e) => println!("could not start local service discovery: {}", e),
+        }
+    }
+    if let Some(addr) = args.stream {
+        let listener = match rt.block_on(TcpListener::bind(addr)) {
+            Ok(l) => l,
+            Err(e) => {
+                println!("could not start stream server on {}: {}", addr, e);
+                if let Some(port_mapper) = &port_mapper {
+                    rt.block_on(port_mapper.unmap_all());
+                }
+                return ExitCode::FAILURE;
+            }
+        };
+        println!("streaming files at http://{}/", addr);
+        rt.spawn(stream::serve(listener, state.clone(), storage.clone()));
+        let mut deadlines = state.subscribe_deadlines();
+        rt.spawn(async move {
+            while let Some(event) = deadlines.recv().await {
+                if let DeadlineEvent::Missed(index) = event {
+                    println!("piece {} missed its streaming deadline", index);
+                }
+            }
+        });
+    }
+
+    let mut seed_events = state.subscribe_seed_events();
+    let result = rt.block_on(async {
+        tokio::select! {
+            result = create_queue(session, state.clone(), storage) => result,
+            _ = tokio::signal::ctrl_c() => Ok(()),
+        }
+    });
+    if let Some(dht) = &dht {
+        if let Err(e) = dht.save(&args.dht.dht_state) {
+            println!("could not save the DHT routing table: {}", e);
+        }
+    }
+    if let Some(port_mapper) = &port_mapper {
+        rt.block_on(port_mapper.unmap_all());
+    }
+    if let Err(e) = result {
+        println!("{}", e);
+        if has_tracker {
+            rt.block_on(announce(
+                &mut req_data,
+                &torrent_info.info_hash,
+                &state,
+                Some(Event::Stopped),
+            ));
+        }
+        return ExitCode::FAILURE;
+    }
+    match seed_events.try_recv() {
+        Ok(event) => {
+            println!("seeding goal reached: {}", event.goal);
+            if event.action == GoalAction::Pause && args.stream.is_some() {
+                println!("torrent paused, still streaming, press ctrl-c to exit");
+                rt.block_on(tokio::signal::ctrl_c()).ok();
+            }
+        }
+        Err(_) if args.stream.is_some() && state.is_complete() => {
+            println!("download finished, still streaming, press ctrl-c to exit");
+            rt.block_on(tokio::signal::ctrl_c()).ok();
+        }
+        Err(_) => {}
+    }
+    if has_tracker {
+        rt.block_on(announce(
+            &mut req_data,
+            &torrent_info.info_hash,
+            &state,
+            Some(Event::Stopped),
+        ));
+    }
+    ExitCode::SUCCESS
+}
+
+/**
+ * Start a DHT node with the ids and nodes saved by the last run, relayed by
+ * `proxy` if given.
+ */
+fn start_dht(
+    rt: &Runtime,
+    args: &DhtArgs,
+    listen_port: Option<u16>,
+    proxy: Option<&Proxy>,
+) -> Option<Arc<Dht>> {
+    let port = listen_port.unwrap_or(LISTENING_PORT as u16);
+    let addrs = if args.dht_listen.is_empty() {
+        vec![
+            SocketAddr::from(([0, 0, 0, 0], port)),
+            SocketAddr::from(([0u16; 8], port)),
+        ]
+    } else {
+        args.dht_listen.clone()
+    };
+    let bound = rt.block_on(async {
+        match proxy {
+            Some(proxy) => {
+                let relay = proxy.udp_associate().await?;
+                Ok(Dht::bind_relayed(relay, NodeId::random()).await)
+            }
+            None => Dht::bind(&addrs, NodeId::random()).await,
+        }
+    });
+    let dht = match bound {
+        Ok(dht) => dht,
+        Err(e) => {
+            println!("could not start the DHT: {}", e);
+            return None;
+        }
+    };
+    let saved = dht::load_state(&args.dht_state).ok();
+    if let Some(saved) = &saved {
+        dht.restore(saved);
+    }
+    let bound: Vec<String> = dht.local_addrs().iter().map(|a| a.to_string()).collect();
+    println!(
+        "DHT node on {}, {} saved nodes",
+        bound.join(", "),
+        saved.map_or(0, |s| s.nodes.len())
     );
+    Some(dht)
+}
+
+/**
+ * uTP on the port of the DHT node, sharing its sockets, or on the peer port.
+ */
+fn start_utp(rt: &Runtime, dht: Option<&Arc<Dht>>, listen_port: Option<u16>) -> Option<Arc<Utp>> {
+    if let Some(dht) = dht {
+        return Some(dht.start_utp());
+    }
+    let port = listen_port.unwrap_or(LISTENING_PORT as u16);
+    let addrs = [
+        SocketAddr::from(([0, 0, 0, 0], port)),
+        SocketAddr::from(([0u16; 8], port)),
+    ];
+    match rt.block_on(Utp::bind(&addrs)) {
+        Ok(utp) => {
+            if let Ok(addr) = utp.local_addr() {
+                println!("accepting uTP peers on {}", addr);
+            }
+            Some(utp)
+        }
+        Err(e) => {
+            println!("could not start uTP: {}", e);
+            None
+        }
+    }
+}
+
+/**
+ * Map the peer port on the router for TCP, and the port of uTP and the DHT
+ * for UDP, and keep the mappings renewed.
+ */
+fn start_port_mapping(
+    rt: &Runtime,
+    gateway: Option<Ipv4Addr>,
+    tcp_port: Option<u16>,
+    udp_port: Option<u16>,
+) -> Option<Arc<PortMapper>> {
+    let gateway = gateway
+        .or_else(port_mapping::default_gateway)
+        .map(|ip| SocketAddr::from((ip, port_mapping::NAT_PMP_PORT)));
+    let port_mapper = match rt.block_on(PortMapper::discover(gateway, port_mapping::SSDP_ADDR)) {
+        Ok(m) => Arc::new(m),
+        Err(e) => {
+            println!("could not map ports: {}", e);
+            return None;
+        }
+    };
+    let ports = [(Protocol::Tcp, tcp_port), (Protocol::Udp, udp_port)];
+    for (protocol, port) in ports.into_iter().filter_map(|(p, port)| Some((p, port?))) {
+        match rt.block_on(port_mapper.map(protocol, port)) {
+            Ok(m) => println!(
+                "{} port {} mapped to external port {} by {}",
+                protocol,
+                port,
+                m.external_port,
+                port_mapper.method()
+            ),
+            Err(e) => println!("could not map {} port {}: {}", protocol, port, e),
+        }
+    }
+    if let Some(ip) = port_mapper.external_ip() {
+        println!("external address {} according to the router", ip);
+    }
+    let renewing = port_mapper.clone();
+    rt.spawn(async move { renewing.renew().await });
+    Some(port_mapper)
+}
 
-    let request = tracker::fetch_tracker_data(&mut req_data, &torrent_info.info_hash);
+/**
+ * Nodes to join the DHT through: the routers given or the default ones, and
+ * the nodes listed by the torrent.
+ */
+fn bootstrap_hosts(args: &DhtArgs, nodes: &[(String, u16)]) -> Vec<String> {
+    let mut bootstrap = args.dht_bootstrap.clone();
+    if bootstrap.is_empty() {
+        bootstrap = dht::DEFAULT_BOOTSTRAP.map(String::from).to_vec();
+    }
+    for (host, port) in nodes {
+        // IPv6 addresses need brackets to be resolved with a port
+        if host.contains(':') {
+            bootstrap.push(format!("[{}]:{}", host, port));
+        } else {
+            bootstrap.push(format!("{}:{}", host, port));
+        }
+    }
+    bootstrap
+}
+
+async fn resolve_hosts(hosts: &[String]) -> Vec<SocketAddr> {
+    let mut addrs = vec![];
+    for host in hosts {
+        match tokio::net::lookup_host(host).await {
+            Ok(resolved) => addrs.extend(resolved),
+            Err(e) => println!("could not resolve DHT node {}: {}", host, e),
+        }
+    }
+    addrs
+}
+
+/**
+ * Start a DHT node and wait until it joined the network, for the commands
+ * that only use the DHT.
+ */
+fn join_dht(
+    rt: &Runtime,
+    args: &DhtArgs,
+    proxy: &ProxyArgs,
+    nodes: &[(String, u16)],
+) -> Option<Arc<Dht>> {
+    if args.no_dht {
+        println!("the DHT is disabled");
+        return None;
+    }
+    let socks_proxy = proxy.peer_proxy().filter(|p| p.kind == ProxyKind::Socks5);
+    if proxy.proxy_only && socks_proxy.is_none() {
+        println!("DHT disabled, only SOCKS5 proxies relay it");
+        return None;
+    }
+    let dht = start_dht(rt, args, None, socks_proxy)?;
+    let routers = rt.block_on(resolve_hosts(&bootstrap_hosts(args, nodes)));
+    let count = rt.block_on(dht.bootstrap(&routers));
+    if count == 0 {
+        println!("could not join the DHT");
+        return None;
+    }
+    println!("DHT bootstrapped, {} nodes", count);
+    Some(dht)
+}
+
+/**
+ * Read the signing key of `path`, or create it.
+ */
+fn load_key(path: &Path) -> Result<SigningKey, String> {
+    match std::fs::read(path) {
+        Ok(bytes) => {
+            let seed: [u8; 32] = bytes
+                .try_into()
+                .map_err(|_| format!("{} is not an ed25519 key", path.display()))?;
+            Ok(SigningKey::from_bytes(&seed))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let mut seed = [0; 32];
+            thread_rng().fill_bytes(&mut seed);
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            // the seed is the private key, only the owner may read it
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            options
+                .open(path)
+                .and_then(|mut file| file.write_all(&seed))
+                .map_err(|e| format!("could not write {}: {}", path.display(), e))?;
+            println!("created a new key in {}", path.display());
+            Ok(SigningKey::from_bytes(&seed))
+        }
+        Err(e) => Err(format!("could not read {}: {}", path.display(), e)),
+    }
+}
+
+fn publish(
+    torrent: &Path,
+    key: &Path,
+    salt: Option<String>,
+    args: &DhtArgs,
+    proxy: &ProxyArgs,
+) -> ExitCode {
+    let torrent_info = read_torrent(torrent);
+    if torrent_info.info_data.private {
+        println!("private torrents are never published in the DHT");
+        return ExitCode::FAILURE;
+    }
+    let signing_key = match load_key(key) {
+        Ok(k) => k,
+        Err(e) => {
+            println!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
     let rt = Runtime::new().unwrap();
-    let tracker_res = rt.block_on(request).unwrap();
-    let peer_list = PeerList::from_bencode(&tracker_res).unwrap();
+    let dht = match join_dht(&rt, args, proxy, &torrent_info.nodes) {
+        Some(dht) => dht,
+        None => return ExitCode::FAILURE,
+    };
+    let salt = salt.unwrap_or_default().into_bytes();
+    let public_key = signing_key.verifying_key().to_bytes();
+    let pointer = magnet::torrent_pointer(&torrent_info.info_hash);
+    let (item, cas) = match rt.block_on(dht.get_mutable(&public_key, &salt)) {
+        // putting the same item again keeps it stored
+        Some(item) if item.value == pointer => (item, None),
+        Some(item) => (
+            MutableItem::sign(&signing_key, &salt, item.seq + 1, pointer),
+            Some(item.seq),
+        ),
+        None => (MutableItem::sign(&signing_key, &salt, 1, pointer), None),
+    };
+    let result = rt.block_on(dht.put_mutable(&item, cas));
+    if let Err(e) = dht.save(&args.dht_state) {
+        println!("could not save the DHT routing table: {}", e);
+    }
+    match result {
+        Ok(nodes) => {
+            println!("version {} stored on {} nodes", item.seq, nodes);
+            let link = MagnetLink {
+                public_key: Some(public_key),
+                salt,
+                ..MagnetLink::default()
+            };
+            println!("{}", link);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            println!("could not publish: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn resolve(link: &str, args: &DhtArgs, proxy: &ProxyArgs) -> ExitCode {
+    let mut link = match MagnetLink::parse(link) {
+        Ok(l) => l,
+        Err(e) => {
+            println!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let public_key = match link.public_key {
+        Some(k) => k,
+        // nothing to look up
+        None => {
+            println!("{}", link);
+            return ExitCode::SUCCESS;
+        }
+    };
+    let rt = Runtime::new().unwrap();
+    let dht = match join_dht(&rt, args, proxy, &[]) {
+        Some(dht) => dht,
+        None => return ExitCode::FAILURE,
+    };
+    let item = rt.block_on(dht.get_mutable(&public_key, &link.salt));
+    if let Err(e) = dht.save(&args.dht_state) {
+        println!("could not save the DHT routing table: {}", e);
+    }
+    let item = match item {
+        Some(item) => item,
+        None => {
+            println!("nothing published under this key");
+            return ExitCode::FAILURE;
+        }
+    };
+    match magnet::pointed_info_hash(&item.value) {
+        Some(info_hash) => {
+            println!("version {}: {}", item.seq, magnet::to_hex(&info_hash));
+            link.info_hash = Some(info_hash);
+            link.public_key = None;
+            link.salt.clear();
+            println!("{}", link);
+            ExitCode::SUCCESS
+        }
+        None => {
+            println!("the item published under this key is not a torrent");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/**
+ * Join the DHT through the `bootstrap` nodes, then look for peers of the
+ * torrent and announce it every `dht::ANNOUNCE_INTERVAL`.
+ */
+async fn dht_announce(
+    dht: Arc<Dht>,
+    bootstrap: Vec<String>,
+    info_hash: NodeId,
+    port: Option<u16>,
+    state: Arc<SharedTorrentState>,
+) {
+    let routers = resolve_hosts(&bootstrap).await;
+    let nodes = dht.bootstrap(&routers).await;
+    println!("DHT bootstrapped, {} nodes", nodes);
+    let mut timer = tokio::time::interval(dht::ANNOUNCE_INTERVAL);
+    loop {
+        timer.tick().await;
+        if dht.node_count() == 0 {
+            dht.bootstrap(&routers).await;
+        }
+        // without a listen port there is nothing to announce
+        let peers = match port {
+            Some(_) => dht.announce(info_hash, port, state.is_complete()).await,
+            None => dht.get_peers(info_hash).await,
+        };
+        let added = state.add_discovered_peers(&peers);
+        println!("DHT: {} peers, {} new", peers.len(), added);
+    }
+}
+
+/**
+ * Announce the torrent on the local network every `lsd::ANNOUNCE_INTERVAL`
+ * if we accept peers, and add the local peers announcing it.
+ */
+async fn local_discovery(
+    lsd: Arc<Lsd>,
+    info_hash: Vec<u8>,
+    port: Option<u16>,
+    state: Arc<SharedTorrentState>,
+) {
+    let mut timer = tokio::time::interval(lsd::ANNOUNCE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = timer.tick(), if port.is_some() => {
+                lsd.announce(&info_hash, port.unwrap()).await;
+            }
+            (announce, peer) = lsd.recv() => {
+                let ours = announce.info_hashes.contains(&info_hash);
+                if ours && state.add_discovered_peers(&[peer]) > 0 {
+                    println!("found local peer {}", peer);
+                }
+            }
+        }
+    }
+}
+
+/**
+ * Report the transfer totals of the torrent to its tracker.
+ */
+async fn announce(
+    req_data: &mut AnnounceURL,
+    info_hash: &[u8],
+    state: &SharedTorrentState,
+    event: Option<Event>,
+) {
+    let (uploaded, downloaded, left) = state.transfer_totals();
+    req_data.set_progress(uploaded as i64, downloaded as i64, left);
+    req_data.set_event(event);
+    if let Err(e) = tracker::fetch_tracker_data(req_data, info_hash).await {
+        println!("announce failed: {}", e);
+    }
+}
+
+/**
+ * Re-announce every tracker interval, and as soon as the download completes.
+ */
+async fn announce_progress(
+    mut req_data: AnnounceURL,
+    info_hash: Vec<u8>,
+    state: Arc<SharedTorrentState>,
+    period: Duration,
+    // the mapping of the peer port, which the router may move on renewal
+    port_mapping: Option<(Arc<PortMapper>, u16)>,
+) {
+    let mut completed = state.subscribe_completed();
+    let mut reported_complete = state.is_complete();
+    let mut timer = tokio::time::interval(period);
+    // the first tick is immediate, the torrent was just announced
+    timer.tick().await;
+    loop {
+        let event = tokio::select! {
+            _ = timer.tick() => None,
+            changed = completed.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+                if reported_complete || !state.is_complete() {
+                    continue;
+                }
+                reported_complete = true;
+                Some(Event::Completed)
+            }
+        };
+        if let Some((port_mapper, port)) = &port_mapping {
+            if let Some(external_port) = port_mapper.external_port(Protocol::Tcp, *port) {
+                req_data.set_port(external_port as i32);
+            }
+        }
+        announce(&mut req_data, &info_hash, &state, event).await;
+    }
+}
+
+fn verify_data(torrent: &Path, output: &Path, workers: Option<usize>) -> ExitCode {
+    let torrent_info = read_torrent(torrent);
+    let storage = Storage::new(output, &torrent_info.info_data, Allocation::Compact);
+    let report = recheck(&storage, &torrent_info, workers);
+
+    for index in report.corrupt_pieces() {
+        let files: Vec<String> = storage
+            .files_for_piece(index)
+            .iter()
+            .map(|i| storage.files()[*i].path.display().to_string())
+            .collect();
+        println!("piece {} is corrupt: {}", index, files.join(", "));
+    }
     println!(
-        "tracker response: {}",
-        torrent_info.info_data.length / torrent_info.info_data.piece_length
+        "{} valid, {} corrupt, {} missing",
+        report.valid_pieces().count(),
+        report.corrupt_pieces().count(),
+        report.missing_pieces().count()
     );
-    // let torrent_state = TorrentState::new(torrent_info, &peer_list);
-    let mut peer_connection = rt.block_on(PeerConnection::new(peer_list.peers[1].ip.clone(), peer_list.peers[1].port)).unwrap();
-    let mut listener = rt.block_on(PeerConnection::listen()).unwrap();
-    let handshake = Handshake::new(torrent_info.info_hash , &client_id);
-    let handshake_req = peer_connection.handshake_with_peer(&handshake);
-    rt.block_on(handshake_req).unwrap();
-    loop {
-        let read_stream = peer_connection.read_from_stream();
-        let response = rt.block_on(read_stream);
-        println!("handhshake res: {:?}", response);
-        if response.len() > 0 { break };
-    }
-    // rt.block_on(create_queue(torrent_state, client_id));
-    //
-    //     let handshake_msg =
-    //         tracker::Handshake::new(torrent_info.info_hash.clone(), &client_id).serialize();
-    //     let connect_to_tracker = tracker::connect_to_peer(&handshake_msg, &peer_list);
-    //
-    //     match rt.block_on(connect_to_tracker) {
-    //         Ok(_) => {}
-    //         Err(_) => {}
-    //     };
+
+    if report.is_complete() {
+        println!("all pieces are valid");
+    }
+    if report.is_corrupt() {
+        ExitCode::FAILURE
+    } else if !report.is_complete() {
+        ExitCode::from(2)
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+/**
+ * Seeders and leechers of `info_hash` according to the tracker of
+ * `announce`.
+ */
+async fn tracker_scrape(
+    announce: &str,
+    info_hash: &[u8],
+    proxy: Option<&Proxy>,
+) -> Result<ScrapeStats, String> {
+    let url = tracker::scrape_url(announce).ok_or("the tracker does not support scrapes")?;
+    let bytes = tracker::fetch_scrape_data(&url, info_hash, proxy)
+        .await
+        .map_err(|e| e.to_string())?;
+    let response = ScrapeResponse::from_bencode(&bytes).map_err(|e| e.to_string())?;
+    if let Some(failure) = response.failure {
+        return Err(failure);
+    }
+    response
+        .files
+        .into_iter()
+        .find(|(hash, _)| hash == info_hash)
+        .map(|(_, stats)| stats)
+        .ok_or_else(|| String::from("the tracker does not know the torrent"))
+}
+
+fn scrape(torrent: &Path, args: &DhtArgs, proxy: &ProxyArgs) -> ExitCode {
+    let torrent_info = read_torrent(torrent);
+    let rt = Runtime::new().unwrap();
+    if !torrent_info.announce.is_empty() {
+        let info_hash = &torrent_info.info_hash;
+        let scrape = tracker_scrape(&torrent_info.announce, info_hash, proxy.proxy.as_ref());
+        match rt.block_on(scrape) {
+            Ok(stats) => {
+                println!(
+                    "{} seeders, {} leechers, {} downloads",
+                    stats.complete, stats.incomplete, stats.downloaded
+                );
+                return ExitCode::SUCCESS;
+            }
+            Err(e) => println!("tracker scrape failed: {}", e),
+        }
+    }
+    if torrent_info.info_data.private {
+        println!("private torrents are not in the DHT");
+        return ExitCode::FAILURE;
+    }
+    let dht = match join_dht(&rt, args, proxy, &torrent_info.nodes) {
+        Some(dht) => dht,
+        None => return ExitCode::FAILURE,
+    };
+    let info_hash = NodeId::from_bytes(&torrent_info.info_hash).unwrap();
+    let (seeds, downloaders) = rt.block_on(dht.scrape(info_hash));
+    if let Err(e) = dht.save(&args.dht_state) {
+        println!("could not save the DHT routing table: {}", e);
+    }
+    println!("about {} seeders, {} leechers (DHT)", seeds, downloaders);
+    ExitCode::SUCCESS
+}
+
+fn parse_target(target: &str) -> Option<NodeId> {
+    let target = magnet::from_hex(target).and_then(|t| NodeId::from_bytes(&t));
+    if target.is_none() {
+        println!("the target must be 40 hex digits");
+    }
+    target
+}
+
+fn sample(target: Option<&str>, args: &DhtArgs, proxy: &ProxyArgs) -> ExitCode {
+    let target = match target.map(parse_target) {
+        Some(Some(target)) => target,
+        Some(None) => return ExitCode::FAILURE,
+        None => NodeId::random(),
+    };
+    let rt = Runtime::new().unwrap();
+    let dht = match join_dht(&rt, args, proxy, &[]) {
+        Some(dht) => dht,
+        None => return ExitCode::FAILURE,
+    };
+    let (samples, total) = rt.block_on(dht.sample_infohashes(target));
+    if let Err(e) = dht.save(&args.dht_state) {
+        println!("could not save the DHT routing table: {}", e);
+    }
+    for info_hash in &samples {
+        println!("{}", magnet::to_hex(&info_hash.0));
+    }
+    println!("{} sampled of {} info hashes", samples.len(), total);
+    ExitCode::SUCCESS
+}
+
+fn put(value: &str, args: &DhtArgs, proxy: &ProxyArgs) -> ExitCode {
+    let rt = Runtime::new().unwrap();
+    let dht = match join_dht(&rt, args, proxy, &[]) {
+        Some(dht) => dht,
+        None => return ExitCode::FAILURE,
+    };
+    let result = rt.block_on(dht.put_immutable(value.as_bytes().into()));
+    if let Err(e) = dht.save(&args.dht_state) {
+        println!("could not save the DHT routing table: {}", e);
+    }
+    match result {
+        Ok(target) => {
+            println!("{}", magnet::to_hex(&target.0));
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            println!("could not store the value: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn get(target: &str, args: &DhtArgs, proxy: &ProxyArgs) -> ExitCode {
+    let target = match parse_target(target) {
+        Some(target) => target,
+        None => return ExitCode::FAILURE,
+    };
+    let rt = Runtime::new().unwrap();
+    let dht = match join_dht(&rt, args, proxy, &[]) {
+        Some(dht) => dht,
+        None => return ExitCode::FAILURE,
+    };
+    let value = rt.block_on(dht.get_immutable(target));
+    if let Err(e) = dht.save(&args.dht_state) {
+        println!("could not save the DHT routing table: {}", e);
+    }
+    match value {
+        Some(Value::Bytes(bytes)) => {
+            println!("{}", String::from_utf8_lossy(&bytes));
+            ExitCode::SUCCESS
+        }
+        Some(value) => {
+            println!("{:?}", value);
+            ExitCode::SUCCESS
+        }
+        None => {
+            println!("nothing stored under this target");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn nodes(target: Option<&str>, args: &DhtArgs, proxy: &ProxyArgs) -> ExitCode {
+    let rt = Runtime::new().unwrap();
+    let dht = match join_dht(&rt, args, proxy, &[]) {
+        Some(dht) => dht,
+        None => return ExitCode::FAILURE,
+    };
+    println!(
+        "node id {}, IPv6 node id {}",
+        magnet::to_hex(&dht.id().0),
+        magnet::to_hex(&dht.id6().0)
+    );
+    let target = match target.map(parse_target) {
+        Some(Some(target)) => target,
+        Some(None) => return ExitCode::FAILURE,
+        None => dht.id(),
+    };
+    let found = rt.block_on(dht.find_node(target));
+    if let Err(e) = dht.save(&args.dht_state) {
+        println!("could not save the DHT routing table: {}", e);
+    }
+    for (id, addr) in &found {
+        println!("{} {}", magnet::to_hex(&id.0), addr);
+    }
+    ExitCode::SUCCESS
+}
+
+fn main() -> ExitCode {
+    let args = Cli::parse();
+    match args.command {
+        Command::Download(args) => download(args),
+        Command::Verify {
+            torrent,
+            output,
+            workers,
+        } => verify_data(&torrent, &output, workers),
+        Command::Publish {
+            torrent,
+            key,
+            salt,
+            dht,
+            proxy,
+        } => publish(&torrent, &key, salt, &dht, &proxy),
+        Command::Resolve { magnet, dht, proxy } => resolve(&magnet, &dht, &proxy),
+        Command::Scrape {
+            torrent,
+            dht,
+            proxy,
+        } => scrape(&torrent, &dht, &proxy),
+        Command::Sample { target, dht, proxy } => sample(target.as_deref(), &dht, &proxy),
+        Command::Put { value, dht, proxy } => put(&value, &dht, &proxy),
+        Command::Get { target, dht, proxy } => get(&target, &dht, &proxy),
+        Command::Nodes { target, dht, proxy } => nodes(target.as_deref(), &dht, &proxy),
+    }
 }
@@ -0,0 +1,581 @@
+use std::{
+    error::Error,
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use num_bigint::BigUint;
+use rand::{thread_rng, Rng};
+use sha1_smol::Sha1;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    time::timeout,
+};
+
+use crate::utp::Transport;
+
+// Message Stream Encryption (MSE/PE) obfuscates connections so that
+// middleboxes can't spot BitTorrent handshakes. Before the usual handshake
+// both sides exchange Diffie-Hellman keys, the initiator A proves it knows
+// the info hash (SKEY) and the two agree on a crypto method:
+//
+//   1 A->B: Ya, PadA
+//   2 B->A: Yb, PadB
+//   3 A->B: HASH('req1', S), HASH('req2', SKEY) xor HASH('req3', S),
+//           ENCRYPT(VC, crypto_provide, len(PadC), PadC, len(IA)), ENCRYPT(IA)
+//   4 B->A: ENCRYPT(VC, crypto_select, len(PadD), PadD)
+//
+// S is the shared secret, VC eight zero bytes and pads up to 512 random
+// bytes, which is why both sides scan for a known pattern instead of reading
+// at fixed offsets. ENCRYPT is RC4 keyed with HASH('keyA', S, SKEY) from A
+// and HASH('keyB', S, SKEY) from B, dropping the first 1024 bytes of key
+// stream. With RC4 selected the rest of the connection stays encrypted, with
+// plaintext only the handshake above is.
+//
+// The receiver learns the torrent from the req2 hash, so it checks every
+// torrent of the session. Incoming plaintext connections are told apart by
+// their first 20 bytes, the protocol string of a regular handshake.
+
+// 768 bit safe prime of the key exchange, the generator is 2
+const PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74\
+                     020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437\
+                     4FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const GENERATOR: u32 = 2;
+const KEY_LENGTH: usize = 96;
+const PRIVATE_KEY_LENGTH: usize = 20;
+const VC: [u8; 8] = [0; 8];
+const MAX_PAD: usize = 512;
+const RC4_DISCARD: usize = 1024;
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+const PROTOCOL: &[u8] = b"\x13BitTorrent protocol";
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Whether connections to and from peers are encrypted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum EncryptionMode {
+    // encrypt when the peer supports it, fall back to plaintext otherwise
+    #[default]
+    Prefer,
+    // refuse peers that don't encrypt
+    Require,
+    // plaintext only, encrypted handshakes are refused
+    Disable,
+}
+
+impl EncryptionMode {
+    fn crypto_provide(&self) -> u32 {
+        match self {
+            EncryptionMode::Require => CRYPTO_RC4,
+            _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+        }
+    }
+
+    /**
+     * Method picked among those the initiator provides, RC4 when possible.
+     */
+    fn crypto_select(&self, provide: u32) -> Option<u32> {
+        if provide & CRYPTO_RC4 != 0 {
+            Some(CRYPTO_RC4)
+        } else if provide & CRYPTO_PLAINTEXT != 0 && *self == EncryptionMode::Prefer {
+            Some(CRYPTO_PLAINTEXT)
+        } else {
+            None
+        }
+    }
+}
+
+pub struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut state = [0; 256];
+        for (i, s) in state.iter_mut().enumerate() {
+            *s = i as u8;
+        }
+        let mut j: u8 = 0;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        Rc4 { state, i: 0, j: 0 }
+    }
+
+    /**
+     * Encrypt or decrypt `data` in place.
+     */
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let k = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[k as usize];
+        }
+    }
+
+    /**
+     * Skip `count` bytes of key stream.
+     */
+    pub fn discard(&mut self, count: usize) {
+        self.apply(&mut vec![0; count]);
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut sha = Sha1::new();
+    for part in parts {
+        sha.update(part);
+    }
+    sha.digest().bytes()
+}
+
+fn xor(a: [u8; 20], b: [u8; 20]) -> [u8; 20] {
+    let mut out = a;
+    for (o, b) in out.iter_mut().zip(b) {
+        *o ^= b;
+    }
+    out
+}
+
+/**
+ * RC4 keyed for the side named by `label`, `keyA` or `keyB`.
+ */
+fn cipher(label: &[u8], secret: &[u8], skey: &[u8]) -> Rc4 {
+    let mut rc4 = Rc4::new(&hash(&[label, secret, skey]));
+    rc4.discard(RC4_DISCARD);
+    rc4
+}
+
+fn random_pad() -> Vec<u8> {
+    let mut rng = thread_rng();
+    let mut pad = vec![0; rng.gen_range(0..=MAX_PAD)];
+    rng.fill(&mut pad[..]);
+    pad
+}
+
+/**
+ * Big endian number left padded to the length of a key.
+ */
+fn to_key_bytes(n: &BigUint) -> Vec<u8> {
+    let bytes = n.to_bytes_be();
+    let mut key = vec![0; KEY_LENGTH - bytes.len()];
+    key.extend(bytes);
+    key
+}
+
+struct KeyPair {
+    private: BigUint,
+    // Y = G^X mod P
+    public: Vec<u8>,
+}
+
+impl KeyPair {
+    fn generate() -> Self {
+        let mut private = [0; PRIVATE_KEY_LENGTH];
+        thread_rng().fill(&mut private);
+        let private = BigUint::from_bytes_be(&private);
+        let prime = prime();
+        let public = to_key_bytes(&BigUint::from(GENERATOR).modpow(&private, &prime));
+        KeyPair { private, public }
+    }
+
+    /**
+     * S = Y^X mod P from the peer's public key. Keys of 0, 1 and P-1 would
+     * make the secret predictable.
+     */
+    fn shared_secret(&self, public: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let prime = prime();
+        let public = BigUint::from_bytes_be(public);
+        let one = BigUint::from(1u32);
+        if public <= one || public >= &prime - &one {
+            return Err("Invalid Diffie-Hellman key!".into());
+        }
+        Ok(to_key_bytes(&public.modpow(&self.private, &prime)))
+    }
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(PRIME.as_bytes(), 16).expect("Error parsing prime!")
+}
+
+/**
+ * Read until the last bytes read are `pattern`, giving up after `limit` bytes.
+ */
+async fn sync<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    pattern: &[u8],
+    limit: usize,
+) -> Result<(), Box<dyn Error>> {
+    let mut window = Vec::with_capacity(limit);
+    while window.len() < limit {
+        window.push(stream.read_u8().await?);
+        if window.ends_with(pattern) {
+            return Ok(());
+        }
+    }
+    Err("Encryption handshake out of sync!".into())
+}
+
+fn read_u16(bytes: &[u8]) -> usize {
+    u16::from_be_bytes([bytes[0], bytes[1]]) as usize
+}
+
+/**
+ * Connection to a peer, encrypted once the handshake selects RC4.
+ */
+pub struct PeerStream {
+    stream: Transport,
+    // peer data read along with the handshake, decrypted, served before
+    // anything else
+    received: Vec<u8>,
+    decrypt: Option<Rc4>,
+    encrypt: Option<Rc4>,
+    // encrypted data not yet written to the socket, reported as written
+    unsent: Vec<u8>,
+}
+
+impl PeerStream {
+    pub fn new(stream: Transport) -> Self {
+        PeerStream {
+            stream,
+            received: vec![],
+            decrypt: None,
+            encrypt: None,
+            unsent: vec![],
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypt.is_some()
+    }
+
+    pub fn is_utp(&self) -> bool {
+        matches!(self.stream, Transport::Utp(_))
+    }
+
+    fn poll_unsent(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.unsent.is_empty() {
+            let n = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.unsent))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.unsent.drain(..n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for PeerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.received.is_empty() {
+            let n = this.received.len().min(buf.remaining());
+            buf.put_slice(&this.received[..n]);
+            this.received.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.stream).poll_read(cx, buf))?;
+        if let Some(cipher) = &mut this.decrypt {
+            cipher.apply(&mut buf.filled_mut()[filled..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for PeerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.encrypt.is_none() {
+            return Pin::new(&mut this.stream).poll_write(cx, buf);
+        }
+        // the key stream moves on with every byte encrypted, so encrypted
+        // data is kept until written rather than encrypted again on retry
+        ready!(this.poll_unsent(cx))?;
+        let mut data = buf.to_vec();
+        if let Some(cipher) = &mut this.encrypt {
+            cipher.apply(&mut data);
+        }
+        this.unsent = data;
+        if let Poll::Ready(Err(e)) = this.poll_unsent(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_unsent(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_unsent(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+/**
+ * Encryption handshake of an outgoing connection to a peer of `info_hash`.
+ */
+pub async fn initiate(
+    peer: &mut PeerStream,
+    info_hash: &[u8],
+    mode: EncryptionMode,
+) -> Result<(), Box<dyn Error>> {
+    timeout(HANDSHAKE_TIMEOUT, initiate_handshake(peer, info_hash, mode)).await?
+}
+
+async fn initiate_handshake(
+    peer: &mut PeerStream,
+    info_hash: &[u8],
+    mode: EncryptionMode,
+) -> Result<(), Box<dyn Error>> {
+    let stream = &mut peer.stream;
+    let keys = KeyPair::generate();
+    stream
+        .write_all(&[keys.public.as_slice(), &random_pad()].concat())
+        .await?;
+    let mut public = [0; KEY_LENGTH];
+    stream.read_exact(&mut public).await?;
+    let secret = keys.shared_secret(&public)?;
+    let mut encrypt = cipher(b"keyA", &secret, info_hash);
+    let mut decrypt = cipher(b"keyB", &secret, info_hash);
+
+    let mut message = hash(&[b"req1", &secret]).to_vec();
+    message.extend(xor(hash(&[b"req2", info_hash]), hash(&[b"req3", &secret])));
+    let pad = random_pad();
+    let mut payload = VC.to_vec();
+    payload.extend(mode.crypto_provide().to_be_bytes());
+    payload.extend((pad.len() as u16).to_be_bytes());
+    payload.extend(pad);
+    // no initial payload, the BitTorrent handshake follows on its own
+    payload.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut payload);
+    message.extend(payload);
+    stream.write_all(&message).await?;
+
+    // the answer starts after PadB, with VC encrypted
+    let mut vc = VC;
+    decrypt.apply(&mut vc);
+    sync(stream, &vc, MAX_PAD + VC.len()).await?;
+    let mut select = [0; 6];
+    stream.read_exact(&mut select).await?;
+    decrypt.apply(&mut select);
+    let pad_length = read_u16(&select[4..]);
+    if pad_length > MAX_PAD {
+        return Err("Encryption padding is too long!".into());
+    }
+    let mut pad = vec![0; pad_length];
+    stream.read_exact(&mut pad).await?;
+    decrypt.apply(&mut pad);
+
+    let selected = u32::from_be_bytes(select[..4].try_into()?);
+    if selected & mode.crypto_provide() == 0 || selected.count_ones() != 1 {
+        return Err(format!("Peer selected unknown crypto method {}!", selected).into());
+    }
+    if selected == CRYPTO_RC4 {
+        peer.encrypt = Some(encrypt);
+        peer.decrypt = Some(decrypt);
+    }
+    Ok(())
+}
+
+/**
+ * Handshake of an incoming connection, encrypted or not. Encrypted peers
+ * must know one of `info_hashes`. The BitTorrent handshake is read from the
+ * stream afterwards either way.
+ */
+pub async fn respond(
+    peer: &mut PeerStream,
+    info_hashes: &[Vec<u8>],
+    mode: EncryptionMode,
+) -> Result<(), Box<dyn Error>> {
+    timeout(
+        HANDSHAKE_TIMEOUT,
+        respond_handshake(peer, info_hashes, mode),
+    )
+    .await?
+}
+
+async fn respond_handshake(
+    peer: &mut PeerStream,
+    info_hashes: &[Vec<u8>],
+    mode: EncryptionMode,
+) -> Result<(), Box<dyn Error>> {
+    let stream = &mut peer.stream;
+    let mut public = [0; KEY_LENGTH];
+    stream.read_exact(&mut public[..PROTOCOL.len()]).await?;
+    if &public[..PROTOCOL.len()] == PROTOCOL {
+        if mode == EncryptionMode::Require {
+            return Err("Plaintext connections are refused!".into());
+        }
+        peer.received = PROTOCOL.to_vec();
+        return Ok(());
+    }
+    if mode == EncryptionMode::Disable {
+        return Err("Encrypted connections are refused!".into());
+    }
+    stream.read_exact(&mut public[PROTOCOL.len()..]).await?;
+    let keys = KeyPair::generate();
+    let secret = keys.shared_secret(&public)?;
+    stream
+        .write_all(&[keys.public.as_slice(), &random_pad()].concat())
+        .await?;
+
+    sync(stream, &hash(&[b"req1", &secret]), MAX_PAD + 20).await?;
+    let mut skey_hash = [0; 20];
+    stream.read_exact(&mut skey_hash).await?;
+    let skey_hash = xor(skey_hash, hash(&[b"req3", &secret]));
+    let info_hash = info_hashes
+        .iter()
+        .find(|h| hash(&[b"req2", h]) == skey_hash)
+        .ok_or("unknown info hash")?;
+    let mut decrypt = cipher(b"keyA", &secret, info_hash);
+    let mut encrypt = cipher(b"keyB", &secret, info_hash);
+
+    let mut header = [0; 14];
+    stream.read_exact(&mut header).await?;
+    decrypt.apply(&mut header);
+    if header[..8] != VC {
+        return Err("Invalid encryption verification constant!".into());
+    }
+    let provide = u32::from_be_bytes(header[8..12].try_into()?);
+    let pad_length = read_u16(&header[12..]);
+    if pad_length > MAX_PAD {
+        return Err("Encryption padding is too long!".into());
+    }
+    let mut pad = vec![0; pad_length + 2];
+    stream.read_exact(&mut pad).await?;
+    decrypt.apply(&mut pad);
+    let mut initial = vec![0; read_u16(&pad[pad_length..])];
+    stream.read_exact(&mut initial).await?;
+    decrypt.apply(&mut initial);
+
+    let selected = mode
+        .crypto_select(provide)
+        .ok_or("No common crypto method!")?;
+    let pad = random_pad();
+    let mut answer = VC.to_vec();
+    answer.extend(selected.to_be_bytes());
+    answer.extend((pad.len() as u16).to_be_bytes());
+    answer.extend(pad);
+    encrypt.apply(&mut answer);
+    stream.write_all(&answer).await?;
+
+    peer.received = initial;
+    if selected == CRYPTO_RC4 {
+        peer.encrypt = Some(encrypt);
+        peer.decrypt = Some(decrypt);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn rc4_and_key_exchange() {
+        let mut data = b"Plaintext".to_vec();
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!(data, [0xbb, 0xf3, 0x16, 0xe8, 0xd9, 0x40, 0xaf, 0x0a, 0xd3]);
+        let mut decrypt = Rc4::new(b"Key");
+        decrypt.apply(&mut data);
+        assert_eq!(data, b"Plaintext");
+
+        let (a, b) = (KeyPair::generate(), KeyPair::generate());
+        assert_eq!(a.public.len(), KEY_LENGTH);
+        assert_eq!(
+            a.shared_secret(&b.public).unwrap(),
+            b.shared_secret(&a.public).unwrap()
+        );
+        assert!(a.shared_secret(&[1]).is_err());
+    }
+
+    async fn connect(
+        outgoing: EncryptionMode,
+        incoming: EncryptionMode,
+    ) -> Result<(PeerStream, PeerStream), String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let info_hash = vec![7; 20];
+        let hashes = vec![vec![1; 20], info_hash.clone()];
+        let accept = tokio::spawn(async move {
+            let stream = Transport::Tcp(listener.accept().await.unwrap().0);
+            let mut peer = PeerStream::new(stream);
+            respond(&mut peer, &hashes, incoming)
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok::<_, String>(peer)
+        });
+        let stream = Transport::Tcp(TcpStream::connect(addr).await.unwrap());
+        let mut peer = PeerStream::new(stream);
+        let initiated = initiate(&mut peer, &info_hash, outgoing).await;
+        let accepted = accept.await.unwrap()?;
+        initiated.map_err(|e| e.to_string())?;
+        Ok((peer, accepted))
+    }
+
+    #[tokio::test]
+    async fn encrypted_connection() {
+        let (mut a, mut b) = connect(EncryptionMode::Prefer, EncryptionMode::Prefer)
+            .await
+            .unwrap();
+        assert!(a.is_encrypted() && b.is_encrypted());
+        a.write_all(PROTOCOL).await.unwrap();
+        a.flush().await.unwrap();
+        let mut received = vec![0; PROTOCOL.len()];
+        b.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, PROTOCOL);
+        b.write_all(b"reply").await.unwrap();
+        b.flush().await.unwrap();
+        let mut received = vec![0; 5];
+        a.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, b"reply");
+
+        // a peer that doesn't allow encryption refuses the handshake
+        assert!(connect(EncryptionMode::Prefer, EncryptionMode::Disable)
+            .await
+            .is_err());
+
+        // incoming plaintext handshakes pass through, unless refused
+        for (mode, accepted) in [
+            (EncryptionMode::Prefer, true),
+            (EncryptionMode::Disable, true),
+            (EncryptionMode::Require, false),
+        ] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(PROTOCOL).await.unwrap();
+            let stream = Transport::Tcp(listener.accept().await.unwrap().0);
+            let mut peer = PeerStream::new(stream);
+            let result = respond(&mut peer, &[], mode).await;
+            assert_eq!(result.is_ok(), accepted);
+            if accepted {
+                let mut received = vec![0; PROTOCOL.len()];
+                peer.read_exact(&mut received).await.unwrap();
+                assert_eq!(received, PROTOCOL);
+                assert!(!peer.is_encrypted());
+            }
+        }
+    }
+}
@@ -1,13 +1,107 @@
 pub mod torrent_info {
     pub use bendy::decoding::{Error, FromBencode, Object, ResultExt};
     use sha1_smol::Sha1;
+    use std::ops::Range;
+
+    #[derive(Debug, Clone)]
+    pub struct FileInfo {
+        pub length: i64,
+        pub path: Vec<String>,
+    }
 
     #[derive(Debug, Clone)]
     pub struct TorrentMetadata {
         pub pieces: Vec<u8>,
         pub piece_length: i32,
-        pub length: i32,
+        // total length of the torrent, summed over `files` in multi-file mode
+        pub length: i64,
         pub name: String,
+        // `None` for single-file torrents
+        pub files: Option<Vec<FileInfo>>,
+        // BEP 27: peers only come from the trackers of the torrent
+        pub private: bool,
+    }
+
+    impl TorrentMetadata {
+        pub fn piece_count(&self) -> usize {
+            self.pieces.len() / 20
+        }
+
+        /**
+         * SHA1 hash of the piece at `index` as listed in `pieces`.
+         */
+        pub fn piece_hash(&self, index: usize) -> Option<&[u8]> {
+            self.pieces.get((index * 20)..(index * 20 + 20))
+        }
+
+        /**
+         * Size of the piece at `index`, the last piece may be shorter.
+         */
+        pub fn piece_size(&self, index: usize) -> i64 {
+            let start = index as i64 * self.piece_length as i64;
+            (self.length - start).clamp(0, self.piece_length as i64)
+        }
+
+        /**
+         * Range of pieces overlapping each file, in the order of `files`.
+         */
+        pub fn file_piece_ranges(&self) -> Vec<Range<usize>> {
+            let lengths = match &self.files {
+                Some(f) => f.iter().map(|file| file.length).collect(),
+                None => vec![self.length],
+            };
+            let piece_length = self.piece_length as i64;
+            let mut offset = 0;
+            let mut ret = vec![];
+            for length in lengths {
+                if length == 0 {
+                    ret.push(0..0);
+                } else {
+                    let first = offset / piece_length;
+                    let last = (offset + length - 1) / piece_length;
+                    ret.push((first as usize)..(last as usize + 1));
+                }
+                offset += length;
+            }
+            ret
+        }
+    }
+
+    impl FromBencode for FileInfo {
+        fn decode_bencode_object(object: Object) -> Result<Self, Error>
+        where
+            Self: Sized,
+        {
+            let mut length = None;
+            let mut path = None;
+
+            let mut decoder = object.try_into_dictionary()?;
+
+            while let Some(pair) = decoder.next_pair()? {
+                match pair {
+                    (b"length", value) => {
+                        length = i64::decode_bencode_object(value)
+                            .context("length")
+                            .map(Some)?;
+                    }
+                    (b"path", value) => {
+                        path = Vec::<String>::decode_bencode_object(value)
+                            .context("path")
+                            .map(Some)?;
+                    }
+                    _ => {
+                        return Err(bendy::decoding::Error::unexpected_field(
+                            "[FileInfo]: excessive fields",
+                        ))
+                    }
+                }
+            }
+
+            let length = length.ok_or_else(|| Error::missing_field("length"))?;
+            let path = path.ok_or_else(|| Error::missing_field("path"))?;
+
+            Ok(FileInfo { length, path })
+        }
     }
 
     #[derive(Debug, Clone)]
@@ -17,6 +111,10 @@ pub mod torrent_info {
         pub creation_date: i32,
         pub created_by: String,
         pub url_list: Vec<String>,
+        // BEP 17 seeding scripts
+        pub http_seeds: Vec<String>,
+        // DHT nodes to bootstrap from, for torrents without a tracker
+        pub nodes: Vec<(String, u16)>,
         pub info_data: TorrentMetadata,
         pub info_hash: Vec<u8>,
     }
@@ -30,6 +128,8 @@ pub mod torrent_info {
             let mut piece_length = None;
             let mut length = None;
             let mut name = None;
+            let mut files = None;
+            let mut private = None;
 
             let mut decoder = object.try_into_dictionary()?;
 
@@ -48,7 +148,7 @@ pub mod torrent_info {
                             .map(Some)?;
                     }
                     (b"length", value) => {
-                        length = i32::decode_bencode_object(value)
+                        length = i64::decode_bencode_object(value)
                             .context("length")
                             .map(Some)?;
                     }
@@ -57,6 +157,16 @@ pub mod torrent_info {
                             .context("name")
                             .map(Some)?;
                     }
+                    (b"files", value) => {
+                        files = Vec::<FileInfo>::decode_bencode_object(value)
+                            .context("files")
+                            .map(Some)?;
+                    }
+                    (b"private", value) => {
+                        private = i64::decode_bencode_object(value)
+                            .context("private")
+                            .map(Some)?;
+                    }
                     _ => {
                         return Err(bendy::decoding::Error::unexpected_field(
                             "[TorrentMetadata]: excessive fields",
@@ -67,7 +177,10 @@ pub mod torrent_info {
 
             let pieces = (pieces.ok_or_else(|| Error::missing_field("pieces"))?).to_vec();
             let piece_length = piece_length.ok_or_else(|| Error::missing_field("piece_length"))?;
-            let length = length.ok_or_else(|| Error::missing_field("length"))?;
+            let length = match &files {
+                Some(f) => f.iter().map(|file| file.length).sum(),
+                None => length.ok_or_else(|| Error::missing_field("length"))?,
+            };
             let name = name.ok_or_else(|| Error::missing_field("name"))?;
 
             Ok(TorrentMetadata {
@@ -75,6 +188,8 @@ pub mod torrent_info {
                 piece_length,
                 length,
                 name,
+                files,
+                private: private == Some(1),
             })
         }
     }
@@ -89,6 +204,8 @@ pub mod torrent_info {
             let mut creation_date = None;
             let mut created_by = None;
             let mut url_list = None;
+            let mut http_seeds = vec![];
+            let mut nodes = vec![];
             let mut info_data = None;
             let mut info_hash = None;
 
@@ -116,11 +233,34 @@ pub mod torrent_info {
                             .context("created by")
                             .map(Some)?;
                     }
+                    // a single web seed may be given as a string (BEP 19)
+                    (b"url-list", Object::Bytes(url)) => {
+                        url_list = Some(vec![String::from_utf8_lossy(url).into_owned()]);
+                    }
                     (b"url-list", value) => {
                         url_list = Vec::<String>::decode_bencode_object(value)
                             .context("url list")
                             .map(Some)?;
                     }
+                    (b"httpseeds", value) => {
+                        http_seeds =
+                            Vec::<String>::decode_bencode_object(value).context("httpseeds")?;
+                    }
+                    (b"nodes", value) => {
+                        let mut list = value.try_into_list().context("nodes")?;
+                        while let Some(node) = list.next_object()? {
+                            let mut node = node.try_into_list().context("nodes")?;
+                            let host = match node.next_object()? {
+                                Some(h) => String::decode_bencode_object(h).context("nodes")?,
+                                None => continue,
+                            };
+                            let port = match node.next_object()? {
+                                Some(p) => u16::decode_bencode_object(p).context("nodes")?,
+                                None => continue,
+                            };
+                            nodes.push((host, port));
+                        }
+                    }
                     (b"info", value) => {
                         let info_dict = value
                             .dictionary_or_else(|obj| Err(obj.into_token()))
@@ -142,12 +282,14 @@ pub mod torrent_info {
                     }
                 }
             }
-            let announce = announce.ok_or_else(|| Error::missing_field("announce"))?;
+            // trackerless torrents find peers through the DHT only
+            let announce = announce.unwrap_or_default();
             let comment = comment.ok_or_else(|| Error::missing_field("comment"))?;
             let creation_date =
                 creation_date.ok_or_else(|| Error::missing_field("creation date"))?;
             let created_by = created_by.ok_or_else(|| Error::missing_field("created by"))?;
-            let url_list = url_list.ok_or_else(|| Error::missing_field("url list"))?;
+            let mut url_list = url_list.unwrap_or_default();
+            url_list.retain(|url| !url.is_empty());
             let info_data = info_data.ok_or_else(|| Error::missing_field("info"))?;
             let info_hash = info_hash.ok_or_else(|| Error::missing_field("info"))?;
 
@@ -157,6 +299,8 @@ pub mod torrent_info {
                 creation_date,
                 created_by,
                 url_list,
+                http_seeds,
+                nodes,
                 info_data,
                 info_hash,
             })
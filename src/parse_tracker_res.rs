@@ -1,4 +1,6 @@
 pub mod peers {
+    use std::net::IpAddr;
+
     pub use bendy::decoding::{Error, FromBencode, Object, ResultExt};
 
     #[derive(Debug, Clone)]
@@ -7,10 +9,12 @@ pub mod peers {
         pub port: i32,
     }
 
-    #[derive(Debug)]
+    #[derive(Debug, Default)]
     pub struct PeerList {
         pub interval: i32,
         pub peers: Vec<Peer>,
+        // our address as seen by the tracker
+        pub external_ip: Option<IpAddr>,
     }
 
     impl FromBencode for PeerList {
@@ -20,6 +24,7 @@ pub mod peers {
         {
             let mut interval = None;
             let mut peers = Vec::new();
+            let mut external_ip = None;
 
             let mut decoder = object.try_into_dictionary()?;
 
@@ -65,13 +70,101 @@ pub mod peers {
                             }
                         }
                     }
+                    (b"external ip", obj) => {
+                        let bytes = obj.try_into_bytes().context("external ip")?;
+                        external_ip = match bytes.len() {
+                            4 => Some(IpAddr::from(<[u8; 4]>::try_from(bytes).unwrap())),
+                            16 => Some(IpAddr::from(<[u8; 16]>::try_from(bytes).unwrap())),
+                            _ => None,
+                        };
+                    }
                     _ => return Err(Error::unexpected_field("[TrackerData]: excessive fields")),
                 }
             }
 
             let interval = interval.ok_or_else(|| Error::missing_field("interval"))?;
 
-            Ok(PeerList { interval, peers })
+            Ok(PeerList {
+                interval,
+                peers,
+                external_ip,
+            })
+        }
+    }
+}
+
+pub mod scrape {
+    use bendy::decoding::{Error, FromBencode, Object, ResultExt};
+
+    #[derive(Debug, Default, Clone, PartialEq, Eq)]
+    pub struct ScrapeStats {
+        // seeders
+        pub complete: i64,
+        // completed downloads ever reported
+        pub downloaded: i64,
+        // leechers
+        pub incomplete: i64,
+    }
+
+    #[derive(Debug, Default)]
+    pub struct ScrapeResponse {
+        // stats by info hash
+        pub files: Vec<(Vec<u8>, ScrapeStats)>,
+        pub failure: Option<String>,
+    }
+
+    impl FromBencode for ScrapeStats {
+        fn decode_bencode_object(object: Object) -> Result<Self, Error>
+        where
+            Self: Sized,
+        {
+            let mut stats = ScrapeStats::default();
+            let mut dict = object.try_into_dictionary()?;
+            while let Some(pair) = dict.next_pair()? {
+                match pair {
+                    (b"complete", value) => {
+                        stats.complete = i64::decode_bencode_object(value).context("complete")?
+                    }
+                    (b"downloaded", value) => {
+                        stats.downloaded =
+                            i64::decode_bencode_object(value).context("downloaded")?
+                    }
+                    (b"incomplete", value) => {
+                        stats.incomplete =
+                            i64::decode_bencode_object(value).context("incomplete")?
+                    }
+                    // e.g. `name`
+                    _ => {}
+                }
+            }
+            Ok(stats)
+        }
+    }
+
+    impl FromBencode for ScrapeResponse {
+        fn decode_bencode_object(object: Object) -> Result<Self, Error>
+        where
+            Self: Sized,
+        {
+            let mut response = ScrapeResponse::default();
+            let mut dict = object.try_into_dictionary()?;
+            while let Some(pair) = dict.next_pair()? {
+                match pair {
+                    (b"files", value) => {
+                        let mut files = value.try_into_dictionary().context("files")?;
+                        while let Some((info_hash, stats)) = files.next_pair()? {
+                            let stats = ScrapeStats::decode_bencode_object(stats)?;
+                            response.files.push((info_hash.to_vec(), stats));
+                        }
+                    }
+                    (b"failure reason", value) => {
+                        let reason = value.try_into_bytes().context("failure reason")?;
+                        response.failure = Some(String::from_utf8_lossy(reason).into_owned());
+                    }
+                    _ => {}
+                }
+            }
+            Ok(response)
         }
     }
 }
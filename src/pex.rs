@@ -0,0 +1,338 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use bendy::{
+    decoding::{Error, FromBencode, Object, ResultExt},
+    encoding::{self, SingleItemEncoder, ToBencode},
+};
+
+// Peer exchange (BEP 11) over the extension protocol (BEP 10).
+// After the handshake both sides send an extended handshake listing the
+// extension messages they support and the id each one is sent under.
+// Every minute a connection sends the peers that connected since its last
+// message and those that disconnected, at most 50 of each. Peers received
+// over PEX are capped per message, and in total by the torrent.
+// Private torrents (BEP 27) only get peers from their tracker, they neither
+// advertise ut_pex nor read PEX messages.
+
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+// added and dropped peers per message, more are ignored
+pub const MAX_PEX_PEERS: usize = 50;
+// extended message id of the extended handshake
+pub const EXTENDED_HANDSHAKE: u8 = 0;
+// extended message id peers send ut_pex to us with
+pub const UT_PEX: u8 = 1;
+
+// flags of an added peer
+pub const PEX_ENCRYPTION: u8 = 0x01;
+pub const PEX_SEED: u8 = 0x02;
+pub const PEX_UTP: u8 = 0x04;
+pub const PEX_OUTGOING: u8 = 0x10;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExtendedHandshake {
+    // id of ut_pex at the sender, `None` if it doesn't support it
+    pub pex: Option<u8>,
+    // port the sender listens on
+    pub port: Option<u16>,
+    pub client: Option<String>,
+    // the sender prefers encrypted connections
+    pub encryption: bool,
+    // address of the receiver as seen by the sender
+    pub yourip: Option<IpAddr>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PexMessage {
+    pub added: Vec<(SocketAddr, u8)>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+/**
+ * Encode an address in compact form: 4 or 16 bytes of IP then the port.
+ */
+pub fn compact_addr(addr: &SocketAddr) -> Vec<u8> {
+    let mut bytes = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    bytes.extend_from_slice(&addr.port().to_be_bytes());
+    bytes
+}
+
+/**
+ * Decode a list of compact addresses, a trailing partial entry is ignored.
+ */
+pub fn compact_addrs(bytes: &[u8], ipv6: bool) -> Vec<SocketAddr> {
+    let size = if ipv6 { 18 } else { 6 };
+    bytes
+        .chunks_exact(size)
+        .map(|chunk| {
+            let port = u16::from_be_bytes([chunk[size - 2], chunk[size - 1]]);
+            let ip = if ipv6 {
+                let octets: [u8; 16] = chunk[..16].try_into().unwrap();
+                IpAddr::V6(Ipv6Addr::from(octets))
+            } else {
+                IpAddr::V4(Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]))
+            };
+            SocketAddr::new(ip, port)
+        })
+        .collect()
+}
+
+impl ExtendedHandshake {
+    /**
+     * Our extended handshake, ut_pex is left out for private torrents.
+     */
+    pub fn new(pex: bool, port: Option<u16>) -> Self {
+        ExtendedHandshake {
+            pex: pex.then_some(UT_PEX),
+            port,
+            client: Some(format!("rust-torrent-client {}", env!("CARGO_PKG_VERSION"))),
+            encryption: false,
+            yourip: None,
+        }
+    }
+}
+
+impl ToBencode for ExtendedHandshake {
+    const MAX_DEPTH: usize = 2;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), encoding::Error> {
+        encoder.emit_dict(|mut e| {
+            if self.encryption {
+                e.emit_pair(b"e", 1)?;
+            }
+            // every supported extension is listed, even when disabled
+            e.emit_pair_with(b"m", |e| {
+                e.emit_dict(|mut m| m.emit_pair(b"ut_pex", self.pex.unwrap_or(0)))
+            })?;
+            if let Some(port) = self.port {
+                e.emit_pair(b"p", port)?;
+            }
+            if let Some(client) = &self.client {
+                e.emit_pair(b"v", client)?;
+            }
+            if let Some(ip) = self.yourip {
+                let bytes = match ip {
+                    IpAddr::V4(ip) => ip.octets().to_vec(),
+                    IpAddr::V6(ip) => ip.octets().to_vec(),
+                };
+                e.emit_pair_with(b"yourip", |e| e.emit_bytes(&bytes))?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl FromBencode for ExtendedHandshake {
+    fn decode_bencode_object(object: Object) -> Result<Self, Error>
+    where
+        Self: Sized,
+    {
+        let mut handshake = ExtendedHandshake::default();
+        let mut dict = object.try_into_dictionary()?;
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"m", value) => {
+                    let mut m = value.try_into_dictionary().context("m")?;
+                    while let Some(pair) = m.next_pair()? {
+                        if let (b"ut_pex", id) = pair {
+                            // 0 means the extension is disabled
+                            let id = u8::decode_bencode_object(id).context("ut_pex")?;
+                            handshake.pex = (id != 0).then_some(id);
+                        }
+                    }
+                }
+                (b"p", value) => {
+                    let port = i64::decode_bencode_object(value).context("p")?;
+                    handshake.port = u16::try_from(port).ok().filter(|p| *p != 0);
+                }
+                (b"v", value) => {
+                    let client = value.try_into_bytes().context("v")?;
+                    handshake.client = Some(String::from_utf8_lossy(client).into_owned());
+                }
+                (b"e", value) => {
+                    handshake.encryption = i64::decode_bencode_object(value).context("e")? == 1;
+                }
+                (b"yourip", value) => {
+                    let ip = value.try_into_bytes().context("yourip")?;
+                    handshake.yourip = match ip.len() {
+                        4 => Some(IpAddr::from(<[u8; 4]>::try_from(ip).unwrap())),
+                        16 => Some(IpAddr::from(<[u8; 16]>::try_from(ip).unwrap())),
+                        _ => None,
+                    };
+                }
+                // other extensions aren't supported
+                _ => {}
+            }
+        }
+        Ok(handshake)
+    }
+}
+
+impl ToBencode for PexMessage {
+    const MAX_DEPTH: usize = 1;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), encoding::Error> {
+        let (added, added6): (Vec<_>, Vec<_>) = self.added.iter().partition(|(a, _)| a.is_ipv4());
+        let (dropped, dropped6): (Vec<_>, Vec<_>) = self.dropped.iter().partition(|a| a.is_ipv4());
+        let addrs = |peers: &[&(SocketAddr, u8)]| -> Vec<u8> {
+            peers.iter().flat_map(|(a, _)| compact_addr(a)).collect()
+        };
+        let flags =
+            |peers: &[&(SocketAddr, u8)]| -> Vec<u8> { peers.iter().map(|(_, f)| *f).collect() };
+        let dropped_addrs = |peers: &[&SocketAddr]| -> Vec<u8> {
+            peers.iter().flat_map(|a| compact_addr(a)).collect()
+        };
+        encoder.emit_dict(|mut e| {
+            e.emit_pair_with(b"added", |e| e.emit_bytes(&addrs(&added)))?;
+            e.emit_pair_with(b"added.f", |e| e.emit_bytes(&flags(&added)))?;
+            e.emit_pair_with(b"added6", |e| e.emit_bytes(&addrs(&added6)))?;
+            e.emit_pair_with(b"added6.f", |e| e.emit_bytes(&flags(&added6)))?;
+            e.emit_pair_with(b"dropped", |e| e.emit_bytes(&dropped_addrs(&dropped)))?;
+            e.emit_pair_with(b"dropped6", |e| e.emit_bytes(&dropped_addrs(&dropped6)))
+        })
+    }
+}
+
+impl FromBencode for PexMessage {
+    fn decode_bencode_object(object: Object) -> Result<Self, Error>
+    where
+        Self: Sized,
+    {
+        let mut added = vec![];
+        let mut added_flags = vec![];
+        let mut added6 = vec![];
+        let mut added6_flags = vec![];
+        let mut dropped = vec![];
+        let mut dict = object.try_into_dictionary()?;
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"added", value) => added = compact_addrs(value.try_into_bytes()?, false),
+                (b"added.f", value) => added_flags = value.try_into_bytes()?.to_vec(),
+                (b"added6", value) => added6 = compact_addrs(value.try_into_bytes()?, true),
+                (b"added6.f", value) => added6_flags = value.try_into_bytes()?.to_vec(),
+                (b"dropped", value) => {
+                    dropped.extend(compact_addrs(value.try_into_bytes()?, false))
+                }
+                (b"dropped6", value) => {
+                    dropped.extend(compact_addrs(value.try_into_bytes()?, true))
+                }
+                _ => {}
+            }
+        }
+        // flags are optional
+        let with_flags = |addrs: Vec<SocketAddr>, flags: &[u8]| {
+            addrs
+                .into_iter()
+                .enumerate()
+                .map(|(i, a)| (a, flags.get(i).copied().unwrap_or(0)))
+                .collect::<Vec<_>>()
+        };
+        let mut added = with_flags(added, &added_flags);
+        added.extend(with_flags(added6, &added6_flags));
+        Ok(PexMessage { added, dropped })
+    }
+}
+
+/**
+ * Peers last sent to one connection, to send only the changes.
+ */
+#[derive(Debug, Default)]
+pub struct PexState {
+    sent: HashMap<SocketAddr, u8>,
+}
+
+impl PexState {
+    /**
+     * Changes between the peers last sent and `connected`, `None` if there
+     * are none. Changes over the per message limit are sent next time.
+     */
+    pub fn update(&mut self, connected: &HashMap<SocketAddr, u8>) -> Option<PexMessage> {
+        let added: Vec<(SocketAddr, u8)> = connected
+            .iter()
+            .filter(|(a, f)| self.sent.get(a) != Some(f))
+            .take(MAX_PEX_PEERS)
+            .map(|(a, f)| (*a, *f))
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .sent
+            .keys()
+            .filter(|a| !connected.contains_key(a))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+        for (addr, flags) in &added {
+            self.sent.insert(*addr, *flags);
+        }
+        for addr in &dropped {
+            self.sent.remove(addr);
+        }
+        Some(PexMessage { added, dropped })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pex_messages() {
+        let handshake = ExtendedHandshake::new(true, Some(6881));
+        let bytes = handshake.to_bencode().unwrap();
+        assert!(bytes.starts_with(b"d1:md6:ut_pexi1ee1:pi6881e1:v"));
+        assert_eq!(ExtendedHandshake::from_bencode(&bytes).unwrap(), handshake);
+        // unknown extensions are skipped, ut_pex 0 means disabled
+        let other =
+            ExtendedHandshake::from_bencode(b"d1:md11:ut_metadatai2e6:ut_pexi0ee4:reqqi250ee")
+                .unwrap();
+        assert_eq!(other.pex, None);
+        let private = ExtendedHandshake::new(false, None).to_bencode().unwrap();
+        assert!(private.starts_with(b"d1:md6:ut_pexi0ee1:v"));
+        let mut handshake = ExtendedHandshake::new(true, None);
+        handshake.yourip = Some("10.0.0.1".parse().unwrap());
+        let bytes = handshake.to_bencode().unwrap();
+        assert!(bytes.ends_with(b"6:yourip4:\x0a\x00\x00\x01e"));
+        assert_eq!(ExtendedHandshake::from_bencode(&bytes).unwrap(), handshake);
+
+        let v4: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:51413".parse().unwrap();
+        let mut connected = HashMap::from([(v4, PEX_SEED | PEX_OUTGOING), (v6, 0)]);
+        let mut state = PexState::default();
+        let message = state.update(&connected).unwrap();
+        let bytes = message.to_bencode().unwrap();
+        assert!(bytes.starts_with(b"d5:added6:\x0a\x00\x00\x01\x1a\xe17:added.f1:\x12"));
+        let decoded = PexMessage::from_bencode(&bytes).unwrap();
+        assert_eq!(decoded.added, vec![(v4, 0x12), (v6, 0)]);
+        assert!(decoded.dropped.is_empty());
+
+        // nothing changed, then one peer dropped
+        assert_eq!(state.update(&connected), None);
+        connected.remove(&v6);
+        let message = state.update(&connected).unwrap();
+        assert_eq!(message.dropped, vec![v6]);
+        assert!(message.added.is_empty());
+        let decoded = PexMessage::from_bencode(&message.to_bencode().unwrap()).unwrap();
+        assert_eq!(decoded.dropped, vec![v6]);
+
+        // capped per message
+        let many: HashMap<SocketAddr, u8> = (0..120)
+            .map(|i| {
+                (
+                    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 1, 0, i)), 6881),
+                    0,
+                )
+            })
+            .collect();
+        assert_eq!(state.update(&many).unwrap().added.len(), MAX_PEX_PEERS);
+        assert_eq!(state.update(&many).unwrap().added.len(), MAX_PEX_PEERS);
+        assert_eq!(state.update(&many).unwrap().added.len(), 20);
+    }
+}
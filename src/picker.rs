@@ -0,0 +1,273 @@
+use std::{cmp::Reverse, collections::HashMap, time::Instant};
+
+// Decides which piece a peer should download next.
+// Pieces with a deadline come first, earliest deadline first. In sequential
+// mode the pieces within the readahead window after the cursor come next, in
+// order. The rest are ordered by priority, then rarest-first using the number
+// of connected peers that advertise the piece, then by index.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, clap::ValueEnum)]
+pub enum Priority {
+    // never downloaded
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sequential {
+    // first piece the reader still needs
+    cursor: usize,
+    // number of pieces after the cursor fetched in order
+    readahead: usize,
+}
+
+pub struct PiecePicker {
+    priorities: Vec<Priority>,
+    // number of stream readers waiting on each piece, a boosted piece has a
+    // high priority whatever its own
+    boosts: Vec<u32>,
+    // number of peers that have each piece
+    availability: Vec<u32>,
+    deadlines: HashMap<usize, Instant>,
+    sequential: Option<Sequential>,
+}
+
+impl PiecePicker {
+    pub fn new(piece_count: usize) -> Self {
+        PiecePicker {
+            priorities: vec![Priority::Normal; piece_count],
+            boosts: vec![0; piece_count],
+            availability: vec![0; piece_count],
+            deadlines: HashMap::new(),
+            sequential: None,
+        }
+    }
+
+    /**
+     * Enable sequential mode with a window of `readahead` pieces, or disable it with `None`.
+     */
+    pub fn set_sequential(&mut self, readahead: Option<usize>) {
+        let cursor = self.sequential.map_or(0, |s| s.cursor);
+        self.sequential = readahead.map(|readahead| Sequential { cursor, readahead });
+    }
+
+    pub fn cursor(&self) -> Option<usize> {
+        self.sequential.map(|s| s.cursor)
+    }
+
+    /**
+     * Move the cursor, e.g. when the reader seeks, then skip over pieces it already has.
+     */
+    pub fn set_cursor<F>(&mut self, index: usize, have: F)
+    where
+        F: Fn(usize) -> bool,
+    {
+        if let Some(s) = self.sequential.as_mut() {
+            s.cursor = index.min(self.priorities.len());
+        }
+        self.advance_cursor(have);
+    }
+
+    pub fn advance_cursor<F>(&mut self, have: F)
+    where
+        F: Fn(usize) -> bool,
+    {
+        let len = self.priorities.len();
+        if let Some(s) = self.sequential.as_mut() {
+            while s.cursor < len && have(s.cursor) {
+                s.cursor += 1;
+            }
+        }
+    }
+
+    fn in_window(&self, index: usize) -> bool {
+        match self.sequential {
+            Some(s) => index >= s.cursor && index < s.cursor + s.readahead,
+            None => false,
+        }
+    }
+
+    pub fn priority(&self, index: usize) -> Priority {
+        if self.boosts.get(index).is_some_and(|b| *b > 0) {
+            return Priority::High;
+        }
+        self.priorities
+            .get(index)
+            .copied()
+            .unwrap_or(Priority::Skip)
+    }
+
+    pub fn set_priority(&mut self, index: usize, priority: Priority) {
+        if let Some(p) = self.priorities.get_mut(index) {
+            *p = priority;
+        }
+    }
+
+    /**
+     * Raise a piece to a high priority until every boost is undone by `unboost`.
+     */
+    pub fn boost(&mut self, index: usize) {
+        if let Some(b) = self.boosts.get_mut(index) {
+            *b += 1;
+        }
+    }
+
+    /**
+     * Undo a `boost`, returns true once the piece is no longer boosted.
+     */
+    pub fn unboost(&mut self, index: usize) -> bool {
+        match self.boosts.get_mut(index) {
+            Some(b) => {
+                *b = b.saturating_sub(1);
+                *b == 0
+            }
+            None => false,
+        }
+    }
+
+    /**
+     * A piece with a deadline is wanted regardless of its priority.
+     */
+    pub fn is_wanted(&self, index: usize) -> bool {
+        self.priority(index) != Priority::Skip || self.deadlines.contains_key(&index)
+    }
+
+    pub fn set_deadline(&mut self, index: usize, deadline: Instant) {
+        if index < self.priorities.len() {
+            self.deadlines.insert(index, deadline);
+        }
+    }
+
+    pub fn clear_deadline(&mut self, index: usize) -> Option<Instant> {
+        self.deadlines.remove(&index)
+    }
+
+    pub fn has_deadline(&self, index: usize) -> bool {
+        self.deadlines.contains_key(&index)
+    }
+
+    pub fn deadlines(&self) -> impl Iterator<Item = (usize, Instant)> + '_ {
+        self.deadlines.iter().map(|(i, d)| (*i, *d))
+    }
+
+    pub fn availability(&self, index: usize) -> u32 {
+        self.availability.get(index).copied().unwrap_or(0)
+    }
+
+    pub fn add_availability(&mut self, index: usize) {
+        if let Some(a) = self.availability.get_mut(index) {
+            *a += 1;
+        }
+    }
+
+    pub fn remove_availability(&mut self, index: usize) {
+        if let Some(a) = self.availability.get_mut(index) {
+            *a = a.saturating_sub(1);
+        }
+    }
+
+    /**
+     * Best wanted piece for which `candidate` returns true.
+     */
+    pub fn pick<F>(&self, candidate: F) -> Option<usize>
+    where
+        F: Fn(usize) -> bool,
+    {
+        // `rev` so that the lowest index wins ties
+        (0..self.priorities.len())
+            .rev()
+            .filter(|i| self.is_wanted(*i) && candidate(*i))
+            .max_by_key(|i| {
+                (
+                    self.deadlines.get(i).map(|d| Reverse(*d)),
+                    self.in_window(*i).then_some(Reverse(*i)),
+                    self.priority(*i),
+                    Reverse(self.availability[*i]),
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pick_priority_then_rarest() {
+        let mut picker = PiecePicker::new(5);
+        for (index, count) in [(0, 3), (1, 1), (2, 2), (3, 1), (4, 5)] {
+            for _ in 0..count {
+                picker.add_availability(index);
+            }
+        }
+        assert_eq!(picker.pick(|_| true), Some(1));
+        assert_eq!(picker.pick(|i| i != 1), Some(3));
+
+        picker.set_priority(4, Priority::High);
+        assert_eq!(picker.pick(|_| true), Some(4));
+
+        picker.set_priority(4, Priority::Skip);
+        picker.set_priority(1, Priority::Low);
+        picker.set_priority(3, Priority::Low);
+        assert_eq!(picker.pick(|_| true), Some(2));
+        assert_eq!(picker.pick(|i| i == 4), None);
+    }
+
+    #[test]
+    fn pick_deadline_first() {
+        let mut picker = PiecePicker::new(4);
+        let now = Instant::now();
+        picker.set_priority(0, Priority::High);
+        picker.set_priority(3, Priority::Skip);
+        picker.set_deadline(2, now + std::time::Duration::from_secs(5));
+        picker.set_deadline(3, now + std::time::Duration::from_secs(1));
+        assert_eq!(picker.pick(|_| true), Some(3));
+        assert_eq!(picker.pick(|i| i != 3), Some(2));
+        assert!(picker.clear_deadline(3).is_some());
+        assert_eq!(picker.pick(|i| i != 2), Some(0));
+    }
+
+    #[test]
+    fn boosts_are_counted() {
+        let mut picker = PiecePicker::new(2);
+        picker.set_priority(1, Priority::Skip);
+        picker.boost(1);
+        picker.boost(1);
+        assert_eq!(picker.priority(1), Priority::High);
+        assert_eq!(picker.pick(|_| true), Some(1));
+        assert!(!picker.unboost(1));
+        assert_eq!(picker.priority(1), Priority::High);
+        assert!(picker.unboost(1));
+        // back to its own priority, never downloaded
+        assert_eq!(picker.priority(1), Priority::Skip);
+        assert!(!picker.is_wanted(1));
+    }
+
+    #[test]
+    fn pick_sequential_window() {
+        let mut picker = PiecePicker::new(8);
+        for index in [0, 1, 2, 3, 4, 6] {
+            picker.add_availability(index);
+        }
+        picker.set_sequential(Some(2));
+        assert_eq!(picker.cursor(), Some(0));
+        assert_eq!(picker.pick(|_| true), Some(0));
+        assert_eq!(picker.pick(|i| i != 0), Some(1));
+        // window is taken, the rest goes rarest-first
+        assert_eq!(picker.pick(|i| i > 1), Some(5));
+
+        picker.advance_cursor(|i| i < 3);
+        assert_eq!(picker.cursor(), Some(3));
+        assert_eq!(picker.pick(|_| true), Some(3));
+
+        picker.set_cursor(6, |i| i == 6);
+        assert_eq!(picker.cursor(), Some(7));
+        assert_eq!(picker.pick(|_| true), Some(7));
+
+        picker.set_sequential(None);
+        assert_eq!(picker.pick(|_| true), Some(5));
+    }
+}
@@ -0,0 +1,851 @@
+use std::{
+    error::Error,
+    fmt, fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Mutex,
+    time::Duration,
+};
+
+use rand::Rng;
+use reqwest::Client;
+use tokio::{net::UdpSocket, time::Instant};
+use url::Url;
+
+// Port mapping: routers drop incoming connections unless a mapping
+// forwards the port to us, so the peer port is mapped for TCP (peers) and
+// UDP (uTP and the DHT) through whichever protocol the gateway speaks:
+//
+// - PCP (RFC 6887), tried first: MAP requests to port 5351 of the default
+//   gateway, carrying our address, a random nonce, the internal port and
+//   the external port we'd like. An ANNOUNCE request probes for support;
+//   gateways only speaking NAT-PMP answer it with version 0 and result 1
+//   (unsupported version).
+// - NAT-PMP (RFC 6886): the same exchange with 12 byte requests, opcode 1
+//   for UDP and 2 for TCP, opcode 0 asking for the external address.
+// - UPnP IGD: an SSDP M-SEARCH multicast to 239.255.255.250:1900 answered
+//   with the URL of the gateway's description, whose WANIPConnection (or
+//   WANPPPConnection) service takes SOAP AddPortMapping and
+//   DeletePortMapping requests.
+//
+// PCP and NAT-PMP requests are resent after 250ms, doubling, 3 times; RFC
+// 6886 says 9 but UPnP is worth trying sooner. Mappings are asked for 2
+// hours and renewed half way through the lifetime granted, asking for the
+// same external port. A lifetime of 0 removes them on shutdown. The gateway
+// may pick another external port, that's the port reported to trackers.
+
+pub const NAT_PMP_PORT: u16 = 5351;
+pub const SSDP_ADDR: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250)), 1900);
+const PCP_VERSION: u8 = 2;
+const NAT_PMP_VERSION: u8 = 0;
+const PCP_ANNOUNCE: u8 = 0;
+const PCP_MAP: u8 = 1;
+const NAT_PMP_EXTERNAL_ADDRESS: u8 = 0;
+// set in the opcode of responses
+const RESPONSE: u8 = 0x80;
+const UNSUPPORTED_VERSION: u16 = 1;
+const NONCE_LENGTH: usize = 12;
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+const RETRIES: u32 = 3;
+const SSDP_TIMEOUT: Duration = Duration::from_secs(2);
+const GATEWAY_DEVICE: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+const WAN_SERVICES: [&str; 2] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:",
+    "urn:schemas-upnp-org:service:WANPPPConnection:",
+];
+// UPnP error of gateways refusing leases that expire
+const ONLY_PERMANENT_LEASES: &str = "725";
+const LEASE: Duration = Duration::from_secs(2 * 60 * 60);
+const MIN_RENEWAL: Duration = Duration::from_secs(60);
+const DESCRIPTION: &str = "torrent-client";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    fn nat_pmp_opcode(self) -> u8 {
+        match self {
+            Protocol::Udp => 1,
+            Protocol::Tcp => 2,
+        }
+    }
+
+    // IANA protocol number, used by PCP
+    fn number(self) -> u8 {
+        match self {
+            Protocol::Tcp => 6,
+            Protocol::Udp => 17,
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "TCP"),
+            Protocol::Udp => write!(f, "UDP"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mapping {
+    pub protocol: Protocol,
+    pub internal_port: u16,
+    pub external_port: u16,
+    // how long the gateway keeps it unless renewed
+    pub lifetime: Duration,
+}
+
+enum Gateway {
+    Pcp {
+        addr: SocketAddr,
+        // identifies our mappings to renew or remove them
+        nonce: [u8; NONCE_LENGTH],
+    },
+    NatPmp {
+        addr: SocketAddr,
+    },
+    Upnp {
+        control_url: Url,
+        service: String,
+    },
+}
+
+/**
+ * The IPv4 default gateway from the kernel routing table, on Linux.
+ */
+pub fn default_gateway() -> Option<Ipv4Addr> {
+    let routes = fs::read_to_string("/proc/net/route").ok()?;
+    // Iface Destination Gateway Flags ..., addresses in little endian hex
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let flags = u16::from_str_radix(fields.get(3)?, 16).ok()?;
+        // RTF_UP | RTF_GATEWAY
+        if *fields.get(1)? != "00000000" || flags & 0x3 != 0x3 {
+            return None;
+        }
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+        Some(Ipv4Addr::from(gateway.swap_bytes()))
+    })
+}
+
+/**
+ * The address of ours `remote` sees, that mappings forward to.
+ */
+fn local_ip(remote: SocketAddr) -> io::Result<Ipv4Addr> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(remote)?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(ip) => Ok(ip),
+        IpAddr::V6(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "port mapping needs an IPv4 gateway",
+        )),
+    }
+}
+
+fn pcp_header(opcode: u8, lifetime: u32, client: Ipv4Addr) -> Vec<u8> {
+    let mut request = vec![PCP_VERSION, opcode, 0, 0];
+    request.extend(lifetime.to_be_bytes());
+    request.extend(client.to_ipv6_mapped().octets());
+    request
+}
+
+fn pcp_map(
+    mapping: &Mapping,
+    lifetime: u32,
+    client: Ipv4Addr,
+    nonce: &[u8; NONCE_LENGTH],
+) -> Vec<u8> {
+    let mut request = pcp_header(PCP_MAP, lifetime, client);
+    request.extend(nonce);
+    request.extend([mapping.protocol.number(), 0, 0, 0]);
+    request.extend(mapping.internal_port.to_be_bytes());
+    request.extend(mapping.external_port.to_be_bytes());
+    // no preference for the external address
+    request.extend(Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+    request
+}
+
+/**
+ * The mapping granted by a PCP MAP response.
+ */
+fn parse_pcp_map(
+    reply: &[u8],
+    protocol: Protocol,
+    nonce: &[u8; NONCE_LENGTH],
+) -> Result<Mapping, Box<dyn Error>> {
+    if reply.len() < 60 || reply[0] != PCP_VERSION || reply[1] != RESPONSE | PCP_MAP {
+        return Err("invalid PCP response".into());
+    }
+    if reply[3] != 0 {
+        return Err(format!("PCP error {}", reply[3]).into());
+    }
+    if reply[24..36] != nonce[..] || reply[36] != protocol.number() {
+        return Err("PCP response to another request".into());
+    }
+    let lifetime = u32::from_be_bytes(reply[4..8].try_into()?);
+    Ok(Mapping {
+        protocol,
+        internal_port: u16::from_be_bytes([reply[40], reply[41]]),
+        external_port: u16::from_be_bytes([reply[42], reply[43]]),
+        lifetime: Duration::from_secs(lifetime as u64),
+    })
+}
+
+fn nat_pmp_map(mapping: &Mapping, lifetime: u32) -> Vec<u8> {
+    let mut request = vec![NAT_PMP_VERSION, mapping.protocol.nat_pmp_opcode(), 0, 0];
+    request.extend(mapping.internal_port.to_be_bytes());
+    request.extend(mapping.external_port.to_be_bytes());
+    request.extend(lifetime.to_be_bytes());
+    request
+}
+
+/**
+ * The result code of a NAT-PMP response, an error unless 0.
+ */
+fn nat_pmp_result(reply: &[u8], opcode: u8, length: usize) -> Result<(), Box<dyn Error>> {
+    if reply.len() < length || reply[0] != NAT_PMP_VERSION || reply[1] != RESPONSE | opcode {
+        return Err("invalid NAT-PMP response".into());
+    }
+    match u16::from_be_bytes([reply[2], reply[3]]) {
+        0 => Ok(()),
+        code => Err(format!("NAT-PMP error {}", code).into()),
+    }
+}
+
+fn parse_nat_pmp_map(reply: &[u8], protocol: Protocol) -> Result<Mapping, Box<dyn Error>> {
+    nat_pmp_result(reply, protocol.nat_pmp_opcode(), 16)?;
+    let lifetime = u32::from_be_bytes(reply[12..16].try_into()?);
+    Ok(Mapping {
+        protocol,
+        internal_port: u16::from_be_bytes([reply[8], reply[9]]),
+        external_port: u16::from_be_bytes([reply[10], reply[11]]),
+        lifetime: Duration::from_secs(lifetime as u64),
+    })
+}
+
+/**
+ * Send `request` to the PCP or NAT-PMP server at `gateway` until it
+ * answers, and the answer.
+ */
+async fn exchange(gateway: SocketAddr, request: &[u8]) -> io::Result<Vec<u8>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    // only the gateway's packets are received
+    socket.connect(gateway).await?;
+    let mut buf = [0; 1100];
+    let mut timeout = INITIAL_TIMEOUT;
+    for _ in 0..RETRIES {
+        socket.send(request).await?;
+        let deadline = Instant::now() + timeout;
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+            let len = match received {
+                Ok(len) => len,
+                // an ICMP port unreachable, nothing listens
+                Err(e) => return Err(e),
+            };
+            // a response to this opcode, or a NAT-PMP server refusing PCP
+            let reply = &buf[..len];
+            if reply.len() >= 4 && (reply[1] == RESPONSE | request[1] || reply[0] != request[0]) {
+                return Ok(reply.to_vec());
+            }
+        }
+        timeout *= 2;
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("no answer from {}", gateway),
+    ))
+}
+
+/**
+ * The value of the first `<name>` element of `xml`.
+ */
+fn tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", name))?;
+    Some(xml[start..end].trim())
+}
+
+/**
+ * The type and control URL of the WAN connection service of a gateway's
+ * description.
+ */
+fn wan_service(description: &str) -> Option<(String, String)> {
+    description.split("<service>").skip(1).find_map(|service| {
+        let kind = tag(service, "serviceType")?;
+        if !WAN_SERVICES.iter().any(|s| kind.starts_with(s)) {
+            return None;
+        }
+        Some((kind.to_string(), tag(service, "controlURL")?.to_string()))
+    })
+}
+
+/**
+ * Multicast an SSDP search for gateways to `ssdp` and find the control URL
+ * of the first one with a WAN connection service.
+ */
+async fn find_upnp_gateway(ssdp: SocketAddr) -> Result<(Url, String), Box<dyn Error>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 1\r\nST: {}\r\n\r\n",
+        ssdp, GATEWAY_DEVICE
+    );
+    // sent twice, it's UDP
+    for _ in 0..2 {
+        socket.send_to(search.as_bytes(), ssdp).await?;
+    }
+    let deadline = Instant::now() + SSDP_TIMEOUT;
+    let mut buf = [0; 2048];
+    let mut searched = vec![];
+    while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, _) = received?;
+        let response = String::from_utf8_lossy(&buf[..len]);
+        let location = response.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim()
+                .eq_ignore_ascii_case("location")
+                .then(|| value.trim().to_string())
+        });
+        let location = match location {
+            Some(l) if !searched.contains(&l) => l,
+            _ => continue,
+        };
+        searched.push(location.clone());
+        let description = match reqwest::get(&location).await {
+            Ok(response) => response.text().await.unwrap_or_default(),
+            Err(_) => continue,
+        };
+        if let Some((service, control_url)) = wan_service(&description) {
+            let control_url = Url::parse(&location)?.join(&control_url)?;
+            return Ok((control_url, service));
+        }
+    }
+    Err("no UPnP gateway found".into())
+}
+
+/**
+ * Call `action` of the WAN connection service at `control_url`, and the
+ * response envelope.
+ */
+async fn soap(
+    control_url: &Url,
+    service: &str,
+    action: &str,
+    args: &[(&str, String)],
+) -> Result<String, Box<dyn Error>> {
+    let args: String = args
+        .iter()
+        .map(|(name, value)| format!("<{0}>{1}</{0}>", name, value))
+        .collect();
+    let body = format!(
+        "<?xml version=\"1.0\"?>\r\n\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><u:{0} xmlns:u=\"{1}\">{2}</u:{0}></s:Body></s:Envelope>",
+        action, service, args
+    );
+    let response = Client::new()
+        .post(control_url.clone())
+        .header("Content-Type", "text/xml; charset=\"utf-8\"")
+        .header("SOAPAction", format!("\"{}#{}\"", service, action))
+        .body(body)
+        .send()
+        .await?;
+    let status = response.status();
+    let text = response.text().await?;
+    if !status.is_success() {
+        // faults carry the UPnP error code
+        return Err(match tag(&text, "errorCode") {
+            Some(code) => format!("UPnP error {}", code).into(),
+            None => format!("UPnP {} failed: {}", action, status).into(),
+        });
+    }
+    Ok(text)
+}
+
+pub struct PortMapper {
+    gateway: Gateway,
+    // the address mappings forward to
+    local_ip: Ipv4Addr,
+    external_ip: Mutex<Option<IpAddr>>,
+    mappings: Mutex<Vec<Mapping>>,
+}
+
+impl PortMapper {
+    /**
+     * Find out how to map ports: PCP or NAT-PMP at `gateway`, usually port
+     * 5351 of the default gateway, else UPnP by searching `ssdp`, usually
+     * `SSDP_ADDR`.
+     */
+    pub async fn discover(
+        gateway: Option<SocketAddr>,
+        ssdp: SocketAddr,
+    ) -> Result<PortMapper, Box<dyn Error>> {
+        if let Some(addr) = gateway {
+            let local_ip = local_ip(addr)?;
+            let probe = pcp_header(PCP_ANNOUNCE, 0, local_ip);
+            if let Ok(reply) = exchange(addr, &probe).await {
+                if reply[0] == PCP_VERSION && reply[3] == 0 {
+                    return Ok(PortMapper::new(
+                        Gateway::Pcp {
+                            addr,
+                            nonce: rand::thread_rng().gen(),
+                        },
+                        local_ip,
+                        None,
+                    ));
+                }
+                if reply[0] == NAT_PMP_VERSION
+                    && u16::from_be_bytes([reply[2], reply[3]]) == UNSUPPORTED_VERSION
+                {
+                    let request = [NAT_PMP_VERSION, NAT_PMP_EXTERNAL_ADDRESS];
+                    let reply = exchange(addr, &request).await?;
+                    nat_pmp_result(&reply, NAT_PMP_EXTERNAL_ADDRESS, 12)?;
+                    let external_ip = Ipv4Addr::new(reply[8], reply[9], reply[10], reply[11]);
+                    return Ok(PortMapper::new(
+                        Gateway::NatPmp { addr },
+                        local_ip,
+                        Some(external_ip.into()),
+                    ));
+                }
+            }
+        }
+        let (control_url, service) = find_upnp_gateway(ssdp).await?;
+        let host = control_url.socket_addrs(|| None)?;
+        let local_ip = local_ip(*host.first().ok_or("the UPnP gateway has no address")?)?;
+        let response = soap(&control_url, &service, "GetExternalIPAddress", &[]).await?;
+        let external_ip = tag(&response, "NewExternalIPAddress").and_then(|ip| ip.parse().ok());
+        Ok(PortMapper::new(
+            Gateway::Upnp {
+                control_url,
+                service,
+            },
+            local_ip,
+            external_ip,
+        ))
+    }
+
+    fn new(gateway: Gateway, local_ip: Ipv4Addr, external_ip: Option<IpAddr>) -> Self {
+        PortMapper {
+            gateway,
+            local_ip,
+            external_ip: Mutex::new(external_ip),
+            mappings: Mutex::new(vec![]),
+        }
+    }
+
+    /**
+     * The protocol mappings are made with.
+     */
+    pub fn method(&self) -> &'static str {
+        match self.gateway {
+            Gateway::Pcp { .. } => "PCP",
+            Gateway::NatPmp { .. } => "NAT-PMP",
+            Gateway::Upnp { .. } => "UPnP",
+        }
+    }
+
+    /**
+     * Our address on the internet as the gateway reported it, PCP only
+     * tells once a port is mapped.
+     */
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        *self
+            .external_ip
+            .lock()
+            .expect("Error unable to lock mutex!")
+    }
+
+    /**
+     * The external port forwarded to `internal_port`, if mapped.
+     */
+    pub fn external_port(&self, protocol: Protocol, internal_port: u16) -> Option<u16> {
+        let mappings = self.mappings.lock().expect("Error unable to lock mutex!");
+        mappings
+            .iter()
+            .find(|m| m.protocol == protocol && m.internal_port == internal_port)
+            .map(|m| m.external_port)
+    }
+
+    /**
+     * Map `internal_port` on the gateway, or renew its mapping, asking for
+     * the same external port.
+     */
+    pub async fn map(
+        &self,
+        protocol: Protocol,
+        internal_port: u16,
+    ) -> Result<Mapping, Box<dyn Error>> {
+        let requested = Mapping {
+            protocol,
+            internal_port,
+            external_port: self
+                .external_port(protocol, internal_port)
+                .unwrap_or(internal_port),
+            lifetime: LEASE,
+        };
+        let mapping = self.request(&requested).await?;
+        let mut mappings = self.mappings.lock().expect("Error unable to lock mutex!");
+        mappings.retain(|m| !(m.protocol == protocol && m.internal_port == internal_port));
+        mappings.push(mapping.clone());
+        Ok(mapping)
+    }
+
+    /**
+     * Ask the gateway for `mapping`, a lifetime of 0 removes it.
+     */
+    async fn request(&self, mapping: &Mapping) -> Result<Mapping, Box<dyn Error>> {
+        let lifetime = mapping.lifetime.as_secs() as u32;
+        match &self.gateway {
+            Gateway::Pcp { addr, nonce } => {
+                let request = pcp_map(mapping, lifetime, self.local_ip, nonce);
+                let reply = exchange(*addr, &request).await?;
+                let granted = parse_pcp_map(&reply, mapping.protocol, nonce)?;
+                let external_ip = Ipv6Addr::from(<[u8; 16]>::try_from(&reply[44..60])?);
+                if let Some(ip) = external_ip
+                    .to_ipv4_mapped()
+                    .filter(|ip| !ip.is_unspecified())
+                {
+                    *self
+                        .external_ip
+                        .lock()
+                        .expect("Error unable to lock mutex!") = Some(ip.into());
+                }
+                Ok(granted)
+            }
+            Gateway::NatPmp { addr } => {
+                let request = nat_pmp_map(mapping, lifetime);
+                let reply = exchange(*addr, &request).await?;
+                parse_nat_pmp_map(&reply, mapping.protocol)
+            }
+            Gateway::Upnp {
+                control_url,
+                service,
+            } if lifetime == 0 => {
+                let args = [
+                    ("NewRemoteHost", String::new()),
+                    ("NewExternalPort", mapping.external_port.to_string()),
+                    ("NewProtocol", mapping.protocol.to_string()),
+                ];
+                soap(control_url, service, "DeletePortMapping", &args).await?;
+                Ok(mapping.clone())
+            }
+            Gateway::Upnp {
+                control_url,
+                service,
+            } => {
+                let mut args = vec![
+                    ("NewRemoteHost", String::new()),
+                    ("NewExternalPort", mapping.external_port.to_string()),
+                    ("NewProtocol", mapping.protocol.to_string()),
+                    ("NewInternalPort", mapping.internal_port.to_string()),
+                    ("NewInternalClient", self.local_ip.to_string()),
+                    ("NewEnabled", String::from("1")),
+                    ("NewPortMappingDescription", String::from(DESCRIPTION)),
+                    ("NewLeaseDuration", lifetime.to_string()),
+                ];
+                let permanent_only = match soap(control_url, service, "AddPortMapping", &args).await
+                {
+                    Ok(_) => false,
+                    Err(e) if e.to_string().ends_with(ONLY_PERMANENT_LEASES) => true,
+                    Err(e) => return Err(e),
+                };
+                if permanent_only {
+                    // renewing a permanent mapping does no harm
+                    args[7].1 = String::from("0");
+                    soap(control_url, service, "AddPortMapping", &args).await?;
+                }
+                Ok(mapping.clone())
+            }
+        }
+    }
+
+    /**
+     * Renew the mappings half way through their lifetime, forever.
+     */
+    pub async fn renew(&self) {
+        loop {
+            let lifetime = {
+                let mappings = self.mappings.lock().expect("Error unable to lock mutex!");
+                mappings.iter().map(|m| m.lifetime).min().unwrap_or(LEASE)
+            };
+            tokio::time::sleep((lifetime / 2).max(MIN_RENEWAL)).await;
+            let mappings = self
+                .mappings
+                .lock()
+                .expect("Error unable to lock mutex!")
+                .clone();
+            for old in mappings {
+                match self.map(old.protocol, old.internal_port).await {
+                    Ok(new) if new.external_port != old.external_port => println!(
+                        "{} port {} now mapped to external port {}",
+                        old.protocol, old.internal_port, new.external_port
+                    ),
+                    Ok(_) => {}
+                    Err(e) => println!(
+                        "could not renew the mapping of {} port {}: {}",
+                        old.protocol, old.internal_port, e
+                    ),
+                }
+            }
+        }
+    }
+
+    /**
+     * Remove all mappings from the gateway.
+     */
+    pub async fn unmap_all(&self) {
+        let mappings: Vec<Mapping> = self
+            .mappings
+            .lock()
+            .expect("Error unable to lock mutex!")
+            .drain(..)
+            .collect();
+        for mapping in mappings {
+            let removal = Mapping {
+                lifetime: Duration::ZERO,
+                ..mapping.clone()
+            };
+            if let Err(e) = self.request(&removal).await {
+                println!(
+                    "could not remove the mapping of {} port {}: {}",
+                    mapping.protocol, mapping.internal_port, e
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::mpsc::{unbounded_channel, UnboundedSender},
+    };
+
+    const EXTERNAL_IP: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 7);
+
+    /**
+     * Stand-in PCP gateway, or NAT-PMP only, mapping internal ports to the
+     * port 1000 above and passing on the lifetimes requested.
+     */
+    async fn gateway(pcp: bool, requests: UnboundedSender<Vec<u8>>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 1100];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                let request = buf[..len].to_vec();
+                let mut reply = vec![request[0], RESPONSE | request[1]];
+                match (request[0], pcp) {
+                    (PCP_VERSION, true) => {
+                        reply.extend([0, 0]);
+                        reply.extend(&request[4..8]);
+                        reply.extend([0; 16]);
+                        if request[1] == PCP_MAP {
+                            let port = u16::from_be_bytes([request[40], request[41]]);
+                            reply.extend(&request[24..42]);
+                            reply.extend((port + 1000).to_be_bytes());
+                            reply.extend(EXTERNAL_IP.to_ipv6_mapped().octets());
+                        }
+                    }
+                    (PCP_VERSION, false) => reply = vec![0, RESPONSE | request[1], 0, 1],
+                    (_, _) if request[1] == NAT_PMP_EXTERNAL_ADDRESS => {
+                        reply.extend([0; 6]);
+                        reply.extend(EXTERNAL_IP.octets());
+                    }
+                    (_, _) => {
+                        let port = u16::from_be_bytes([request[4], request[5]]);
+                        reply.extend([0; 6]);
+                        reply.extend(&request[4..6]);
+                        reply.extend((port + 1000).to_be_bytes());
+                        reply.extend(&request[8..12]);
+                    }
+                }
+                requests.send(request).unwrap();
+                socket.send_to(&reply, from).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn pcp_and_nat_pmp() {
+        let unused = "127.0.0.1:9".parse().unwrap();
+        for pcp in [true, false] {
+            let (sender, mut requests) = unbounded_channel();
+            let addr = gateway(pcp, sender).await;
+            let mapper = PortMapper::discover(Some(addr), unused).await.unwrap();
+            assert_eq!(mapper.method(), if pcp { "PCP" } else { "NAT-PMP" });
+            assert_eq!(mapper.external_ip().is_some(), !pcp);
+
+            let mapping = mapper.map(Protocol::Tcp, 6881).await.unwrap();
+            assert_eq!(mapping.external_port, 7881);
+            assert_eq!(mapping.lifetime, LEASE);
+            mapper.map(Protocol::Udp, 6881).await.unwrap();
+            assert_eq!(mapper.external_port(Protocol::Udp, 6881), Some(7881));
+            assert_eq!(mapper.external_ip(), Some(EXTERNAL_IP.into()));
+            // renewals ask for the port granted
+            mapper.map(Protocol::Tcp, 6881).await.unwrap();
+            mapper.unmap_all().await;
+            assert_eq!(mapper.external_port(Protocol::Tcp, 6881), None);
+
+            let mut sent = vec![];
+            while let Ok(request) = requests.try_recv() {
+                sent.push(request);
+            }
+            // the probe, or the refused probe and the address request
+            let sent = &sent[if pcp { 1 } else { 2 }..];
+            assert_eq!(sent.len(), 5);
+            let (ports, lifetime) = if pcp { (42, 4) } else { (6, 8) };
+            let requested: Vec<(u16, u32)> = sent
+                .iter()
+                .map(|r| {
+                    let port = u16::from_be_bytes([r[ports], r[ports + 1]]);
+                    let lifetime =
+                        u32::from_be_bytes(r[lifetime..lifetime + 4].try_into().unwrap());
+                    (port, lifetime)
+                })
+                .collect();
+            assert_eq!(requested[0], (6881, 7200));
+            assert_eq!(requested[2], (7881, 7200));
+            assert_eq!(requested[3].1, 0);
+            assert_eq!(requested[4].1, 0);
+            if pcp {
+                // mappings are renewed and removed with the same nonce
+                assert!(sent.iter().all(|r| r[24..36] == sent[0][24..36]));
+            }
+        }
+    }
+
+    async fn read_request(stream: &mut TcpStream) -> (String, String) {
+        let mut head = vec![];
+        let mut byte = [0];
+        while !head.ends_with(b"\r\n\r\n") && stream.read(&mut byte).await.unwrap_or(0) == 1 {
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8_lossy(&head).to_string();
+        let length = head
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("content-length")
+                    .then(|| value.trim().parse().unwrap())
+            })
+            .unwrap_or(0);
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).await.unwrap();
+        (head, String::from_utf8(body).unwrap())
+    }
+
+    /**
+     * Stand-in UPnP gateway answering SSDP searches, serving its
+     * description and taking SOAP actions, the bodies of which it passes on.
+     */
+    async fn upnp_gateway(actions: UnboundedSender<String>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let http = listener.local_addr().unwrap();
+        let ssdp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ssdp_addr = ssdp.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 1500];
+            while let Ok((len, from)) = ssdp.recv_from(&mut buf).await {
+                let search = String::from_utf8_lossy(&buf[..len]).to_string();
+                assert!(search.starts_with("M-SEARCH * HTTP/1.1\r\n"));
+                assert!(search.contains(GATEWAY_DEVICE));
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nST: {}\r\nLOCATION: http://{}/rootDesc.xml\r\n\r\n",
+                    GATEWAY_DEVICE, http
+                );
+                ssdp.send_to(response.as_bytes(), from).await.unwrap();
+            }
+        });
+        tokio::spawn(async move {
+            let description = "<?xml version=\"1.0\"?><root><device><serviceList>\
+                <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+                <controlURL>/ctl/L3F</controlURL></service>\
+                <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+                <controlURL>/ctl/IPConn</controlURL></service>\
+                </serviceList></device></root>";
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let (head, body) = read_request(&mut stream).await;
+                let (status, body) = if head.starts_with("GET /rootDesc.xml ") {
+                    ("200 OK", description.to_string())
+                } else if head.starts_with("POST /ctl/IPConn ") {
+                    let reply = if body.contains("GetExternalIPAddress") {
+                        format!(
+                            "<NewExternalIPAddress>{}</NewExternalIPAddress>",
+                            EXTERNAL_IP
+                        )
+                    } else {
+                        String::new()
+                    };
+                    // this router only takes permanent mappings
+                    let refused = body.contains("<NewLeaseDuration>7200<");
+                    actions.send(body).unwrap();
+                    if refused {
+                        (
+                            "500 Internal Server Error",
+                            String::from("<errorCode>725</errorCode>"),
+                        )
+                    } else {
+                        (
+                            "200 OK",
+                            format!("<s:Envelope><s:Body>{}</s:Body></s:Envelope>", reply),
+                        )
+                    }
+                } else {
+                    ("404 Not Found", String::new())
+                };
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.ok();
+            }
+        });
+        ssdp_addr
+    }
+
+    #[tokio::test]
+    async fn upnp() {
+        let (sender, mut actions) = unbounded_channel();
+        let ssdp = upnp_gateway(sender).await;
+        let mapper = PortMapper::discover(None, ssdp).await.unwrap();
+        assert_eq!(mapper.method(), "UPnP");
+        assert_eq!(mapper.external_ip(), Some(EXTERNAL_IP.into()));
+        let mapping = mapper.map(Protocol::Udp, 6881).await.unwrap();
+        assert_eq!(mapping.external_port, 6881);
+        assert_eq!(mapper.external_port(Protocol::Udp, 6881), Some(6881));
+        mapper.unmap_all().await;
+
+        assert!(actions
+            .recv()
+            .await
+            .unwrap()
+            .contains("<u:GetExternalIPAddress "));
+        let refused = actions.recv().await.unwrap();
+        assert!(refused.contains("<u:AddPortMapping "));
+        let added = actions.recv().await.unwrap();
+        for arg in [
+            "<NewExternalPort>6881</NewExternalPort>",
+            "<NewProtocol>UDP</NewProtocol>",
+            "<NewInternalClient>127.0.0.1</NewInternalClient>",
+            "<NewLeaseDuration>0</NewLeaseDuration>",
+        ] {
+            assert!(added.contains(arg), "{} in {}", arg, added);
+        }
+        let deleted = actions.recv().await.unwrap();
+        assert!(deleted.contains("<u:DeletePortMapping "));
+        assert!(deleted.contains("<NewExternalPort>6881</NewExternalPort>"));
+    }
+}
@@ -0,0 +1,631 @@
+use std::{
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use percent_encoding::percent_decode_str;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+use url::Url;
+
+// Proxies, for networks only letting traffic out through one:
+//
+// - SOCKS5 (RFC 1928): a greeting lists the authentication methods we
+//   support, no authentication and, with credentials, username/password
+//   (RFC 1929). A CONNECT request then turns the connection into a tunnel to
+//   a host name or address. A UDP ASSOCIATE request gets the address of a
+//   relay instead, datagrams to and from it carry a header with the address
+//   of the other side:
+//
+//     RSV (2 bytes) | FRAG | ATYP | address | port | data
+//
+//   The association lasts as long as the TCP connection asking for it.
+// - HTTP: a `CONNECT host:port` request, with `Proxy-Authorization: Basic`
+//   given credentials, turns the connection into a tunnel on a 2xx answer.
+//   Only TCP gets through.
+//
+// Trackers and web seeds are fetched by reqwest, through its own support of
+// both proxies. Peers are connected to through the tunnels, and with SOCKS5
+// the DHT goes through the UDP relay. Nothing else goes through the proxy:
+// in proxy-only mode it's all turned off, along with incoming connections.
+
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const USERNAME_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const AUTHENTICATION_VERSION: u8 = 1;
+const CONNECT: u8 = 1;
+const UDP_ASSOCIATE: u8 = 3;
+const IPV4: u8 = 1;
+const DOMAIN_NAME: u8 = 3;
+const IPV6: u8 = 4;
+const MAX_RESPONSE_HEAD: usize = 8192;
+
+/// What goes through the proxy
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProxyMode {
+    /// Tracker announces only
+    Trackers,
+    /// Trackers, peers, web seeds, and the DHT through a SOCKS5 proxy
+    #[default]
+    All,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyKind {
+    Socks5,
+    Http,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Proxy {
+    pub kind: ProxyKind,
+    host: String,
+    port: u16,
+    credentials: Option<(String, String)>,
+}
+
+impl fmt::Display for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let scheme = match self.kind {
+            ProxyKind::Socks5 => "socks5",
+            ProxyKind::Http => "http",
+        };
+        // without the credentials
+        write!(f, "{}://{}", scheme, self.authority())
+    }
+}
+
+/**
+ * A reply error of a SOCKS5 proxy.
+ */
+fn socks_error(code: u8) -> io::Error {
+    let reason = match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    };
+    io::Error::other(format!("SOCKS5 proxy: {}", reason))
+}
+
+/**
+ * The address of a SOCKS5 request or datagram header, host names are
+ * resolved by the proxy.
+ */
+fn encode_address(host: &str, port: u16) -> io::Result<Vec<u8>> {
+    let mut bytes = match host.parse() {
+        Ok(IpAddr::V4(ip)) => [&[IPV4][..], &ip.octets()].concat(),
+        Ok(IpAddr::V6(ip)) => [&[IPV6][..], &ip.octets()].concat(),
+        Err(_) => {
+            let length =
+                u8::try_from(host.len()).map_err(|_| io::Error::other("host name too long"))?;
+            [&[DOMAIN_NAME, length][..], host.as_bytes()].concat()
+        }
+    };
+    bytes.extend(port.to_be_bytes());
+    Ok(bytes)
+}
+
+/**
+ * Read the address of a SOCKS5 reply, domain names aren't expected.
+ */
+async fn read_address<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<SocketAddr> {
+    let ip = match stream.read_u8().await? {
+        IPV4 => {
+            let mut octets = [0; 4];
+            stream.read_exact(&mut octets).await?;
+            IpAddr::from(octets)
+        }
+        IPV6 => {
+            let mut octets = [0; 16];
+            stream.read_exact(&mut octets).await?;
+            IpAddr::from(octets)
+        }
+        DOMAIN_NAME => {
+            // skipped, nothing can be done with it
+            let mut name = vec![0; stream.read_u8().await? as usize];
+            stream.read_exact(&mut name).await?;
+            IpAddr::from(Ipv4Addr::UNSPECIFIED)
+        }
+        _ => return Err(socks_error(8)),
+    };
+    Ok(SocketAddr::new(ip, stream.read_u16().await?))
+}
+
+impl Proxy {
+    /**
+     * Read `socks5://[user:password@]host[:port]` or
+     * `http://[user:password@]host[:port]`, the ports defaulting to 1080 and
+     * 80.
+     */
+    pub fn parse(url: &str) -> Result<Proxy, String> {
+        let url = Url::parse(url).map_err(|e| format!("invalid proxy URL: {}", e))?;
+        let (kind, default_port) = match url.scheme() {
+            "socks5" | "socks5h" => (ProxyKind::Socks5, 1080),
+            "http" => (ProxyKind::Http, 80),
+            scheme => return Err(format!("unsupported proxy scheme `{}`", scheme)),
+        };
+        // IPv6 addresses come in brackets
+        let host = url
+            .host_str()
+            .ok_or("the proxy URL has no host")?
+            .trim_matches(|c| c == '[' || c == ']');
+        let decode = |s| percent_decode_str(s).decode_utf8_lossy().to_string();
+        let credentials = match (url.username(), url.password()) {
+            ("", None) => None,
+            (username, password) => Some((decode(username), decode(password.unwrap_or("")))),
+        };
+        Ok(Proxy {
+            kind,
+            host: host.to_string(),
+            port: url.port().unwrap_or(default_port),
+            credentials,
+        })
+    }
+
+    fn authority(&self) -> String {
+        match self.host.parse::<Ipv6Addr>() {
+            Ok(ip) => format!("[{}]:{}", ip, self.port),
+            Err(_) => format!("{}:{}", self.host, self.port),
+        }
+    }
+
+    /**
+     * The proxy for reqwest clients; host names are resolved by the proxy,
+     * like those of peers.
+     */
+    pub fn to_reqwest(&self) -> reqwest::Result<reqwest::Proxy> {
+        let scheme = match self.kind {
+            ProxyKind::Socks5 => "socks5h",
+            ProxyKind::Http => "http",
+        };
+        let proxy = reqwest::Proxy::all(format!("{}://{}", scheme, self.authority()))?;
+        Ok(match &self.credentials {
+            Some((username, password)) => proxy.basic_auth(username, password),
+            None => proxy,
+        })
+    }
+
+    /**
+     * Open a tunnel to `host`, a name or address, through the proxy.
+     */
+    pub async fn connect(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        match self.kind {
+            ProxyKind::Socks5 => {
+                self.socks_request(&mut stream, CONNECT, &encode_address(host, port)?)
+                    .await?;
+            }
+            ProxyKind::Http => self.http_connect(&mut stream, host, port).await?,
+        }
+        Ok(stream)
+    }
+
+    /**
+     * Greet a SOCKS5 proxy and authenticate if it asks to.
+     */
+    async fn socks_handshake(&self, stream: &mut TcpStream) -> io::Result<()> {
+        let greeting = match self.credentials {
+            Some(_) => vec![SOCKS_VERSION, 2, NO_AUTHENTICATION, USERNAME_PASSWORD],
+            None => vec![SOCKS_VERSION, 1, NO_AUTHENTICATION],
+        };
+        stream.write_all(&greeting).await?;
+        let mut choice = [0; 2];
+        stream.read_exact(&mut choice).await?;
+        if choice[0] != SOCKS_VERSION {
+            return Err(io::Error::other("not a SOCKS5 proxy"));
+        }
+        match (choice[1], &self.credentials) {
+            (NO_AUTHENTICATION, _) => Ok(()),
+            (USERNAME_PASSWORD, Some((username, password))) => {
+                let too_long = |_| io::Error::other("SOCKS5 proxy: credentials too long");
+                let mut request = vec![
+                    AUTHENTICATION_VERSION,
+                    u8::try_from(username.len()).map_err(too_long)?,
+                ];
+                request.extend(username.as_bytes());
+                request.push(u8::try_from(password.len()).map_err(too_long)?);
+                request.extend(password.as_bytes());
+                stream.write_all(&request).await?;
+                let mut status = [0; 2];
+                stream.read_exact(&mut status).await?;
+                match status[1] {
+                    0 => Ok(()),
+                    _ => Err(io::Error::other("SOCKS5 proxy: authentication failed")),
+                }
+            }
+            (NO_ACCEPTABLE_METHOD, None) => {
+                Err(io::Error::other("SOCKS5 proxy: credentials required"))
+            }
+            _ => Err(io::Error::other(
+                "SOCKS5 proxy: no acceptable authentication method",
+            )),
+        }
+    }
+
+    /**
+     * Send a SOCKS5 `command` for `address`, and the address the proxy
+     * bound for it.
+     */
+    async fn socks_request(
+        &self,
+        stream: &mut TcpStream,
+        command: u8,
+        address: &[u8],
+    ) -> io::Result<SocketAddr> {
+        self.socks_handshake(stream).await?;
+        let mut request = vec![SOCKS_VERSION, command, 0];
+        request.extend(address);
+        stream.write_all(&request).await?;
+        let mut reply = [0; 3];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != SOCKS_VERSION {
+            return Err(io::Error::other("not a SOCKS5 proxy"));
+        }
+        if reply[1] != 0 {
+            return Err(socks_error(reply[1]));
+        }
+        read_address(stream).await
+    }
+
+    async fn http_connect(&self, stream: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+        let target = match host.parse::<Ipv6Addr>() {
+            Ok(ip) => format!("[{}]:{}", ip, port),
+            Err(_) => format!("{}:{}", host, port),
+        };
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+        if let Some((username, password)) = &self.credentials {
+            let token = STANDARD.encode(format!("{}:{}", username, password));
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+        // byte by byte, what follows the head belongs to the tunnel
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() > MAX_RESPONSE_HEAD {
+                return Err(io::Error::other("HTTP proxy: response head too long"));
+            }
+            head.push(stream.read_u8().await?);
+        }
+        let head = String::from_utf8_lossy(&head);
+        let status = head.lines().next().unwrap_or("");
+        match status.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(io::Error::other(format!("HTTP proxy: {}", status))),
+        }
+    }
+
+    /**
+     * Ask a SOCKS5 proxy for a UDP relay.
+     */
+    pub async fn udp_associate(&self) -> io::Result<UdpRelay> {
+        if self.kind != ProxyKind::Socks5 {
+            return Err(io::Error::other("only SOCKS5 proxies relay UDP"));
+        }
+        let mut control = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        let proxy_ip = control.peer_addr()?.ip();
+        let socket = UdpSocket::bind(SocketAddr::new(control.local_addr()?.ip(), 0)).await?;
+        // where the datagrams will come from, unknown behind a NAT
+        let address = encode_address(&Ipv4Addr::UNSPECIFIED.to_string(), 0)?;
+        let mut relay = self
+            .socks_request(&mut control, UDP_ASSOCIATE, &address)
+            .await?;
+        // the relay is on the proxy when it doesn't say
+        if relay.ip().is_unspecified() {
+            relay.set_ip(proxy_ip);
+        }
+        Ok(UdpRelay {
+            socket: Arc::new(socket),
+            relay,
+            _control: control,
+        })
+    }
+}
+
+/**
+ * UDP association with a SOCKS5 proxy: datagrams sent on `socket` to the
+ * relay are forwarded to the address in their header.
+ */
+pub struct UdpRelay {
+    socket: Arc<UdpSocket>,
+    relay: SocketAddr,
+    // the association ends with this connection
+    _control: TcpStream,
+}
+
+impl UdpRelay {
+    pub fn socket(&self) -> Arc<UdpSocket> {
+        self.socket.clone()
+    }
+
+    pub fn relay_addr(&self) -> SocketAddr {
+        self.relay
+    }
+
+    /**
+     * The datagram asking the relay to send `payload` to `to`.
+     */
+    pub fn encapsulate(&self, payload: &[u8], to: SocketAddr) -> Vec<u8> {
+        let mut datagram = vec![0, 0, 0];
+        match to.ip() {
+            IpAddr::V4(ip) => {
+                datagram.push(IPV4);
+                datagram.extend(ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                datagram.push(IPV6);
+                datagram.extend(ip.octets());
+            }
+        }
+        datagram.extend(to.port().to_be_bytes());
+        datagram.extend(payload);
+        datagram
+    }
+
+    /**
+     * The sender and payload of a datagram from the relay, `None` for
+     * fragments, which aren't reassembled.
+     */
+    pub fn decapsulate(datagram: &[u8]) -> Option<(SocketAddr, &[u8])> {
+        if datagram.len() < 4 || datagram[2] != 0 {
+            return None;
+        }
+        let (ip, rest): (IpAddr, _) = match datagram[3] {
+            IPV4 => {
+                let octets: [u8; 4] = datagram.get(4..8)?.try_into().ok()?;
+                (octets.into(), &datagram[8..])
+            }
+            IPV6 => {
+                let octets: [u8; 16] = datagram.get(4..20)?.try_into().ok()?;
+                (octets.into(), &datagram[20..])
+            }
+            _ => return None,
+        };
+        let port = u16::from_be_bytes(rest.get(..2)?.try_into().ok()?);
+        Some((SocketAddr::new(ip, port), &rest[2..]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dht::{Dht, NodeId};
+    use tokio::net::TcpListener;
+
+    async fn echo_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    tokio::io::copy(&mut reader, &mut writer).await.ok();
+                });
+            }
+        });
+        addr
+    }
+
+    async fn read_target(stream: &mut TcpStream) -> (String, u16) {
+        let host = match stream.read_u8().await.unwrap() {
+            IPV4 => {
+                let mut octets = [0; 4];
+                stream.read_exact(&mut octets).await.unwrap();
+                Ipv4Addr::from(octets).to_string()
+            }
+            DOMAIN_NAME => {
+                let mut name = vec![0; stream.read_u8().await.unwrap() as usize];
+                stream.read_exact(&mut name).await.unwrap();
+                String::from_utf8(name).unwrap()
+            }
+            atyp => panic!("address type {}", atyp),
+        };
+        (host, stream.read_u16().await.unwrap())
+    }
+
+    /**
+     * Stand-in SOCKS5 proxy taking `user`/`pass` only, connecting and
+     * relaying UDP from 127.0.0.1.
+     */
+    async fn socks_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut greeting = [0; 2];
+                    stream.read_exact(&mut greeting).await.unwrap();
+                    let mut methods = vec![0; greeting[1] as usize];
+                    stream.read_exact(&mut methods).await.unwrap();
+                    if !methods.contains(&USERNAME_PASSWORD) {
+                        stream
+                            .write_all(&[SOCKS_VERSION, NO_ACCEPTABLE_METHOD])
+                            .await
+                            .unwrap();
+                        return;
+                    }
+                    stream
+                        .write_all(&[SOCKS_VERSION, USERNAME_PASSWORD])
+                        .await
+                        .unwrap();
+                    stream.read_u8().await.unwrap();
+                    let mut username = vec![0; stream.read_u8().await.unwrap() as usize];
+                    stream.read_exact(&mut username).await.unwrap();
+                    let mut password = vec![0; stream.read_u8().await.unwrap() as usize];
+                    stream.read_exact(&mut password).await.unwrap();
+                    if (&username[..], &password[..]) != (b"user", b"p@ss") {
+                        stream
+                            .write_all(&[AUTHENTICATION_VERSION, 1])
+                            .await
+                            .unwrap();
+                        return;
+                    }
+                    stream
+                        .write_all(&[AUTHENTICATION_VERSION, 0])
+                        .await
+                        .unwrap();
+
+                    let mut request = [0; 3];
+                    stream.read_exact(&mut request).await.unwrap();
+                    let (host, port) = read_target(&mut stream).await;
+                    let bound = if request[1] == CONNECT {
+                        let target = TcpStream::connect((host.as_str(), port)).await.unwrap();
+                        Err(target)
+                    } else {
+                        Ok(UdpSocket::bind("127.0.0.1:0").await.unwrap())
+                    };
+                    let local = match &bound {
+                        Err(target) => target.local_addr().unwrap(),
+                        Ok(socket) => socket.local_addr().unwrap(),
+                    };
+                    let mut reply = vec![SOCKS_VERSION, 0, 0];
+                    reply.extend(encode_address(&local.ip().to_string(), local.port()).unwrap());
+                    stream.write_all(&reply).await.unwrap();
+                    match bound {
+                        Err(mut target) => {
+                            tokio::io::copy_bidirectional(&mut stream, &mut target)
+                                .await
+                                .ok();
+                        }
+                        Ok(socket) => {
+                            let relay = tokio::spawn(relay_udp(socket));
+                            // until the association ends
+                            stream.read_u8().await.ok();
+                            relay.abort();
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    async fn relay_udp(socket: UdpSocket) {
+        let mut buf = [0; 2048];
+        let mut client = None;
+        loop {
+            let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+            match client {
+                Some(client) if client != from => {
+                    let mut datagram = vec![0, 0, 0, IPV4];
+                    datagram.extend(match from.ip() {
+                        IpAddr::V4(ip) => ip.octets(),
+                        IpAddr::V6(_) => unreachable!(),
+                    });
+                    datagram.extend(from.port().to_be_bytes());
+                    datagram.extend(&buf[..len]);
+                    socket.send_to(&datagram, client).await.unwrap();
+                }
+                _ => {
+                    client = Some(from);
+                    let (to, payload) = UdpRelay::decapsulate(&buf[..len]).unwrap();
+                    socket.send_to(payload, to).await.unwrap();
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn socks5() {
+        let echo = echo_server().await;
+        let server = socks_server().await;
+        let proxy = Proxy::parse(&format!("socks5://user:p%40ss@{}", server)).unwrap();
+        assert_eq!(proxy.kind, ProxyKind::Socks5);
+        assert_eq!(proxy.to_string(), format!("socks5://{}", server));
+
+        // the proxy resolves host names
+        let mut stream = proxy.connect("localhost", echo.port()).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut pong = [0; 4];
+        stream.read_exact(&mut pong).await.unwrap();
+        assert_eq!(&pong, b"ping");
+
+        let wrong = Proxy::parse(&format!("socks5://user:guess@{}", server)).unwrap();
+        let refused = wrong.connect("127.0.0.1", echo.port()).await.unwrap_err();
+        assert!(refused.to_string().contains("authentication failed"));
+        let anonymous = Proxy::parse(&format!("socks5://{}", server)).unwrap();
+        assert!(anonymous.connect("127.0.0.1", echo.port()).await.is_err());
+
+        // a DHT node behind the relay reaches others
+        let relay = proxy.udp_associate().await.unwrap();
+        let relayed = Dht::bind_relayed(relay, NodeId::random()).await;
+        assert!(relayed.is_relayed());
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let other = Dht::bind(&[addr], NodeId::random()).await.unwrap();
+        let id = relayed.ping(other.local_addr().unwrap()).await.unwrap();
+        assert_eq!(id, other.id());
+    }
+
+    /**
+     * Stand-in HTTP proxy opening tunnels for `user:pass`.
+     */
+    async fn http_proxy() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut head = vec![];
+                    while !head.ends_with(b"\r\n\r\n") {
+                        head.push(stream.read_u8().await.unwrap());
+                    }
+                    let head = String::from_utf8(head).unwrap();
+                    let target = head.strip_prefix("CONNECT ").unwrap();
+                    let target = target.split(' ').next().unwrap().to_string();
+                    assert!(head.contains(&format!("\r\nHost: {}\r\n", target)));
+                    // base64 of user:pass
+                    if !head.contains("\r\nProxy-Authorization: Basic dXNlcjpwYXNz\r\n") {
+                        let refusal = "HTTP/1.1 407 Proxy Authentication Required\r\n\r\n";
+                        stream.write_all(refusal.as_bytes()).await.unwrap();
+                        return;
+                    }
+                    let mut target = TcpStream::connect(target).await.unwrap();
+                    // the tunnel starts right after the head
+                    let established = "HTTP/1.1 200 Connection established\r\n\r\nhello";
+                    stream.write_all(established.as_bytes()).await.unwrap();
+                    tokio::io::copy_bidirectional(&mut stream, &mut target)
+                        .await
+                        .ok();
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn http_connect() {
+        let echo = echo_server().await;
+        let server = http_proxy().await;
+        let proxy = Proxy::parse(&format!("http://user:pass@{}", server)).unwrap();
+        assert_eq!(proxy.kind, ProxyKind::Http);
+        assert_eq!(
+            Proxy::parse("http://[::1]").unwrap().to_string(),
+            "http://[::1]:80"
+        );
+        assert!(Proxy::parse("ftp://127.0.0.1").is_err());
+
+        let mut stream = proxy.connect("127.0.0.1", echo.port()).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut received = [0; 9];
+        stream.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"helloping");
+
+        let anonymous = Proxy::parse(&format!("http://{}", server)).unwrap();
+        let refused = anonymous
+            .connect("127.0.0.1", echo.port())
+            .await
+            .unwrap_err();
+        assert!(refused.to_string().contains("407"));
+    }
+}
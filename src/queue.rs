@@ -1,9 +1,44 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    error::Error,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    ops::Range,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use bendy::{decoding::FromBencode, encoding::ToBencode};
+use rand::thread_rng;
+use tokio::{
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        watch, Semaphore,
+    },
+    task::JoinSet,
+    time::interval,
+};
 
 use crate::{
-    connect_tracker::tracker::{self, Handshake, PeerConnection},
-    parse_torrent::torrent_info::TorrentInfo,
+    choker::{
+        ChokeCandidate, Choker, DEFAULT_UPLOAD_SLOTS, NEW_PEER_AGE, RECHOKE_INTERVAL, SNUB_TIMEOUT,
+    },
+    connect_tracker::tracker::{
+        Extensions, Handshake, Message, MessageId, MessageWriter, PeerConnection,
+    },
+    mse::EncryptionMode,
+    parse_torrent::torrent_info::{TorrentInfo, TorrentMetadata},
     parse_tracker_res::peers::{Peer, PeerList},
+    pex::{
+        ExtendedHandshake, PexMessage, PexState, EXTENDED_HANDSHAKE, MAX_PEX_PEERS, PEX_ENCRYPTION,
+        PEX_INTERVAL, PEX_OUTGOING, PEX_SEED, PEX_UTP, UT_PEX,
+    },
+    picker::{PiecePicker, Priority},
+    rate::{RateLimiter, RateLimits, TransferLimits, TransferRate},
+    seeding::{GoalAction, SeedEvent, SeedGoals, SeedStats},
+    session::Session,
+    storage::{is_disk_full, Storage},
+    verify::{check_piece, PieceStatus, VerifyReport},
+    webseed::{self, WebSeed},
 };
 
 // Exchanging pieces described in `TorrentMetadata`:
@@ -24,7 +59,41 @@ use crate::{
 // - Update bitfield
 //
 // Seeding:
-// - If a request is received, send piece if the piece exists
+// - Send our bitfield after the handshake and `have` for every piece we finish
+// - If a request is received, send piece if the piece exists and the peer is unchoked
+// - Requests are queued until sent, a `cancel` removes a queued request
+// - Which peers are unchoked is decided by the `Choker` every 10 seconds
+// - Super-seeding (BEP 16): no bitfield, each peer is told about one piece and
+//   only learns about the next once the last one shows up at another peer
+//
+// Fast extension (BEP 6), when both handshakes set it:
+// - `have all`/`have none` replace an empty or full bitfield
+// - every dropped request is answered with `reject request`, so requests
+//   survive a choke and are only sent again once rejected
+// - each peer is granted an allowed fast set it may request while choked
+// - pieces suggested by the peer are picked first
+
+const BLOCK_SIZE: u32 = 16384;
+const MAX_PENDING_REQUESTS: usize = 5;
+// requests from a peer waiting to be served, later requests are dropped
+const MAX_QUEUED_UPLOADS: usize = 250;
+// size of the allowed fast set granted to each peer
+const ALLOWED_FAST_PIECES: usize = 10;
+// suggestions kept per peer, older ones are forgotten
+const MAX_SUGGESTED_PIECES: usize = 16;
+pub const DEFAULT_MAX_CONNECTIONS: usize = 50;
+// peers known to a torrent, discovered peers past this are ignored
+const MAX_PEERS: usize = 1000;
+// a deadline piece is only handed to a peer if fewer than this many
+// faster peers could download it instead
+const DEADLINE_PEERS: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeadlineEvent {
+    // the piece was downloaded, possibly after missing its deadline
+    Completed(usize),
+    Missed(usize),
+}
 
 struct PeerState {
     is_interested: bool,
@@ -32,147 +101,1939 @@ struct PeerState {
     client_interested: bool,
     client_choked: bool,
     peer_info: Peer,
+    // pieces advertised by the peer through `bitfield` and `have`
+    bitfield: Vec<u8>,
+    download_rate: TransferRate,
+    upload_rate: TransferRate,
+    // set while a connection to the peer is open
+    connected_at: Option<Instant>,
+    // last time the peer sent us a block
+    last_block: Option<Instant>,
+    limits: Arc<TransferLimits>,
+    // super-seeding: piece revealed to the peer that no other peer has
+    // reported since, and every piece revealed to it
+    offered: Option<usize>,
+    revealed: Vec<u8>,
+    // Fast extension: pieces the peer lets us request while choked, pieces
+    // we let it request while choked, and pieces it suggested
+    allowed_fast: HashSet<usize>,
+    granted_fast: HashSet<usize>,
+    suggested: VecDeque<usize>,
+    // address the peer accepts connections on, for incoming peers only
+    // known once their extended handshake tells the port
+    listen_addr: Option<SocketAddr>,
+    incoming: bool,
+    // the peer prefers encrypted connections
+    encryption: bool,
+    // connected over uTP rather than TCP
+    utp: bool,
+    // web seeds have every piece and are downloaded from over HTTP instead
+    // of connected to
+    web_seed: Option<WebSeed>,
 }
 
 pub struct TorrentState {
     bitfield: Vec<u8>,
+    // pieces set in `bitfield`
+    completed: usize,
     info: TorrentInfo,
     peers: Vec<PeerState>,
+    piece_count: usize,
+    // pieces currently assigned to a peer
+    in_progress: HashSet<usize>,
+    // set when the torrent can't make progress, e.g. the disk is full
+    paused: Option<String>,
+    picker: PiecePicker,
+    deadline_events: Option<UnboundedSender<DeadlineEvent>>,
+    // deadline pieces already reported as missed
+    missed_deadlines: HashSet<usize>,
+    // connections to peers of this torrent, incoming and outgoing
+    max_connections: usize,
+    // largest block a peer may request
+    max_request: u32,
+    // keep serving peers once every wanted piece is downloaded
+    seeding: bool,
+    choker: Choker,
+    // limits of every peer of the torrent
+    peer_limits: RateLimits,
+    // goals of this torrent, missing ones come from the session
+    seed_goals: SeedGoals,
+    // when the torrent completed
+    seeding_since: Option<Instant>,
+    last_upload: Option<Instant>,
+    seed_goal_reached: Option<SeedEvent>,
+    seed_events: Option<UnboundedSender<SeedEvent>>,
+    super_seeding: bool,
+    // number of peers each piece was revealed to while super-seeding
+    super_seed_offers: Vec<u32>,
+    // peers found after the tracker announce, e.g. through PEX
+    new_peers: Option<UnboundedSender<usize>>,
+    // more peers may still be found, e.g. by the DHT, so running out of
+    // peers doesn't end the download
+    discovering: bool,
+}
+
+fn bit_is_set(bitfield: &[u8], index: usize) -> bool {
+    let shift = 7 - (index % 8);
+    match bitfield.get(index / 8) {
+        Some(v) => ((*v >> shift) & 0x1) != 0,
+        None => false,
+    }
+}
+
+/**
+ * Canonical allowed fast set of BEP 6: pieces derived from the peer's /24
+ * network and the info hash, so that reconnecting doesn't change the set.
+ */
+fn allowed_fast_set(ip: Ipv4Addr, info_hash: &[u8], piece_count: usize, k: usize) -> Vec<usize> {
+    let k = k.min(piece_count);
+    let mut set = vec![];
+    let mut x = (u32::from(ip) & 0xffffff00).to_be_bytes().to_vec();
+    x.extend_from_slice(info_hash);
+    while set.len() < k {
+        x = sha1_smol::Sha1::from(&x).digest().bytes().to_vec();
+        for chunk in x.chunks(4) {
+            if set.len() >= k {
+                break;
+            }
+            let index = (u32::from_be_bytes(chunk.try_into().unwrap()) as usize) % piece_count;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+    set
+}
+
+fn set_bit(bitfield: &mut [u8], index: usize, on: bool) {
+    let shift = 7 - (index % 8);
+    if let Some(v) = bitfield.get_mut(index / 8) {
+        if on {
+            *v |= 0x1 << shift;
+        } else {
+            *v &= !(0x1 << shift);
+        }
+    };
 }
 
 impl TorrentState {
     pub fn new(info: TorrentInfo, peer_list: &PeerList) -> Self {
-        let peer_state: Vec<PeerState> = peer_list
+        let length = info.info_data.length as u64;
+        let piece_count = length.div_ceil(info.info_data.piece_length as u64) as usize;
+        let bitfield_len = piece_count.div_ceil(8);
+
+        let mut state = TorrentState {
+            bitfield: vec![0x00; bitfield_len],
+            completed: 0,
+            info,
+            peers: vec![],
+            piece_count,
+            in_progress: HashSet::new(),
+            paused: None,
+            picker: PiecePicker::new(piece_count),
+            deadline_events: None,
+            missed_deadlines: HashSet::new(),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_request: BLOCK_SIZE,
+            seeding: false,
+            choker: Choker::new(DEFAULT_UPLOAD_SLOTS),
+            peer_limits: RateLimits::default(),
+            seed_goals: SeedGoals::default(),
+            seeding_since: None,
+            last_upload: None,
+            seed_goal_reached: None,
+            seed_events: None,
+            super_seeding: false,
+            super_seed_offers: vec![0; piece_count],
+            new_peers: None,
+            discovering: false,
+        };
+        state.add_peers(&peer_list.peers);
+        state
+    }
+
+    pub fn add_peers(&mut self, peers: &[Peer]) {
+        for p in peers {
+            self.add_peer(p.clone());
+        }
+    }
+
+    /**
+     * Track a new peer, returns its index in `peers`.
+     */
+    pub fn add_peer(&mut self, peer: Peer) -> usize {
+        self.peers.push(PeerState {
+            is_interested: false,
+            is_choked: true,
+            client_choked: true,
+            client_interested: true,
+            peer_info: peer,
+            bitfield: vec![0x00; self.bitfield.len()],
+            download_rate: TransferRate::default(),
+            upload_rate: TransferRate::default(),
+            connected_at: None,
+            last_block: None,
+            limits: Arc::new(TransferLimits::new(self.peer_limits)),
+            offered: None,
+            revealed: vec![0x00; self.bitfield.len()],
+            allowed_fast: HashSet::new(),
+            granted_fast: HashSet::new(),
+            suggested: VecDeque::new(),
+            listen_addr: None,
+            incoming: false,
+            encryption: false,
+            utp: false,
+            web_seed: None,
+        });
+        let peer = self.peers.last_mut().unwrap();
+        peer.listen_addr = format!("{}:{}", peer.peer_info.ip, peer.peer_info.port)
+            .parse()
+            .ok()
+            .or_else(|| {
+                // IPv6 addresses in tracker responses aren't bracketed
+                let ip = peer.peer_info.ip.parse().ok()?;
+                Some(SocketAddr::new(
+                    ip,
+                    u16::try_from(peer.peer_info.port).ok()?,
+                ))
+            });
+        self.peers.len() - 1
+    }
+
+    /**
+     * Track a web seed as a peer having every piece, so that the picker
+     * counts it like the other seeds.
+     */
+    pub fn add_web_seed(&mut self, seed: WebSeed) -> usize {
+        let peer_index = self.add_peer(Peer {
+            ip: seed.to_string(),
+            port: 0,
+        });
+        self.peers[peer_index].listen_addr = None;
+        self.peers[peer_index].web_seed = Some(seed);
+        for index in 0..self.piece_count {
+            self.set_peer_have(peer_index, index);
+        }
+        peer_index
+    }
+
+    /**
+     * Track a peer that connected to us, its port isn't one it listens on.
+     * The entry of a disconnected incoming peer is reused, preferably the
+     * one with the same address, so that reconnecting peers don't pile up.
+     * Returns `None` over the `MAX_PEERS` limit.
+     */
+    pub fn add_incoming_peer(&mut self, peer: Peer) -> Option<usize> {
+        let free =
+            |p: &PeerState| p.incoming && p.connected_at.is_none() && p.listen_addr.is_none();
+        let peer_index = match self
             .peers
             .iter()
-            .map(|p| PeerState {
-                is_interested: false,
-                is_choked: false,
-                client_choked: false,
-                client_interested: true,
-                peer_info: p.clone(),
+            .position(|p| free(p) && p.peer_info.ip == peer.ip && p.peer_info.port == peer.port)
+            .or_else(|| self.peers.iter().position(free))
+        {
+            Some(i) => {
+                self.peers[i].peer_info = peer;
+                i
+            }
+            None if self.peers.len() >= MAX_PEERS => return None,
+            None => {
+                let i = self.add_peer(peer);
+                self.peers[i].listen_addr = None;
+                self.peers[i].incoming = true;
+                i
+            }
+        };
+        // claim the entry before the handshake finishes
+        self.peer_connected(peer_index);
+        Some(peer_index)
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.info.info_data.private
+    }
+
+    /**
+     * Track peers found by other means than the tracker, peers already known
+     * or over the `MAX_PEERS` limit are ignored. The new peers are sent to
+     * the subscriber of `subscribe_new_peers`.
+     */
+    pub fn add_discovered_peers(&mut self, addrs: &[SocketAddr]) -> usize {
+        let mut added = 0;
+        for addr in addrs {
+            let known = self.peers.iter().any(|p| p.listen_addr == Some(*addr));
+            if known || self.peers.len() >= MAX_PEERS || addr.port() == 0 {
+                continue;
+            }
+            let peer_index = self.add_peer(Peer {
+                ip: addr.ip().to_string(),
+                port: addr.port() as i32,
+            });
+            self.peers[peer_index].listen_addr = Some(*addr);
+            if let Some(tx) = &self.new_peers {
+                let _ = tx.send(peer_index);
+            }
+            added += 1;
+        }
+        added
+    }
+
+    pub fn subscribe_new_peers(&mut self) -> UnboundedReceiver<usize> {
+        let (tx, rx) = unbounded_channel();
+        self.new_peers = Some(tx);
+        rx
+    }
+
+    /**
+     * Connected peers to advertise over PEX to peer `exclude`, with their
+     * PEX flags.
+     */
+    fn pex_peers(&self, exclude: usize) -> HashMap<SocketAddr, u8> {
+        self.peers
+            .iter()
+            .enumerate()
+            .filter(|(i, p)| *i != exclude && p.connected_at.is_some())
+            .filter_map(|(i, p)| {
+                let mut flags = 0;
+                if self.peer_is_seed(i) {
+                    flags |= PEX_SEED;
+                }
+                if p.encryption {
+                    flags |= PEX_ENCRYPTION;
+                }
+                if p.utp {
+                    flags |= PEX_UTP;
+                }
+                if !p.incoming {
+                    flags |= PEX_OUTGOING;
+                }
+                Some((p.listen_addr?, flags))
             })
-            .collect();
+            .collect()
+    }
+
+    pub fn set_max_connections(&mut self, max_connections: usize) {
+        self.max_connections = max_connections;
+    }
+
+    pub fn set_max_request(&mut self, max_request: u32) {
+        self.max_request = max_request;
+    }
+
+    pub fn set_seeding(&mut self, seeding: bool) {
+        self.seeding = seeding;
+    }
+
+    pub fn set_upload_slots(&mut self, slots: usize) {
+        self.choker.set_slots(slots);
+    }
+
+    pub fn set_seed_goals(&mut self, goals: SeedGoals) {
+        self.seed_goals = goals;
+    }
+
+    pub fn subscribe_seed_events(&mut self) -> UnboundedReceiver<SeedEvent> {
+        let (tx, rx) = unbounded_channel();
+        self.seed_events = Some(tx);
+        rx
+    }
 
-        let bitfield_len: usize =
-            (info.info_data.length / info.info_data.piece_length / 8) as usize;
-        let mut bitfield: Vec<u8> = Vec::with_capacity(bitfield_len);
-        for i in 0..bitfield_len {
-            bitfield.push(0x00);
+    /**
+     * Check the seeding goals of a completed torrent, falling back to the
+     * `global` ones. The torrent is paused and an event sent the first time a
+     * goal is reached.
+     */
+    fn check_seed_goals(&mut self, global: SeedGoals) -> Option<SeedEvent> {
+        if self.seed_goal_reached.is_some() {
+            return None;
+        }
+        if !self.is_complete() {
+            self.seeding_since = None;
+            return None;
         }
+        let now = Instant::now();
+        let stats = SeedStats {
+            uploaded: self.uploaded(),
+            downloaded: self.downloaded(),
+            size: self.info.info_data.length as u64,
+            seeding_since: *self.seeding_since.get_or_insert(now),
+            last_upload: self.last_upload,
+        };
+        let goals = self.seed_goals.or(global);
+        let event = SeedEvent {
+            goal: goals.reached(&stats, now)?,
+            action: goals.action.unwrap_or_default(),
+        };
+        self.seed_goal_reached = Some(event);
+        if self.paused.is_none() {
+            self.paused = Some(format!("seeding goal reached: {}", event.goal));
+        }
+        if let Some(tx) = &self.seed_events {
+            tx.send(event).ok();
+        }
+        Some(event)
+    }
 
-        TorrentState {
-            bitfield,
-            info: info.clone(),
-            peers: peer_state,
+    pub fn set_peer_rate_limits(&mut self, limits: RateLimits) {
+        self.peer_limits = limits;
+        for peer in self.peers.iter() {
+            peer.limits.set(limits);
         }
     }
 
-    pub fn check_piece(&self, index: usize) -> bool {
-        let byte_index = index / 8;
-        let shift = 7 - (index % 8);
-        match self.bitfield.get(byte_index) {
-            Some(v) => {
-                return ((*v >> shift) & 0x1) != 0;
+    fn peer_connected(&mut self, peer_index: usize) {
+        let peer = &mut self.peers[peer_index];
+        peer.connected_at = Some(Instant::now());
+        peer.last_block = None;
+        peer.is_choked = true;
+        peer.is_interested = false;
+        peer.client_choked = true;
+        peer.client_interested = false;
+        peer.offered = None;
+        peer.revealed.fill(0x00);
+        peer.allowed_fast.clear();
+        peer.granted_fast.clear();
+        peer.suggested.clear();
+    }
+
+    /**
+     * Unchoke a newly interested peer right away if an upload slot is free,
+     * instead of waiting for the next rechoke.
+     */
+    fn set_peer_interested(&mut self, peer_index: usize, interested: bool) {
+        self.peers[peer_index].is_interested = interested;
+        let unchoked = self
+            .peers
+            .iter()
+            .filter(|p| p.is_interested && !p.is_choked)
+            .count();
+        if interested && unchoked < self.choker.slots() {
+            self.peers[peer_index].is_choked = false;
+        }
+    }
+
+    /**
+     * Run the choker over the connected peers.
+     * Returns true if any peer's choke state changed.
+     */
+    fn rechoke(&mut self) -> bool {
+        let now = Instant::now();
+        let seeding = self.is_complete();
+        let candidates: Vec<ChokeCandidate> = self
+            .peers
+            .iter()
+            .enumerate()
+            .filter_map(|(i, p)| {
+                let connected_at = p.connected_at?;
+                let last_block = p.last_block.unwrap_or(connected_at);
+                Some(ChokeCandidate {
+                    peer: i,
+                    interested: p.is_interested,
+                    rate: if seeding {
+                        p.upload_rate.rate()
+                    } else {
+                        p.download_rate.rate()
+                    },
+                    snubbed: !seeding
+                        && p.client_interested
+                        && now.duration_since(last_block) > SNUB_TIMEOUT,
+                    new: now.duration_since(connected_at) < NEW_PEER_AGE,
+                })
+            })
+            .collect();
+        let unchoked = self.choker.rechoke(&candidates, &mut thread_rng());
+        let mut changed = false;
+        for c in candidates {
+            let choked = !unchoked.contains(&c.peer);
+            if self.peers[c.peer].is_choked != choked {
+                self.peers[c.peer].is_choked = choked;
+                changed = true;
             }
-            None => {
-                return false;
+        }
+        changed
+    }
+
+    /**
+     * Check a block request from a peer.
+     * Returns `Ok(false)` for a valid request that can't be served right now,
+     * i.e. the peer is choked or the piece is missing, and `Err` if the request
+     * is out of bounds.
+     */
+    pub fn check_request(
+        &self,
+        peer_index: usize,
+        index: usize,
+        begin: u32,
+        length: u32,
+    ) -> Result<bool, String> {
+        if index >= self.piece_count {
+            return Err(format!("request for piece {} out of range", index));
+        }
+        if length == 0 || length > self.max_request {
+            return Err(format!("request of {} bytes", length));
+        }
+        let piece_size = self.info.info_data.piece_size(index);
+        if begin as i64 + length as i64 > piece_size {
+            return Err(format!(
+                "request {}+{} past the end of piece {}",
+                begin, length, index
+            ));
+        }
+        let peer = &self.peers[peer_index];
+        let hidden = self.is_super_seeding() && !bit_is_set(&peer.revealed, index);
+        let unchoked = !peer.is_choked || peer.granted_fast.contains(&index);
+        Ok(unchoked && self.check_piece(index) && !hidden)
+    }
+
+    /**
+     * Whether a peer advertised every piece of the torrent.
+     */
+    fn peer_is_seed(&self, peer_index: usize) -> bool {
+        let bitfield = &self.peers[peer_index].bitfield;
+        (0..self.piece_count).all(|i| bit_is_set(bitfield, i))
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.peers.iter().map(|p| p.upload_rate.total()).sum()
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.peers.iter().map(|p| p.download_rate.total()).sum()
+    }
+
+    /**
+     * Translate per-file priorities into piece priorities.
+     * A piece shared by several files gets the highest priority among them,
+     * files missing from `priorities` keep the normal priority.
+     */
+    pub fn set_file_priorities(&mut self, priorities: &[Priority]) {
+        let mut piece_priorities = vec![Priority::Skip; self.piece_count];
+        for (i, range) in self
+            .info
+            .info_data
+            .file_piece_ranges()
+            .into_iter()
+            .enumerate()
+        {
+            let priority = priorities.get(i).copied().unwrap_or_default();
+            for index in range {
+                piece_priorities[index] = piece_priorities[index].max(priority);
             }
-        };
+        }
+        for (index, priority) in piece_priorities.into_iter().enumerate() {
+            self.set_piece_priority(index..(index + 1), priority);
+        }
+    }
+
+    pub fn check_piece(&self, index: usize) -> bool {
+        bit_is_set(&self.bitfield, index)
     }
 
     pub fn set_bitfield_on(&mut self, index: usize) {
-        let byte_index = index / 8;
-        let shift = 7 - (index % 8);
-        if let Some(v) = self.bitfield.get_mut(byte_index) {
-            *v = *v | (0x1 << shift);
-        };
+        if index < self.piece_count && !self.check_piece(index) {
+            self.completed += 1;
+        }
+        set_bit(&mut self.bitfield, index, true);
     }
 
     pub fn set_bitfield_off(&mut self, index: usize) {
-        let byte_index = index / 8;
-        let shift = 7 - (index % 8);
-        if let Some(v) = self.bitfield.get_mut(byte_index) {
-            *v = *v & !(0x1 << shift);
-        };
+        if index < self.piece_count && self.check_piece(index) {
+            self.completed -= 1;
+        }
+        set_bit(&mut self.bitfield, index, false);
     }
 
-    pub fn get_next_required_piece(&self) -> Option<usize> {
-        for (i, byte) in self.bitfield.iter().enumerate() {
-            if *byte == 0xff {
-                continue;
-            };
-            for offset in 7..0 {
-                if (*byte >> offset) & 0x1 == 0x0 {
-                    return Some((i * 8) + (7 - offset));
-                }
+    /**
+     * Take the pieces of a recheck, only the ones that passed count as downloaded.
+     */
+    pub fn load_verified(&mut self, report: &VerifyReport) {
+        for (index, status) in report.statuses.iter().enumerate() {
+            if *status == PieceStatus::Valid {
+                self.set_bitfield_on(index);
+            } else {
+                self.set_bitfield_off(index);
+            }
+        }
+    }
+
+    pub fn completed_pieces(&self) -> usize {
+        self.completed
+    }
+
+    pub fn set_piece_priority(&mut self, range: Range<usize>, priority: Priority) {
+        for index in range {
+            self.picker.set_priority(index, priority);
+        }
+    }
+
+    /**
+     * Download the piece before `deadline` from now, ahead of every other piece.
+     * Pieces that are already downloaded are ignored.
+     */
+    pub fn set_piece_deadline(&mut self, index: usize, deadline: Duration) {
+        if index < self.piece_count && !self.check_piece(index) {
+            self.picker.set_deadline(index, Instant::now() + deadline);
+            self.missed_deadlines.remove(&index);
+        }
+    }
+
+    /**
+     * Raise pieces for a stream reader. They keep a high priority until
+     * every reader that raised them released them.
+     */
+    pub fn boost_pieces(&mut self, range: Range<usize>) {
+        for index in range {
+            self.picker.boost(index);
+        }
+    }
+
+    /**
+     * Undo `boost_pieces`, pieces no reader waits for anymore also lose their
+     * deadline.
+     */
+    pub fn release_pieces(&mut self, range: Range<usize>) {
+        for index in range {
+            if self.picker.unboost(index) && self.picker.clear_deadline(index).is_some() {
+                self.missed_deadlines.remove(&index);
             }
         }
-        return None;
+    }
+
+    /**
+     * Receive a `DeadlineEvent` when a deadline piece completes or misses its time.
+     */
+    pub fn subscribe_deadlines(&mut self) -> UnboundedReceiver<DeadlineEvent> {
+        let (tx, rx) = unbounded_channel();
+        self.deadline_events = Some(tx);
+        rx
+    }
+
+    fn notify_deadline(&self, event: DeadlineEvent) {
+        if let Some(tx) = &self.deadline_events {
+            tx.send(event).ok();
+        }
+    }
+
+    /**
+     * Report deadline pieces whose time passed, each one only once.
+     */
+    pub fn check_deadlines(&mut self) {
+        let now = Instant::now();
+        let missed: Vec<usize> = self
+            .picker
+            .deadlines()
+            .filter(|(i, d)| *d <= now && !self.missed_deadlines.contains(i))
+            .map(|(i, _)| i)
+            .collect();
+        for index in missed {
+            self.missed_deadlines.insert(index);
+            self.notify_deadline(DeadlineEvent::Missed(index));
+        }
+    }
+
+    /**
+     * Download pieces in order from the cursor with a window of `readahead`
+     * pieces, `None` goes back to rarest-first only.
+     */
+    pub fn set_sequential(&mut self, readahead: Option<usize>) {
+        self.picker.set_sequential(readahead);
+        let bitfield = &self.bitfield;
+        self.picker.advance_cursor(|i| bit_is_set(bitfield, i));
+    }
+
+    /**
+     * Next piece of the sequential window, `None` when not downloading in order.
+     */
+    pub fn cursor(&self) -> Option<usize> {
+        self.picker.cursor()
+    }
+
+    /**
+     * Move the sequential cursor to `index`, e.g. when the reader seeks.
+     */
+    pub fn seek(&mut self, index: usize) {
+        let bitfield = &self.bitfield;
+        self.picker.set_cursor(index, |i| bit_is_set(bitfield, i));
+    }
+
+    fn finish_piece(&mut self, index: usize) {
+        self.in_progress.remove(&index);
+        self.set_bitfield_on(index);
+        let bitfield = &self.bitfield;
+        self.picker.advance_cursor(|i| bit_is_set(bitfield, i));
+        if self.picker.clear_deadline(index).is_some() {
+            self.missed_deadlines.remove(&index);
+            self.notify_deadline(DeadlineEvent::Completed(index));
+        }
+    }
+
+    /**
+     * Whether every piece that isn't skipped has been downloaded.
+     */
+    pub fn is_complete(&self) -> bool {
+        (0..self.piece_count).all(|i| self.check_piece(i) || !self.picker.is_wanted(i))
+    }
+
+    /**
+     * Number of wanted bytes still missing, reported to the tracker as `left`.
+     */
+    pub fn left(&self) -> i64 {
+        (0..self.piece_count)
+            .filter(|i| !self.check_piece(*i) && self.picker.is_wanted(*i))
+            .map(|i| self.info.info_data.piece_size(i))
+            .sum()
+    }
+
+    /**
+     * Record a piece advertised by a peer. Returns true if that releases a
+     * super-seeding offer to another peer.
+     */
+    fn set_peer_have(&mut self, peer_index: usize, index: usize) -> bool {
+        if index >= self.piece_count || bit_is_set(&self.peers[peer_index].bitfield, index) {
+            return false;
+        }
+        set_bit(&mut self.peers[peer_index].bitfield, index, true);
+        self.picker.add_availability(index);
+
+        let mut propagated = false;
+        for (i, peer) in self.peers.iter_mut().enumerate() {
+            if i != peer_index && peer.offered == Some(index) {
+                peer.offered = None;
+                propagated = true;
+            }
+        }
+        propagated
+    }
+
+    pub fn set_super_seeding(&mut self, super_seeding: bool) {
+        self.super_seeding = super_seeding;
+    }
+
+    /**
+     * Super-seeding only applies once we have every piece.
+     */
+    fn is_super_seeding(&self) -> bool {
+        self.super_seeding && self.completed == self.piece_count
+    }
+
+    /**
+     * Reveal a piece to a peer while super-seeding: the piece revealed the
+     * fewest times, then the rarest. Nothing is revealed until the previous
+     * piece revealed to the peer shows up at another peer.
+     */
+    fn super_seed_piece(&mut self, peer_index: usize) -> Option<usize> {
+        if !self.is_super_seeding() || self.peers[peer_index].offered.is_some() {
+            return None;
+        }
+        let peer = &self.peers[peer_index];
+        let index = (0..self.piece_count)
+            .filter(|i| !bit_is_set(&peer.bitfield, *i) && !bit_is_set(&peer.revealed, *i))
+            .min_by_key(|i| (self.super_seed_offers[*i], self.picker.availability(*i)))?;
+        let peer = &mut self.peers[peer_index];
+        peer.offered = Some(index);
+        set_bit(&mut peer.revealed, index, true);
+        self.super_seed_offers[index] += 1;
+        Some(index)
+    }
+
+    /**
+     * Next missing piece that the peer has and nobody else is downloading.
+     * While the peer chokes us only its allowed fast pieces can be requested.
+     * Pieces the peer suggested come first unless a piece has a deadline.
+     */
+    fn next_piece_for_peer(&self, peer_index: usize, choked: bool) -> Option<usize> {
+        let peer = &self.peers[peer_index];
+        let candidate = |i: usize| {
+            !self.check_piece(i)
+                && !self.in_progress.contains(&i)
+                && bit_is_set(&peer.bitfield, i)
+                && (!choked || peer.allowed_fast.contains(&i))
+                && (!self.picker.has_deadline(i) || self.is_fast_peer_for(peer_index, i))
+        };
+        let picked = self.picker.pick(candidate);
+        if picked.is_some_and(|i| self.picker.has_deadline(i)) {
+            return picked;
+        }
+        peer.suggested
+            .iter()
+            .copied()
+            .find(|i| self.picker.is_wanted(*i) && candidate(*i))
+            .or(picked)
+    }
+
+    /**
+     * Pick the pieces the peer may request while choked, see `allowed_fast_set`.
+     */
+    fn grant_allowed_fast(&mut self, peer_index: usize) -> Vec<usize> {
+        let set = match self.peers[peer_index].peer_info.ip.parse() {
+            Ok(IpAddr::V4(ip)) => allowed_fast_set(
+                ip,
+                &self.info.info_hash,
+                self.piece_count,
+                ALLOWED_FAST_PIECES,
+            ),
+            _ => vec![],
+        };
+        self.peers[peer_index].granted_fast = set.iter().copied().collect();
+        set
+    }
+
+    /**
+     * Whether the peer is among the fastest peers that have the piece.
+     */
+    fn is_fast_peer_for(&self, peer_index: usize, index: usize) -> bool {
+        let rate = self.peers[peer_index].download_rate.rate();
+        let faster = self
+            .peers
+            .iter()
+            .enumerate()
+            .filter(|(j, p)| {
+                *j != peer_index && bit_is_set(&p.bitfield, index) && p.download_rate.rate() > rate
+            })
+            .count();
+        faster < DEADLINE_PEERS
     }
 }
 
 pub struct SharedTorrentState {
     mutex: Mutex<TorrentState>,
+    // number of completed pieces, bumped every time a piece is verified
+    completed: watch::Sender<usize>,
+    // bumped every time the choker or super-seeding changes what should be
+    // sent to a peer
+    peer_updates: watch::Sender<()>,
+    limits: Arc<TransferLimits>,
+    // one permit per connected peer
+    connection_slots: Arc<Semaphore>,
 }
 
 impl SharedTorrentState {
     pub fn new(state: TorrentState) -> Self {
-        SharedTorrentState { mutex: Mutex::new(state)}
+        let (completed, _) = watch::channel(state.completed_pieces());
+        let (peer_updates, _) = watch::channel(());
+        SharedTorrentState {
+            peer_updates,
+            limits: Arc::new(TransferLimits::default()),
+            connection_slots: Arc::new(Semaphore::new(state.max_connections)),
+            mutex: Mutex::new(state),
+            completed,
+        }
+    }
+
+    pub fn connection_slots(&self) -> Arc<Semaphore> {
+        self.connection_slots.clone()
+    }
+
+    pub fn add_incoming_peer(&self, peer: Peer) -> Option<usize> {
+        self.lock().add_incoming_peer(peer)
+    }
+
+    pub fn web_seed(&self, peer_index: usize) -> Option<WebSeed> {
+        self.lock().peers[peer_index].web_seed.clone()
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.lock().is_private()
+    }
+
+    pub fn add_discovered_peers(&self, addrs: &[SocketAddr]) -> usize {
+        self.lock().add_discovered_peers(addrs)
+    }
+
+    pub fn subscribe_new_peers(&self) -> UnboundedReceiver<usize> {
+        self.lock().subscribe_new_peers()
+    }
+
+    pub fn set_discovering(&self, discovering: bool) {
+        self.lock().discovering = discovering;
+    }
+
+    pub fn is_discovering(&self) -> bool {
+        self.lock().discovering
+    }
+
+    /**
+     * Learn the listen port and preferences of a peer from its extended handshake.
+     */
+    pub fn set_peer_extended(&self, peer_index: usize, handshake: &ExtendedHandshake) {
+        let mut lock = self.lock();
+        let peer = &mut lock.peers[peer_index];
+        if let (Some(port), Ok(ip)) = (handshake.port, peer.peer_info.ip.parse()) {
+            peer.listen_addr = Some(SocketAddr::new(ip, port));
+        }
+        peer.encryption |= handshake.encryption;
+    }
+
+    /**
+     * Remember whether the peer is connected encrypted and over uTP, for PEX.
+     */
+    pub fn set_peer_transport(&self, peer_index: usize, encrypted: bool, utp: bool) {
+        let mut lock = self.lock();
+        let peer = &mut lock.peers[peer_index];
+        peer.encryption |= encrypted;
+        peer.utp = utp;
+    }
+
+    pub fn pex_peers(&self, exclude: usize) -> HashMap<SocketAddr, u8> {
+        self.lock().pex_peers(exclude)
+    }
+
+    /**
+     * Limits of the torrent and of one of its peers.
+     */
+    pub fn limits(&self, peer_index: usize) -> [Arc<TransferLimits>; 2] {
+        let peer = self.lock().peers[peer_index].limits.clone();
+        [self.limits.clone(), peer]
+    }
+
+    /**
+     * Change the limits of the torrent, connected peers pick them up on their
+     * next message.
+     */
+    pub fn set_rate_limits(&self, limits: RateLimits) {
+        self.limits.set(limits);
+    }
+
+    /**
+     * Change the limits of every peer, connected ones included.
+     */
+    pub fn set_peer_rate_limits(&self, limits: RateLimits) {
+        self.lock().set_peer_rate_limits(limits);
+    }
+
+    pub fn peer_count(&self) -> usize {
+        self.lock().peers.len()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, TorrentState> {
+        self.mutex.lock().expect("Error unable to lock mutex!")
+    }
+
+    pub fn info_hash(&self) -> Vec<u8> {
+        self.lock().info.info_hash.clone()
+    }
+
+    pub fn bitfield(&self) -> Vec<u8> {
+        self.lock().bitfield.clone()
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.lock().piece_count
+    }
+
+    /**
+     * Receiver notified every time a piece is verified.
+     */
+    pub fn subscribe_completed(&self) -> watch::Receiver<usize> {
+        self.completed.subscribe()
     }
 
-    pub fn get_handshake(&self, client_id: &str, peer_index: usize) -> Handshake {
-        let lock = self.mutex.lock().expect("Error unable to lock mutex!");
-        let handshake = Handshake::new(lock.info.info_hash.clone(), client_id);
-        return handshake;
+    pub fn is_seeding(&self) -> bool {
+        self.lock().seeding
+    }
+
+    /**
+     * (uploaded, downloaded, left) as reported to the tracker.
+     */
+    pub fn transfer_totals(&self) -> (u64, u64, i64) {
+        let lock = self.lock();
+        (lock.uploaded(), lock.downloaded(), lock.left())
+    }
+
+    pub fn get_handshake(&self, client_id: &str, extensions: Extensions) -> Handshake {
+        let lock = self.lock();
+        Handshake::new(lock.info.info_hash.clone(), client_id).with_extensions(extensions)
     }
 
     pub fn get_ip_port(&self, peer_index: usize) -> (String, i32) {
-        let lock = self.mutex.lock().expect("Error unable to lock mutex!");
+        let lock = self.lock();
         let peer = &lock.peers[peer_index];
-        return (peer.peer_info.ip.clone(), peer.peer_info.port);
+        (peer.peer_info.ip.clone(), peer.peer_info.port)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.lock().is_complete()
+    }
+
+    pub fn piece_size(&self, index: usize) -> u32 {
+        self.lock().info.info_data.piece_size(index) as u32
+    }
+
+    pub fn metadata(&self) -> TorrentMetadata {
+        self.lock().info.info_data.clone()
+    }
+
+    /**
+     * Stop requesting pieces, peers disconnect on their next message.
+     */
+    pub fn pause(&self, reason: String) {
+        let mut lock = self.lock();
+        if lock.paused.is_none() {
+            lock.paused = Some(reason);
+        }
+    }
+
+    pub fn paused_reason(&self) -> Option<String> {
+        self.lock().paused.clone()
+    }
+
+    pub fn check_hash(&self, index: usize, data: &[u8]) -> bool {
+        check_piece(&self.lock().info.info_data, index, data)
+    }
+
+    pub fn set_peer_bitfield(&self, peer_index: usize, bitfield: &[u8]) {
+        let mut lock = self.lock();
+        let piece_count = lock.piece_count;
+        let mut propagated = false;
+        for index in 0..piece_count {
+            if bit_is_set(bitfield, index) {
+                propagated |= lock.set_peer_have(peer_index, index);
+            }
+        }
+        if propagated {
+            self.peer_updates.send_replace(());
+        }
+    }
+
+    pub fn set_peer_have(&self, peer_index: usize, index: usize) {
+        if self.lock().set_peer_have(peer_index, index) {
+            self.peer_updates.send_replace(());
+        }
+    }
+
+    pub fn set_peer_have_all(&self, peer_index: usize) {
+        let mut lock = self.lock();
+        let piece_count = lock.piece_count;
+        let mut propagated = false;
+        for index in 0..piece_count {
+            propagated |= lock.set_peer_have(peer_index, index);
+        }
+        if propagated {
+            self.peer_updates.send_replace(());
+        }
+    }
+
+    pub fn add_allowed_fast(&self, peer_index: usize, index: usize) {
+        let mut lock = self.lock();
+        if index < lock.piece_count {
+            lock.peers[peer_index].allowed_fast.insert(index);
+        }
+    }
+
+    pub fn is_allowed_fast(&self, peer_index: usize, index: usize) -> bool {
+        self.lock().peers[peer_index].allowed_fast.contains(&index)
+    }
+
+    pub fn is_granted_fast(&self, peer_index: usize, index: usize) -> bool {
+        self.lock().peers[peer_index].granted_fast.contains(&index)
+    }
+
+    pub fn grant_allowed_fast(&self, peer_index: usize) -> Vec<usize> {
+        self.lock().grant_allowed_fast(peer_index)
+    }
+
+    pub fn add_suggested(&self, peer_index: usize, index: usize) {
+        let mut lock = self.lock();
+        if index >= lock.piece_count {
+            return;
+        }
+        let suggested = &mut lock.peers[peer_index].suggested;
+        if !suggested.contains(&index) {
+            if suggested.len() == MAX_SUGGESTED_PIECES {
+                suggested.pop_front();
+            }
+            suggested.push_back(index);
+        }
+    }
+
+    pub fn is_super_seeding(&self) -> bool {
+        self.lock().is_super_seeding()
+    }
+
+    pub fn super_seed_piece(&self, peer_index: usize) -> Option<usize> {
+        self.lock().super_seed_piece(peer_index)
+    }
+
+    /**
+     * Forget the pieces of a disconnected peer.
+     */
+    pub fn peer_disconnected(&self, peer_index: usize) {
+        let mut lock = self.lock();
+        let bitfield = std::mem::take(&mut lock.peers[peer_index].bitfield);
+        for index in 0..lock.piece_count {
+            if bit_is_set(&bitfield, index) {
+                lock.picker.remove_availability(index);
+            }
+        }
+        lock.peers[peer_index].bitfield = vec![0x00; bitfield.len()];
+        let peer = &mut lock.peers[peer_index];
+        peer.offered = None;
+        peer.connected_at = None;
+        peer.is_choked = true;
+        peer.is_interested = false;
+    }
+
+    pub fn peer_connected(&self, peer_index: usize) {
+        self.lock().peer_connected(peer_index);
+    }
+
+    pub fn set_client_choked(&self, peer_index: usize, choked: bool) {
+        self.lock().peers[peer_index].client_choked = choked;
+    }
+
+    pub fn set_client_interested(&self, peer_index: usize, interested: bool) {
+        self.lock().peers[peer_index].client_interested = interested;
+    }
+
+    pub fn set_peer_interested(&self, peer_index: usize, interested: bool) {
+        self.lock().set_peer_interested(peer_index, interested);
+    }
+
+    pub fn is_peer_choked(&self, peer_index: usize) -> bool {
+        self.lock().peers[peer_index].is_choked
+    }
+
+    pub fn rechoke(&self) {
+        if self.lock().rechoke() {
+            self.peer_updates.send_replace(());
+        }
+    }
+
+    pub fn subscribe_peer_updates(&self) -> watch::Receiver<()> {
+        self.peer_updates.subscribe()
+    }
+
+    pub fn check_request(
+        &self,
+        peer_index: usize,
+        index: usize,
+        begin: u32,
+        length: u32,
+    ) -> Result<bool, String> {
+        self.lock().check_request(peer_index, index, begin, length)
+    }
+
+    pub fn peer_is_seed(&self, peer_index: usize) -> bool {
+        self.lock().peer_is_seed(peer_index)
+    }
+
+    /**
+     * Assign the next piece the peer can provide to it.
+     */
+    pub fn claim_piece(&self, peer_index: usize, choked: bool) -> Option<usize> {
+        let mut lock = self.lock();
+        let index = lock.next_piece_for_peer(peer_index, choked)?;
+        lock.in_progress.insert(index);
+        Some(index)
+    }
+
+    pub fn release_piece(&self, index: usize) {
+        self.lock().in_progress.remove(&index);
+    }
+
+    /**
+     * Mark a verified piece as downloaded, returns (completed, total).
+     */
+    pub fn finish_piece(&self, index: usize) -> (usize, usize) {
+        let mut lock = self.lock();
+        lock.finish_piece(index);
+        let completed = lock.completed_pieces();
+        self.completed.send_replace(completed);
+        (completed, lock.piece_count)
+    }
+
+    pub fn has_piece(&self, index: usize) -> bool {
+        self.lock().check_piece(index)
+    }
+
+    /**
+     * Resolve once the piece at `index` is downloaded and verified.
+     */
+    pub async fn wait_for_piece(&self, index: usize) {
+        let mut completed = self.completed.subscribe();
+        while !self.has_piece(index) {
+            if completed.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    pub fn record_download(&self, peer_index: usize, bytes: u64) {
+        let mut lock = self.lock();
+        let peer = &mut lock.peers[peer_index];
+        peer.download_rate.record(bytes);
+        peer.last_block = Some(Instant::now());
+    }
+
+    pub fn record_upload(&self, peer_index: usize, bytes: u64) {
+        let mut lock = self.lock();
+        lock.peers[peer_index].upload_rate.record(bytes);
+        lock.last_upload = Some(Instant::now());
+    }
+
+    pub fn subscribe_seed_events(&self) -> UnboundedReceiver<SeedEvent> {
+        self.lock().subscribe_seed_events()
+    }
+
+    pub fn check_seed_goals(&self, global: SeedGoals) -> Option<SeedEvent> {
+        self.lock().check_seed_goals(global)
+    }
+
+    pub fn boost_pieces(&self, range: Range<usize>) {
+        self.lock().boost_pieces(range);
+    }
+
+    pub fn release_pieces(&self, range: Range<usize>) {
+        self.lock().release_pieces(range);
+    }
+
+    pub fn set_piece_deadline(&self, index: usize, deadline: Duration) {
+        self.lock().set_piece_deadline(index, deadline);
+    }
+
+    pub fn subscribe_deadlines(&self) -> UnboundedReceiver<DeadlineEvent> {
+        self.lock().subscribe_deadlines()
+    }
+
+    pub fn check_deadlines(&self) {
+        self.lock().check_deadlines();
+    }
+
+    pub fn seek(&self, index: usize) {
+        self.lock().seek(index);
+    }
+}
+
+// A piece being assembled from blocks requested from a single peer
+struct PieceDownload {
+    index: usize,
+    size: u32,
+    data: Vec<u8>,
+    received: Vec<bool>,
+    requested: Vec<bool>,
+}
+
+impl PieceDownload {
+    fn new(index: usize, size: u32) -> Self {
+        let blocks = size.div_ceil(BLOCK_SIZE) as usize;
+        PieceDownload {
+            index,
+            size,
+            data: vec![0; size as usize],
+            received: vec![false; blocks],
+            requested: vec![false; blocks],
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.received.iter().all(|r| *r)
+    }
+
+    /**
+     * Blocks requested and neither received nor rejected yet.
+     */
+    fn pending(&self) -> usize {
+        self.requested
+            .iter()
+            .zip(&self.received)
+            .filter(|(requested, received)| **requested && !**received)
+            .count()
+    }
+
+    fn next_block(&self) -> Option<usize> {
+        (0..self.received.len()).find(|b| !self.requested[*b] && !self.received[*b])
+    }
+
+    /**
+     * Request a rejected block again.
+     */
+    fn reject(&mut self, begin: u32) {
+        let block = (begin / BLOCK_SIZE) as usize;
+        if let Some(requested) = self.requested.get_mut(block) {
+            *requested = false;
+        }
+    }
+}
+
+// A block requested by a peer: <index><begin><length>
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BlockRequest {
+    index: u32,
+    begin: u32,
+    length: u32,
+}
+
+impl BlockRequest {
+    fn message(&self, id: MessageId) -> Message {
+        Message::block(id, self.index, self.begin, self.length)
+    }
+
+    fn from_message(message: &Message) -> Option<Self> {
+        Some(BlockRequest {
+            index: message.payload_u32(0)?,
+            begin: message.payload_u32(4)?,
+            length: message.payload_u32(8)?,
+        })
+    }
+}
+
+/**
+ * Bytes of a message counted against the rate limits: the piece data, or the
+ * whole message with its header when counting protocol overhead.
+ */
+fn counted_bytes(message: &Message, count_overhead: bool) -> u64 {
+    if count_overhead {
+        return 4 + message.length as u64;
+    }
+    match message.id {
+        Some(MessageId::Piece) => message
+            .payload
+            .as_ref()
+            .map_or(0, |p| p.len().saturating_sub(8)) as u64,
+        _ => 0,
+    }
+}
+
+/**
+ * Wait until every limiter allows transferring `bytes`.
+ */
+async fn throttle<'a>(limiters: impl Iterator<Item = &'a RateLimiter>, bytes: u64) {
+    if bytes == 0 {
+        return;
+    }
+    let wait = limiters.map(|l| l.reserve(bytes)).max().unwrap_or_default();
+    if !wait.is_zero() {
+        tokio::time::sleep(wait).await;
+    }
+}
+
+// Sends messages to a peer within the upload limits of the session, the
+// torrent and the peer
+struct PeerWriter<'a> {
+    writer: MessageWriter,
+    limits: &'a [Arc<TransferLimits>],
+    session: &'a Session,
+}
+
+impl PeerWriter<'_> {
+    async fn send(&mut self, message: &Message) -> Result<(), Box<dyn Error>> {
+        let bytes = counted_bytes(message, self.session.count_overhead());
+        throttle(self.limits.iter().map(|l| &l.upload), bytes).await;
+        self.writer.send_messsage_to_peer(message).await
+    }
+}
+
+/**
+ * Send `have` for the pieces we got since they were last announced to the
+ * peer. While super-seeding pieces are revealed one at a time instead.
+ */
+async fn announce_pieces(
+    state: &SharedTorrentState,
+    writer: &mut PeerWriter<'_>,
+    peer_index: usize,
+    announced: &mut [u8],
+) -> Result<(), Box<dyn Error>> {
+    let pieces: Vec<usize> = if state.is_super_seeding() {
+        state.super_seed_piece(peer_index).into_iter().collect()
+    } else {
+        let bitfield = state.bitfield();
+        (0..state.piece_count())
+            .filter(|i| bit_is_set(&bitfield, *i) && !bit_is_set(announced, *i))
+            .collect()
+    };
+    for index in pieces {
+        writer
+            .send(&Message::piece_index(MessageId::Have, index as u32))
+            .await?;
+        set_bit(announced, index, true);
+    }
+    Ok(())
+}
+
+async fn connect_to_peer(
+    session: Arc<Session>,
+    state: Arc<SharedTorrentState>,
+    storage: Arc<Storage>,
+    peer_index: usize,
+) -> Result<(), Box<dyn Error>> {
+    let handshake =
+        state.get_handshake(session.client_id(), session.extensions(state.is_private()));
+    let (ip, port) = state.get_ip_port(peer_index);
+    let mut peer_connection = PeerConnection::connect(
+        ip,
+        port,
+        handshake.get_hash(),
+        session.encryption(),
+        session.utp(),
+        session.proxy(),
+    )
+    .await?;
+    peer_connection.handshake_with_peer(&handshake).await?;
+    let peer_handshake = peer_connection.read_handshake().await?;
+    if peer_handshake.get_hash() != handshake.get_hash() {
+        return Err("Peer responded with a different info hash!".into());
+    }
+    let extensions = handshake.extensions().common(&peer_handshake.extensions());
+    handle_peer(
+        session,
+        state,
+        storage,
+        peer_connection,
+        peer_index,
+        extensions,
+    )
+    .await
+}
+
+/**
+ * Exchange messages with a peer after the handshake, for both incoming and
+ * outgoing connections. The peer's pieces are forgotten when it disconnects.
+ */
+pub async fn handle_peer(
+    session: Arc<Session>,
+    state: Arc<SharedTorrentState>,
+    storage: Arc<Storage>,
+    peer_connection: PeerConnection,
+    peer_index: usize,
+    extensions: Extensions,
+) -> Result<(), Box<dyn Error>> {
+    state.set_peer_transport(
+        peer_index,
+        peer_connection.is_encrypted(),
+        peer_connection.is_utp(),
+    );
+    let mut current = None;
+    let result = exchange_pieces(
+        &session,
+        &state,
+        &storage,
+        peer_connection,
+        peer_index,
+        extensions,
+        &mut current,
+    )
+    .await;
+    if let Some(piece) = current {
+        state.release_piece(piece.index);
+    }
+    state.peer_disconnected(peer_index);
+    result
+}
+
+/**
+ * Store a block of the piece being downloaded, the piece is checked and
+ * written once all of its blocks arrived.
+ */
+fn receive_block(
+    state: &SharedTorrentState,
+    storage: &Storage,
+    current: &mut Option<PieceDownload>,
+    peer_index: usize,
+    message: &Message,
+) -> Result<(), Box<dyn Error>> {
+    let (index, begin) = match (message.payload_u32(0), message.payload_u32(4)) {
+        (Some(i), Some(b)) => (i as usize, b),
+        _ => return Ok(()),
+    };
+    let piece = match current.as_mut() {
+        Some(p) if p.index == index => p,
+        _ => return Ok(()),
+    };
+    let block = &message.payload.as_ref().unwrap()[8..];
+    let block_index = (begin / BLOCK_SIZE) as usize;
+    if begin as usize + block.len() > piece.data.len() || block_index >= piece.received.len() {
+        return Ok(());
+    }
+    piece.data[(begin as usize)..(begin as usize + block.len())].copy_from_slice(block);
+    state.record_download(peer_index, block.len() as u64);
+    piece.received[block_index] = true;
+    if !piece.is_done() {
+        return Ok(());
+    }
+
+    let piece = current.take().unwrap();
+    store_piece(state, storage, piece.index, &piece.data)?;
+    Ok(())
+}
+
+/**
+ * Check a downloaded piece and write it, the piece is released for another
+ * peer if it is corrupt. Returns whether the piece was valid.
+ */
+pub fn store_piece(
+    state: &SharedTorrentState,
+    storage: &Storage,
+    index: usize,
+    data: &[u8],
+) -> Result<bool, Box<dyn Error>> {
+    if !state.check_hash(index, data) {
+        println!("piece {} failed hash check", index);
+        state.release_piece(index);
+        return Ok(false);
+    }
+    if let Err(e) = storage.write_piece(index, data) {
+        state.release_piece(index);
+        if is_disk_full(&e) {
+            state.pause(format!("disk full while writing piece {}: {}", index, e));
+            return Ok(true);
+        }
+        return Err(Box::new(e));
     }
+    let (completed, total) = state.finish_piece(index);
+    println!("downloaded piece {} ({}/{})", index, completed, total);
+    Ok(true)
+}
+
+async fn exchange_pieces(
+    session: &Arc<Session>,
+    state: &SharedTorrentState,
+    storage: &Arc<Storage>,
+    peer_connection: PeerConnection,
+    peer_index: usize,
+    extensions: Extensions,
+    current: &mut Option<PieceDownload>,
+) -> Result<(), Box<dyn Error>> {
+    let (mut reader, writer) = peer_connection.split();
+    let mut limits = vec![session.limits()];
+    limits.extend(state.limits(peer_index));
+    let mut writer = PeerWriter {
+        writer,
+        limits: &limits,
+        session,
+    };
+    // read on a separate task so that reading a message is never interrupted
+    // halfway by a `have` or an upload, the task is aborted when `reader_task` drops
+    let (message_sender, mut messages) = unbounded_channel();
+    let mut reader_task = JoinSet::new();
+    let (reader_limits, reader_session) = (limits.clone(), session.clone());
+    reader_task.spawn(async move {
+        loop {
+            let message = reader.read_message().await.map_err(|e| e.to_string());
+            if let Ok(m) = &message {
+                // not reading the next message slows down the peer
+                let bytes = counted_bytes(m, reader_session.count_overhead());
+                throttle(reader_limits.iter().map(|l| &l.download), bytes).await;
+            }
+            let failed = message.is_err();
+            if message_sender.send(message).is_err() || failed {
+                return;
+            }
+        }
+    });
 
-    pub fn get_required_piece(&self) -> Option<usize> {
-        let lock = self.mutex.lock().expect("Error unable to lock mutex!");
-        return lock.get_next_required_piece();
+    let mut completed = state.subscribe_completed();
+    let mut peer_updates = state.subscribe_peer_updates();
+    state.peer_connected(peer_index);
+    // pieces the peer knows we have
+    let mut announced = state.bitfield();
+    let piece_count = state.piece_count();
+    if state.is_super_seeding() {
+        // look like a peer without pieces
+        announced.fill(0x00);
+    }
+    let pieces = (0..piece_count)
+        .filter(|i| bit_is_set(&announced, *i))
+        .count();
+    // with the fast extension one of bitfield, have all or have none is required
+    if extensions.fast && pieces == 0 {
+        writer
+            .send(&Message::new(MessageId::HaveNone, None))
+            .await?;
+    } else if extensions.fast && pieces == piece_count {
+        writer.send(&Message::new(MessageId::HaveAll, None)).await?;
+    } else if pieces > 0 {
+        writer
+            .send(&Message::new(MessageId::Bitfield, Some(announced.clone())))
+            .await?;
+    }
+    if extensions.fast && !state.is_super_seeding() {
+        for index in state.grant_allowed_fast(peer_index) {
+            writer
+                .send(&Message::piece_index(MessageId::AllowedFast, index as u32))
+                .await?;
+        }
+    }
+    announce_pieces(state, &mut writer, peer_index, &mut announced).await?;
+    let pex_enabled = extensions.extended && !state.is_private();
+    if extensions.extended {
+        let mut handshake = ExtendedHandshake::new(pex_enabled, session.listen_port());
+        handshake.encryption = session.encryption() != EncryptionMode::Disable;
+        // lets the peer learn its external address
+        handshake.yourip = state.get_ip_port(peer_index).0.parse().ok();
+        writer
+            .send(&Message::extended(
+                EXTENDED_HANDSHAKE,
+                &handshake.to_bencode().map_err(|e| e.to_string())?,
+            ))
+            .await?;
+    }
+    let dht = session.dht().filter(|_| extensions.dht);
+    if let Some(dht) = &dht {
+        // the peer adds our DHT node to its routing table
+        let port = dht.local_addr()?.port();
+        writer
+            .send(&Message::new(
+                MessageId::Port,
+                Some(port.to_be_bytes().to_vec()),
+            ))
+            .await?;
+    }
+    // id of ut_pex at the peer once its extended handshake arrived
+    let mut pex_id = None;
+    let mut pex = PexState::default();
+    let mut pex_timer = interval(PEX_INTERVAL);
+    let mut pex_received: Option<Instant> = None;
+
+    let mut uploads: VecDeque<BlockRequest> = VecDeque::new();
+    let mut interested = false;
+    let mut choked = true;
+    // whether we choke the peer, as last sent to it
+    let mut peer_choked = true;
+    loop {
+        if state.paused_reason().is_some() {
+            return Ok(());
+        }
+        let complete = state.is_complete();
+        if complete && state.peer_is_seed(peer_index) {
+            // neither side has anything the other wants
+            return Ok(());
+        }
+        if complete == interested {
+            interested = !complete;
+            let id = if interested {
+                MessageId::Interested
+            } else {
+                MessageId::NotInterested
+            };
+            writer.send(&Message::new(id, None)).await?;
+            state.set_client_interested(peer_index, interested);
+        }
+        if state.is_peer_choked(peer_index) != peer_choked {
+            peer_choked = !peer_choked;
+            let id = if peer_choked {
+                // requests are dropped on choke, the peer requests them again;
+                // with the fast extension every dropped request is rejected
+                let (kept, dropped) = uploads.drain(..).partition(|r| {
+                    extensions.fast && state.is_granted_fast(peer_index, r.index as usize)
+                });
+                uploads = kept;
+                if extensions.fast {
+                    for r in Vec::from(dropped) {
+                        writer.send(&r.message(MessageId::RejectRequest)).await?;
+                    }
+                }
+                MessageId::Choke
+            } else {
+                MessageId::Unchoke
+            };
+            writer.send(&Message::new(id, None)).await?;
+        }
+        if choked {
+            // once every request of a choked piece is answered it is given back
+            let stalled = current
+                .as_ref()
+                .is_some_and(|p| p.pending() == 0 && !state.is_allowed_fast(peer_index, p.index));
+            if stalled {
+                state.release_piece(current.take().unwrap().index);
+            }
+        }
+        if interested && (!choked || extensions.fast) {
+            if current.is_none() {
+                *current = state
+                    .claim_piece(peer_index, choked)
+                    .map(|i| PieceDownload::new(i, state.piece_size(i)));
+            }
+            if let Some(piece) = current.as_mut() {
+                let allowed = !choked || state.is_allowed_fast(peer_index, piece.index);
+                while allowed && piece.pending() < MAX_PENDING_REQUESTS {
+                    let block = match piece.next_block() {
+                        Some(b) => b,
+                        None => break,
+                    };
+                    let begin = block as u32 * BLOCK_SIZE;
+                    let length = BLOCK_SIZE.min(piece.size - begin);
+                    let request =
+                        Message::block(MessageId::Request, piece.index as u32, begin, length);
+                    writer.send(&request).await?;
+                    piece.requested[block] = true;
+                }
+            }
+        }
+
+        tokio::select! {
+            // handle incoming messages first so that a `cancel` arrives
+            // before the block it cancels is sent
+            biased;
+            message = messages.recv() => {
+                let message = match message {
+                    Some(Ok(m)) => m,
+                    Some(Err(e)) => return Err(e.into()),
+                    None => return Err("connection closed".into()),
+                };
+                match message.id {
+                    Some(MessageId::Choke) => {
+                        choked = true;
+                        state.set_client_choked(peer_index, true);
+                        // without the fast extension outstanding requests are
+                        // dropped silently, with it each one gets rejected
+                        if !extensions.fast {
+                            if let Some(piece) = current.take() {
+                                state.release_piece(piece.index);
+                            }
+                        }
+                    }
+                    Some(MessageId::Unchoke) => {
+                        choked = false;
+                        state.set_client_choked(peer_index, false);
+                    }
+                    Some(MessageId::Interested) => state.set_peer_interested(peer_index, true),
+                    Some(MessageId::NotInterested) => state.set_peer_interested(peer_index, false),
+                    Some(MessageId::Have) => {
+                        if let Some(index) = message.payload_u32(0) {
+                            state.set_peer_have(peer_index, index as usize);
+                        }
+                    }
+                    Some(MessageId::Bitfield) => {
+                        state.set_peer_bitfield(peer_index, message.payload.as_deref().unwrap_or(&[]));
+                    }
+                    Some(MessageId::Request) => {
+                        let request = BlockRequest::from_message(&message)
+                            .ok_or("malformed request")?;
+                        let servable = state.check_request(
+                            peer_index,
+                            request.index as usize,
+                            request.begin,
+                            request.length,
+                        )?;
+                        if servable && uploads.len() < MAX_QUEUED_UPLOADS {
+                            uploads.push_back(request);
+                        } else if extensions.fast {
+                            writer.send(&request.message(MessageId::RejectRequest)).await?;
+                        }
+                    }
+                    Some(MessageId::Cancel) => {
+                        if let Some(request) = BlockRequest::from_message(&message) {
+                            let queued = uploads.len();
+                            uploads.retain(|r| *r != request);
+                            // with the fast extension a cancel is answered
+                            // with the piece or a reject
+                            if extensions.fast && uploads.len() < queued {
+                                writer.send(&request.message(MessageId::RejectRequest)).await?;
+                            }
+                        }
+                    }
+                    Some(MessageId::RejectRequest) => {
+                        if let Some(request) = BlockRequest::from_message(&message) {
+                            if let Some(piece) = current.as_mut().filter(|p| p.index == request.index as usize) {
+                                piece.reject(request.begin);
+                            }
+                        }
+                    }
+                    Some(MessageId::HaveAll) => state.set_peer_have_all(peer_index),
+                    Some(MessageId::HaveNone) => {}
+                    Some(MessageId::AllowedFast) => {
+                        if let Some(index) = message.payload_u32(0) {
+                            state.add_allowed_fast(peer_index, index as usize);
+                        }
+                    }
+                    Some(MessageId::SuggestPiece) => {
+                        if let Some(index) = message.payload_u32(0) {
+                            state.add_suggested(peer_index, index as usize);
+                        }
+                    }
+                    Some(MessageId::Piece) => {
+                        receive_block(state, storage, current, peer_index, &message)?;
+                    }
+                    Some(MessageId::Port) => {
+                        let port = message.payload.as_deref().and_then(|p| p.get(..2));
+                        let ip = state.get_ip_port(peer_index).0.parse::<IpAddr>();
+                        if let (Some(dht), Some(port), Ok(ip)) = (&dht, port, ip) {
+                            // the node is added to the routing table if it answers
+                            let (dht, addr) = (dht.clone(), SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]])));
+                            tokio::spawn(async move { dht.ping(addr).await });
+                        }
+                    }
+                    Some(MessageId::Extended) => {
+                        let payload = message.payload.as_deref().unwrap_or(&[]);
+                        match payload.first() {
+                            Some(&EXTENDED_HANDSHAKE) => {
+                                let handshake = ExtendedHandshake::from_bencode(&payload[1..])
+                                    .map_err(|e| format!("malformed extended handshake: {}", e))?;
+                                state.set_peer_extended(peer_index, &handshake);
+                                let peer_ip = state.get_ip_port(peer_index).0.parse::<IpAddr>();
+                                if let (Some(dht), Some(ip), Ok(voter)) = (session.dht(), handshake.yourip, peer_ip) {
+                                    dht.vote_external_ip(ip, Some(voter));
+                                }
+                                if pex_enabled {
+                                    pex_id = handshake.pex;
+                                }
+                            }
+                            Some(&UT_PEX) if pex_enabled => {
+                                // peers sending more than once a minute are ignored
+                                let flooding = pex_received
+                                    .is_some_and(|t| t.elapsed() < PEX_INTERVAL / 2);
+                                if let (false, Ok(m)) = (flooding, PexMessage::from_bencode(&payload[1..])) {
+                                    pex_received = Some(Instant::now());
+                                    let added: Vec<SocketAddr> =
+                                        m.added.iter().take(MAX_PEX_PEERS).map(|(a, _)| *a).collect();
+                                    state.add_discovered_peers(&added);
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ = completed.changed() => {
+                announce_pieces(state, &mut writer, peer_index, &mut announced).await?;
+            }
+            _ = peer_updates.changed() => {
+                announce_pieces(state, &mut writer, peer_index, &mut announced).await?;
+            }
+            _ = pex_timer.tick(), if pex_id.is_some() => {
+                if let Some(m) = pex.update(&state.pex_peers(peer_index)) {
+                    writer
+                        .send(&Message::extended(pex_id.unwrap(), &m.to_bencode().map_err(|e| e.to_string())?))
+                        .await?;
+                }
+            }
+            _ = std::future::ready(()), if !uploads.is_empty() => {
+                let request = uploads.pop_front().unwrap();
+                let offset = request.index as u64 * storage.piece_length() + request.begin as u64;
+                let length = request.length as u64;
+                // reads hit the disk, keep them off the runtime threads
+                let disk = storage.clone();
+                let block = tokio::task::spawn_blocking(move || disk.read(offset, length)).await??;
+                writer
+                    .send(&Message::piece(request.index, request.begin, &block))
+                    .await?;
+                state.record_upload(peer_index, block.len() as u64);
+            }
+        }
     }
 }
 
-pub async fn create_queue(state: TorrentState, client_id: String) {
-    let total_peers: usize = state.peers.len();
-    let connections: usize = if total_peers < 100 { total_peers } else { 100 };
-    println!("total connections: {}", connections);
-    let state = Arc::new(SharedTorrentState::new(state));
+/**
+ * Download every missing piece from the peers in `state`.
+ * Returns once the torrent is complete or every peer has disconnected,
+ * or an error if the torrent had to be paused. A seeding torrent keeps
+ * serving peers after completing until one of its seeding goals is reached.
+ */
+pub async fn create_queue(
+    session: Arc<Session>,
+    state: Arc<SharedTorrentState>,
+    storage: Arc<Storage>,
+) -> Result<(), Box<dyn Error>> {
+    let total_peers: usize = state.peer_count();
+    println!("total peers: {}", total_peers);
+    let mut tasks = JoinSet::new();
+    let mut deadline_timer = interval(Duration::from_secs(1));
+    let mut choke_timer = interval(RECHOKE_INTERVAL);
+    let mut new_peers = state.subscribe_new_peers();
 
-    for i in 0..(connections - 1) {
+    let spawn_peer = |tasks: &mut JoinSet<()>, i: usize| {
         let shared_state = state.clone();
-        let shared_id = client_id.clone();
-        tokio::spawn(async move {
-                let handshake = shared_state.get_handshake(&shared_id, i);
-                let (ip, port) = shared_state.get_ip_port(i);
-                let mut peer_connection = PeerConnection::new(ip, port).await.unwrap();
-                peer_connection.handshake_with_peer(&handshake).await;
-                let piece_index = shared_state.get_required_piece();
-//                 let parsed_res = tracker::Message::read(res.unwrap());
-//                 match parsed_res.unwrap().id {
-//                     Some(id) => println!("recieved message {:?} from ip: {}", id, ip),
-//                     None => println!("did not recieve message")
-//                 }
+        let shared_storage = storage.clone();
+        let shared_session = session.clone();
+        tasks.spawn(async move {
+            // wait for a free slot in both the torrent and the global limit
+            let _torrent_slot = shared_state.connection_slots().acquire_owned().await;
+            let _global_slot = shared_session.connection_slots().acquire_owned().await;
+            let (ip, port) = shared_state.get_ip_port(i);
+            if let Err(e) = connect_to_peer(shared_session, shared_state, shared_storage, i).await {
+                println!("peer {}:{} disconnected: {}", ip, port, e);
+            }
         });
+    };
+    for i in 0..total_peers {
+        match state.web_seed(i) {
+            Some(seed) => {
+                let (state, storage) = (state.clone(), storage.clone());
+                let proxy = session.proxy();
+                tasks.spawn(async move {
+                    if let Err(e) = webseed::download(&state, &storage, i, proxy.as_ref()).await {
+                        println!("web seed {} stopped: {}", seed, e);
+                    }
+                });
+            }
+            None => spawn_peer(&mut tasks, i),
+        }
+    }
+
+    loop {
+        tokio::select! {
+            _ = tasks.join_next(), if !tasks.is_empty() => {}
+            Some(i) = new_peers.recv() => spawn_peer(&mut tasks, i),
+            _ = deadline_timer.tick() => {
+                state.check_deadlines();
+                if let Some(event) = state.check_seed_goals(session.seed_goals()) {
+                    if event.action == GoalAction::Stop {
+                        session.remove_torrent(&state.info_hash());
+                    }
+                    tasks.abort_all();
+                    break;
+                }
+            }
+            _ = choke_timer.tick() => state.rechoke(),
+        }
+        let seeding = state.is_complete() && state.is_seeding();
+        if state.is_complete() && !seeding {
+            tasks.abort_all();
+            break;
+        }
+        // a seed waits for incoming peers, a torrent using the DHT for new ones
+        if tasks.is_empty() && !seeding && !state.is_discovering() {
+            break;
+        }
+        if let Some(reason) = state.paused_reason() {
+            tasks.abort_all();
+            return Err(format!("torrent paused: {}", reason).into());
+        }
     }
+    Ok(())
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
-    use crate::parse_torrent::torrent_info::TorrentMetadata;
+    use crate::{
+        parse_torrent::torrent_info::{FileInfo, TorrentMetadata},
+        seeding::SeedGoal,
+        storage::Allocation,
+    };
+
+    /**
+     * Torrent of `piece_count` pieces without piece hashes, shared by the
+     * tests that only care about the piece layout.
+     */
+    pub(crate) fn test_torrent(piece_count: usize, piece_length: i32) -> TorrentInfo {
+        TorrentInfo {
+            announce: String::from(""),
+            comment: String::from(""),
+            creation_date: 0,
+            created_by: String::from(""),
+            url_list: vec![],
+            http_seeds: vec![],
+            nodes: vec![],
+            info_data: TorrentMetadata {
+                pieces: vec![],
+                piece_length,
+                length: piece_count as i64 * piece_length as i64,
+                name: String::from(""),
+                private: false,
+                files: None,
+            },
+            info_hash: vec![],
+        }
+    }
 
     #[test]
     fn bitfield_set() {
         let peerlist = PeerList {
             interval: 0,
             peers: vec![],
+            external_ip: None,
         };
 
         let t_metadata = TorrentMetadata {
@@ -180,6 +2041,8 @@ mod tests {
             piece_length: 2,
             length: 48,
             name: String::from(""),
+            private: false,
+            files: None,
         };
 
         let torrent_info = TorrentInfo {
@@ -188,6 +2051,8 @@ mod tests {
             creation_date: 0,
             created_by: String::from(""),
             url_list: vec![],
+            http_seeds: vec![],
+            nodes: vec![],
             info_data: t_metadata,
             info_hash: vec![],
         };
@@ -204,4 +2069,408 @@ mod tests {
         assert_eq!(torrent_queue.bitfield[2], 0x02);
         assert!(!torrent_queue.check_piece(23));
     }
+
+    #[test]
+    fn file_priorities() {
+        let mut torrent_info = test_torrent(3, 4);
+        torrent_info.info_data.files = Some(vec![
+            FileInfo {
+                length: 3,
+                path: vec![String::from("a")],
+            },
+            FileInfo {
+                length: 3,
+                path: vec![String::from("b")],
+            },
+            FileInfo {
+                length: 6,
+                path: vec![String::from("c")],
+            },
+        ]);
+        let mut state = TorrentState::new(torrent_info, &PeerList::default());
+        state.set_file_priorities(&[Priority::Skip, Priority::Low, Priority::High]);
+
+        // piece 0 is shared by the skipped `a` and the wanted `b`
+        assert_eq!(state.picker.priority(0), Priority::Low);
+        assert_eq!(state.picker.priority(1), Priority::High);
+        assert_eq!(state.picker.pick(|i| !state.check_piece(i)), Some(1));
+        assert_eq!(state.left(), 12);
+
+        state.set_file_priorities(&[Priority::Skip, Priority::Skip]);
+        assert_eq!(state.picker.priority(0), Priority::Skip);
+        assert_eq!(state.left(), 8);
+        state.set_bitfield_on(1);
+        state.set_bitfield_on(2);
+        assert!(state.is_complete());
+    }
+
+    #[test]
+    fn recheck_clears_failed_pieces() {
+        let mut state = TorrentState::new(test_torrent(3, 4), &PeerList::default());
+        state.set_bitfield_on(0);
+        state.set_bitfield_on(1);
+        state.load_verified(&VerifyReport {
+            statuses: vec![
+                PieceStatus::Valid,
+                PieceStatus::Corrupt,
+                PieceStatus::Missing,
+            ],
+        });
+        assert!(state.check_piece(0));
+        assert!(!state.check_piece(1) && !state.check_piece(2));
+        assert_eq!(state.completed_pieces(), 1);
+    }
+
+    #[test]
+    fn deadline_pieces() {
+        let torrent_info = test_torrent(4, 4);
+        let peers: Vec<Peer> = (0..5)
+            .map(|i| Peer {
+                ip: String::from("127.0.0.1"),
+                port: 6881 + i,
+            })
+            .collect();
+        let mut state = TorrentState::new(torrent_info, &PeerList::default());
+        state.add_peers(&peers);
+        let mut events = state.subscribe_deadlines();
+        for (i, rate) in [100, 200, 300, 400, 0].iter().enumerate() {
+            state.peers[i].download_rate.record(*rate);
+            for index in 0..4 {
+                state.set_peer_have(i, index);
+            }
+        }
+
+        state.set_piece_priority(0..2, Priority::High);
+        state.set_piece_deadline(3, Duration::from_secs(60));
+        state.set_piece_deadline(2, Duration::ZERO);
+        // only the three fastest peers get deadline pieces
+        assert_eq!(state.next_piece_for_peer(3, false), Some(2));
+        assert_eq!(state.next_piece_for_peer(1, false), Some(2));
+        assert_eq!(state.next_piece_for_peer(0, false), Some(0));
+        assert_eq!(state.next_piece_for_peer(4, false), Some(0));
+
+        state.check_deadlines();
+        state.check_deadlines();
+        state.finish_piece(2);
+        assert_eq!(events.try_recv(), Ok(DeadlineEvent::Missed(2)));
+        assert_eq!(events.try_recv(), Ok(DeadlineEvent::Completed(2)));
+        assert!(events.try_recv().is_err());
+        assert_eq!(state.next_piece_for_peer(3, false), Some(3));
+    }
+
+    #[tokio::test]
+    async fn serve_requests() {
+        let data = [7u8, 8, 9, 10, 11, 12];
+        let mut pieces = vec![];
+        for chunk in data.chunks(4) {
+            pieces.extend_from_slice(&sha1_smol::Sha1::from(chunk).digest().bytes());
+        }
+        let mut torrent_info = test_torrent(2, 4);
+        torrent_info.info_data.length = 6;
+        torrent_info.info_data.name = String::from("seed");
+        torrent_info.info_data.pieces = pieces;
+        let dir = std::env::temp_dir().join(format!("seed-test-{}", std::process::id()));
+        let storage = Arc::new(Storage::new(
+            &dir,
+            &torrent_info.info_data,
+            Allocation::Compact,
+        ));
+        storage.write(0, &data).unwrap();
+        let mut torrent_state = TorrentState::new(torrent_info, &PeerList::default());
+        torrent_state.set_bitfield_on(0);
+        torrent_state.set_bitfield_on(1);
+        torrent_state.set_seeding(true);
+        let state = Arc::new(SharedTorrentState::new(torrent_state));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (seed_state, seed_storage) = (state.clone(), storage.clone());
+        let seed = tokio::spawn(async move {
+            let (stream, addr) = listener.accept().await.unwrap();
+            let peer_index = seed_state
+                .add_incoming_peer(Peer {
+                    ip: addr.ip().to_string(),
+                    port: addr.port() as i32,
+                })
+                .unwrap();
+            let connection = PeerConnection::from_stream(stream, addr);
+            let session = Arc::new(Session::new(String::from("-RS0001-000000000000"), 10));
+            handle_peer(
+                session,
+                seed_state,
+                seed_storage,
+                connection,
+                peer_index,
+                Extensions::default(),
+            )
+            .await
+            .map_err(|e| e.to_string())
+        });
+
+        let client = PeerConnection::new(addr.ip().to_string(), addr.port() as i32, None)
+            .await
+            .unwrap();
+        let (mut reader, mut writer) = client.split();
+        let bitfield = reader.read_message().await.unwrap();
+        assert!(matches!(bitfield.id, Some(MessageId::Bitfield)));
+        assert_eq!(bitfield.payload, Some(vec![0xc0]));
+
+        // choked, the request is dropped
+        let request = Message::block(MessageId::Request, 1, 0, 2);
+        writer.send_messsage_to_peer(&request).await.unwrap();
+        let interested = Message::new(MessageId::Interested, None);
+        writer.send_messsage_to_peer(&interested).await.unwrap();
+        let unchoke = reader.read_message().await.unwrap();
+        assert!(matches!(unchoke.id, Some(MessageId::Unchoke)));
+
+        writer.send_messsage_to_peer(&request).await.unwrap();
+        let piece = reader.read_message().await.unwrap();
+        assert!(matches!(piece.id, Some(MessageId::Piece)));
+        assert_eq!(piece.payload, Some(vec![0, 0, 0, 1, 0, 0, 0, 0, 11, 12]));
+        assert_eq!(state.transfer_totals().0, 2);
+
+        // past the end of the last piece
+        let invalid = Message::block(MessageId::Request, 1, 1, 2);
+        writer.send_messsage_to_peer(&invalid).await.unwrap();
+        assert!(reader.read_message().await.is_err());
+        assert!(seed.await.unwrap().is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn live_rate_limits() {
+        let torrent_info = test_torrent(1, 4);
+        let state = SharedTorrentState::new(TorrentState::new(torrent_info, &PeerList::default()));
+        let peer_index = state
+            .add_incoming_peer(Peer {
+                ip: String::from("127.0.0.1"),
+                port: 6881,
+            })
+            .unwrap();
+        let [_, peer] = state.limits(peer_index);
+        let start = Instant::now();
+        throttle([&peer.upload].into_iter(), 1500).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        // the connected peer keeps its limiter, which picks up the new rate
+        state.set_peer_rate_limits(RateLimits {
+            upload: Some(1000),
+            download: None,
+            burst: None,
+        });
+
+        let block = Message::piece(0, 0, &[0; 1500]);
+        assert_eq!(counted_bytes(&block, false), 1500);
+        assert_eq!(counted_bytes(&block, true), 1513);
+        assert_eq!(
+            counted_bytes(&Message::new(MessageId::Unchoke, None), false),
+            0
+        );
+        let start = Instant::now();
+        throttle([&peer.upload].into_iter(), 1500).await;
+        assert!(start.elapsed() >= Duration::from_millis(400));
+    }
+
+    #[test]
+    fn seed_goal_pauses_torrent() {
+        let torrent_info = test_torrent(1, 4);
+        let mut state = TorrentState::new(torrent_info, &PeerList::default());
+        let mut events = state.subscribe_seed_events();
+        let global = SeedGoals {
+            ratio: Some(1.0),
+            ..Default::default()
+        };
+        state.set_seed_goals(SeedGoals {
+            seed_time: Some(Duration::ZERO),
+            action: Some(GoalAction::Pause),
+            ..Default::default()
+        });
+        // not complete, not seeding yet
+        assert_eq!(state.check_seed_goals(global), None);
+
+        state.set_bitfield_on(0);
+        let event = state.check_seed_goals(global).unwrap();
+        assert!(matches!(event.goal, SeedGoal::SeedTime(_)));
+        assert_eq!(event.action, GoalAction::Pause);
+        assert_eq!(events.try_recv(), Ok(event));
+        assert!(state.paused.is_some());
+        // reported once
+        assert_eq!(state.check_seed_goals(global), None);
+    }
+
+    #[test]
+    fn torrent_seed_goals_override_global() {
+        let torrent_info = test_torrent(1, 4);
+        let mut state = TorrentState::new(torrent_info, &PeerList::default());
+        let peer = state.add_peer(Peer {
+            ip: String::from("127.0.0.1"),
+            port: 6881,
+        });
+        state.set_bitfield_on(0);
+        state.peers[peer].upload_rate.record(8);
+        let global = SeedGoals {
+            ratio: Some(1.0),
+            action: Some(GoalAction::Pause),
+            ..Default::default()
+        };
+        state.set_seed_goals(SeedGoals {
+            ratio: Some(3.0),
+            ..Default::default()
+        });
+        // a ratio of 2 reaches the global goal but not the torrent's own
+        assert_eq!(state.check_seed_goals(global), None);
+
+        state.peers[peer].upload_rate.record(4);
+        let event = state.check_seed_goals(global).unwrap();
+        assert_eq!(event.goal, SeedGoal::Ratio(3.0));
+        // the action still comes from the session
+        assert_eq!(event.action, GoalAction::Pause);
+    }
+
+    #[test]
+    fn super_seeding() {
+        let torrent_info = test_torrent(3, 4);
+        let mut state = TorrentState::new(torrent_info, &PeerList::default());
+        for i in 0..3 {
+            let peer = state.add_peer(Peer {
+                ip: String::from("127.0.0.1"),
+                port: 6881 + i,
+            });
+            state.peer_connected(peer);
+            state.peers[peer].is_choked = false;
+        }
+        state.set_super_seeding(true);
+        assert_eq!(state.super_seed_piece(0), None);
+        for index in 0..3 {
+            state.set_bitfield_on(index);
+        }
+        state.set_bitfield_on(1);
+        assert_eq!(state.completed_pieces(), 3);
+        // piece 0 is already at peer 2, the rest is revealed once each
+        state.set_peer_have(2, 0);
+        assert_eq!(state.super_seed_piece(0), Some(1));
+        assert_eq!(state.super_seed_piece(1), Some(2));
+        assert_eq!(state.super_seed_piece(0), None);
+        assert_eq!(state.check_request(0, 1, 0, 4), Ok(true));
+        assert_eq!(state.check_request(0, 2, 0, 4), Ok(false));
+
+        // the peer itself getting the piece doesn't count
+        assert!(!state.set_peer_have(0, 1));
+        assert_eq!(state.super_seed_piece(0), None);
+        assert!(state.set_peer_have(2, 1));
+        // piece 2 was already revealed to peer 1
+        assert_eq!(state.super_seed_piece(0), Some(0));
+
+        state.set_super_seeding(false);
+        assert_eq!(state.check_request(1, 0, 0, 4), Ok(true));
+    }
+
+    #[test]
+    fn allowed_fast_pieces() {
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+        let info_hash = vec![0xaa; 20];
+        assert_eq!(
+            allowed_fast_set(ip, &info_hash, 1313, 7),
+            vec![1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            allowed_fast_set(ip, &info_hash, 1313, 9),
+            vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+        // the last byte of the address is ignored
+        assert_eq!(
+            allowed_fast_set(Ipv4Addr::new(80, 4, 4, 1), &info_hash, 1313, 7),
+            allowed_fast_set(ip, &info_hash, 1313, 7)
+        );
+        assert_eq!(allowed_fast_set(ip, &info_hash, 3, 10).len(), 3);
+
+        let mut torrent_info = test_torrent(4, 4);
+        torrent_info.info_hash = info_hash.clone();
+        let mut state = TorrentState::new(torrent_info, &PeerList::default());
+        let peer = state.add_peer(Peer {
+            ip: String::from("80.4.4.200"),
+            port: 6881,
+        });
+        state.peer_connected(peer);
+        state.set_bitfield_on(0);
+        state.set_bitfield_on(1);
+        // choked peers may only request the pieces granted to them
+        let granted = state.grant_allowed_fast(peer);
+        assert_eq!(granted.len(), 4);
+        assert_eq!(state.check_request(peer, 0, 0, 4), Ok(true));
+        state.peers[peer].granted_fast.remove(&0);
+        assert_eq!(state.check_request(peer, 0, 0, 4), Ok(false));
+
+        // while choked only allowed fast pieces are picked, suggestions first
+        for index in 2..4 {
+            state.set_peer_have(peer, index);
+        }
+        assert_eq!(state.next_piece_for_peer(peer, true), None);
+        state.peers[peer].allowed_fast.insert(2);
+        assert_eq!(state.next_piece_for_peer(peer, true), Some(2));
+        state.peers[peer].suggested.push_back(3);
+        assert_eq!(state.next_piece_for_peer(peer, false), Some(3));
+    }
+
+    #[test]
+    fn discovered_peers() {
+        let torrent_info = test_torrent(1, 4);
+        let peer_list = PeerList {
+            interval: 0,
+            peers: vec![Peer {
+                ip: String::from("10.0.0.1"),
+                port: 6881,
+            }],
+            external_ip: None,
+        };
+        let mut state = TorrentState::new(torrent_info, &peer_list);
+        let mut new_peers = state.subscribe_new_peers();
+        let incoming = state
+            .add_incoming_peer(Peer {
+                ip: String::from("10.0.0.2"),
+                port: 50123,
+            })
+            .unwrap();
+        let known: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        assert_eq!(state.add_discovered_peers(&[known, v6, v6]), 1);
+        assert_eq!(new_peers.try_recv(), Ok(2));
+        assert!(new_peers.try_recv().is_err());
+
+        // incoming peers are advertised once their listen port is known
+        for i in 0..3 {
+            state.peer_connected(i);
+        }
+        state.set_bitfield_on(0);
+        state.set_peer_have(0, 0);
+        let advertised = state.pex_peers(2);
+        assert_eq!(advertised.len(), 1);
+        assert_eq!(advertised[&known], PEX_SEED | PEX_OUTGOING);
+        state.peers[incoming].listen_addr = Some("10.0.0.2:6881".parse().unwrap());
+        state.peers[incoming].encryption = true;
+        assert_eq!(state.pex_peers(0).len(), 2);
+        assert_eq!(
+            state.pex_peers(0)[&"10.0.0.2:6881".parse().unwrap()],
+            PEX_ENCRYPTION
+        );
+
+        // capped in total
+        let many: Vec<SocketAddr> = (0..MAX_PEERS as u32)
+            .map(|i| SocketAddr::new(IpAddr::V4(Ipv4Addr::from(0x0b000000 + i)), 6881))
+            .collect();
+        assert_eq!(state.add_discovered_peers(&many), MAX_PEERS - 3);
+        assert_eq!(state.peers.len(), MAX_PEERS);
+
+        // incoming peers take the entry of a disconnected one
+        let other = Peer {
+            ip: String::from("10.0.0.3"),
+            port: 50124,
+        };
+        assert_eq!(state.add_incoming_peer(other.clone()), None);
+        state.peers[incoming].listen_addr = None;
+        state.peers[incoming].connected_at = None;
+        assert_eq!(state.add_incoming_peer(other), Some(incoming));
+        assert_eq!(state.peers[incoming].peer_info.port, 50124);
+        assert_eq!(state.peers.len(), MAX_PEERS);
+    }
 }
@@ -0,0 +1,215 @@
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// Transfer rate averaged over a sliding window of recent transfers, and
+// token buckets limiting it.
+
+const WINDOW: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub struct TransferRate {
+    total: u64,
+    samples: VecDeque<(Instant, u64)>,
+    created: Instant,
+}
+
+impl Default for TransferRate {
+    fn default() -> Self {
+        TransferRate {
+            total: 0,
+            samples: VecDeque::new(),
+            created: Instant::now(),
+        }
+    }
+}
+
+impl TransferRate {
+    pub fn record(&mut self, bytes: u64) {
+        let now = Instant::now();
+        self.total += bytes;
+        self.samples.push_back((now, bytes));
+        self.expire(now);
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((t, _)) = self.samples.front() {
+            if now.duration_since(*t) <= WINDOW {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    /**
+     * Bytes transferred since creation.
+     */
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /**
+     * Bytes per second over the window, or since creation if that's shorter.
+     */
+    pub fn rate(&self) -> f64 {
+        let now = Instant::now();
+        let elapsed = now
+            .duration_since(self.created)
+            .clamp(Duration::from_secs(1), WINDOW);
+        let bytes: u64 = self
+            .samples
+            .iter()
+            .filter(|(t, _)| now.duration_since(*t) <= WINDOW)
+            .map(|(_, b)| b)
+            .sum();
+        bytes as f64 / elapsed.as_secs_f64()
+    }
+}
+
+// Limits in bytes per second, `None` is unlimited. The burst defaults to one
+// second worth of the rate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimits {
+    pub upload: Option<u64>,
+    pub download: Option<u64>,
+    pub burst: Option<u64>,
+}
+
+struct Bucket {
+    rate: Option<u64>,
+    burst: u64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        self.tokens = match self.rate {
+            Some(rate) => {
+                let elapsed = now.duration_since(self.updated).as_secs_f64();
+                (self.tokens + elapsed * rate as f64).min(self.burst as f64)
+            }
+            // a limit set later starts with a full bucket
+            None => self.burst as f64,
+        };
+        self.updated = now;
+    }
+}
+
+// Token bucket: tokens are bytes, refilled at `rate` bytes per second up to
+// the burst. A transfer may take more tokens than are available, it then
+// waits until the debt is paid back, so transfers larger than the burst
+// still go through.
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::new(None, None)
+    }
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<u64>, burst: Option<u64>) -> Self {
+        let limiter = RateLimiter {
+            bucket: Mutex::new(Bucket {
+                rate: None,
+                burst: 0,
+                tokens: 0.0,
+                updated: Instant::now(),
+            }),
+        };
+        limiter.set_rate(rate, burst);
+        if let Ok(mut bucket) = limiter.bucket.lock() {
+            bucket.tokens = bucket.burst as f64;
+        }
+        limiter
+    }
+
+    /**
+     * Change the limit, transfers waiting on the old rate are not interrupted.
+     * A rate of 0 is unlimited.
+     */
+    pub fn set_rate(&self, rate: Option<u64>, burst: Option<u64>) {
+        let mut bucket = self.bucket.lock().expect("Error unable to lock mutex!");
+        bucket.refill(Instant::now());
+        bucket.rate = rate.filter(|r| *r > 0);
+        bucket.burst = burst.or(bucket.rate).unwrap_or(0).max(1);
+        bucket.tokens = bucket.tokens.min(bucket.burst as f64);
+    }
+
+    /**
+     * Take `bytes` tokens, returns how long to wait before transferring them.
+     */
+    pub fn reserve(&self, bytes: u64) -> Duration {
+        let mut bucket = self.bucket.lock().expect("Error unable to lock mutex!");
+        let rate = match bucket.rate {
+            Some(r) => r,
+            None => return Duration::ZERO,
+        };
+        bucket.refill(Instant::now());
+        bucket.tokens -= bytes as f64;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate as f64)
+        }
+    }
+}
+
+// Upload and download limiters of one level: the session, a torrent or a peer
+#[derive(Default)]
+pub struct TransferLimits {
+    pub upload: RateLimiter,
+    pub download: RateLimiter,
+}
+
+impl TransferLimits {
+    pub fn new(limits: RateLimits) -> Self {
+        TransferLimits {
+            upload: RateLimiter::new(limits.upload, limits.burst),
+            download: RateLimiter::new(limits.download, limits.burst),
+        }
+    }
+
+    pub fn set(&self, limits: RateLimits) {
+        self.upload.set_rate(limits.upload, limits.burst);
+        self.download.set_rate(limits.download, limits.burst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_over_window() {
+        let mut rate = TransferRate::default();
+        assert_eq!(rate.rate(), 0.0);
+        rate.record(1000);
+        rate.record(500);
+        assert_eq!(rate.total(), 1500);
+        // less than a second old, averaged over one second
+        assert_eq!(rate.rate(), 1500.0);
+    }
+
+    #[test]
+    fn token_bucket() {
+        let limiter = RateLimiter::new(Some(1000), None);
+        // the burst is available right away, then the debt is paid at the rate
+        assert_eq!(limiter.reserve(1000), Duration::ZERO);
+        let wait = limiter.reserve(500);
+        assert!(wait > Duration::from_millis(450) && wait <= Duration::from_millis(500));
+
+        limiter.set_rate(Some(0), None);
+        assert_eq!(limiter.reserve(1 << 30), Duration::ZERO);
+
+        // a transfer larger than the burst waits for the difference
+        limiter.set_rate(Some(100), Some(10));
+        let wait = limiter.reserve(110);
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_millis(1100));
+    }
+}
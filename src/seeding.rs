@@ -0,0 +1,163 @@
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+// When to stop seeding a completed torrent. Goals set on a torrent take
+// precedence over the session wide ones, the first goal reached ends seeding.
+// - ratio: uploaded bytes over downloaded bytes, the torrent size is used
+//   instead when less was downloaded, e.g. when seeding existing data
+// - seed time: time since the torrent completed
+// - idle: time without uploading to any peer since the torrent completed
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum GoalAction {
+    // disconnect the peers and remove the torrent from the session, so that
+    // incoming peers are turned away
+    #[default]
+    Stop,
+    // disconnect the peers but keep the torrent, e.g. for streaming
+    Pause,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SeedGoals {
+    pub ratio: Option<f64>,
+    pub seed_time: Option<Duration>,
+    pub idle: Option<Duration>,
+    pub action: Option<GoalAction>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SeedGoal {
+    Ratio(f64),
+    SeedTime(Duration),
+    Idle(Duration),
+}
+
+impl fmt::Display for SeedGoal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SeedGoal::Ratio(r) => write!(f, "share ratio {:.2}", r),
+            SeedGoal::SeedTime(t) => write!(f, "seeded for {}s", t.as_secs()),
+            SeedGoal::Idle(t) => write!(f, "no uploads for {}s", t.as_secs()),
+        }
+    }
+}
+
+// Emitted once when a goal is reached
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SeedEvent {
+    pub goal: SeedGoal,
+    pub action: GoalAction,
+}
+
+// Transfer counters of a torrent at the time goals are checked
+#[derive(Debug, Clone, Copy)]
+pub struct SeedStats {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub size: u64,
+    // when the torrent completed
+    pub seeding_since: Instant,
+    pub last_upload: Option<Instant>,
+}
+
+impl SeedGoals {
+    pub fn is_set(&self) -> bool {
+        self.ratio.is_some() || self.seed_time.is_some() || self.idle.is_some()
+    }
+
+    /**
+     * Fill the goals missing from `self` with those of `fallback`.
+     */
+    pub fn or(self, fallback: SeedGoals) -> SeedGoals {
+        SeedGoals {
+            ratio: self.ratio.or(fallback.ratio),
+            seed_time: self.seed_time.or(fallback.seed_time),
+            idle: self.idle.or(fallback.idle),
+            action: self.action.or(fallback.action),
+        }
+    }
+
+    /**
+     * First goal reached by a torrent with `stats`, if any.
+     */
+    pub fn reached(&self, stats: &SeedStats, now: Instant) -> Option<SeedGoal> {
+        if let Some(target) = self.ratio {
+            let ratio = stats.uploaded as f64 / stats.downloaded.max(stats.size).max(1) as f64;
+            if ratio >= target {
+                return Some(SeedGoal::Ratio(ratio));
+            }
+        }
+        let seeded = now.duration_since(stats.seeding_since);
+        if let Some(target) = self.seed_time {
+            if seeded >= target {
+                return Some(SeedGoal::SeedTime(seeded));
+            }
+        }
+        if let Some(target) = self.idle {
+            let idle = match stats.last_upload {
+                Some(t) if t > stats.seeding_since => now.duration_since(t),
+                _ => seeded,
+            };
+            if idle >= target {
+                return Some(SeedGoal::Idle(idle));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn goals_reached() {
+        let start = Instant::now();
+        let mut stats = SeedStats {
+            uploaded: 150,
+            downloaded: 0,
+            size: 100,
+            seeding_since: start,
+            last_upload: None,
+        };
+        let torrent = SeedGoals {
+            ratio: Some(2.0),
+            ..Default::default()
+        };
+        let global = SeedGoals {
+            ratio: Some(1.0),
+            seed_time: Some(Duration::from_secs(3600)),
+            idle: Some(Duration::from_secs(600)),
+            action: Some(GoalAction::Pause),
+        };
+        let goals = torrent.or(global);
+        assert_eq!(goals.ratio, Some(2.0));
+        assert_eq!(goals.action, Some(GoalAction::Pause));
+        assert!(goals.is_set());
+        assert_eq!(goals.reached(&stats, start), None);
+
+        stats.uploaded = 200;
+        assert_eq!(goals.reached(&stats, start), Some(SeedGoal::Ratio(2.0)));
+        // counted against the downloaded bytes when more than the size
+        stats.downloaded = 400;
+        assert_eq!(goals.reached(&stats, start), None);
+
+        stats.last_upload = Some(start + Duration::from_secs(300));
+        let now = start + Duration::from_secs(800);
+        assert_eq!(goals.reached(&stats, now), None);
+        let now = start + Duration::from_secs(900);
+        assert_eq!(
+            goals.reached(&stats, now),
+            Some(SeedGoal::Idle(Duration::from_secs(600)))
+        );
+        stats.last_upload = Some(start + Duration::from_secs(3000));
+        let now = start + Duration::from_secs(3600);
+        assert_eq!(
+            goals.reached(&stats, now),
+            Some(SeedGoal::SeedTime(Duration::from_secs(3600)))
+        );
+    }
+}
@@ -0,0 +1,333 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{net::TcpListener, sync::Semaphore, time::timeout};
+
+use crate::{
+    connect_tracker::tracker::{Extensions, PeerConnection},
+    dht::Dht,
+    mse::EncryptionMode,
+    parse_tracker_res::peers::Peer,
+    proxy::Proxy,
+    queue::{handle_peer, SharedTorrentState},
+    rate::{RateLimits, TransferLimits},
+    seeding::SeedGoals,
+    storage::Storage,
+    utp::Utp,
+};
+
+// Torrents running in this client, shared by everything that isn't tied to a
+// single torrent: incoming connections are routed to a torrent by the info
+// hash of their handshake, and the global connection limit spans all torrents.
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_MAX_CONNECTIONS: usize = 200;
+
+struct TorrentEntry {
+    state: Arc<SharedTorrentState>,
+    storage: Arc<Storage>,
+}
+
+pub struct Session {
+    client_id: String,
+    torrents: Mutex<HashMap<Vec<u8>, TorrentEntry>>,
+    // one permit per connected peer across all torrents
+    connection_slots: Arc<Semaphore>,
+    limits: Arc<TransferLimits>,
+    // count message headers and non-piece messages against the limits
+    count_overhead: AtomicBool,
+    // session wide goals, each can be overridden by a torrent
+    seed_goals: Mutex<SeedGoals>,
+    // port peers can connect to, 0 when not listening
+    listen_port: AtomicU16,
+    dht: Mutex<Option<Arc<Dht>>>,
+    encryption: Mutex<EncryptionMode>,
+    utp: Mutex<Option<Arc<Utp>>>,
+    // peers and web seeds are connected to through it
+    proxy: Mutex<Option<Proxy>>,
+}
+
+impl Session {
+    pub fn new(client_id: String, max_connections: usize) -> Self {
+        Session {
+            client_id,
+            torrents: Mutex::new(HashMap::new()),
+            connection_slots: Arc::new(Semaphore::new(max_connections)),
+            limits: Arc::new(TransferLimits::default()),
+            count_overhead: AtomicBool::new(false),
+            seed_goals: Mutex::new(SeedGoals::default()),
+            listen_port: AtomicU16::new(0),
+            dht: Mutex::new(None),
+            encryption: Mutex::new(EncryptionMode::default()),
+            utp: Mutex::new(None),
+            proxy: Mutex::new(None),
+        }
+    }
+
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    pub fn connection_slots(&self) -> Arc<Semaphore> {
+        self.connection_slots.clone()
+    }
+
+    pub fn limits(&self) -> Arc<TransferLimits> {
+        self.limits.clone()
+    }
+
+    /**
+     * Change the global limits, connected peers pick them up on their next message.
+     */
+    pub fn set_rate_limits(&self, limits: RateLimits) {
+        self.limits.set(limits);
+    }
+
+    pub fn count_overhead(&self) -> bool {
+        self.count_overhead.load(Ordering::Relaxed)
+    }
+
+    pub fn set_count_overhead(&self, count_overhead: bool) {
+        self.count_overhead.store(count_overhead, Ordering::Relaxed);
+    }
+
+    pub fn listen_port(&self) -> Option<u16> {
+        Some(self.listen_port.load(Ordering::Relaxed)).filter(|p| *p != 0)
+    }
+
+    pub fn set_listen_port(&self, port: u16) {
+        self.listen_port.store(port, Ordering::Relaxed);
+    }
+
+    pub fn dht(&self) -> Option<Arc<Dht>> {
+        self.dht
+            .lock()
+            .expect("Error unable to lock mutex!")
+            .clone()
+    }
+
+    pub fn set_dht(&self, dht: Option<Arc<Dht>>) {
+        *self.dht.lock().expect("Error unable to lock mutex!") = dht;
+    }
+
+    /**
+     * Extensions to advertise to the peers of a torrent, private torrents
+     * don't use the DHT.
+     */
+    pub fn extensions(&self, private: bool) -> Extensions {
+        Extensions {
+            dht: !private && self.dht().is_some(),
+            ..Extensions::supported()
+        }
+    }
+
+    pub fn utp(&self) -> Option<Arc<Utp>> {
+        self.utp
+            .lock()
+            .expect("Error unable to lock mutex!")
+            .clone()
+    }
+
+    pub fn set_utp(&self, utp: Option<Arc<Utp>>) {
+        *self.utp.lock().expect("Error unable to lock mutex!") = utp;
+    }
+
+    pub fn proxy(&self) -> Option<Proxy> {
+        self.proxy
+            .lock()
+            .expect("Error unable to lock mutex!")
+            .clone()
+    }
+
+    pub fn set_proxy(&self, proxy: Option<Proxy>) {
+        *self.proxy.lock().expect("Error unable to lock mutex!") = proxy;
+    }
+
+    pub fn encryption(&self) -> EncryptionMode {
+        *self.encryption.lock().expect("Error unable to lock mutex!")
+    }
+
+    pub fn set_encryption(&self, mode: EncryptionMode) {
+        *self.encryption.lock().expect("Error unable to lock mutex!") = mode;
+    }
+
+    pub fn seed_goals(&self) -> SeedGoals {
+        *self.seed_goals.lock().expect("Error unable to lock mutex!")
+    }
+
+    pub fn set_seed_goals(&self, goals: SeedGoals) {
+        *self.seed_goals.lock().expect("Error unable to lock mutex!") = goals;
+    }
+
+    pub fn add_torrent(&self, state: Arc<SharedTorrentState>, storage: Arc<Storage>) {
+        let mut torrents = self.torrents.lock().expect("Error unable to lock mutex!");
+        torrents.insert(state.info_hash(), TorrentEntry { state, storage });
+    }
+
+    /**
+     * Stop routing incoming peers to a torrent.
+     */
+    pub fn remove_torrent(&self, info_hash: &[u8]) {
+        let mut torrents = self.torrents.lock().expect("Error unable to lock mutex!");
+        torrents.remove(info_hash);
+    }
+
+    fn info_hashes(&self) -> Vec<Vec<u8>> {
+        let torrents = self.torrents.lock().expect("Error unable to lock mutex!");
+        torrents.keys().cloned().collect()
+    }
+
+    fn find_torrent(&self, info_hash: &[u8]) -> Option<(Arc<SharedTorrentState>, Arc<Storage>)> {
+        let torrents = self.torrents.lock().expect("Error unable to lock mutex!");
+        torrents
+            .get(info_hash)
+            .map(|t| (t.state.clone(), t.storage.clone()))
+    }
+}
+
+async fn accept_peer(
+    session: Arc<Session>,
+    mut connection: PeerConnection,
+    addr: SocketAddr,
+) -> Result<(), Box<dyn Error>> {
+    let _global_slot = session
+        .connection_slots()
+        .try_acquire_owned()
+        .map_err(|_| "global connection limit reached")?;
+    connection
+        .accept_encryption(&session.info_hashes(), session.encryption())
+        .await?;
+    let handshake = timeout(HANDSHAKE_TIMEOUT, connection.read_handshake()).await??;
+    let (state, storage) = session
+        .find_torrent(handshake.get_hash())
+        .ok_or("unknown info hash")?;
+    let _torrent_slot = state
+        .connection_slots()
+        .try_acquire_owned()
+        .map_err(|_| "torrent connection limit reached")?;
+
+    let our_handshake =
+        state.get_handshake(session.client_id(), session.extensions(state.is_private()));
+    connection.handshake_with_peer(&our_handshake).await?;
+    let extensions = our_handshake.extensions().common(&handshake.extensions());
+    let peer_index = state
+        .add_incoming_peer(Peer {
+            ip: addr.ip().to_string(),
+            port: addr.port() as i32,
+        })
+        .ok_or("too many peers")?;
+    handle_peer(session, state, storage, connection, peer_index, extensions).await
+}
+
+/**
+ * Accept incoming peers until the listener fails.
+ */
+pub async fn listen(session: Arc<Session>, listener: TcpListener) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(s) => s,
+            Err(e) => {
+                println!("peer listener stopped: {}", e);
+                return;
+            }
+        };
+        let session = session.clone();
+        let connection = PeerConnection::from_stream(stream, addr);
+        tokio::spawn(async move {
+            if let Err(e) = accept_peer(session, connection, addr).await {
+                println!("incoming peer {} disconnected: {}", addr, e);
+            }
+        });
+    }
+}
+
+/**
+ * Accept peers connecting over uTP.
+ */
+pub async fn listen_utp(session: Arc<Session>, utp: Arc<Utp>) {
+    loop {
+        let (stream, addr) = utp.accept().await;
+        let session = session.clone();
+        let connection = PeerConnection::from_utp(stream);
+        tokio::spawn(async move {
+            if let Err(e) = accept_peer(session, connection, addr).await {
+                println!("incoming uTP peer {} disconnected: {}", addr, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        connect_tracker::tracker::Handshake,
+        parse_tracker_res::peers::PeerList,
+        queue::{tests::test_torrent, TorrentState},
+        storage::Allocation,
+    };
+    use std::path::Path;
+
+    fn torrent(info_hash: u8) -> (Arc<SharedTorrentState>, Arc<Storage>) {
+        let mut info = test_torrent(1, 4);
+        info.info_data.name = String::from("file");
+        info.info_data.pieces = vec![0; 20];
+        info.info_hash = vec![info_hash; 20];
+        let storage = Storage::new(Path::new("/tmp"), &info.info_data, Allocation::Compact);
+        let mut state = TorrentState::new(info, &PeerList::default());
+        state.set_max_connections(1);
+        (Arc::new(SharedTorrentState::new(state)), Arc::new(storage))
+    }
+
+    async fn connect(addr: SocketAddr, info_hash: u8) -> PeerConnection {
+        let mut connection = PeerConnection::new(addr.ip().to_string(), addr.port() as i32, None)
+            .await
+            .unwrap();
+        let handshake = Handshake::new(vec![info_hash; 20], "-TR2940-k8hj0wgej6ch");
+        connection.handshake_with_peer(&handshake).await.unwrap();
+        connection
+    }
+
+    #[tokio::test]
+    async fn route_by_info_hash() {
+        let session = Arc::new(Session::new(String::from("-RS0001-000000000000"), 10));
+        let (state, storage) = torrent(1);
+        session.add_torrent(state.clone(), storage);
+
+        for bind in ["127.0.0.1:0", "[::1]:0"] {
+            let listener = match PeerConnection::listen(bind.parse().unwrap()) {
+                Ok(l) => l,
+                // no IPv6 in this environment
+                Err(_) => continue,
+            };
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(listen(session.clone(), listener));
+
+            // unknown torrents are dropped without a handshake
+            let mut unknown = connect(addr, 2).await;
+            assert!(unknown.read_handshake().await.is_err());
+
+            let mut known = connect(addr, 1).await;
+            let handshake = known.read_handshake().await.unwrap();
+            assert_eq!(handshake.get_hash(), &vec![1; 20]);
+
+            // the torrent allows a single connection
+            let mut rejected = connect(addr, 1).await;
+            assert!(rejected.read_handshake().await.is_err());
+            drop(known);
+        }
+        assert!(state.peer_count() >= 1);
+
+        // removed torrents are no longer found for incoming peers
+        session.remove_torrent(&state.info_hash());
+        assert!(session.find_torrent(&state.info_hash()).is_none());
+    }
+}
@@ -0,0 +1,445 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{parse_torrent::torrent_info::TorrentMetadata, picker::Priority};
+
+// Maps the flat byte range of a torrent onto the files on disk.
+// Single-file torrents are stored as `<dir>/<name>`, multi-file torrents as
+// `<dir>/<name>/<path...>` for every entry in `files`.
+//
+// Skipped files are never created. Pieces on the edge between a skipped and a
+// wanted file still have to be downloaded whole to be verified, the bytes that
+// belong to the skipped file go to a parts file `<dir>/.<name>.parts` instead,
+// one piece sized slot per edge piece.
+
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    pub path: PathBuf,
+    pub length: u64,
+    // offset of the first byte of the file within the torrent
+    pub offset: u64,
+}
+
+// How files are created before pieces arrive out of order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Allocation {
+    // set every file to its final length up front, holes are left unallocated
+    Sparse,
+    // reserve every block on disk up front to avoid fragmentation
+    Full,
+    // files grow as pieces are written
+    #[default]
+    Compact,
+}
+
+#[derive(Debug)]
+pub struct Storage {
+    files: Vec<FileEntry>,
+    piece_length: u64,
+    total_length: u64,
+    allocation: Allocation,
+    parts_path: PathBuf,
+    // files with `Priority::Skip`
+    skipped: Vec<bool>,
+    // slot in the parts file of every edge piece
+    parts_slots: HashMap<usize, u64>,
+}
+
+/**
+ * Whether a write failed because the disk or the user's quota is full.
+ */
+pub fn is_disk_full(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded
+    )
+}
+
+/**
+ * Reserve the blocks of a file, falling back to writing zeros on filesystems
+ * without fallocate support.
+ */
+#[cfg(target_os = "linux")]
+fn preallocate(file: &File, length: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    if length == 0 {
+        return Ok(());
+    }
+    let ret = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, length as libc::off_t) };
+    if ret != 0 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => write_zeros(file, length),
+            _ => Err(e),
+        };
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn preallocate(file: &File, length: u64) -> io::Result<()> {
+    write_zeros(file, length)
+}
+
+/**
+ * Reserve the blocks of a file by writing zeros past its end.
+ */
+fn write_zeros(mut file: &File, length: u64) -> io::Result<()> {
+    let mut offset = file.metadata()?.len();
+    if offset >= length {
+        return Ok(());
+    }
+    let zeros = vec![0; 1 << 16];
+    file.seek(SeekFrom::Start(offset))?;
+    while offset < length {
+        let n = (length - offset).min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..n])?;
+        offset += n as u64;
+    }
+    Ok(())
+}
+
+/**
+ * Drop path components that could escape the download directory.
+ */
+fn sanitize(path: &[String]) -> PathBuf {
+    let mut ret = PathBuf::new();
+    for part in path {
+        let p = Path::new(part);
+        if p.components().count() == 1 {
+            if let Some(Component::Normal(c)) = p.components().next() {
+                ret.push(c);
+            }
+        }
+    }
+    ret
+}
+
+impl Storage {
+    pub fn new(dir: &Path, metadata: &TorrentMetadata, allocation: Allocation) -> Self {
+        let name = sanitize(std::slice::from_ref(&metadata.name));
+        let parts_path = dir.join(format!(".{}.parts", name.display()));
+        let root = dir.join(name);
+        let mut files = vec![];
+        match &metadata.files {
+            None => files.push(FileEntry {
+                path: root,
+                length: metadata.length as u64,
+                offset: 0,
+            }),
+            Some(list) => {
+                let mut offset = 0;
+                for f in list {
+                    files.push(FileEntry {
+                        path: root.join(sanitize(&f.path)),
+                        length: f.length as u64,
+                        offset,
+                    });
+                    offset += f.length as u64;
+                }
+            }
+        }
+
+        Storage {
+            piece_length: metadata.piece_length as u64,
+            total_length: metadata.length as u64,
+            allocation,
+            parts_path,
+            skipped: vec![false; files.len()],
+            parts_slots: HashMap::new(),
+            files,
+        }
+    }
+
+    /**
+     * Apply per-file priorities, files missing from `priorities` are wanted.
+     */
+    pub fn set_file_priorities(&mut self, priorities: &[Priority]) {
+        self.skipped = (0..self.files.len())
+            .map(|i| priorities.get(i) == Some(&Priority::Skip))
+            .collect();
+
+        self.parts_slots.clear();
+        let piece_count = self.total_length.div_ceil(self.piece_length) as usize;
+        for index in 0..piece_count {
+            let files = self.files_for_piece(index);
+            let skipped = files.iter().any(|i| self.skipped[*i]);
+            let wanted = files.iter().any(|i| !self.skipped[*i]);
+            if skipped && wanted {
+                let slot = self.parts_slots.len() as u64;
+                self.parts_slots.insert(index, slot);
+            }
+        }
+    }
+
+    /**
+     * Create every file according to the allocation mode.
+     * Files that already exist keep their data.
+     */
+    pub fn allocate(&self) -> io::Result<()> {
+        if self.allocation == Allocation::Compact {
+            return Ok(());
+        }
+        for (i, f) in self.files.iter().enumerate() {
+            if self.skipped[i] {
+                continue;
+            }
+            let file = self.open_for_write(&f.path)?;
+            match self.allocation {
+                Allocation::Sparse => {
+                    if file.metadata()?.len() < f.length {
+                        file.set_len(f.length)?;
+                    }
+                }
+                Allocation::Full => preallocate(&file, f.length)?,
+                Allocation::Compact => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn open_for_write(&self, path: &Path) -> io::Result<File> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+    }
+
+    pub fn files(&self) -> &[FileEntry] {
+        &self.files
+    }
+
+    pub fn piece_length(&self) -> u64 {
+        self.piece_length
+    }
+
+    pub fn piece_size(&self, index: usize) -> u64 {
+        let start = index as u64 * self.piece_length;
+        self.total_length
+            .saturating_sub(start)
+            .min(self.piece_length)
+    }
+
+    /**
+     * Split a torrent byte range into (file index, offset in file, length) chunks.
+     */
+    fn spans(&self, offset: u64, length: u64) -> Vec<(usize, u64, u64)> {
+        let end = offset + length;
+        let mut ret = vec![];
+        for (i, f) in self.files.iter().enumerate() {
+            let f_end = f.offset + f.length;
+            if f_end <= offset || f.offset >= end || f.length == 0 {
+                continue;
+            }
+            let start = offset.max(f.offset);
+            let stop = end.min(f_end);
+            ret.push((i, start - f.offset, stop - start));
+        }
+        ret
+    }
+
+    /**
+     * Indices into `files()` of every file the piece at `index` touches.
+     */
+    pub fn files_for_piece(&self, index: usize) -> Vec<usize> {
+        let offset = index as u64 * self.piece_length;
+        self.spans(offset, self.piece_size(index))
+            .iter()
+            .map(|(i, _, _)| *i)
+            .collect()
+    }
+
+    /**
+     * Resolve a torrent byte range to (path, offset in file, length) chunks,
+     * redirecting the skipped part of edge pieces to the parts file.
+     */
+    fn locate(&self, offset: u64, length: u64) -> Vec<(&Path, u64, u64)> {
+        let mut ret = vec![];
+        for (i, file_offset, len) in self.spans(offset, length) {
+            if !self.skipped[i] {
+                ret.push((self.files[i].path.as_path(), file_offset, len));
+                continue;
+            }
+            // split at piece boundaries, only edge pieces have a slot
+            let mut start = self.files[i].offset + file_offset;
+            let end = start + len;
+            while start < end {
+                let index = (start / self.piece_length) as usize;
+                let piece_start = index as u64 * self.piece_length;
+                let chunk = end.min(piece_start + self.piece_length) - start;
+                match self.parts_slots.get(&index) {
+                    Some(slot) => ret.push((
+                        self.parts_path.as_path(),
+                        slot * self.piece_length + (start - piece_start),
+                        chunk,
+                    )),
+                    None => ret.push((
+                        self.files[i].path.as_path(),
+                        start - self.files[i].offset,
+                        chunk,
+                    )),
+                }
+                start += chunk;
+            }
+        }
+        ret
+    }
+
+    /**
+     * Read `length` bytes starting at torrent `offset`.
+     * Fails with `UnexpectedEof` if a file is missing or too short.
+     */
+    pub fn read(&self, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+        let mut ret = Vec::with_capacity(length as usize);
+        for (path, file_offset, len) in self.locate(offset, length) {
+            let mut file = match File::open(path) {
+                Ok(f) => f,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, e))
+                }
+                Err(e) => return Err(e),
+            };
+            file.seek(SeekFrom::Start(file_offset))?;
+            let start = ret.len();
+            ret.resize(start + len as usize, 0);
+            file.read_exact(&mut ret[start..])?;
+        }
+        Ok(ret)
+    }
+
+    pub fn read_piece(&self, index: usize) -> io::Result<Vec<u8>> {
+        self.read(index as u64 * self.piece_length, self.piece_size(index))
+    }
+
+    /**
+     * Write `data` at torrent `offset`, creating files and directories as needed.
+     */
+    pub fn write(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut written = 0;
+        for (path, file_offset, len) in self.locate(offset, data.len() as u64) {
+            let mut file = self.open_for_write(path)?;
+            file.seek(SeekFrom::Start(file_offset))?;
+            file.write_all(&data[written..(written + len as usize)])?;
+            written += len as usize;
+        }
+        Ok(())
+    }
+
+    pub fn write_piece(&self, index: usize, data: &[u8]) -> io::Result<()> {
+        self.write(index as u64 * self.piece_length, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_torrent::torrent_info::FileInfo;
+
+    fn metadata() -> TorrentMetadata {
+        TorrentMetadata {
+            pieces: vec![],
+            piece_length: 4,
+            length: 10,
+            name: String::from("multi"),
+            private: false,
+            files: Some(vec![
+                FileInfo {
+                    length: 3,
+                    path: vec![String::from("a")],
+                },
+                FileInfo {
+                    length: 7,
+                    path: vec![String::from(".."), String::from("sub"), String::from("b")],
+                },
+            ]),
+        }
+    }
+
+    #[test]
+    fn piece_spans_files() {
+        let storage = Storage::new(Path::new("/tmp"), &metadata(), Allocation::Compact);
+        assert_eq!(storage.piece_size(2), 2);
+        assert_eq!(storage.files_for_piece(0), vec![0, 1]);
+        assert_eq!(storage.files_for_piece(2), vec![1]);
+        assert_eq!(storage.files()[1].path, Path::new("/tmp/multi/sub/b"));
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        let dir = std::env::temp_dir().join(format!("storage-test-{}", std::process::id()));
+        let storage = Storage::new(&dir, &metadata(), Allocation::Compact);
+        assert!(storage.read_piece(0).is_err());
+        storage.write_piece(0, &[1, 2, 3, 4]).unwrap();
+        storage.write_piece(2, &[9, 10]).unwrap();
+        assert_eq!(storage.read_piece(0).unwrap(), vec![1, 2, 3, 4]);
+        assert_eq!(storage.read(8, 2).unwrap(), vec![9, 10]);
+        assert_eq!(fs::read(dir.join("multi/a")).unwrap(), vec![1, 2, 3]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn skipped_file_goes_to_parts() {
+        let dir = std::env::temp_dir().join(format!("storage-parts-{}", std::process::id()));
+        let mut storage = Storage::new(&dir, &metadata(), Allocation::Sparse);
+        storage.set_file_priorities(&[Priority::Skip, Priority::Normal]);
+        storage.allocate().unwrap();
+        storage.write_piece(0, &[1, 2, 3, 4]).unwrap();
+        storage.write_piece(1, &[5, 6, 7, 8]).unwrap();
+
+        assert!(!dir.join("multi/a").exists());
+        assert_eq!(fs::read(dir.join(".multi.parts")).unwrap(), vec![1, 2, 3]);
+        assert_eq!(storage.read_piece(0).unwrap(), vec![1, 2, 3, 4]);
+        assert_eq!(storage.read(3, 5).unwrap(), vec![4, 5, 6, 7, 8]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn allocation_modes() {
+        for allocation in [Allocation::Sparse, Allocation::Full, Allocation::Compact] {
+            let dir = std::env::temp_dir().join(format!(
+                "storage-alloc-{:?}-{}",
+                allocation,
+                std::process::id()
+            ));
+            let storage = Storage::new(&dir, &metadata(), allocation);
+            storage.allocate().unwrap();
+            storage.write_piece(2, &[9, 10]).unwrap();
+            let expected = if allocation == Allocation::Compact {
+                None
+            } else {
+                Some(3)
+            };
+            assert_eq!(
+                fs::metadata(dir.join("multi/a")).ok().map(|m| m.len()),
+                expected
+            );
+            assert_eq!(fs::metadata(dir.join("multi/sub/b")).unwrap().len(), 7);
+            // allocating again must not truncate written data
+            storage.allocate().unwrap();
+            assert_eq!(storage.read(8, 2).unwrap(), vec![9, 10]);
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn zero_fill_keeps_data() {
+        let path = std::env::temp_dir().join(format!("storage-zeros-{}", std::process::id()));
+        fs::write(&path, [1, 2, 3]).unwrap();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        write_zeros(&file, 70000).unwrap();
+        let data = fs::read(&path).unwrap();
+        assert_eq!(data.len(), 70000);
+        assert_eq!(data[..3], [1, 2, 3]);
+        assert!(data[3..].iter().all(|b| *b == 0));
+        fs::remove_file(path).unwrap();
+    }
+}
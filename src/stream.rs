@@ -0,0 +1,439 @@
+use std::{io, ops::Range, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+
+use crate::{queue::SharedTorrentState, storage::Storage};
+
+// Serves the files of a torrent over HTTP while it downloads:
+// - `GET /` lists the files, `GET /<index>/<name>` serves a file
+// - `Range: bytes=...` requests are answered with `206 Partial Content`
+// - Reads wait until the pieces covering the range are verified. The next
+//   few pieces from the read position get a high priority and the missing
+//   ones a deadline so the picker fetches them first. Both are dropped once
+//   every reader is past a piece or gone.
+
+const MAX_HEADER_SIZE: usize = 8192;
+// pieces from the read position raised to a high priority
+const BOOST_PIECES: usize = 8;
+// pieces ahead of the read position that get a deadline
+const DEADLINE_PIECES: usize = 4;
+const DEADLINE_STEP: Duration = Duration::from_secs(2);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+struct Request {
+    method: String,
+    path: String,
+    range: Option<String>,
+}
+
+fn parse_request(head: &str) -> Option<Request> {
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_string();
+    let path = request_line.next()?.to_string();
+    let mut range = None;
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("range") {
+                range = Some(value.trim().to_string());
+            }
+        }
+    }
+    Some(Request {
+        method,
+        path,
+        range,
+    })
+}
+
+/**
+ * Parse a `Range` header into an inclusive byte range of a file of `length` bytes.
+ * Only the first range of a multi-range request is served.
+ * Returns `Err` if the range can't be satisfied.
+ */
+fn parse_range(header: &str, length: u64) -> Result<(u64, u64), ()> {
+    let spec = header.strip_prefix("bytes=").ok_or(())?;
+    let first = spec.split(',').next().ok_or(())?.trim();
+    let (start, end) = first.split_once('-').ok_or(())?;
+    let (start, end) = match (start.is_empty(), end.is_empty()) {
+        // suffix range: last `end` bytes
+        (true, false) => {
+            let suffix: u64 = end.parse().map_err(|_| ())?;
+            if suffix == 0 {
+                return Err(());
+            }
+            (length.saturating_sub(suffix), length.saturating_sub(1))
+        }
+        (false, true) => (start.parse().map_err(|_| ())?, length.saturating_sub(1)),
+        (false, false) => {
+            let end: u64 = end.parse().map_err(|_| ())?;
+            (
+                start.parse().map_err(|_| ())?,
+                end.min(length.saturating_sub(1)),
+            )
+        }
+        (true, true) => return Err(()),
+    };
+    if start > end || start >= length {
+        return Err(());
+    }
+    Ok((start, end))
+}
+
+fn content_type(path: &str) -> &'static str {
+    let extension = path.rsplit('.').next().unwrap_or("").to_ascii_lowercase();
+    match extension.as_str() {
+        "mp4" | "m4v" => "video/mp4",
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        "avi" => "video/x-msvideo",
+        "mov" => "video/quicktime",
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" => "audio/ogg",
+        "txt" => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
+async fn write_status(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await
+}
+
+async fn read_head(stream: &mut TcpStream) -> io::Result<Option<String>> {
+    let mut buffer = vec![];
+    let mut chunk = [0; 1024];
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+        if let Some(end) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            return Ok(Some(String::from_utf8_lossy(&buffer[..end]).to_string()));
+        }
+        if buffer.len() > MAX_HEADER_SIZE {
+            return Ok(None);
+        }
+    }
+}
+
+// Pieces raised for one reader: a window from its read position, moved along
+// as it reads and released once it is done or disconnects.
+struct Boost {
+    state: Arc<SharedTorrentState>,
+    pieces: Range<usize>,
+}
+
+impl Boost {
+    fn new(state: Arc<SharedTorrentState>) -> Self {
+        Boost {
+            state,
+            pieces: 0..0,
+        }
+    }
+
+    /**
+     * Raise up to `BOOST_PIECES` pieces from `index` on, but not past `last`,
+     * and release the pieces before `index`. `index` never goes back.
+     */
+    fn advance(&mut self, index: usize, last: usize) {
+        let end = (index + BOOST_PIECES).min(last + 1).max(index);
+        self.state.boost_pieces(self.pieces.end.max(index)..end);
+        self.state
+            .release_pieces(self.pieces.start..index.min(self.pieces.end));
+        self.pieces = index..end;
+    }
+
+    /**
+     * Give the missing raised pieces a deadline, earliest first, so the
+     * picker fetches them ahead of everything else.
+     */
+    fn hurry(&self) {
+        self.state.seek(self.pieces.start);
+        let mut deadline = DEADLINE_STEP;
+        for index in self
+            .pieces
+            .clone()
+            .filter(|i| !self.state.has_piece(*i))
+            .take(DEADLINE_PIECES)
+        {
+            self.state.set_piece_deadline(index, deadline);
+            deadline += DEADLINE_STEP;
+        }
+    }
+}
+
+impl Drop for Boost {
+    fn drop(&mut self) {
+        self.state.release_pieces(self.pieces.clone());
+    }
+}
+
+/**
+ * Wait until the piece at `index` is verified, fails if the client closes
+ * the connection meanwhile.
+ */
+async fn wait_for_piece(
+    stream: &mut TcpStream,
+    state: &SharedTorrentState,
+    index: usize,
+) -> io::Result<()> {
+    let mut buffer = [0; 256];
+    loop {
+        tokio::select! {
+            _ = state.wait_for_piece(index) => return Ok(()),
+            read = stream.read(&mut buffer) => {
+                if read? == 0 {
+                    return Err(io::ErrorKind::ConnectionAborted.into());
+                }
+            }
+        }
+    }
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    state: Arc<SharedTorrentState>,
+    storage: Arc<Storage>,
+) -> io::Result<()> {
+    let request = match read_head(&mut stream)
+        .await?
+        .and_then(|h| parse_request(&h))
+    {
+        Some(r) => r,
+        None => return write_status(&mut stream, "400 Bad Request", "bad request").await,
+    };
+    if request.method != "GET" && request.method != "HEAD" {
+        return write_status(&mut stream, "405 Method Not Allowed", "method not allowed").await;
+    }
+
+    if request.path == "/" {
+        let mut body = String::new();
+        for (i, f) in storage.files().iter().enumerate() {
+            let name = f
+                .path
+                .file_name()
+                .map(|n| n.to_string_lossy())
+                .unwrap_or_default();
+            let encoded: String = url::form_urlencoded::byte_serialize(name.as_bytes()).collect();
+            body.push_str(&format!("/{}/{}\n", i, encoded.replace('+', "%20")));
+        }
+        return write_status(&mut stream, "200 OK", &body).await;
+    }
+
+    let file = request
+        .path
+        .trim_start_matches('/')
+        .split('/')
+        .next()
+        .and_then(|i| i.parse::<usize>().ok())
+        .and_then(|i| storage.files().get(i));
+    let file = match file {
+        Some(f) => f.clone(),
+        None => return write_status(&mut stream, "404 Not Found", "no such file").await,
+    };
+
+    let (start, end, status) = match &request.range {
+        Some(header) => match parse_range(header, file.length) {
+            Ok((start, end)) => (start, end, "206 Partial Content"),
+            Err(_) => {
+                let response = format!(
+                    "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    file.length
+                );
+                return stream.write_all(response.as_bytes()).await;
+            }
+        },
+        None => (0, file.length.saturating_sub(1), "200 OK"),
+    };
+    let length = if file.length == 0 { 0 } else { end - start + 1 };
+
+    let mut head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\nConnection: close\r\n",
+        status,
+        content_type(&file.path.to_string_lossy()),
+        length
+    );
+    if request.range.is_some() {
+        head.push_str(&format!(
+            "Content-Range: bytes {}-{}/{}\r\n",
+            start, end, file.length
+        ));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    if request.method == "HEAD" || length == 0 {
+        return Ok(());
+    }
+
+    let piece_length = storage.piece_length();
+    let mut offset = file.offset + start;
+    let stop = file.offset + end + 1;
+    let last = ((stop - 1) / piece_length) as usize;
+    let mut boost = Boost::new(state.clone());
+    while offset < stop {
+        let index = (offset / piece_length) as usize;
+        boost.advance(index, last);
+        if !state.has_piece(index) {
+            boost.hurry();
+            wait_for_piece(&mut stream, &state, index).await?;
+        }
+        let chunk = stop.min((index as u64 + 1) * piece_length) - offset;
+        let reader = storage.clone();
+        let data = tokio::task::spawn_blocking(move || reader.read(offset, chunk))
+            .await
+            .map_err(io::Error::other)??;
+        // a reader that stops reading would keep its pieces raised
+        timeout(WRITE_TIMEOUT, stream.write_all(&data))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        offset += chunk;
+    }
+    Ok(())
+}
+
+/**
+ * Accept HTTP connections until the listener fails.
+ */
+pub async fn serve(listener: TcpListener, state: Arc<SharedTorrentState>, storage: Arc<Storage>) {
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(s) => s,
+            Err(e) => {
+                println!("stream server stopped: {}", e);
+                return;
+            }
+        };
+        let state = state.clone();
+        let storage = storage.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, state, storage).await {
+                println!("stream client disconnected: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        parse_tracker_res::peers::PeerList,
+        picker::Priority,
+        queue::{tests::test_torrent, TorrentState},
+        storage::Allocation,
+    };
+    use std::{net::SocketAddr, path::Path};
+
+    /**
+     * Serve a 10 byte movie with only its first piece downloaded and the
+     * others skipped.
+     */
+    async fn movie(dir: &Path) -> (SocketAddr, Arc<SharedTorrentState>, Arc<Storage>) {
+        let mut info = test_torrent(3, 4);
+        info.info_data.length = 10;
+        info.info_data.name = String::from("movie.mp4");
+        let storage = Arc::new(Storage::new(dir, &info.info_data, Allocation::Compact));
+        storage.write_piece(0, &[0, 1, 2, 3]).unwrap();
+        let mut torrent_state = TorrentState::new(info, &PeerList::default());
+        torrent_state.set_bitfield_on(0);
+        torrent_state.set_piece_priority(1..3, Priority::Skip);
+        let state = Arc::new(SharedTorrentState::new(torrent_state));
+        assert!(state.is_complete());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, state.clone(), storage.clone()));
+        (addr, state, storage)
+    }
+
+    #[test]
+    fn range_header() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Ok((0, 99)));
+        assert_eq!(parse_range("bytes=500-", 1000), Ok((500, 999)));
+        assert_eq!(parse_range("bytes=-100", 1000), Ok((900, 999)));
+        assert_eq!(parse_range("bytes=900-2000", 1000), Ok((900, 999)));
+        assert_eq!(parse_range("bytes=0-1,5-9", 1000), Ok((0, 1)));
+        assert_eq!(parse_range("bytes=1000-", 1000), Err(()));
+        assert_eq!(parse_range("bytes=5-1", 1000), Err(()));
+        assert_eq!(parse_range("items=0-1", 1000), Err(()));
+    }
+
+    #[tokio::test]
+    async fn range_waits_for_piece() {
+        let dir = std::env::temp_dir().join(format!("stream-test-{}", std::process::id()));
+        let (addr, state, storage) = movie(&dir).await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /0/movie.mp4 HTTP/1.1\r\nRange: bytes=2-5\r\n\r\n")
+            .await
+            .unwrap();
+
+        // piece 1 is missing, the response must not complete yet
+        let mut response = vec![];
+        let read = tokio::time::timeout(
+            Duration::from_millis(200),
+            client.read_to_end(&mut response),
+        )
+        .await;
+        assert!(read.is_err());
+        // the reader wants the piece even though it is skipped
+        assert!(!state.is_complete());
+
+        storage.write_piece(1, &[4, 5, 6, 7]).unwrap();
+        state.finish_piece(1);
+        client.read_to_end(&mut response).await.unwrap();
+        let response = String::from_utf8_lossy(&response).to_string();
+        assert!(response.starts_with("HTTP/1.1 206 Partial Content"));
+        assert!(response.contains("Content-Range: bytes 2-5/10"));
+        assert!(response.contains("Content-Type: video/mp4"));
+        assert!(response.ends_with("\u{2}\u{3}\u{4}\u{5}"));
+        assert!(state.is_complete());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn disconnect_releases_pieces() {
+        let dir =
+            std::env::temp_dir().join(format!("stream-disconnect-test-{}", std::process::id()));
+        let (addr, state, _) = movie(&dir).await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /0/movie.mp4 HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        // the head and the first piece, then the server waits for piece 1
+        let mut response = vec![];
+        let mut chunk = [0; 256];
+        while !response.ends_with(&[0, 1, 2, 3]) {
+            let n = client.read(&mut chunk).await.unwrap();
+            assert!(n > 0);
+            response.extend_from_slice(&chunk[..n]);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!state.is_complete());
+
+        // the skipped pieces lose their priority and deadline with the reader
+        drop(client);
+        let released = tokio::time::timeout(Duration::from_secs(2), async {
+            while !state.is_complete() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        assert!(released.is_ok());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
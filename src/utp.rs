@@ -0,0 +1,1169 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex, Weak},
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use rand::random;
+use socket2::{Domain, SockRef, Socket, Type};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf},
+    net::{TcpStream, UdpSocket},
+    sync::{
+        mpsc::{channel, unbounded_channel, Receiver, Sender, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    task::JoinHandle,
+    time::sleep_until,
+};
+
+// uTP (BEP 29): reliable, ordered streams over UDP that yield to other
+// traffic. Every packet starts with a 20 byte header:
+//
+//   <type|version><extension><connection_id><timestamp_microseconds>
+//   <timestamp_difference_microseconds><wnd_size><seq_nr><ack_nr>
+//
+// followed by a chain of extensions, of which selective ACKs are used, and
+// the payload. Packets are numbered, the receiver acks the last one received
+// in order and lists later ones it got in a selective ACK bitmask.
+//
+// Congestion control is LEDBAT: each side reports how long the other's
+// packets took to arrive, by clocks that aren't synchronized, so only the
+// growth over the smallest delay seen in the last minutes counts. It is
+// caused by queues filling up along the path. The send window grows while
+// that queuing delay is below 100ms and shrinks above, a lost packet halves
+// it. TCP fills queues until packets are dropped, so uTP backs off first.
+//
+// A connection is a task exchanging packets with the peer on one side and
+// bytes with a `DuplexStream` on the other, whose other end is the
+// `UtpStream` handed to the caller. Connections are told apart by the
+// sender's address and the connection id, the initiator receives on the id
+// of its SYN and sends on the next one. The sockets may be shared with the
+// DHT, whose bencoded messages start with `d`, which is never the first byte
+// of a uTP packet.
+
+const VERSION: u8 = 1;
+const HEADER_LENGTH: usize = 20;
+const EXTENSION_SELECTIVE_ACK: u8 = 1;
+// payload of a packet, small enough to avoid fragmentation
+const MAX_PAYLOAD: usize = 1200;
+const PACKET_SIZE: usize = HEADER_LENGTH + MAX_PAYLOAD;
+const MIN_WINDOW: f64 = (2 * PACKET_SIZE) as f64;
+const MAX_WINDOW: f64 = (1 << 20) as f64;
+// bytes we buffer for the application, advertised as our receive window
+const RECEIVE_WINDOW: usize = 1 << 20;
+const MAX_UNACKED: usize = 512;
+// connections accepted but not yet taken by `accept`, SYNs beyond are reset
+const MAX_PENDING: usize = 64;
+const TARGET_DELAY: f64 = 100_000.0;
+const GAIN: f64 = 1.0;
+const BASE_DELAY_HISTORY: Duration = Duration::from_secs(2 * 60);
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(30);
+const SYN_RETRIES: u32 = 2;
+const MAX_RETRIES: u32 = 6;
+const DUPLICATE_ACKS: u32 = 3;
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(29);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PacketType {
+    Data,
+    Fin,
+    State,
+    Reset,
+    Syn,
+}
+
+impl PacketType {
+    fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            0 => Some(PacketType::Data),
+            1 => Some(PacketType::Fin),
+            2 => Some(PacketType::State),
+            3 => Some(PacketType::Reset),
+            4 => Some(PacketType::Syn),
+            _ => None,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            PacketType::Data => 0,
+            PacketType::Fin => 1,
+            PacketType::State => 2,
+            PacketType::Reset => 3,
+            PacketType::Syn => 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Packet {
+    kind: PacketType,
+    connection_id: u16,
+    timestamp: u32,
+    timestamp_difference: u32,
+    window: u32,
+    seq_nr: u16,
+    ack_nr: u16,
+    // bit i of the mask stands for packet ack_nr + 2 + i
+    selective_ack: Option<Vec<u8>>,
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    fn new(kind: PacketType, connection_id: u16, seq_nr: u16, ack_nr: u16) -> Self {
+        Packet {
+            kind,
+            connection_id,
+            timestamp: 0,
+            timestamp_difference: 0,
+            window: 0,
+            seq_nr,
+            ack_nr,
+            selective_ack: None,
+            payload: vec![],
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LENGTH + self.payload.len());
+        bytes.push(self.kind.to_u8() << 4 | VERSION);
+        bytes.push(match self.selective_ack {
+            Some(_) => EXTENSION_SELECTIVE_ACK,
+            None => 0,
+        });
+        bytes.extend(self.connection_id.to_be_bytes());
+        bytes.extend(self.timestamp.to_be_bytes());
+        bytes.extend(self.timestamp_difference.to_be_bytes());
+        bytes.extend(self.window.to_be_bytes());
+        bytes.extend(self.seq_nr.to_be_bytes());
+        bytes.extend(self.ack_nr.to_be_bytes());
+        if let Some(mask) = &self.selective_ack {
+            bytes.push(0);
+            bytes.push(mask.len() as u8);
+            bytes.extend(mask);
+        }
+        bytes.extend(&self.payload);
+        bytes
+    }
+
+    /**
+     * Read a packet, `None` if it isn't a uTP version 1 packet. Unknown
+     * extensions are skipped.
+     */
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LENGTH || bytes[0] & 0x0f != VERSION {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_be_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes(bytes[i..(i + 4)].try_into().unwrap());
+        let mut packet = Packet {
+            kind: PacketType::from_u8(bytes[0] >> 4)?,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_difference: u32_at(8),
+            window: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            selective_ack: None,
+            payload: vec![],
+        };
+        let mut extension = bytes[1];
+        let mut offset = HEADER_LENGTH;
+        while extension != 0 {
+            let next = *bytes.get(offset)?;
+            let length = *bytes.get(offset + 1)? as usize;
+            let data = bytes.get((offset + 2)..(offset + 2 + length))?;
+            if extension == EXTENSION_SELECTIVE_ACK {
+                packet.selective_ack = Some(data.to_vec());
+            }
+            extension = next;
+            offset += 2 + length;
+        }
+        packet.payload = bytes[offset..].to_vec();
+        Some(packet)
+    }
+
+    /**
+     * Whether the selective ACK covers packet `seq_nr`.
+     */
+    fn selectively_acks(&self, seq_nr: u16) -> bool {
+        let mask = match &self.selective_ack {
+            Some(m) => m,
+            None => return false,
+        };
+        let bit = seq_nr.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize;
+        mask.get(bit / 8).is_some_and(|b| b & (1 << (bit % 8)) != 0)
+    }
+}
+
+/**
+ * Whether sequence number `a` comes before `b`, they wrap around.
+ */
+fn seq_less(a: u16, b: u16) -> bool {
+    a != b && b.wrapping_sub(a) < 0x8000
+}
+
+fn timestamp() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u32
+}
+
+/**
+ * Send without waiting, the packet is dropped if the socket buffer is full
+ * like any UDP packet. Tokio's `try_send_to` would also drop it until the
+ * socket has been seen writable, e.g. the first SYN.
+ */
+fn send_packet(socket: &UdpSocket, packet: &Packet, to: SocketAddr) {
+    let _ = SockRef::from(socket).send_to(&packet.encode(), &to.into());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    SynSent,
+    Connected,
+}
+
+struct SentPacket {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+}
+
+impl SentPacket {
+    fn size(&self) -> usize {
+        HEADER_LENGTH + self.packet.payload.len()
+    }
+}
+
+// Smallest one way delay of each of the last minutes, the base LEDBAT
+// measures queuing against
+#[derive(Default)]
+struct DelayHistory {
+    minutes: VecDeque<(Instant, u32)>,
+    current: Option<u32>,
+}
+
+impl DelayHistory {
+    fn add(&mut self, delay: u32) {
+        let now = Instant::now();
+        match self.minutes.back_mut() {
+            Some((start, min)) if start.elapsed() < Duration::from_secs(60) => {
+                *min = (*min).min(delay)
+            }
+            _ => self.minutes.push_back((now, delay)),
+        }
+        while self
+            .minutes
+            .front()
+            .is_some_and(|(start, _)| start.elapsed() > BASE_DELAY_HISTORY)
+        {
+            self.minutes.pop_front();
+        }
+        self.current = Some(delay);
+    }
+
+    /**
+     * Delay added by queues on the path, in microseconds.
+     */
+    fn queuing_delay(&self) -> f64 {
+        let base = self.minutes.iter().map(|(_, d)| *d).min();
+        match (self.current, base) {
+            (Some(current), Some(base)) => current.saturating_sub(base) as f64,
+            _ => 0.0,
+        }
+    }
+}
+
+struct Connection {
+    utp: Arc<Utp>,
+    socket: Arc<UdpSocket>,
+    remote: SocketAddr,
+    recv_id: u16,
+    send_id: u16,
+    state: State,
+    // next packet we send, and last packet received in order
+    seq_nr: u16,
+    ack_nr: u16,
+    unacked: VecDeque<SentPacket>,
+    in_flight: usize,
+    // congestion window in bytes, and the receive window of the peer
+    window: f64,
+    peer_window: usize,
+    // smoothed round trip time and its variation
+    rtt: Option<(Duration, Duration)>,
+    rto: Duration,
+    retries: u32,
+    last_ack: u16,
+    duplicate_acks: u32,
+    // packets sent before this one count as lost in the same loss event
+    recovery: Option<u16>,
+    // started when a packet is sent with none unacked, restarted when acks
+    // arrive or on a retransmission timeout
+    timer: Instant,
+    delays: DelayHistory,
+    // one way delay of the last packet received, reported back to the peer
+    reply_delay: u32,
+    // packets received out of order, by sequence number
+    out_of_order: HashMap<u16, Packet>,
+    // data received in order, not yet taken by the application
+    received: Vec<u8>,
+    fin_sent: bool,
+    peer_finished: bool,
+    last_received: Instant,
+    last_sent: Instant,
+    connected: Option<oneshot::Sender<()>>,
+}
+
+impl Connection {
+    fn new(utp: Arc<Utp>, socket: Arc<UdpSocket>, remote: SocketAddr, recv_id: u16) -> Self {
+        let now = Instant::now();
+        Connection {
+            utp,
+            socket,
+            remote,
+            recv_id,
+            send_id: 0,
+            state: State::Connected,
+            seq_nr: 1,
+            ack_nr: 0,
+            unacked: VecDeque::new(),
+            in_flight: 0,
+            window: MIN_WINDOW,
+            peer_window: RECEIVE_WINDOW,
+            rtt: None,
+            rto: INITIAL_RTO,
+            retries: 0,
+            last_ack: 0,
+            duplicate_acks: 0,
+            recovery: None,
+            timer: now,
+            delays: DelayHistory::default(),
+            reply_delay: 0,
+            out_of_order: HashMap::new(),
+            received: vec![],
+            fin_sent: false,
+            peer_finished: false,
+            last_received: now,
+            last_sent: now,
+            connected: None,
+        }
+    }
+
+    fn advertised_window(&self) -> u32 {
+        let buffered = self.received.len()
+            + self
+                .out_of_order
+                .values()
+                .map(|p| p.payload.len())
+                .sum::<usize>();
+        RECEIVE_WINDOW.saturating_sub(buffered) as u32
+    }
+
+    fn send_window(&self) -> usize {
+        (self.window as usize).min(self.peer_window.max(PACKET_SIZE))
+    }
+
+    fn can_send(&self) -> bool {
+        self.state == State::Connected
+            && !self.fin_sent
+            && self.unacked.len() < MAX_UNACKED
+            && (self.in_flight == 0 || self.in_flight + PACKET_SIZE <= self.send_window())
+    }
+
+    fn transmit(&mut self, packet: &mut Packet) {
+        packet.timestamp = timestamp();
+        packet.timestamp_difference = self.reply_delay;
+        packet.window = self.advertised_window();
+        if packet.kind != PacketType::Syn {
+            packet.ack_nr = self.ack_nr;
+        }
+        send_packet(&self.socket, packet, self.remote);
+        self.last_sent = Instant::now();
+    }
+
+    /**
+     * Send a packet taking the next sequence number, kept until acked.
+     */
+    fn send(&mut self, kind: PacketType, payload: Vec<u8>) {
+        let connection_id = match kind {
+            PacketType::Syn => self.recv_id,
+            _ => self.send_id,
+        };
+        let mut packet = Packet::new(kind, connection_id, self.seq_nr, self.ack_nr);
+        packet.payload = payload;
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.transmit(&mut packet);
+        if self.unacked.is_empty() {
+            self.timer = Instant::now();
+        }
+        let sent = SentPacket {
+            packet,
+            sent_at: Instant::now(),
+            transmissions: 1,
+        };
+        self.in_flight += sent.size();
+        self.unacked.push_back(sent);
+    }
+
+    fn resend(&mut self, index: usize) {
+        let mut packet = self.unacked[index].packet.clone();
+        self.transmit(&mut packet);
+        let sent = &mut self.unacked[index];
+        sent.packet = packet;
+        sent.sent_at = Instant::now();
+        sent.transmissions += 1;
+    }
+
+    /**
+     * Ack the packets received, listing those after a gap.
+     */
+    fn send_state(&mut self) {
+        let mut packet = Packet::new(PacketType::State, self.send_id, self.seq_nr, self.ack_nr);
+        let last = self
+            .out_of_order
+            .keys()
+            .map(|s| s.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize)
+            .max();
+        if let Some(last) = last {
+            // a multiple of 4 bytes
+            let mut mask = vec![0; (last / 32 + 1) * 4];
+            for seq_nr in self.out_of_order.keys() {
+                let bit = seq_nr.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize;
+                mask[bit / 8] |= 1 << (bit % 8);
+            }
+            packet.selective_ack = Some(mask);
+        }
+        self.transmit(&mut packet);
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        let (srtt, rttvar) = match self.rtt {
+            None => (sample, sample / 2),
+            Some((srtt, rttvar)) => {
+                let deviation = srtt.abs_diff(sample);
+                (srtt * 7 / 8 + sample / 8, rttvar * 3 / 4 + deviation / 4)
+            }
+        };
+        self.rtt = Some((srtt, rttvar));
+        self.rto = (srtt + rttvar * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /**
+     * LEDBAT: grow the window in proportion to how far the queuing delay
+     * is below the target, shrink it above.
+     */
+    fn grow_window(&mut self, acked_bytes: usize) {
+        let off_target = (TARGET_DELAY - self.delays.queuing_delay()) / TARGET_DELAY;
+        self.window += GAIN * off_target * acked_bytes as f64 * PACKET_SIZE as f64 / self.window;
+        self.window = self.window.clamp(MIN_WINDOW, MAX_WINDOW);
+    }
+
+    fn process_ack(&mut self, packet: &Packet) {
+        // acks of packets never sent are ignored
+        if seq_less(self.seq_nr.wrapping_sub(1), packet.ack_nr) {
+            return;
+        }
+        let now = Instant::now();
+        let mut acked_bytes = 0;
+        let mut samples = vec![];
+        self.unacked.retain(|sent| {
+            let seq_nr = sent.packet.seq_nr;
+            let acked = !seq_less(packet.ack_nr, seq_nr) || packet.selectively_acks(seq_nr);
+            if acked {
+                acked_bytes += sent.size();
+                // retransmitted packets don't tell which copy was acked
+                if sent.transmissions == 1 {
+                    samples.push(now - sent.sent_at);
+                }
+            }
+            !acked
+        });
+        for sample in samples {
+            self.update_rtt(sample);
+        }
+        if acked_bytes > 0 {
+            self.in_flight -= acked_bytes;
+            self.timer = now;
+            self.retries = 0;
+            self.duplicate_acks = 0;
+            self.grow_window(acked_bytes);
+        } else if !self.unacked.is_empty() && packet.ack_nr == self.last_ack {
+            self.duplicate_acks += 1;
+        }
+        self.last_ack = packet.ack_nr;
+
+        // a packet is lost once 3 later ones arrived, or the packet after
+        // the ack when the same ack keeps coming; each is resent once, a
+        // timeout takes over if the copy is lost too
+        let sacked: Vec<u16> = match &packet.selective_ack {
+            Some(mask) => (0..(mask.len() * 8))
+                .filter(|bit| mask[bit / 8] & (1 << (bit % 8)) != 0)
+                .map(|bit| packet.ack_nr.wrapping_add(2).wrapping_add(bit as u16))
+                .collect(),
+            None => vec![],
+        };
+        let lost: Vec<usize> = (0..self.unacked.len())
+            .filter(|i| {
+                let sent = &self.unacked[*i];
+                let seq_nr = sent.packet.seq_nr;
+                let later = sacked.iter().filter(|s| seq_less(seq_nr, **s)).count() as u32;
+                let duplicate = *i == 0
+                    && seq_nr == packet.ack_nr.wrapping_add(1)
+                    && self.duplicate_acks >= DUPLICATE_ACKS;
+                sent.transmissions == 1 && (later >= DUPLICATE_ACKS || duplicate)
+            })
+            .collect();
+        if let Some(first) = lost.first() {
+            // one loss event per window of data
+            let seq_nr = self.unacked[*first].packet.seq_nr;
+            if self.recovery.is_none_or(|r| !seq_less(seq_nr, r)) {
+                self.window = (self.window / 2.0).max(MIN_WINDOW);
+                self.recovery = Some(self.seq_nr);
+            }
+        }
+        for index in lost {
+            self.resend(index);
+        }
+    }
+
+    fn deliver(&mut self, packet: Packet) {
+        self.ack_nr = packet.seq_nr;
+        if packet.kind == PacketType::Fin {
+            self.peer_finished = true;
+        } else {
+            self.received.extend(packet.payload);
+        }
+    }
+
+    fn receive_data(&mut self, packet: Packet) {
+        if self.peer_finished {
+            self.send_state();
+            return;
+        }
+        let expected = self.ack_nr.wrapping_add(1);
+        if packet.seq_nr == expected {
+            self.deliver(packet);
+            while let Some(next) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+                self.deliver(next);
+                if self.peer_finished {
+                    self.out_of_order.clear();
+                }
+            }
+        } else if seq_less(expected, packet.seq_nr)
+            && packet.seq_nr.wrapping_sub(expected) < MAX_UNACKED as u16
+        {
+            self.out_of_order.insert(packet.seq_nr, packet);
+        }
+        self.send_state();
+    }
+
+    /**
+     * Handle a packet of the peer, false when the connection is reset.
+     */
+    fn handle(&mut self, packet: Packet) -> bool {
+        self.last_received = Instant::now();
+        self.peer_window = packet.window as usize;
+        self.reply_delay = timestamp().wrapping_sub(packet.timestamp);
+        if packet.timestamp_difference != 0 {
+            self.delays.add(packet.timestamp_difference);
+        }
+        match packet.kind {
+            PacketType::Reset => return false,
+            // our answer to the SYN was lost
+            PacketType::Syn => {
+                self.send_state();
+                return true;
+            }
+            _ => {}
+        }
+        if self.state == State::SynSent {
+            if packet.kind != PacketType::State {
+                return true;
+            }
+            self.ack_nr = packet.seq_nr.wrapping_sub(1);
+            self.state = State::Connected;
+            if let Some(connected) = self.connected.take() {
+                let _ = connected.send(());
+            }
+        }
+        self.process_ack(&packet);
+        if matches!(packet.kind, PacketType::Data | PacketType::Fin) {
+            self.receive_data(packet);
+        }
+        true
+    }
+
+    fn deadline(&self) -> Instant {
+        let mut deadline =
+            (self.last_received + IDLE_TIMEOUT).min(self.last_sent + KEEPALIVE_INTERVAL);
+        if !self.unacked.is_empty() {
+            deadline = deadline.min(self.timer + self.rto);
+        }
+        deadline
+    }
+
+    /**
+     * Retransmit what wasn't acked in time, false when the peer is gone.
+     */
+    fn on_timer(&mut self) -> bool {
+        let now = Instant::now();
+        if now >= self.last_received + IDLE_TIMEOUT {
+            return false;
+        }
+        if !self.unacked.is_empty() && now >= self.timer + self.rto {
+            self.retries += 1;
+            let max_retries = match self.state {
+                State::SynSent => SYN_RETRIES,
+                State::Connected => MAX_RETRIES,
+            };
+            if self.retries > max_retries {
+                return false;
+            }
+            self.rto = (self.rto * 2).min(MAX_RTO);
+            self.window = MIN_WINDOW;
+            self.timer = now;
+            self.resend(0);
+        }
+        if now >= self.last_sent + KEEPALIVE_INTERVAL && self.state == State::Connected {
+            self.send_state();
+        }
+        true
+    }
+
+    fn is_closed(&self, app_gone: bool) -> bool {
+        self.fin_sent && self.unacked.is_empty() && (self.peer_finished || app_gone)
+    }
+
+    async fn run(mut self, app: DuplexStream, mut packets: UnboundedReceiver<Packet>) {
+        let (mut app_reader, mut app_writer) = tokio::io::split(app);
+        let mut buf = vec![0; MAX_PAYLOAD];
+        let mut app_gone = false;
+        let mut eof_sent = false;
+        while !self.is_closed(app_gone) {
+            let can_send = self.can_send();
+            let deadline = self.deadline().into();
+            let received = std::mem::take(&mut self.received);
+            tokio::select! {
+                packet = packets.recv() => {
+                    self.received = received;
+                    let alive = match packet {
+                        Some(packet) => self.handle(packet),
+                        None => false,
+                    };
+                    if !alive {
+                        break;
+                    }
+                }
+                read = app_reader.read(&mut buf), if can_send => {
+                    self.received = received;
+                    match read {
+                        Ok(n) if n > 0 => self.send(PacketType::Data, buf[..n].to_vec()),
+                        _ => {
+                            self.send(PacketType::Fin, vec![]);
+                            self.fin_sent = true;
+                        }
+                    }
+                }
+                written = app_writer.write(&received), if !received.is_empty() => {
+                    match written {
+                        Ok(n) => self.received = received[n..].to_vec(),
+                        // the application dropped the stream
+                        Err(_) => app_gone = true,
+                    }
+                }
+                _ = sleep_until(deadline) => {
+                    self.received = received;
+                    if !self.on_timer() {
+                        break;
+                    }
+                }
+            }
+            if self.peer_finished && self.received.is_empty() && !eof_sent {
+                let _ = app_writer.shutdown().await;
+                eof_sent = true;
+            }
+        }
+        self.utp.remove(self.remote, self.recv_id);
+    }
+}
+
+/**
+ * A uTP connection, read and written like a TCP stream.
+ */
+pub struct UtpStream {
+    stream: DuplexStream,
+    peer: SocketAddr,
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/**
+ * Stream to a peer over either transport.
+ */
+pub enum Transport {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+}
+
+impl AsyncRead for Transport {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            Transport::Utp(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Transport {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Transport::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            Transport::Utp(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(s) => Pin::new(s).poll_flush(cx),
+            Transport::Utp(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            Transport::Utp(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+type Incoming = (UtpStream, SocketAddr);
+
+pub struct Utp {
+    socket: Option<Arc<UdpSocket>>,
+    socket6: Option<Arc<UdpSocket>>,
+    // packets of each connection by peer address and receive id
+    connections: Mutex<HashMap<(SocketAddr, u16), UnboundedSender<Packet>>>,
+    incoming: Sender<Incoming>,
+    accepted: tokio::sync::Mutex<Receiver<Incoming>>,
+    receivers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Drop for Utp {
+    fn drop(&mut self) {
+        for receiver in self.receivers.get_mut().unwrap().drain(..) {
+            receiver.abort();
+        }
+    }
+}
+
+fn bind_socket(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/**
+ * Dispatch incoming packets until the sockets are dropped.
+ */
+async fn receive(socket: Arc<UdpSocket>, utp: Weak<Utp>) {
+    let mut buf = vec![0; 65536];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(r) => r,
+            Err(_) => continue,
+        };
+        match utp.upgrade() {
+            Some(utp) => utp.receive(&buf[..len], from),
+            None => return,
+        }
+    }
+}
+
+impl Utp {
+    /**
+     * uTP over sockets read by someone else, who passes their uTP packets
+     * to `receive`.
+     */
+    pub fn new(socket: Option<Arc<UdpSocket>>, socket6: Option<Arc<UdpSocket>>) -> Arc<Utp> {
+        let (incoming, accepted) = channel(MAX_PENDING);
+        Arc::new(Utp {
+            socket,
+            socket6,
+            connections: Mutex::new(HashMap::new()),
+            incoming,
+            accepted: tokio::sync::Mutex::new(accepted),
+            receivers: Mutex::new(vec![]),
+        })
+    }
+
+    /**
+     * uTP on its own sockets, one per address family among `addrs`.
+     * Addresses that can't be bound are skipped as long as one can.
+     */
+    pub async fn bind(addrs: &[SocketAddr]) -> io::Result<Arc<Utp>> {
+        let mut sockets = [None, None];
+        let mut error = io::Error::new(io::ErrorKind::InvalidInput, "no address to bind");
+        for addr in addrs {
+            let socket = &mut sockets[addr.is_ipv6() as usize];
+            if socket.is_none() {
+                match bind_socket(*addr) {
+                    Ok(s) => *socket = Some(Arc::new(s)),
+                    Err(e) => error = e,
+                }
+            }
+        }
+        let [socket, socket6] = sockets;
+        if socket.is_none() && socket6.is_none() {
+            return Err(error);
+        }
+        let utp = Utp::new(socket, socket6);
+        let receivers = [&utp.socket, &utp.socket6]
+            .into_iter()
+            .flatten()
+            .map(|socket| tokio::spawn(receive(socket.clone(), Arc::downgrade(&utp))))
+            .collect();
+        *utp.receivers.lock().expect("Error unable to lock mutex!") = receivers;
+        Ok(utp)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self.socket.as_ref().or(self.socket6.as_ref()) {
+            Some(socket) => socket.local_addr(),
+            None => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    fn socket(&self, ipv6: bool) -> Option<&Arc<UdpSocket>> {
+        if ipv6 {
+            self.socket6.as_ref()
+        } else {
+            self.socket.as_ref()
+        }
+    }
+
+    fn remove(&self, remote: SocketAddr, recv_id: u16) {
+        let mut connections = self
+            .connections
+            .lock()
+            .expect("Error unable to lock mutex!");
+        connections.remove(&(remote, recv_id));
+    }
+
+    fn send_reset(&self, packet: &Packet, from: SocketAddr) {
+        if let Some(socket) = self.socket(from.is_ipv6()) {
+            let mut reset = Packet::new(
+                PacketType::Reset,
+                packet.connection_id,
+                random(),
+                packet.seq_nr,
+            );
+            reset.timestamp = timestamp();
+            send_packet(socket, &reset, from);
+        }
+    }
+
+    /**
+     * Handle a packet received on one of the sockets.
+     */
+    pub fn receive(self: &Arc<Self>, bytes: &[u8], from: SocketAddr) {
+        let packet = match Packet::decode(bytes) {
+            Some(p) => p,
+            None => return,
+        };
+        // a SYN is sent on the id the initiator receives on, the connection
+        // answering it receives on the next one
+        let recv_id = match packet.kind {
+            PacketType::Syn => packet.connection_id.wrapping_add(1),
+            _ => packet.connection_id,
+        };
+        let connection = {
+            let connections = self
+                .connections
+                .lock()
+                .expect("Error unable to lock mutex!");
+            connections.get(&(from, recv_id)).cloned()
+        };
+        match connection {
+            Some(connection) => {
+                let _ = connection.send(packet);
+            }
+            None if packet.kind == PacketType::Syn => self.accept_syn(packet, from),
+            None if packet.kind != PacketType::Reset => self.send_reset(&packet, from),
+            None => {}
+        }
+    }
+
+    fn accept_syn(self: &Arc<Self>, syn: Packet, from: SocketAddr) {
+        let socket = match self.socket(from.is_ipv6()) {
+            Some(s) => s.clone(),
+            None => return,
+        };
+        let recv_id = syn.connection_id.wrapping_add(1);
+        let (tx, rx) = unbounded_channel();
+        let permit = {
+            let mut connections = self
+                .connections
+                .lock()
+                .expect("Error unable to lock mutex!");
+            // a retransmitted SYN already has its connection
+            if connections.contains_key(&(from, recv_id)) {
+                return;
+            }
+            let permit = match self.incoming.try_reserve() {
+                Ok(p) => p,
+                Err(_) => {
+                    self.send_reset(&syn, from);
+                    return;
+                }
+            };
+            connections.insert((from, recv_id), tx);
+            permit
+        };
+        let mut connection = Connection::new(self.clone(), socket, from, recv_id);
+        connection.send_id = syn.connection_id;
+        connection.seq_nr = random();
+        connection.ack_nr = syn.seq_nr;
+        connection.last_ack = connection.seq_nr.wrapping_sub(1);
+        connection.reply_delay = timestamp().wrapping_sub(syn.timestamp);
+        connection.send_state();
+
+        let (stream, app) = tokio::io::duplex(RECEIVE_WINDOW);
+        tokio::spawn(connection.run(app, rx));
+        permit.send((UtpStream { stream, peer: from }, from));
+    }
+
+    /**
+     * Wait for the next incoming connection.
+     */
+    pub async fn accept(&self) -> (UtpStream, SocketAddr) {
+        let mut accepted = self.accepted.lock().await;
+        accepted
+            .recv()
+            .await
+            .expect("Error incoming connections closed!")
+    }
+
+    /**
+     * Connect to `addr`, fails once the SYN went unanswered a few times.
+     */
+    pub async fn connect(self: &Arc<Self>, addr: SocketAddr) -> io::Result<UtpStream> {
+        let socket = self
+            .socket(addr.is_ipv6())
+            .ok_or(io::ErrorKind::AddrNotAvailable)?
+            .clone();
+        let (tx, rx) = unbounded_channel();
+        let recv_id = {
+            let mut connections = self
+                .connections
+                .lock()
+                .expect("Error unable to lock mutex!");
+            let recv_id = loop {
+                let id: u16 = random();
+                if !connections.contains_key(&(addr, id))
+                    && !connections.contains_key(&(addr, id.wrapping_add(1)))
+                {
+                    break id;
+                }
+            };
+            connections.insert((addr, recv_id), tx);
+            recv_id
+        };
+        let mut connection = Connection::new(self.clone(), socket, addr, recv_id);
+        connection.send_id = recv_id.wrapping_add(1);
+        connection.state = State::SynSent;
+        let (connected, is_connected) = oneshot::channel();
+        connection.connected = Some(connected);
+        connection.send(PacketType::Syn, vec![]);
+        connection.last_ack = 0;
+
+        let (stream, app) = tokio::io::duplex(RECEIVE_WINDOW);
+        tokio::spawn(connection.run(app, rx));
+        is_connected.await.map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("uTP connection to {} failed", addr),
+            )
+        })?;
+        Ok(UtpStream { stream, peer: addr })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets() {
+        let mut packet = Packet::new(PacketType::State, 0x1234, 10, 7);
+        packet.timestamp = 1;
+        packet.timestamp_difference = 2;
+        packet.window = 3;
+        packet.selective_ack = Some(vec![0b101, 0, 0, 0]);
+        let bytes = packet.encode();
+        assert_eq!(&bytes[..4], &[0x21, EXTENSION_SELECTIVE_ACK, 0x12, 0x34]);
+        assert_eq!(Packet::decode(&bytes), Some(packet.clone()));
+        // 7 + 2 and 7 + 4 arrived, 7 + 3 didn't
+        assert!(packet.selectively_acks(9) && packet.selectively_acks(11));
+        assert!(!packet.selectively_acks(8) && !packet.selectively_acks(10));
+
+        let mut data = Packet::new(PacketType::Data, 1, 2, 3);
+        data.payload = b"d1:ad2:id20:".to_vec();
+        assert_eq!(Packet::decode(&data.encode()), Some(data));
+        // DHT messages aren't uTP packets
+        assert_eq!(
+            Packet::decode(b"d1:ad2:id20:xxxxxxxxxxxxxxxxxxxxe1:q4:ping1:t2:aa1:y1:qe"),
+            None
+        );
+
+        assert!(seq_less(65535, 0) && seq_less(1, 2) && !seq_less(2, 1));
+    }
+
+    #[tokio::test]
+    async fn pending_connections() {
+        let server = Utp::bind(&["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let from = client.local_addr().unwrap();
+        let syn = |id| Packet::new(PacketType::Syn, id, 1, 0).encode();
+        let count = || server.connections.lock().unwrap().len();
+        // a retransmitted SYN doesn't open another connection
+        server.receive(&syn(0), from);
+        server.receive(&syn(0), from);
+        assert_eq!(count(), 1);
+        // until `accept` takes some, SYNs past the limit are reset
+        for id in 1..MAX_PENDING as u16 {
+            server.receive(&syn(id * 2), from);
+        }
+        assert_eq!(count(), MAX_PENDING);
+        // retransmitted SYNs are dropped, not reset, when the queue is full
+        server.accept_syn(Packet::new(PacketType::Syn, 0, 1, 0), from);
+        let last = MAX_PENDING as u16 * 2;
+        server.receive(&syn(last), from);
+        assert_eq!(count(), MAX_PENDING);
+        let mut buf = [0; 1500];
+        let reset = loop {
+            let received = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf));
+            let (len, _) = received.await.unwrap().unwrap();
+            let packet = Packet::decode(&buf[..len]).unwrap();
+            if packet.kind == PacketType::Reset {
+                break packet;
+            }
+        };
+        assert_eq!(reset.connection_id, last);
+
+        assert_eq!(server.accept().await.1, from);
+        server.receive(&syn(last), from);
+        assert_eq!(count(), MAX_PENDING + 1);
+    }
+
+    #[tokio::test]
+    async fn transfer() {
+        let server = Utp::bind(&["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+        let client = Utp::bind(&["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let data: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+
+        let expected = data.clone();
+        let echo = tokio::spawn(async move {
+            let (mut stream, from) = server.accept().await;
+            assert_eq!(stream.peer_addr(), from);
+            let mut received = vec![0; expected.len()];
+            stream.read_exact(&mut received).await.unwrap();
+            assert!(received == expected);
+            stream.write_all(b"done").await.unwrap();
+            // the client closing is seen as the end of the stream
+            assert_eq!(stream.read(&mut received).await.unwrap(), 0);
+        });
+
+        let mut stream = client.connect(addr).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"done");
+        drop(stream);
+        tokio::time::timeout(Duration::from_secs(10), echo)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn shared_with_dht() {
+        use crate::dht::{Dht, NodeId};
+
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let node = Dht::bind(&[addr], NodeId::random()).await.unwrap();
+        let utp = node.start_utp();
+        let other = Dht::bind(&[addr], NodeId::random()).await.unwrap();
+        let port = node.local_addr().unwrap();
+        assert_eq!(utp.local_addr().unwrap(), port);
+
+        let client = Utp::bind(&[addr]).await.unwrap();
+        let accept = tokio::spawn(async move {
+            let (mut stream, _) = utp.accept().await;
+            stream.write_all(b"hello").await.unwrap();
+            stream
+        });
+        let mut stream = client.connect(port).await.unwrap();
+        // the node still answers DHT queries on the port
+        assert!(other.ping(port).await.is_ok());
+        let mut hello = [0; 5];
+        stream.read_exact(&mut hello).await.unwrap();
+        assert_eq!(&hello, b"hello");
+        drop(accept.await.unwrap());
+    }
+}
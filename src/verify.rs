@@ -0,0 +1,183 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread,
+};
+
+use sha1_smol::Sha1;
+
+use crate::{parse_torrent::torrent_info::TorrentMetadata, storage::Storage};
+
+// Recheck of data already on disk:
+// Every piece is read back through `Storage` and hashed against `TorrentMetadata.pieces`.
+// Workers pull the next piece index from a shared counter, so slow disks or
+// large pieces don't leave threads idle. Results are funneled back to the
+// caller thread which reports progress.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceStatus {
+    Valid,
+    // one of the files backing the piece is absent or too short,
+    // or the piece is still zeroed out by preallocation
+    Missing,
+    // all bytes are present but the hash does not match
+    Corrupt,
+}
+
+#[derive(Debug)]
+pub struct VerifyReport {
+    pub statuses: Vec<PieceStatus>,
+}
+
+impl VerifyReport {
+    pub fn valid_pieces(&self) -> impl Iterator<Item = usize> + '_ {
+        self.pieces_with(PieceStatus::Valid)
+    }
+
+    pub fn corrupt_pieces(&self) -> impl Iterator<Item = usize> + '_ {
+        self.pieces_with(PieceStatus::Corrupt)
+    }
+
+    pub fn missing_pieces(&self) -> impl Iterator<Item = usize> + '_ {
+        self.pieces_with(PieceStatus::Missing)
+    }
+
+    fn pieces_with(&self, status: PieceStatus) -> impl Iterator<Item = usize> + '_ {
+        self.statuses
+            .iter()
+            .enumerate()
+            .filter(move |(_, s)| **s == status)
+            .map(|(i, _)| i)
+    }
+
+    pub fn is_corrupt(&self) -> bool {
+        self.corrupt_pieces().next().is_some()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.statuses.iter().all(|s| *s == PieceStatus::Valid)
+    }
+}
+
+pub fn check_piece(metadata: &TorrentMetadata, index: usize, data: &[u8]) -> bool {
+    let mut hasher = Sha1::new();
+    hasher.update(data);
+    metadata.piece_hash(index) == Some(&hasher.digest().bytes()[..])
+}
+
+fn verify_piece(storage: &Storage, metadata: &TorrentMetadata, index: usize) -> PieceStatus {
+    match storage.read_piece(index) {
+        Ok(data) if check_piece(metadata, index, &data) => PieceStatus::Valid,
+        Ok(data) if data.iter().all(|b| *b == 0) => PieceStatus::Missing,
+        Ok(_) => PieceStatus::Corrupt,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => PieceStatus::Missing,
+        Err(e) => {
+            println!("could not read piece {}: {}", index, e);
+            PieceStatus::Missing
+        }
+    }
+}
+
+/**
+ * Hash every piece using `workers` threads.
+ * `on_progress` is called with (checked, total) after each piece.
+ */
+pub fn verify<F>(
+    storage: &Storage,
+    metadata: &TorrentMetadata,
+    workers: usize,
+    mut on_progress: F,
+) -> VerifyReport
+where
+    F: FnMut(usize, usize),
+{
+    let total = metadata.piece_count();
+    let next = AtomicUsize::new(0);
+    let mut statuses = vec![PieceStatus::Missing; total];
+    let (tx, rx) = mpsc::channel();
+
+    thread::scope(|s| {
+        for _ in 0..workers.max(1) {
+            let tx = tx.clone();
+            let next = &next;
+            s.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                if index >= total {
+                    break;
+                }
+                let status = verify_piece(storage, metadata, index);
+                if tx.send((index, status)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+
+        for (checked, (index, status)) in rx.iter().enumerate() {
+            statuses[index] = status;
+            on_progress(checked + 1, total);
+        }
+    });
+
+    VerifyReport { statuses }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Allocation;
+    use std::{fs, path::Path};
+
+    fn hash(data: &[u8]) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        hasher.update(data);
+        hasher.digest().bytes().to_vec()
+    }
+
+    #[test]
+    fn verify_detects_corrupt_and_missing() {
+        let data: Vec<u8> = (0..10).collect();
+        let mut pieces = vec![];
+        for chunk in data.chunks(4) {
+            pieces.append(&mut hash(chunk));
+        }
+        let metadata = TorrentMetadata {
+            pieces,
+            piece_length: 4,
+            length: 10,
+            name: String::from("file"),
+            private: false,
+            files: None,
+        };
+        let dir = std::env::temp_dir().join(format!("verify-test-{}", std::process::id()));
+        let storage = Storage::new(&dir, &metadata, Allocation::Sparse);
+
+        let report = verify(&storage, &metadata, 2, |_, _| {});
+        assert_eq!(report.missing_pieces().count(), 3);
+        assert!(!report.is_corrupt());
+
+        // preallocated but never written pieces are not corrupt
+        storage.allocate().unwrap();
+        let report = verify(&storage, &metadata, 2, |_, _| {});
+        assert_eq!(report.missing_pieces().count(), 3);
+
+        let mut on_disk = data.clone();
+        on_disk[5] = 0xff;
+        storage.write(0, &on_disk[..8]).unwrap();
+
+        let mut progress = vec![];
+        let report = verify(&storage, &metadata, 4, |checked, total| {
+            progress.push((checked, total))
+        });
+        assert_eq!(progress.last(), Some(&(3, 3)));
+        assert_eq!(report.valid_pieces().collect::<Vec<_>>(), vec![0]);
+        assert_eq!(report.corrupt_pieces().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(report.missing_pieces().collect::<Vec<_>>(), vec![2]);
+        assert!(report.is_corrupt());
+        assert!(Path::new(&dir.join("file")).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,513 @@
+use std::{error::Error, fmt, time::Duration};
+
+use reqwest::{header::RANGE, Client, Response, StatusCode};
+use url::form_urlencoded::byte_serialize;
+
+use crate::{
+    parse_torrent::torrent_info::TorrentMetadata,
+    proxy::Proxy,
+    queue::{store_piece, SharedTorrentState},
+    storage::Storage,
+};
+
+// Web seeds (BEP 19): HTTP servers hosting the files of the torrent, listed
+// in its `url-list`. Each web seed is a peer that has every piece, so the
+// picker hands it pieces like any other peer, and a piece is fetched with
+// one `Range` request per file it overlaps:
+// - single-file torrents: the URL is the file, or a directory holding it if
+//   it ends with `/`
+// - multi-file torrents: the URL is a directory holding `<name>/<path...>`
+// HTTP seeds (BEP 17), listed in `httpseeds`, are scripts serving pieces
+// by index instead: `<url>?info_hash=<hash>&piece=<index>&ranges=0-<last>`,
+// the ranges being inclusive byte offsets in the piece. A busy script
+// answers 503 with the number of seconds to wait as the body.
+// Pieces are verified like those of peers. After an error the web seed
+// waits before its next request, twice as long after every failure in a
+// row, and is given up after too many of them.
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+// failures in a row before the web seed is given up
+const MAX_FAILURES: u32 = 8;
+// wait for a piece to become available, e.g. released by a peer
+const IDLE_WAIT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WebSeed {
+    // BEP 19 server of the files
+    Url(String),
+    // BEP 17 script serving pieces
+    HttpSeed(String),
+}
+
+impl fmt::Display for WebSeed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WebSeed::Url(url) | WebSeed::HttpSeed(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+pub enum Fetched {
+    Piece(Vec<u8>),
+    // the server is busy and asked to come back later
+    RetryAfter(Duration),
+}
+
+/**
+ * Escape a path segment for a URL, keeping unreserved characters.
+ */
+fn escape_segment(segment: &str) -> String {
+    let mut escaped = String::new();
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                escaped.push(byte as char)
+            }
+            _ => escaped.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    escaped
+}
+
+/**
+ * URL of every file of the torrent on the web seed at `base`.
+ */
+pub fn file_urls(base: &str, metadata: &TorrentMetadata) -> Vec<String> {
+    let name = escape_segment(&metadata.name);
+    match &metadata.files {
+        None if base.ends_with('/') => vec![format!("{}{}", base, name)],
+        None => vec![base.to_string()],
+        Some(files) => {
+            let separator = if base.ends_with('/') { "" } else { "/" };
+            files
+                .iter()
+                .map(|f| {
+                    let path: Vec<String> = f.path.iter().map(|p| escape_segment(p)).collect();
+                    format!("{}{}{}/{}", base, separator, name, path.join("/"))
+                })
+                .collect()
+        }
+    }
+}
+
+/**
+ * Byte ranges of the files covering `length` bytes at `offset` in the
+ * torrent, as (file index, start, end inclusive).
+ */
+pub fn file_ranges(metadata: &TorrentMetadata, offset: u64, length: u64) -> Vec<(usize, u64, u64)> {
+    let lengths: Vec<u64> = match &metadata.files {
+        None => vec![metadata.length as u64],
+        Some(files) => files.iter().map(|f| f.length as u64).collect(),
+    };
+    let mut ranges = vec![];
+    let mut file_offset = 0;
+    for (i, file_length) in lengths.into_iter().enumerate() {
+        let start = offset.max(file_offset);
+        let end = (offset + length).min(file_offset + file_length);
+        if start < end {
+            ranges.push((i, start - file_offset, end - file_offset - 1));
+        }
+        file_offset += file_length;
+    }
+    ranges
+}
+
+/**
+ * Read the body up to `limit` bytes, the rest isn't downloaded. Returns
+ * whether the body was longer.
+ */
+async fn read_prefix(
+    mut response: Response,
+    limit: usize,
+) -> Result<(Vec<u8>, bool), Box<dyn Error>> {
+    let mut body = vec![];
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > limit {
+            body.extend_from_slice(&chunk[..(limit - body.len())]);
+            return Ok((body, true));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok((body, false))
+}
+
+/**
+ * Fetch the bytes `start..=end` of the file at `url`.
+ */
+async fn fetch_range(
+    client: &Client,
+    url: &str,
+    start: u64,
+    end: u64,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let response = client
+        .get(url)
+        .header(RANGE, format!("bytes={}-{}", start, end))
+        .send()
+        .await?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(format!("{} for {}", status, url).into());
+    }
+    let length = (end - start + 1) as usize;
+    match status {
+        StatusCode::PARTIAL_CONTENT => {
+            if response
+                .content_length()
+                .is_some_and(|l| l != length as u64)
+            {
+                return Err(format!("wrong response length for {}", url).into());
+            }
+            match read_prefix(response, length).await? {
+                (body, false) if body.len() == length => Ok(body),
+                _ => Err(format!("wrong response length for {}", url).into()),
+            }
+        }
+        // the server ignored the range and sends the whole file, which is
+        // only read up to the end of the range
+        StatusCode::OK => {
+            let (body, _) = read_prefix(response, end as usize + 1).await?;
+            if body.len() <= end as usize {
+                return Err(format!("short response for {}", url).into());
+            }
+            Ok(body[start as usize..].to_vec())
+        }
+        _ => Err(format!("short response for {}", url).into()),
+    }
+}
+
+/**
+ * Download piece `index` from the web seed at `base`.
+ */
+pub async fn fetch_piece(
+    client: &Client,
+    base: &str,
+    metadata: &TorrentMetadata,
+    index: usize,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let urls = file_urls(base, metadata);
+    let offset = index as u64 * metadata.piece_length as u64;
+    let length = metadata.piece_size(index) as u64;
+    let mut data = Vec::with_capacity(length as usize);
+    for (file, start, end) in file_ranges(metadata, offset, length) {
+        data.extend(fetch_range(client, &urls[file], start, end).await?);
+    }
+    Ok(data)
+}
+
+/**
+ * Download piece `index` of `size` bytes from the HTTP seed script at `url`.
+ */
+pub async fn fetch_http_seed_piece(
+    client: &Client,
+    url: &str,
+    info_hash: &[u8],
+    index: usize,
+    size: u32,
+) -> Result<Fetched, Box<dyn Error>> {
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let url = format!(
+        "{}{}info_hash={}&piece={}&ranges=0-{}",
+        url,
+        separator,
+        byte_serialize(info_hash).collect::<String>(),
+        index,
+        size - 1
+    );
+    let response = client.get(&url).send().await?;
+    let status = response.status();
+    let (body, longer) = read_prefix(response, size as usize).await?;
+    if longer && status == StatusCode::OK {
+        return Err(format!("response longer than piece {}", index).into());
+    }
+    let retry_after = std::str::from_utf8(&body)
+        .ok()
+        .and_then(|b| b.trim().parse().ok());
+    match (status, retry_after) {
+        (StatusCode::SERVICE_UNAVAILABLE, Some(seconds)) => {
+            Ok(Fetched::RetryAfter(Duration::from_secs(seconds)))
+        }
+        (StatusCode::OK, _) if body.len() == size as usize => Ok(Fetched::Piece(body)),
+        // some scripts answer busy with a 200
+        (StatusCode::OK, Some(seconds)) => Ok(Fetched::RetryAfter(Duration::from_secs(seconds))),
+        (StatusCode::OK, None) => Err(format!("short response for piece {}", index).into()),
+        (status, _) => Err(format!("{} for piece {}", status, index).into()),
+    }
+}
+
+/**
+ * Download pieces from the web seed tracked as peer `peer_index` until the
+ * torrent is complete or paused, through `proxy` if given.
+ */
+pub async fn download(
+    state: &SharedTorrentState,
+    storage: &Storage,
+    peer_index: usize,
+    proxy: Option<&Proxy>,
+) -> Result<(), Box<dyn Error>> {
+    let seed = state.web_seed(peer_index).ok_or("not a web seed")?;
+    let metadata = state.metadata();
+    let info_hash = state.info_hash();
+    let mut builder = Client::builder().timeout(REQUEST_TIMEOUT);
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(proxy.to_reqwest()?);
+    }
+    let client = builder.build()?;
+    let mut failures = 0;
+    loop {
+        if state.is_complete() || state.paused_reason().is_some() {
+            return Ok(());
+        }
+        let index = match state.claim_piece(peer_index, false) {
+            Some(i) => i,
+            None => {
+                tokio::time::sleep(IDLE_WAIT).await;
+                continue;
+            }
+        };
+        let fetched = match &seed {
+            WebSeed::Url(url) => fetch_piece(&client, url, &metadata, index)
+                .await
+                .map(Fetched::Piece),
+            WebSeed::HttpSeed(url) => {
+                let size = metadata.piece_size(index) as u32;
+                fetch_http_seed_piece(&client, url, &info_hash, index, size).await
+            }
+        }
+        .map_err(|e| e.to_string());
+        let valid = match fetched {
+            Ok(Fetched::Piece(data)) => {
+                let valid = store_piece(state, storage, index, &data)?;
+                // only verified pieces count towards the download
+                if valid {
+                    state.record_download(peer_index, data.len() as u64);
+                }
+                valid
+            }
+            // being busy isn't a failure
+            Ok(Fetched::RetryAfter(wait)) => {
+                println!("web seed {} busy, retrying in {:?}", seed, wait);
+                state.release_piece(index);
+                tokio::time::sleep(wait.min(MAX_BACKOFF)).await;
+                continue;
+            }
+            Err(e) => {
+                println!("web seed {}: {}", seed, e);
+                state.release_piece(index);
+                false
+            }
+        };
+        if valid {
+            failures = 0;
+            continue;
+        }
+        failures += 1;
+        if failures >= MAX_FAILURES {
+            return Err(format!("{} failures in a row", failures).into());
+        }
+        let backoff = MIN_BACKOFF * 2u32.pow(failures - 1);
+        tokio::time::sleep(backoff.min(MAX_BACKOFF)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        parse_torrent::torrent_info::{FileInfo, TorrentInfo},
+        parse_tracker_res::peers::PeerList,
+        queue::{tests::test_torrent, TorrentState},
+        storage::Allocation,
+    };
+    use std::{collections::HashMap, sync::Arc};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    async fn read_head(stream: &mut TcpStream) -> String {
+        let mut head = vec![];
+        let mut byte = [0];
+        while !head.ends_with(b"\r\n\r\n") && stream.read(&mut byte).await.unwrap_or(0) == 1 {
+            head.push(byte[0]);
+        }
+        String::from_utf8_lossy(&head).to_string()
+    }
+
+    async fn respond(stream: &mut TcpStream, status: &str, body: &[u8]) {
+        let head = format!(
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await.ok();
+        stream.write_all(body).await.ok();
+    }
+
+    /**
+     * Stand-in web server answering `Range` requests for `files` by path,
+     * and 503 for anything else.
+     */
+    async fn serve(listener: TcpListener, files: HashMap<String, Vec<u8>>) {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let head = read_head(&mut stream).await;
+            let path = head.split(' ').nth(1).unwrap_or("").to_string();
+            let range = head.lines().find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                let range = name.eq_ignore_ascii_case("range").then_some(value.trim())?;
+                range.strip_prefix("bytes=")
+            });
+            match (files.get(&path), range) {
+                (Some(file), Some(range)) => {
+                    let (start, end) = range.split_once('-').unwrap();
+                    let (start, end): (usize, usize) =
+                        (start.parse().unwrap(), end.parse().unwrap());
+                    let body = &file[start..=end.min(file.len() - 1)];
+                    respond(&mut stream, "206 Partial Content", body).await;
+                }
+                _ => respond(&mut stream, "503 Service Unavailable", b"").await,
+            }
+        }
+    }
+
+    /**
+     * Stand-in HTTP seed script of a torrent with `piece_length` byte pieces,
+     * busy for its first request.
+     */
+    async fn serve_script(listener: TcpListener, content: Vec<u8>, piece_length: usize) {
+        let mut busy = true;
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let head = read_head(&mut stream).await;
+            if std::mem::take(&mut busy) {
+                respond(&mut stream, "503 Service Unavailable", b"0").await;
+                continue;
+            }
+            let path = head.split(' ').nth(1).unwrap_or("");
+            let query = path.split_once('?').map_or("", |(_, q)| q);
+            let param = |key: &str| {
+                query
+                    .split('&')
+                    .find_map(|p| p.strip_prefix(key)?.strip_prefix('='))
+                    .unwrap_or("")
+            };
+            let piece: usize = param("piece").parse().unwrap();
+            let (start, end) = param("ranges").split_once('-').unwrap();
+            let (start, end): (usize, usize) = (start.parse().unwrap(), end.parse().unwrap());
+            let offset = piece * piece_length;
+            let body = &content[offset + start..=offset + end];
+            respond(&mut stream, "200 OK", body).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn web_seed_download() {
+        let content: Vec<u8> = (0..22).collect();
+        let mut metadata = TorrentMetadata {
+            pieces: vec![],
+            piece_length: 8,
+            length: 22,
+            name: String::from("my files"),
+            private: false,
+            files: Some(vec![
+                FileInfo {
+                    length: 5,
+                    path: vec![String::from("a.txt")],
+                },
+                FileInfo {
+                    length: 0,
+                    path: vec![String::from("empty")],
+                },
+                FileInfo {
+                    length: 17,
+                    path: vec![String::from("dir"), String::from("b&c.bin")],
+                },
+            ]),
+        };
+        for piece in content.chunks(8) {
+            metadata
+                .pieces
+                .extend(sha1_smol::Sha1::from(piece).digest().bytes());
+        }
+        assert_eq!(
+            file_urls("http://host/seed", &metadata),
+            vec![
+                "http://host/seed/my%20files/a.txt",
+                "http://host/seed/my%20files/empty",
+                "http://host/seed/my%20files/dir/b%26c.bin"
+            ]
+        );
+        assert_eq!(file_ranges(&metadata, 0, 8), vec![(0, 0, 4), (2, 0, 2)]);
+        assert_eq!(file_ranges(&metadata, 16, 6), vec![(2, 11, 16)]);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}/", listener.local_addr().unwrap());
+        let files = HashMap::from([
+            (String::from("/my%20files/a.txt"), content[..5].to_vec()),
+            (
+                String::from("/my%20files/dir/b%26c.bin"),
+                content[5..].to_vec(),
+            ),
+        ]);
+        tokio::spawn(serve(listener, files));
+
+        let client = Client::new();
+        let piece = fetch_piece(&client, &base, &metadata, 0).await.unwrap();
+        assert_eq!(piece, content[..8]);
+        let missing = fetch_piece(&client, "http://127.0.0.1:1/", &metadata, 0).await;
+        assert!(missing.is_err());
+        let mut single = metadata.clone();
+        single.files = None;
+        let unavailable = fetch_piece(&client, &base, &single, 0).await;
+        assert!(unavailable.unwrap_err().to_string().starts_with("503"));
+
+        // a server ignoring the range is only read up to its end
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/whole", listener.local_addr().unwrap());
+        let whole = content.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                read_head(&mut stream).await;
+                respond(&mut stream, "200 OK", &whole).await;
+            }
+        });
+        let range = fetch_range(&client, &url, 2, 5).await.unwrap();
+        assert_eq!(range, content[2..=5]);
+
+        // the web seed is mixed with the peers and downloads every piece
+        let info = TorrentInfo {
+            url_list: vec![base.clone()],
+            info_data: metadata.clone(),
+            ..test_torrent(3, 8)
+        };
+        let dir = std::env::temp_dir().join(format!("webseed-test-{}", std::process::id()));
+        let storage = Storage::new(&dir, &metadata, Allocation::Compact);
+        let mut torrent_state = TorrentState::new(info.clone(), &PeerList::default());
+        let peer_index = torrent_state.add_web_seed(WebSeed::Url(base.clone()));
+        let state = Arc::new(SharedTorrentState::new(torrent_state));
+        download(&state, &storage, peer_index, None).await.unwrap();
+        assert!(state.is_complete());
+        assert_eq!(storage.read(0, 22).unwrap(), content);
+        std::fs::remove_dir_all(&dir).ok();
+
+        // HTTP seeds ask to come back when busy
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let script = format!("http://{}/seed.php", listener.local_addr().unwrap());
+        tokio::spawn(serve_script(listener, content.clone(), 8));
+        let info_hash = [0x12; 20];
+        let busy = fetch_http_seed_piece(&client, &script, &info_hash, 2, 6).await;
+        assert!(matches!(busy, Ok(Fetched::RetryAfter(d)) if d.is_zero()));
+        let piece = fetch_http_seed_piece(&client, &script, &info_hash, 2, 6).await;
+        assert!(matches!(piece, Ok(Fetched::Piece(p)) if p == content[16..]));
+
+        let dir = std::env::temp_dir().join(format!("httpseed-test-{}", std::process::id()));
+        let storage = Storage::new(&dir, &metadata, Allocation::Compact);
+        let mut torrent_state = TorrentState::new(info, &PeerList::default());
+        let peer_index = torrent_state.add_web_seed(WebSeed::HttpSeed(script));
+        let state = Arc::new(SharedTorrentState::new(torrent_state));
+        download(&state, &storage, peer_index, None).await.unwrap();
+        assert_eq!(storage.read(0, 22).unwrap(), content);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}